@@ -0,0 +1,277 @@
+//! HTTP/JSON admin API for inspecting and managing a running broker, and a
+//! client for it.
+//!
+//! | Request | Response |
+//! |---|---|
+//! | `GET /clients` | every connected client, as [`ClientInfo`] |
+//! | `GET /clients/{id}` | one client |
+//! | `DELETE /clients/{id}` | disconnects the client |
+//! | `GET /topics` | every subscribed topic with its subscribers, as [`TopicInfo`] |
+//! | `GET /topics/{topic}` | the subscribers of one topic |
+//! | `GET /scheduled` | every delayed publish waiting for its time, as [`ScheduledInfo`] |
+//! | `DELETE /scheduled/{id}` | cancels a delayed publish |
+
+use std::{collections::BTreeMap, io, sync::atomic::Ordering};
+
+use serde::{Deserialize, Serialize};
+use tokio::net::{TcpListener, TcpStream};
+use tracing::{debug, info};
+
+use crate::{
+    broker::{Broker, ClientId, Session},
+    http::{self, Response},
+    schedule::Scheduled,
+};
+
+/// A connected client.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ClientInfo {
+    /// Connection id, unique within the broker; what `DELETE` takes.
+    pub id: ClientId,
+    pub peer: String,
+    pub core: usize,
+    /// Empty until the client logs in.
+    pub client_id: String,
+    pub username: Option<String>,
+    pub subscriptions: Vec<u16>,
+    /// Publishes waiting to be sent to the client.
+    pub queued: u64,
+    /// Payload bytes received from the client.
+    pub bytes_in: u64,
+    /// Payload bytes sent to the client.
+    pub bytes_out: u64,
+    /// Publishes of the client that were over a rate limit.
+    pub throttled: u64,
+}
+
+impl From<&Session> for ClientInfo {
+    fn from(session: &Session) -> Self {
+        ClientInfo {
+            id: session.id,
+            peer: session.peer.clone(),
+            core: session.core,
+            client_id: session.client_id.read().unwrap().clone(),
+            username: session.username.read().unwrap().clone(),
+            subscriptions: session
+                .subscriptions
+                .read()
+                .unwrap()
+                .iter()
+                .copied()
+                .collect(),
+            queued: session.queued.load(Ordering::Relaxed),
+            bytes_in: session.bytes_in.load(Ordering::Relaxed),
+            bytes_out: session.bytes_out.load(Ordering::Relaxed),
+            throttled: session.throttled.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// A topic and the clients subscribed to it.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TopicInfo {
+    pub topic: u16,
+    pub subscribers: Vec<ClientId>,
+}
+
+/// A delayed publish waiting for its time.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ScheduledInfo {
+    /// Unique within the broker, and across its restarts if it has a store;
+    /// what `DELETE` takes.
+    pub id: u64,
+    pub topic: u16,
+    pub qos: u8,
+    pub retain: bool,
+    /// When it is routed, in milliseconds since the Unix epoch.
+    pub due: u64,
+    /// Payload bytes.
+    pub size: usize,
+}
+
+impl From<&Scheduled> for ScheduledInfo {
+    fn from(scheduled: &Scheduled) -> Self {
+        let packet = &scheduled.packet;
+        ScheduledInfo {
+            id: scheduled.id,
+            topic: packet.topic_name,
+            qos: packet.qos as u8,
+            retain: packet.retain,
+            due: scheduled.due,
+            size: packet.payload.len(),
+        }
+    }
+}
+
+/// Serves the admin API on `listener` until it fails. It has no
+/// authentication of its own, so `listener` should only be reachable by
+/// operators.
+pub async fn serve(listener: TcpListener, broker: Broker) -> io::Result<()> {
+    loop {
+        let (stream, addr) = listener.accept().await?;
+        let broker = broker.clone();
+        tokio::spawn(async move {
+            if let Err(e) = respond(stream, &broker).await {
+                debug!(peer = %addr, error = %e, "admin request failed");
+            }
+        });
+    }
+}
+
+/// Answers one request and closes the connection.
+async fn respond(mut stream: TcpStream, broker: &Broker) -> io::Result<()> {
+    let Some(request) = http::read_request(&mut stream).await? else {
+        return Ok(());
+    };
+    let segments: Vec<&str> = request.path.trim_matches('/').split('/').collect();
+    let response = match (request.method.as_str(), segments.as_slice()) {
+        ("GET", ["clients"]) => json(&clients(broker)),
+        ("GET", ["clients", id]) => match id.parse().ok().and_then(|id| client(broker, id)) {
+            Some(client) => json(&client),
+            None => Response::text("404 Not Found", "no such client"),
+        },
+        ("DELETE", ["clients", id]) => match id.parse() {
+            Ok(id) if broker.disconnect(id) => {
+                info!(conn = id, "client disconnected through the admin API");
+                Response::text("200 OK", "disconnected")
+            }
+            _ => Response::text("404 Not Found", "no such client"),
+        },
+        ("GET", ["topics"]) => json(&topics(broker)),
+        ("GET", ["topics", topic]) => match topic.parse() {
+            Ok(topic) => json(&subscribers(broker, topic)),
+            Err(_) => Response::text("404 Not Found", "no such topic"),
+        },
+        ("GET", ["scheduled"]) => {
+            let scheduled: Vec<ScheduledInfo> =
+                broker.scheduled().iter().map(ScheduledInfo::from).collect();
+            json(&scheduled)
+        }
+        ("DELETE", ["scheduled", id]) => {
+            match id.parse().map(|id| (id, broker.cancel_scheduled(id))) {
+                Ok((id, Ok(true))) => {
+                    info!(id, "scheduled publish cancelled through the admin API");
+                    Response::text("200 OK", "cancelled")
+                }
+                Ok((_, Err(e))) => Response::text("500 Internal Server Error", &e.to_string()),
+                _ => Response::text("404 Not Found", "no such scheduled publish"),
+            }
+        }
+        (_, ["clients"] | ["clients", _] | ["topics"] | ["topics", _])
+        | (_, ["scheduled"] | ["scheduled", _]) => {
+            Response::text("405 Method Not Allowed", "method not allowed")
+        }
+        _ => Response::text("404 Not Found", "not found"),
+    };
+    http::write_response(&mut stream, response).await
+}
+
+fn json(value: &impl Serialize) -> Response {
+    Response {
+        status: "200 OK",
+        content_type: "application/json",
+        body: serde_json::to_string(value).expect("admin responses serialize"),
+    }
+}
+
+fn clients(broker: &Broker) -> Vec<ClientInfo> {
+    let mut clients: Vec<ClientInfo> = broker
+        .sessions()
+        .iter()
+        .map(|session| ClientInfo::from(&**session))
+        .collect();
+    clients.sort_by_key(|client| client.id);
+    clients
+}
+
+fn client(broker: &Broker, id: ClientId) -> Option<ClientInfo> {
+    broker
+        .sessions()
+        .iter()
+        .find(|session| session.id == id)
+        .map(|session| ClientInfo::from(&**session))
+}
+
+fn topics(broker: &Broker) -> Vec<TopicInfo> {
+    let mut topics = BTreeMap::<u16, Vec<ClientId>>::new();
+    for client in clients(broker) {
+        for topic in client.subscriptions {
+            topics.entry(topic).or_default().push(client.id);
+        }
+    }
+    topics
+        .into_iter()
+        .map(|(topic, subscribers)| TopicInfo { topic, subscribers })
+        .collect()
+}
+
+fn subscribers(broker: &Broker, topic: u16) -> TopicInfo {
+    let subscribers = clients(broker)
+        .into_iter()
+        .filter(|client| client.subscriptions.contains(&topic))
+        .map(|client| client.id)
+        .collect();
+    TopicInfo { topic, subscribers }
+}
+
+/// Talks to the admin API of a broker.
+pub struct AdminClient {
+    addr: String,
+}
+
+impl AdminClient {
+    /// `addr` is the `ADDR:PORT` the admin API listens on.
+    pub fn new(addr: &str) -> AdminClient {
+        AdminClient {
+            addr: addr.to_string(),
+        }
+    }
+
+    pub async fn clients(&self) -> io::Result<Vec<ClientInfo>> {
+        self.get("/clients").await
+    }
+
+    /// Fails with [`io::ErrorKind::NotFound`] if client `id` is not connected.
+    pub async fn client(&self, id: ClientId) -> io::Result<ClientInfo> {
+        self.get(&format!("/clients/{}", id)).await
+    }
+
+    pub async fn topics(&self) -> io::Result<Vec<TopicInfo>> {
+        self.get("/topics").await
+    }
+
+    pub async fn topic(&self, topic: u16) -> io::Result<TopicInfo> {
+        self.get(&format!("/topics/{}", topic)).await
+    }
+
+    /// Fails with [`io::ErrorKind::NotFound`] if client `id` is not connected.
+    pub async fn disconnect(&self, id: ClientId) -> io::Result<()> {
+        self.call("DELETE", &format!("/clients/{}", id)).await?;
+        Ok(())
+    }
+
+    pub async fn scheduled(&self) -> io::Result<Vec<ScheduledInfo>> {
+        self.get("/scheduled").await
+    }
+
+    /// Fails with [`io::ErrorKind::NotFound`] if delayed publish `id` is not
+    /// waiting, as once it has been routed.
+    pub async fn cancel(&self, id: u64) -> io::Result<()> {
+        self.call("DELETE", &format!("/scheduled/{}", id)).await?;
+        Ok(())
+    }
+
+    async fn get<T: for<'de> Deserialize<'de>>(&self, path: &str) -> io::Result<T> {
+        let body = self.call("GET", path).await?;
+        serde_json::from_str(&body).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    async fn call(&self, method: &str, path: &str) -> io::Result<String> {
+        let (status, body) = http::call(&self.addr, method, path).await?;
+        match status {
+            200..=299 => Ok(body),
+            404 => Err(io::Error::new(io::ErrorKind::NotFound, body.trim())),
+            _ => Err(io::Error::other(format!("{}: {}", status, body.trim()))),
+        }
+    }
+}
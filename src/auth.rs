@@ -0,0 +1,261 @@
+use std::{
+    collections::{hash_map::DefaultHasher, HashMap},
+    fs,
+    hash::{Hash, Hasher},
+    io,
+    ops::RangeInclusive,
+    path::Path,
+};
+
+use argon2::{password_hash::PasswordHash, Argon2, PasswordVerifier};
+use async_trait::async_trait;
+
+/// Checks the credentials clients present in their connect packet.
+#[async_trait]
+pub trait Authenticator: Send + Sync {
+    /// Returns whether `username` may log in with `password`.
+    async fn authenticate(&self, client_id: &str, username: &str, password: &[u8]) -> bool;
+
+    /// Identifies the credentials of `username`, such as a hash of their
+    /// entry, or `None` if there is no such user. On reload, sessions of users
+    /// whose fingerprint changed are closed, and the others are kept without
+    /// checking a password again. By default every user keeps their sessions.
+    async fn fingerprint(&self, _username: &str) -> Option<u64> {
+        Some(0)
+    }
+}
+
+/// Users loaded from a file of `username:hash` lines, such as one written by
+/// `htpasswd -B`. Hashes are bcrypt (`$2b$`, `$2y$`) or argon2 PHC strings
+/// (`$argon2id$`). Blank lines and lines starting with `#` are ignored.
+pub struct PasswordFile {
+    users: HashMap<String, String>,
+}
+
+impl PasswordFile {
+    pub fn load(path: &Path) -> io::Result<PasswordFile> {
+        let contents = fs::read_to_string(path)
+            .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path.display(), e)))?;
+        PasswordFile::parse(&contents).map_err(|e| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{}:{}", path.display(), e),
+            )
+        })
+    }
+
+    /// Parses password file contents. Errors are prefixed with the line number.
+    pub fn parse(contents: &str) -> Result<PasswordFile, String> {
+        let mut users = HashMap::new();
+        for (n, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let Some((username, hash)) = line.split_once(':') else {
+                return Err(format!("{}: expected username:hash", n + 1));
+            };
+            let valid = if hash.starts_with("$argon2") {
+                PasswordHash::new(hash).is_ok()
+            } else {
+                hash.starts_with("$2")
+            };
+            if !valid {
+                return Err(format!(
+                    "{}: {} has no bcrypt or argon2 hash",
+                    n + 1,
+                    username
+                ));
+            }
+            users.insert(username.to_string(), hash.to_string());
+        }
+
+        Ok(PasswordFile { users })
+    }
+}
+
+#[async_trait]
+impl Authenticator for PasswordFile {
+    async fn authenticate(&self, _client_id: &str, username: &str, password: &[u8]) -> bool {
+        let Some(hash) = self.users.get(username).cloned() else {
+            return false;
+        };
+        let password = password.to_vec();
+
+        // Both hashes are deliberately slow, so keep them off the broker's threads.
+        tokio::task::spawn_blocking(move || verify(&password, &hash))
+            .await
+            .unwrap_or(false)
+    }
+
+    async fn fingerprint(&self, username: &str) -> Option<u64> {
+        let mut hasher = DefaultHasher::new();
+        self.users.get(username)?.hash(&mut hasher);
+        Some(hasher.finish())
+    }
+}
+
+fn verify(password: &[u8], hash: &str) -> bool {
+    if hash.starts_with("$argon2") {
+        PasswordHash::new(hash)
+            .map(|hash| Argon2::default().verify_password(password, &hash).is_ok())
+            .unwrap_or(false)
+    } else {
+        bcrypt::verify(password, hash).unwrap_or(false)
+    }
+}
+
+/// What a client wants to do with a topic.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Access {
+    Publish,
+    Subscribe,
+}
+
+/// Decides which topics a client may publish or subscribe to.
+#[async_trait]
+pub trait Authorizer: Send + Sync {
+    /// Returns whether the client logged in as `username` (or anonymous, if
+    /// `None`) may `access` `topic`.
+    async fn authorize(
+        &self,
+        client_id: &str,
+        username: Option<&str>,
+        access: Access,
+        topic: u16,
+    ) -> bool;
+}
+
+/// Rules loaded from an ACL file in the style of mosquitto's:
+///
+/// ```text
+/// # Applies to every client, including anonymous ones.
+/// topic read 0-99
+///
+/// user alice
+/// topic readwrite 7
+/// topic write 100-199
+/// ```
+///
+/// `topic` lines grant `read` (subscribe), `write` (publish) or `readwrite`
+/// on a topic or an inclusive range of topics. Lines before the first `user`
+/// apply to everyone, later ones to the user named above them. Anything not
+/// granted is denied.
+pub struct AclFile {
+    everyone: Vec<Rule>,
+    users: HashMap<String, Vec<Rule>>,
+}
+
+struct Rule {
+    publish: bool,
+    subscribe: bool,
+    topics: RangeInclusive<u16>,
+}
+
+impl Rule {
+    fn allows(&self, access: Access, topic: u16) -> bool {
+        let granted = match access {
+            Access::Publish => self.publish,
+            Access::Subscribe => self.subscribe,
+        };
+        granted && self.topics.contains(&topic)
+    }
+}
+
+impl AclFile {
+    pub fn load(path: &Path) -> io::Result<AclFile> {
+        let contents = fs::read_to_string(path)
+            .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path.display(), e)))?;
+        AclFile::parse(&contents).map_err(|e| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{}:{}", path.display(), e),
+            )
+        })
+    }
+
+    /// Parses ACL file contents. Errors are prefixed with the line number.
+    pub fn parse(contents: &str) -> Result<AclFile, String> {
+        let mut acl = AclFile {
+            everyone: Vec::new(),
+            users: HashMap::new(),
+        };
+        let mut user = None;
+        for (n, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let words: Vec<&str> = line.split_whitespace().collect();
+            match words[..] {
+                ["user", username] => {
+                    acl.users.entry(username.to_string()).or_default();
+                    user = Some(username.to_string());
+                }
+                ["topic", access, topics] => {
+                    let (publish, subscribe) = match access {
+                        "read" => (false, true),
+                        "write" => (true, false),
+                        "readwrite" => (true, true),
+                        _ => return Err(format!("{}: unknown access {}", n + 1, access)),
+                    };
+                    let topics = parse_topics(topics)
+                        .ok_or_else(|| format!("{}: invalid topics {}", n + 1, topics))?;
+                    let rule = Rule {
+                        publish,
+                        subscribe,
+                        topics,
+                    };
+                    match &user {
+                        Some(user) => acl.users.entry(user.clone()).or_default().push(rule),
+                        None => acl.everyone.push(rule),
+                    }
+                }
+                _ => {
+                    return Err(format!(
+                        "{}: expected `user NAME` or `topic ACCESS TOPICS`",
+                        n + 1
+                    ))
+                }
+            }
+        }
+
+        Ok(acl)
+    }
+}
+
+/// A topic `N` or an inclusive range `LO-HI`.
+pub(crate) fn parse_topics(topics: &str) -> Option<RangeInclusive<u16>> {
+    match topics.split_once('-') {
+        Some((lo, hi)) => {
+            let (lo, hi) = (lo.parse().ok()?, hi.parse().ok()?);
+            (lo <= hi).then_some(lo..=hi)
+        }
+        None => {
+            let topic = topics.parse().ok()?;
+            Some(topic..=topic)
+        }
+    }
+}
+
+#[async_trait]
+impl Authorizer for AclFile {
+    async fn authorize(
+        &self,
+        _client_id: &str,
+        username: Option<&str>,
+        access: Access,
+        topic: u16,
+    ) -> bool {
+        let user_rules = username
+            .and_then(|username| self.users.get(username))
+            .into_iter()
+            .flatten();
+        self.everyone
+            .iter()
+            .chain(user_rules)
+            .any(|rule| rule.allows(access, topic))
+    }
+}
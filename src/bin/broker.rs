@@ -1,55 +1,69 @@
 use std::{collections::HashMap, sync::Arc};
 
-use bytes::{Buf, BufMut, BytesMut};
+use bytes::{Buf, BufMut};
 use clap::Parser;
 use futures::sink::SinkExt;
-use mqtiny::*;
-use tokio::{
-    io::AsyncWrite,
-    io::AsyncWriteExt,
-    net::{TcpListener, TcpStream},
-    pin,
-    sync::Mutex,
+use mqtiny::{
+    listener::{ListenAddr, Listener},
+    logging::LogArgs,
+    transport::Stream,
+    *,
 };
+use tokio::sync::Mutex;
 use tokio_stream::StreamExt;
-use tokio_util::codec::{Decoder, Encoder, Framed, FramedRead};
-
-const MAX_TOPIC: usize = 100;
-const NEW_CONNECTION: u64 = 100;
+use tokio_util::codec::{Decoder, Encoder, Framed};
+use tracing::{info, info_span, warn, Instrument};
 
 #[derive(Parser, Debug)]
 struct Args {
-    /// MQTiny service port
+    /// MQTiny service port, used when no --listen is given
     #[arg(short, long, default_value_t = 1883)]
     port: u16,
+
+    /// Address to listen on as ADDR:PORT, tls://ADDR:PORT, ws://ADDR:PORT or unix:///PATH, with options (repeatable)
+    #[arg(short, long)]
+    listen: Vec<ListenAddr>,
+
+    #[command(flatten)]
+    log: LogArgs,
 }
 
 #[tokio::main(flavor = "current_thread")]
 async fn main() {
-    let args = Args::parse();
-
-    let listener = TcpListener::bind(&format!("127.0.0.1:{}", args.port))
-        .await
-        .unwrap();
-    println!("Listening on 127.0.0.1:{}", args.port);
+    let mut args = Args::parse();
+    args.log.init().unwrap();
+    if args.listen.is_empty() {
+        args.listen
+            .push(ListenAddr::new(&format!("127.0.0.1:{}", args.port)));
+    }
 
+    let listeners = Listener::bind_all(&args.listen, false).await.unwrap();
     let table = Arc::new(Mutex::new(HashMap::new()));
 
-    loop {
-        let (client, _) = listener.accept().await.unwrap();
-
+    let accept_loops = listeners.into_iter().map(|listener| {
+        info!(listener = %listener.local_addr().unwrap(), "listening");
         let table = table.clone();
 
-        tokio::spawn(async move {
-            process(client, table).await;
-        });
-    }
+        async move {
+            loop {
+                let (client, addr, slot) = listener.accept().await.unwrap();
+
+                let table = table.clone();
+
+                tokio::spawn(
+                    async move {
+                        process(client, table).await;
+                        drop(slot);
+                    }
+                    .instrument(info_span!("connection", peer = %addr)),
+                );
+            }
+        }
+    });
+    futures::future::join_all(accept_loops).await;
 }
 
-async fn process(
-    client: TcpStream,
-    table: Arc<Mutex<HashMap<u16, Framed<TcpStream, MQTinyCodec>>>>,
-) {
+async fn process(client: Stream, table: Arc<Mutex<HashMap<u16, Framed<Stream, MQTinyCodec>>>>) {
     let codec = MQTinyCodec {};
     let mut framed = Framed::new(client, codec);
 
@@ -70,15 +84,13 @@ async fn process(
                                 variable_header: mqtiny::VariableHeader { topic_name: 0 },
                                 payload: None,
                             };
-                            framed.send(data);
-                            framed.flush();
+                            let _ = framed.send(data).await;
                         }
                         QoS::AtMostOnce => {}
                     }
 
                     if let Some(framed) = table.get_mut(&data.variable_header.topic_name) {
-                        framed.send(data);
-                        framed.flush();
+                        let _ = framed.send(data).await;
                     }
                 }
                 PacketType::Subscribe => {
@@ -88,7 +100,7 @@ async fn process(
                 }
                 _ => {}
             },
-            Err(err) => eprintln!("error: {:?}", err),
+            Err(err) => warn!(error = %err, "invalid packet"),
         }
     }
 }
@@ -174,26 +186,3 @@ impl Encoder<Packet> for MQTinyCodec {
         Ok(())
     }
 }
-
-fn encode(item: &Packet, dst: &mut bytes::BytesMut) -> Result<(), std::io::ErrorKind> {
-    let fixed_header_len = 2;
-    dst.reserve(fixed_header_len + item.fixed_header.remaining_length as usize);
-    dst.put_u8(((item.fixed_header.packet_type as u8) << 4) + ((item.fixed_header.qos as u8) << 1));
-    dst.put_u8(item.fixed_header.remaining_length);
-
-    match item.fixed_header.packet_type {
-        PacketType::Publish => {
-            dst.put_u16(item.variable_header.topic_name);
-            if let Some(payload) = item.payload {
-                dst.put(payload);
-            }
-        }
-        PacketType::Subscribe => {
-            dst.put_u16(item.variable_header.topic_name);
-        }
-        PacketType::Puback => {}
-        _ => {}
-    }
-
-    Ok(())
-}
@@ -1,12 +1,9 @@
 use clap::Parser;
+use mqtiny::client::{self, Client};
 use std::{
-    thread,
+    error::Error,
     time::{Duration, SystemTime, UNIX_EPOCH},
 };
-use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt},
-    net::TcpStream,
-};
 
 #[derive(Parser, Debug)]
 #[command(name = "MQTiny", author = "Ryo OUCHI")]
@@ -26,29 +23,53 @@ struct Args {
     /// Number of messages to publish
     #[arg(short, long, default_value_t = 5000)]
     messages: u32,
+
+    /// Topic to send requests on
+    #[arg(short, long, default_value_t = 1)]
+    topic: u16,
+
+    /// Topic to receive responses on
+    #[arg(short, long, default_value_t = 2)]
+    response_topic: u16,
+
+    /// Seconds to wait for each response
+    #[arg(long, default_value_t = 5)]
+    timeout: u64,
+
+    /// Answer the requests on --topic with their payload instead of sending requests
+    #[arg(long)]
+    respond: bool,
+}
+
+fn now() -> u128 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_nanos()
 }
 
 #[tokio::main]
-async fn main() {
+async fn main() -> Result<(), Box<dyn Error>> {
     let args = Args::parse();
-    let mut stream = TcpStream::connect(&format!("{}:{}", args.ip, args.port))
-        .await
-        .unwrap();
+    let mut client = Client::connect(&client::address(&args.ip, args.port)).await?;
 
-    for _ in 0..args.messages {
-        let mut request = Vec::new();
-        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
-        request.write_u128(now.as_nanos()).await.unwrap();
-        stream.write_all(&request).await.unwrap();
+    if args.respond {
+        client
+            .serve(args.topic, |request| async move { request.payload })
+            .await?;
+        return Ok(());
+    }
 
-        let a = stream.read_u128().await.unwrap();
-        let b = SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .unwrap()
-            .as_nanos();
+    client.subscribe_responses(args.response_topic).await?;
+    let timeout = Duration::from_secs(args.timeout);
+    for _ in 0..args.messages {
+        let sent = now().to_be_bytes().to_vec();
+        let response = client.request(args.topic, sent, timeout).await?;
+        let sent = u128::from_be_bytes(response.payload[..].try_into()?);
 
-        println!("{},", b - a);
+        println!("{},", now() - sent);
 
-        thread::sleep(Duration::from_micros(args.interval_of_msg));
+        tokio::time::sleep(Duration::from_micros(args.interval_of_msg)).await;
     }
+    Ok(())
 }
@@ -0,0 +1,154 @@
+use clap::{Parser, Subcommand};
+use mqtiny::{
+    admin::{AdminClient, ClientInfo, ScheduledInfo},
+    broker::ClientId,
+};
+use std::{
+    io, process,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+#[derive(Parser, Debug)]
+#[command(name = "mqtiny-admin", author = "Ryo OUCHI")]
+/// Inspects and manages a running broker through its admin API (--admin)
+struct Args {
+    /// Address of the broker's admin API
+    #[arg(short, long, default_value = "127.0.0.1:9101")]
+    admin: String,
+
+    /// Print the raw JSON responses
+    #[arg(long)]
+    json: bool,
+
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// List the connected clients
+    Clients,
+    /// Show one client
+    Client { id: ClientId },
+    /// List the subscribed topics and their subscribers, or those of TOPIC
+    Topics { topic: Option<u16> },
+    /// Disconnect a client
+    Kick { id: ClientId },
+    /// List the delayed publishes waiting for their time
+    Scheduled,
+    /// Cancel a delayed publish
+    Cancel { id: u64 },
+}
+
+#[tokio::main(flavor = "current_thread")]
+async fn main() {
+    let args = Args::parse();
+    if let Err(e) = run(&args).await {
+        eprintln!("{}", e);
+        process::exit(1);
+    }
+}
+
+async fn run(args: &Args) -> io::Result<()> {
+    let admin = AdminClient::new(&args.admin);
+    match args.command {
+        Command::Clients => {
+            let clients = admin.clients().await?;
+            if args.json {
+                print_json(&clients);
+            } else {
+                print_clients(&clients);
+            }
+        }
+        Command::Client { id } => {
+            let client = admin.client(id).await?;
+            if args.json {
+                print_json(&client);
+            } else {
+                print_clients(&[client]);
+            }
+        }
+        Command::Topics { topic } => {
+            let topics = match topic {
+                Some(topic) => vec![admin.topic(topic).await?],
+                None => admin.topics().await?,
+            };
+            if args.json {
+                print_json(&topics);
+            } else {
+                println!("{:<8} SUBSCRIBERS", "TOPIC");
+                for topic in topics {
+                    println!("{:<8} {}", topic.topic, join(&topic.subscribers));
+                }
+            }
+        }
+        Command::Kick { id } => {
+            admin.disconnect(id).await?;
+            println!("disconnected client {}", id);
+        }
+        Command::Scheduled => {
+            let scheduled = admin.scheduled().await?;
+            if args.json {
+                print_json(&scheduled);
+            } else {
+                print_scheduled(&scheduled);
+            }
+        }
+        Command::Cancel { id } => {
+            admin.cancel(id).await?;
+            println!("cancelled scheduled publish {}", id);
+        }
+    }
+    Ok(())
+}
+
+fn print_clients(clients: &[ClientInfo]) {
+    println!(
+        "{:<6} {:<24} {:<16} {:<12} {:>8} {:>10} {:>10}  SUBSCRIPTIONS",
+        "ID", "PEER", "CLIENT ID", "USERNAME", "QUEUED", "BYTES IN", "BYTES OUT"
+    );
+    for client in clients {
+        println!(
+            "{:<6} {:<24} {:<16} {:<12} {:>8} {:>10} {:>10}  {}",
+            client.id,
+            client.peer,
+            client.client_id,
+            client.username.as_deref().unwrap_or("-"),
+            client.queued,
+            client.bytes_in,
+            client.bytes_out,
+            join(&client.subscriptions),
+        );
+    }
+}
+
+fn print_scheduled(scheduled: &[ScheduledInfo]) {
+    println!(
+        "{:<6} {:<8} {:<4} {:<6} {:>8} {:>8}",
+        "ID", "TOPIC", "QOS", "RETAIN", "SIZE", "DUE IN"
+    );
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    for publish in scheduled {
+        let due_in = Duration::from_millis(publish.due).saturating_sub(now);
+        println!(
+            "{:<6} {:<8} {:<4} {:<6} {:>8} {:>7}s",
+            publish.id,
+            publish.topic,
+            publish.qos,
+            publish.retain,
+            publish.size,
+            due_in.as_secs(),
+        );
+    }
+}
+
+fn join(values: &[impl ToString]) -> String {
+    let values: Vec<_> = values.iter().map(ToString::to_string).collect();
+    values.join(",")
+}
+
+fn print_json(value: &impl serde::Serialize) {
+    println!("{}", serde_json::to_string_pretty(value).unwrap());
+}
@@ -1,36 +1,17 @@
 use clap::Parser;
-use std::{error::Error, thread};
-use tokio::{
-    io::AsyncWriteExt,
-    net::TcpStream,
-    time::{Duration, Instant},
+use mqtiny::{
+    client::{self, Client, ConnectOptions},
+    logging::LogArgs,
+    MqttPublishPacket, QoS,
 };
-
-#[allow(unused)]
-#[derive(Debug)]
-enum PacketType {
-    Unknown = 0,
-    Connect = 1,
-    Connack = 2,
-    Publish = 3,
-    Puback = 4,
-    Pubrec = 5,
-    Pubrel = 6,
-    Pubcomp = 7,
-    Subscribe = 8,
-    Suback = 9,
-    Unsubscribe = 10,
-    Unsuback = 11,
-    Pingreq = 12,
-    Pingresp = 13,
-    Disconnect = 14,
-    Reserved = 15,
-}
+use std::{error::Error, path::PathBuf, thread};
+use tokio::time::{Duration, Instant};
+use tracing::info;
 
 #[derive(Parser, Debug)]
 #[command(name = "MQTiny", author = "Ryo OUCHI")]
 struct Args {
-    /// Address of the MQTiny server to connect
+    /// Address of the MQTiny server to connect, optionally prefixed with tls:// or ws://, or unix:///PATH
     #[arg(short, long)]
     ip: String,
 
@@ -61,70 +42,103 @@ struct Args {
     /// QoS level
     #[arg(short, long, default_value_t = 0)]
     qos: u8,
+
+    /// Seconds after which a message is dropped if no subscriber has received it
+    #[arg(short, long)]
+    expiry: Option<u32>,
+
+    /// Seconds for the broker to hold each message back before delivering it
+    #[arg(short, long)]
+    delay: Option<u32>,
+
+    /// PEM CA certificate to verify a tls:// broker with [default: system roots]
+    #[arg(long)]
+    ca: Option<PathBuf>,
+
+    /// PEM client certificate, for brokers that require one
+    #[arg(long, requires = "key")]
+    cert: Option<PathBuf>,
+
+    /// PEM private key of --cert
+    #[arg(long, requires = "cert")]
+    key: Option<PathBuf>,
+
+    /// Username to log in with
+    #[arg(short, long)]
+    username: Option<String>,
+
+    /// Password to log in with
+    #[arg(short = 'P', long, requires = "username")]
+    password: Option<String>,
+    #[command(flatten)]
+    log: LogArgs,
 }
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error>> {
     let args = Args::parse();
+    args.log.init()?;
+    let addr = client::address(&args.ip, args.port);
+    let options = ConnectOptions {
+        ca: args.ca.clone(),
+        cert: args.cert.clone(),
+        key: args.key.clone(),
+        username: args.username.clone(),
+        password: args.password.clone(),
+        ..Default::default()
+    };
+    let qos = QoS::from_usize(args.qos.into()).ok_or("invalid QoS level")?;
 
     let mut connections = Vec::new();
     let mut handles = Vec::new();
 
     for _ in 0..args.count {
-        let stream = TcpStream::connect(&format!("{}:{}", args.ip, args.port))
-            .await
-            .unwrap();
-        connections.push(stream);
+        let client = Client::connect_with(&addr, &options).await.unwrap();
+        connections.push(client);
     }
 
-    println!("start publishing");
-
-    loop {
-        if let Some(mut stream) = connections.pop() {
-            handles.push(tokio::spawn(async move {
-                let start = Instant::now();
-
-                let mut request = Vec::new();
-                let packet_type = PacketType::Publish; // [7:4] in fixed header
-                let topic_length = 2;
-                let total_length = args.size + topic_length;
-                let payload = "A".repeat(args.size.into());
-
-                request.push(((packet_type as u8) << 4) as u8 + (args.qos << 1) as u8);
-                request.push(total_length as u8);
-                request.push((args.topic >> 8) as u8);
-                request.push((args.topic & 0xff) as u8);
-                request.extend_from_slice(payload.as_bytes());
-
-                // println!("{:?}", request);
-
-                let mut count = 0;
+    info!(clients = args.count, topic = args.topic, "start publishing");
+
+    while let Some(mut client) = connections.pop() {
+        handles.push(tokio::spawn(async move {
+            let start = Instant::now();
+
+            let packet = MqttPublishPacket {
+                topic_name: args.topic,
+                qos,
+                retain: false,
+                seq: None,
+                expiry: args.expiry,
+                delay: args.delay,
+                response_topic: None,
+                correlation_data: None,
+                payload: "A".repeat(args.size.into()).into_bytes(),
+            };
+
+            let mut count = 0;
+
+            client.publish_packet(packet.clone()).await.unwrap();
+            thread::sleep(Duration::from_millis(1000));
+            count += 1;
+
+            for _ in 0..args.messages - 1 {
+                //
+                // Send Publish packet
+                //
+                client.publish_packet(packet.clone()).await.unwrap();
+
+                if args.interval_of_msg != 0 {
+                    thread::sleep(Duration::from_millis(args.interval_of_msg));
+                }
 
-                stream.write_all(&request).await.unwrap();
-                thread::sleep(Duration::from_millis(1000));
                 count += 1;
+            }
 
-                for _ in 0..args.messages - 1 {
-                    //
-                    // Send Publish packet
-                    //
-                    stream.write_all(&request).await.unwrap();
-
-                    if args.interval_of_msg != 0 {
-                        thread::sleep(Duration::from_millis(args.interval_of_msg));
-                    }
-
-                    count += 1;
-                }
-
-                println!("published {} messages", count);
+            println!("published {} messages", count);
 
-                let elapsed = start.elapsed();
-                println!("{:?}", elapsed);
-            }));
-        } else {
-            break;
-        }
+            let elapsed = start.elapsed();
+            println!("{:?}", elapsed);
+        }));
     }
 
     futures::future::join_all(handles).await;
@@ -1,22 +1,28 @@
 use clap::Parser;
-use futures::SinkExt;
-use mqtiny::*;
-use std::{collections::HashMap, error::Error, io, net::SocketAddr, sync::Arc};
-use tokio::{
-    net::{TcpListener, TcpStream},
-    sync::{mpsc, Mutex},
+use mqtiny::{
+    listener::{ListenAddr, Listener},
+    logging::LogArgs,
+    transport::Stream,
+    *,
 };
+use std::{collections::HashMap, error::Error, io, sync::Arc};
+use tokio::sync::{mpsc, Mutex};
 use tokio_stream::StreamExt;
-use tokio_util::codec::{Decoder, Encoder, Framed};
-
-use bytes::{Buf, BufMut, BytesMut};
-use tokio::{io::AsyncWrite, io::AsyncWriteExt};
+use tokio_util::codec::Framed;
+use tracing::{info, info_span, trace, Instrument};
 
 #[derive(Parser, Debug)]
 struct Args {
-    /// MQTiny service port
+    /// MQTiny service port, used when no --listen is given
     #[arg(short, long, default_value_t = 1883)]
     port: u16,
+
+    /// Address to listen on as ADDR:PORT, tls://ADDR:PORT, ws://ADDR:PORT or unix:///PATH, with options (repeatable)
+    #[arg(short, long)]
+    listen: Vec<ListenAddr>,
+
+    #[command(flatten)]
+    log: LogArgs,
 }
 
 #[tokio::main]
@@ -24,23 +30,44 @@ async fn main() -> Result<(), Box<dyn Error>> {
     // Create the shared subscriber table.
     let subscription_table = Arc::new(Mutex::new(SubscriptionTable::new()));
     let clients = Arc::new(Mutex::new(Clients::new()));
-    let args = Args::parse();
-
-    let listener = TcpListener::bind(&format!("127.0.0.1:{}", args.port)).await?;
-
-    println!("Listening {}...", args.port);
+    let mut args = Args::parse();
+    args.log.init()?;
+    if args.listen.is_empty() {
+        args.listen
+            .push(ListenAddr::new(&format!("127.0.0.1:{}", args.port)));
+    }
 
-    loop {
-        let (stream, addr) = listener.accept().await?;
+    let listeners = Listener::bind_all(&args.listen, false).await?;
 
+    let accept_loops = listeners.into_iter().map(|listener| {
         let subscription_table = Arc::clone(&subscription_table);
         let clients = Arc::clone(&clients);
 
         tokio::spawn(async move {
-            process(subscription_table, clients, stream, addr)
-                .await
-                .unwrap();
-        });
+            info!(listener = %listener.local_addr()?, "listening");
+
+            loop {
+                let (stream, addr, slot) = listener.accept().await?;
+
+                let subscription_table = Arc::clone(&subscription_table);
+                let clients = Arc::clone(&clients);
+
+                tokio::spawn(
+                    async move {
+                        process(subscription_table, clients, stream).await.unwrap();
+                        drop(slot);
+                    }
+                    .instrument(info_span!("connection", peer = %addr)),
+                );
+            }
+
+            #[allow(unreachable_code)]
+            io::Result::Ok(())
+        })
+    });
+
+    for accept_loop in futures::future::join_all(accept_loops).await {
+        accept_loop??;
     }
 
     Ok(())
@@ -49,34 +76,35 @@ async fn main() -> Result<(), Box<dyn Error>> {
 async fn process(
     subscription_table: Arc<Mutex<SubscriptionTable>>,
     clients: Arc<Mutex<Clients>>,
-    stream: TcpStream,
-    addr: SocketAddr,
+    stream: Stream,
 ) -> Result<(), Box<dyn Error>> {
-    let mut framed = Framed::new(stream, MQTinyCodec {});
-    let mut client = Client::new(clients, framed).await?;
+    let framed = Framed::new(stream, MQTinyCodec::default());
+    let mut client = Client::new(Arc::clone(&clients), framed).await?;
 
     loop {
         tokio::select! {
             Some(msg)=client.rx.recv()=>{
-                println!("{:?}",msg);
+                trace!(size = msg.len(), "message for subscriber");
             }
             result=client.framed.next()=>match result{
                 Some(Ok(msg)) => {
                     match msg{
                         MqttPacket::Publish(publish)=>{
-                            println!("{:?}",publish);
+                            trace!(topic = publish.topic_name, size = publish.payload.len(), "publish");
                             let subscription_table=subscription_table.lock().await;
                             let subscriptions=subscription_table.get_subscriptions(&publish.topic_name);
                             let clients=clients.lock().await;
                             for subscription in subscriptions{
-                                let Some(tx)=clients.clients.get(&subscription);
-                                tx.send(publish.payload.into());
+                                if let Some(tx)=clients.clients.get(&subscription){
+                                    let _ = tx.send(publish.payload.clone());
+                                }
                             }
                         }
                         MqttPacket::Subscribe(subscribe) => {
                             let mut subscription_table=subscription_table.lock().await;
-                            subscription_table.add_subscription(subscribe.topic_name, client.framed.get_ref().peer_addr()?);
+                            subscription_table.add_subscription(subscribe.topic_name, client.id);
                         },
+                        _ => {},
                     }
                 },
                 Some(Err(_))=>{},
@@ -85,15 +113,13 @@ async fn process(
         }
     }
 
-    {
-        println!("{}:{} is disconnected.", addr.ip().to_string(), addr.port());
-    }
+    info!("client disconnected");
 
     Ok(())
 }
 
 struct SubscriptionTable {
-    subscriptions: HashMap<Topic, Vec<SocketAddr>>,
+    subscriptions: HashMap<Topic, Vec<ClientId>>,
 }
 impl SubscriptionTable {
     fn new() -> SubscriptionTable {
@@ -101,48 +127,48 @@ impl SubscriptionTable {
             subscriptions: HashMap::new(),
         }
     }
-    fn add_subscription(&mut self, topic: Topic, client_addr: SocketAddr) {
-        self.subscriptions
-            .entry(topic)
-            .or_insert(Vec::new())
-            .push(client_addr);
+    fn add_subscription(&mut self, topic: Topic, client_id: ClientId) {
+        self.subscriptions.entry(topic).or_default().push(client_id);
     }
-    fn remove_subscription(&mut self, topic: &Topic, client_addr: &SocketAddr) {
+    #[allow(unused)]
+    fn remove_subscription(&mut self, topic: &Topic, client_id: &ClientId) {
         if let Some(clients) = self.subscriptions.get_mut(topic) {
-            clients.retain(|c| c != client_addr);
+            clients.retain(|c| c != client_id);
         }
     }
-    fn get_subscriptions(&self, topic: &Topic) -> Vec<SocketAddr> {
-        self.subscriptions
-            .get(topic)
-            .map(|clients| clients.clone())
-            .unwrap_or_default()
+    fn get_subscriptions(&self, topic: &Topic) -> Vec<ClientId> {
+        self.subscriptions.get(topic).cloned().unwrap_or_default()
     }
 }
 struct Clients {
-    clients: HashMap<SocketAddr, Tx>,
+    clients: HashMap<ClientId, Tx>,
+    next_id: ClientId,
 }
 impl Clients {
     fn new() -> Self {
         Clients {
             clients: HashMap::new(),
+            next_id: 0,
         }
     }
 }
 struct Client {
-    framed: Framed<TcpStream, MQTinyCodec>,
+    id: ClientId,
+    framed: Framed<Stream, MQTinyCodec>,
     rx: Rx,
 }
 impl Client {
     async fn new(
         clients: Arc<Mutex<Clients>>,
-        framed: Framed<TcpStream, MQTinyCodec>,
+        framed: Framed<Stream, MQTinyCodec>,
     ) -> io::Result<Client> {
-        let addr = framed.get_ref().peer_addr()?;
         let (tx, rx) = mpsc::unbounded_channel();
-        clients.lock().await.clients.insert(addr, tx);
+        let mut clients = clients.lock().await;
+        let id = clients.next_id;
+        clients.next_id += 1;
+        clients.clients.insert(id, tx);
 
-        Ok(Client { framed, rx })
+        Ok(Client { id, framed, rx })
     }
 }
 
@@ -152,6 +178,8 @@ type Rx = mpsc::UnboundedReceiver<Vec<u8>>;
 
 type Topic = u16;
 
+type ClientId = u64;
+
 // impl Encoder<Packet> for MQTinyCodec {
 //     type Error = std::io::Error;
 //     fn encode(&mut self, item: Packet, dst: &mut bytes::BytesMut) -> Result<(), Self::Error> {
@@ -1,16 +1,17 @@
-use bytes::BufMut;
 use clap::Parser;
-use mqtiny::*;
-use std::str;
+use mqtiny::{
+    client::{self, Client, ConnectOptions},
+    logging::LogArgs,
+    *,
+};
+use std::path::PathBuf;
 use tokio::io::AsyncWriteExt;
-use tokio::net::TcpStream;
-use tokio_stream::StreamExt;
-use tokio_util::codec::FramedRead;
+use tracing::{error, info, warn};
 
 #[derive(Parser, Debug)]
 #[command(name = "MQTiny", author = "Ryo OUCHI")]
 struct Args {
-    /// Address of the MQTiny server to connect
+    /// Address of the MQTiny server to connect, optionally prefixed with tls:// or ws://, or unix:///PATH
     #[arg(short, long)]
     ip: String,
 
@@ -25,63 +26,105 @@ struct Args {
     /// is FPGA?
     #[arg(short, long, default_value_t = false)]
     fpga: bool,
+
+    /// PEM CA certificate to verify a tls:// broker with [default: system roots]
+    #[arg(long)]
+    ca: Option<PathBuf>,
+
+    /// PEM client certificate, for brokers that require one
+    #[arg(long, requires = "key")]
+    cert: Option<PathBuf>,
+
+    /// PEM private key of --cert
+    #[arg(long, requires = "cert")]
+    key: Option<PathBuf>,
+
+    /// Username to log in with
+    #[arg(short, long)]
+    username: Option<String>,
+
+    /// Password to log in with
+    #[arg(short = 'P', long, requires = "username")]
+    password: Option<String>,
+
+    /// Replay the history of the topic from this sequence number before live messages
+    #[arg(long)]
+    replay_from: Option<u64>,
+
+    /// Join this shared subscription group: each message goes to one member of the group
+    #[arg(short, long, conflicts_with = "replay_from")]
+    group: Option<String>,
+
+    /// How the group picks the member for each message
+    #[arg(long, value_enum, default_value_t, requires = "group")]
+    strategy: ShareStrategy,
+    #[command(flatten)]
+    log: LogArgs,
 }
 #[tokio::main]
 async fn main() {
     let args = Args::parse();
+    args.log.init().unwrap();
+    let addr = client::address(&args.ip, args.port);
+    let options = ConnectOptions {
+        ca: args.ca.clone(),
+        cert: args.cert.clone(),
+        key: args.key.clone(),
+        username: args.username.clone(),
+        password: args.password.clone(),
+        ..Default::default()
+    };
 
-    let mut stream = TcpStream::connect(&format!("{}:{}", args.ip, args.port))
-        .await
-        .unwrap();
-    println!("Connecting on {}:{}", args.ip, args.port);
+    let mut client = Client::connect_with(&addr, &options).await.unwrap();
+    info!(broker = %addr, "connected");
 
     //
     // Send Subscrive packet
     //
 
-    let mut request = Vec::new();
-    let packet_type = PacketType::Subscribe;
-    let topic_length = 2;
-    let total_length = topic_length;
-
-    request.push(((packet_type as u8) << 4) as u8);
-    request.push(total_length as u8);
-    request.put_u16(args.topic);
+    match (&args.group, args.replay_from) {
+        (Some(group), _) => client
+            .subscribe_shared(args.topic, group, args.strategy)
+            .await
+            .unwrap(),
+        (None, Some(seq)) => client
+            .subscribe_from(args.topic, Replay::FromSeq(seq))
+            .await
+            .unwrap(),
+        (None, None) => client.subscribe(args.topic).await.unwrap(),
+    }
     if args.fpga {
-        request.push(0 as u8); // padding
-        request.push(0 as u8); // padding
-        request.push(0 as u8); // padding
-        request.push(0 as u8); // padding
-        request.push(0 as u8); // padding
-        request.push(0 as u8); // padding
+        client.get_mut().write_all(&[0; 6]).await.unwrap(); // padding
     }
 
-    stream.write_all(&request).await.unwrap();
-    request.clear();
-
-    let codec = MQTinyCodec {};
-    let (r, _) = stream.split();
-    let mut frame_reader = FramedRead::new(r, codec);
-
     let mut count = 0;
-    while let Some(frame) = frame_reader.next().await {
+    while let Some(frame) = client.recv().await {
         match frame {
             Ok(data) => {
                 match data {
-                    MqttPacket::Publish(MqttPublishPacket {
-                        topic_name: _,
-                        qos: _,
-                        payload: _,
-                    }) => {}
-                    MqttPacket::Subscribe(_) => todo!(),
-                    MqttPacket::Puback(_) => todo!(),
+                    // Group members acknowledge, or the publishes go to
+                    // another member once this one leaves.
+                    MqttPacket::Publish(MqttPublishPacket { qos, .. }) => {
+                        if args.group.is_some() && qos != QoS::AtMostOnce {
+                            client.ack().await.unwrap();
+                        }
+                    }
+                    MqttPacket::Suback(suback) => {
+                        error!(
+                            topic = suback.topic_name,
+                            return_code = ?suback.return_code,
+                            "subscription refused"
+                        );
+                        break;
+                    }
+                    _ => continue,
                 }
                 count += 1;
                 if count % 1000 == 0 {
                     println!("{}", count);
                 };
             }
-            Err(err) => eprintln!("error: {:?}", err),
+            Err(err) => warn!(error = %err, "invalid packet"),
         }
     }
 }
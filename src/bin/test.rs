@@ -1,153 +1,387 @@
-use clap::Parser;
-use futures::SinkExt;
-use mqtiny::*;
-use std::{collections::HashMap, error::Error, net::SocketAddr, sync::Arc};
+use clap::{ArgAction, Parser};
+use futures::future::{try_join, try_join_all};
+use mqtiny::{
+    admin,
+    broker::{Broker, BrokerBuilder, Command, Rx, Tx},
+    config::{Config, Runtime},
+    listener::{ListenAddr, Listener},
+    logging::{self, LogArgs, LogHandle},
+    metrics,
+    ratelimit::RateLimit,
+    store::Fsync,
+};
+use std::{error::Error, io, path::PathBuf, process, thread};
 use tokio::{
-    net::{TcpListener, TcpStream},
-    sync::{mpsc, Mutex},
+    net::TcpListener,
+    runtime,
+    signal::unix::{signal, SignalKind},
+    sync::mpsc,
 };
-use tokio_stream::StreamExt;
-use tokio_util::codec::Framed;
+use tracing::{error, info, warn};
 
-#[derive(Parser, Debug)]
+#[derive(Parser, Debug, Clone)]
 struct Args {
-    /// MQTiny service port
+    /// TOML config file; the other options override its settings
+    #[arg(short, long)]
+    config: Option<PathBuf>,
+
+    /// Validate the configuration and exit without starting the broker
+    #[arg(long)]
+    check_config: bool,
+
+    /// MQTiny service port, used when no listener is configured
     #[arg(short, long, default_value_t = 1883)]
     port: u16,
+
+    /// Address to listen on as ADDR:PORT, tls://ADDR:PORT, ws://ADDR:PORT or unix:///PATH, with options (repeatable)
+    #[arg(short, long)]
+    listen: Vec<ListenAddr>,
+
+    /// Runtime the broker runs on [default: current-thread]
+    #[arg(short, long, value_enum)]
+    runtime: Option<Runtime>,
+
+    /// Number of worker threads (multi-thread) or cores (thread-per-core) [default: all cores]
+    #[arg(short, long)]
+    threads: Option<usize>,
+
+    /// File of username:hash lines (bcrypt or argon2) to authenticate logins against
+    #[arg(long)]
+    password_file: Option<PathBuf>,
+
+    /// Whether clients may connect without logging in [default: true]
+    #[arg(long, action = ArgAction::Set)]
+    allow_anonymous: Option<bool>,
+
+    /// ACL file of the topics each user may publish (write) or subscribe (read) to
+    #[arg(long)]
+    acl_file: Option<PathBuf>,
+
+    /// Limit on the publishes of each client, as messages=N,bytes=N,action=drop|delay|disconnect (per second)
+    #[arg(long)]
+    rate_limit: Option<RateLimit>,
+
+    /// Seconds between two publishes of the broker statistics topics, 0 to disable [default: 10]
+    #[arg(long)]
+    sys_interval: Option<u64>,
+
+    /// Address as ADDR:PORT to serve Prometheus metrics on at /metrics
+    #[arg(long)]
+    metrics: Option<String>,
+
+    /// Address as ADDR:PORT to serve the admin API on, for mqtiny-admin
+    #[arg(long)]
+    admin: Option<String>,
+
+    /// Topic to republish the publishes the broker drops on, wrapped in an envelope
+    #[arg(long)]
+    dead_letter_topic: Option<u16>,
+
+    /// Write-ahead log to keep retained publishes and persistent sessions in across restarts
+    #[arg(long)]
+    store: Option<PathBuf>,
+
+    /// When the store log is flushed to disk [default: interval]
+    #[arg(long, value_enum)]
+    fsync: Option<Fsync>,
+
+    /// Maximum number of clients connected at once, across all listeners
+    #[arg(long)]
+    max_connections: Option<usize>,
+
+    /// Maximum number of clients connected at once from one IP address
+    #[arg(long)]
+    max_connections_per_ip: Option<usize>,
+
+    /// Size in bytes of the largest packet a client may send
+    #[arg(long)]
+    max_packet_size: Option<usize>,
+
+    /// Maximum number of topics one client may subscribe to
+    #[arg(long)]
+    max_subscriptions: Option<usize>,
+
+    /// Address to accept the links of the other cluster nodes on, with the options of --listen
+    #[arg(long)]
+    peer_listen: Option<ListenAddr>,
+
+    /// Peer listener of another cluster node to link to (repeatable)
+    #[arg(long)]
+    peer: Vec<String>,
+
+    #[command(flatten)]
+    log: LogArgs,
 }
 
-#[tokio::main]
-async fn main() -> Result<(), Box<dyn Error>> {
-    let args = Args::parse();
-    let listener = TcpListener::bind(format!("0.0.0.0:{}", args.port)).await?;
-    println!("Listening port: {}...", args.port);
+impl Args {
+    /// The config file, if any, with the command line applied on top.
+    fn config(&self) -> Result<Config, Box<dyn Error>> {
+        let mut config = match &self.config {
+            Some(path) => Config::load(path)?,
+            None => Config::default(),
+        };
+        if !self.listen.is_empty() {
+            config.listeners = self.listen.clone();
+        }
+        if config.listeners.is_empty() {
+            config
+                .listeners
+                .push(ListenAddr::new(&format!("0.0.0.0:{}", self.port)));
+        }
+        config.runtime = self.runtime.or(config.runtime);
+        config.threads = self.threads.or(config.threads);
+        let auth = &mut config.auth;
+        auth.password_file = self.password_file.clone().or(auth.password_file.take());
+        auth.allow_anonymous = self.allow_anonymous.unwrap_or(auth.allow_anonymous);
+        auth.acl_file = self.acl_file.clone().or(auth.acl_file.take());
+        if let Some(level) = &self.log.log_level {
+            logging::parse_filter(level)?;
+            config.log.level = level.clone();
+        }
+        config.log.format = self.log.log_format.unwrap_or(config.log.format);
+        config.rate_limit.client = self.rate_limit.or(config.rate_limit.client);
+        config.stats.interval = self.sys_interval.unwrap_or(config.stats.interval);
+        config.metrics.address = self.metrics.clone().or(config.metrics.address);
+        config.admin.address = self.admin.clone().or(config.admin.address);
+        config.dead_letter.topic = self.dead_letter_topic.or(config.dead_letter.topic);
+        config.store.path = self.store.clone().or(config.store.path);
+        config.store.fsync = self.fsync.unwrap_or(config.store.fsync);
+        let limits = &mut config.limits;
+        limits.max_connections = self.max_connections.or(limits.max_connections);
+        limits.max_connections_per_ip = self
+            .max_connections_per_ip
+            .or(limits.max_connections_per_ip);
+        limits.max_packet_size = self.max_packet_size.or(limits.max_packet_size);
+        limits.max_subscriptions = self.max_subscriptions.or(limits.max_subscriptions);
+        let cluster = &mut config.cluster;
+        cluster.listener = self.peer_listen.clone().or(cluster.listener.take());
+        if !self.peer.is_empty() {
+            cluster.peers = self.peer.clone();
+        }
+        Ok(config)
+    }
 
-    let (tx, mut rx) = mpsc::unbounded_channel();
-    let clients = Arc::new(Mutex::new(HashMap::<SocketAddr, Tx>::new()));
+    /// Loads the configuration and the files it refers to.
+    fn load(&self) -> Result<(Config, BrokerBuilder), Box<dyn Error>> {
+        let config = self.config()?;
+        let builder = config.broker()?;
+        Ok((config, builder))
+    }
+}
 
-    {
-        let clients = clients.clone();
-        tokio::spawn(async move {
-            manage(&mut rx, clients).await;
-        });
+fn main() -> Result<(), Box<dyn Error>> {
+    let args = Args::parse();
+    let (config, builder) = args.load().unwrap_or_else(|e| {
+        eprintln!("invalid configuration: {}", e);
+        process::exit(2);
+    });
+    if args.check_config {
+        println!("Configuration OK");
+        return Ok(());
     }
+    let logs = logging::init(&config.log.level, config.log.format)?;
+    let builder = match config.store()? {
+        Some(store) => builder.store(store),
+        None => builder,
+    };
 
-    loop {
-        let tx = tx.clone();
-        let (stream, _) = listener.accept().await?;
-        let clients = Arc::clone(&clients);
+    let threads = config
+        .threads
+        .unwrap_or_else(|| thread::available_parallelism().map_or(1, |n| n.get()));
+    let reloader = Reloader {
+        args,
+        running: config,
+        logs,
+    };
 
-        tokio::spawn(async move {
-            process(stream, tx, clients).await.unwrap();
-        });
+    match reloader.running.runtime.unwrap_or_default() {
+        Runtime::CurrentThread => runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()?
+            .block_on(serve(reloader, builder))?,
+        Runtime::MultiThread => runtime::Builder::new_multi_thread()
+            .worker_threads(threads)
+            .enable_all()
+            .build()?
+            .block_on(serve(reloader, builder))?,
+        Runtime::ThreadPerCore => serve_per_core(reloader, builder, threads)?,
     }
+
+    Ok(())
 }
 
-async fn manage(rx: &mut Rx, clients: Arc<Mutex<HashMap<SocketAddr, Tx>>>) {
-    let mut subscription_table = HashMap::<u16, Vec<SocketAddr>>::new();
-    while let Some(cmd) = rx.recv().await {
-        // match cmd {
-        //     MqttPacket::Publish(_) => todo!(),
-        //     MqttPacket::Subscribe(subscribe) => subscription_table
-        //         .get(&subscribe.topic_name)
-        //         .unwrap()
-        //         .push(value),
-        // }
-        match cmd {
-            Command::Publish { packet } => {
-                if let Some(subscriptions) = subscription_table.get(&packet.topic_name) {
-                    let mut clients = clients.lock().await;
-                    for subscriber in subscriptions {
-                        if let Some(subscriber) = clients.get_mut(subscriber) {
-                            subscriber
-                                .send(Command::Publish {
-                                    packet: packet.clone(),
-                                })
-                                .unwrap();
-                        }
-                    }
+/// Applies the configuration again on every SIGHUP.
+#[derive(Clone)]
+struct Reloader {
+    args: Args,
+    running: Config,
+    logs: LogHandle,
+}
+
+impl Reloader {
+    /// Sends the new access policy and limits to every core, sets the new
+    /// limits of the listeners and the new log level. Settings that cannot
+    /// change at runtime are reported and left as they are; a configuration
+    /// that fails to load is ignored.
+    async fn run(mut self, cores: Vec<Tx>) -> io::Result<()> {
+        let mut hangup = signal(SignalKind::hangup())?;
+        while hangup.recv().await.is_some() {
+            let (config, builder) = match self.args.load() {
+                Ok(loaded) => loaded,
+                Err(e) => {
+                    error!(error = %e, "not reloading, invalid configuration");
+                    continue;
                 }
+            };
+            for setting in config.restart_required(&self.running) {
+                warn!(setting, "setting changed; restart the broker to apply it");
             }
-            Command::Subscribe { packet, client } => {
-                match subscription_table.get_mut(&packet.topic_name) {
-                    Some(subscriptions) => subscriptions.push(client),
-                    None => {
-                        subscription_table.insert(packet.topic_name, vec![client]);
-                    }
-                    // Some(subscriptions) => subscriptions.push(client),
-                    // None => subscription_table.insert(packet.topic_name, vec![client]),
+            for tx in &cores {
+                let _ = tx.send(Command::Reload {
+                    settings: builder.settings().clone(),
+                });
+            }
+            // Bound listeners share their limits with the running addresses.
+            for (running, listen) in self.running.listeners.iter_mut().zip(&config.listeners) {
+                if !running.rebind(listen) {
+                    running.reload(listen);
                 }
             }
+            self.logs.set_level(&config.log.level)?;
+            info!("configuration reloaded");
+            self.running.auth = config.auth;
+            self.running.log.level = config.log.level;
+            self.running.rate_limit = config.rate_limit;
+            self.running.limits = config.limits;
         }
+        Ok(())
     }
 }
 
-async fn process(
-    stream: TcpStream,
-    tx_to_manager: Tx,
-    clients: Arc<Mutex<HashMap<SocketAddr, Tx>>>,
-) -> Result<(), Box<dyn Error>> {
-    let mut framed = Framed::new(stream, MQTinyCodec {});
-    let (tx, mut rx) = mpsc::unbounded_channel();
-    {
-        clients
-            .lock()
-            .await
-            .insert(framed.get_ref().peer_addr()?, tx);
+async fn serve(reloader: Reloader, builder: BrokerBuilder) -> Result<(), Box<dyn Error>> {
+    let listeners = Listener::bind_all(&reloader.running.listeners, false).await?;
+    for listener in &listeners {
+        info!(listener = %listener.local_addr()?, "listening");
     }
+    let peer_listeners = bind_peers(&reloader.running).await?;
+    let (tx, rx) = mpsc::unbounded_channel();
+    let broker = builder.spawn_core(0, tx.clone(), rx, vec![tx.clone()]);
+    serve_http(&reloader.running, &broker).await?;
+    tokio::spawn(reloader.run(vec![tx]));
 
-    loop {
-        tokio::select! {
-            Some(msg) = rx.recv() => {
-                match msg{
-                    Command::Publish { packet } => {
-                        framed.send(mqtiny::MqttPacket::Publish(packet)).await.unwrap();
-                    },
-                    _ => {},
-                }
-            }
-            result = framed.next() => match result {
-                Some(Ok(packet)) => match packet {
-                    MqttPacket::Publish(publish) => {
-                        tx_to_manager
-                            .send(Command::Publish { packet: publish })
-                            .unwrap();
-                    }
-                    MqttPacket::Subscribe(subscribe) => {
-                        // println!("{:?}", subscribe.topic_name);
-                        tx_to_manager
-                            .send(Command::Subscribe {
-                                packet: subscribe,
-                                client: framed.get_ref().peer_addr()?,
-                            })
-                            .unwrap();
-                    }
-                    _=>{},
-                },
-                Some(Err(e)) => eprintln!("{}", e),
-                None => break,
-        }
-        }
+    try_join(
+        try_join_all(listeners.iter().map(|listener| broker.serve(listener))),
+        try_join_all(
+            peer_listeners
+                .iter()
+                .map(|listener| broker.serve_peers(listener)),
+        ),
+    )
+    .await?;
+    Ok(())
+}
+
+/// Binds the listener for the links of the other cluster nodes, if one is
+/// configured.
+async fn bind_peers(config: &Config) -> io::Result<Vec<Listener>> {
+    let Some(listen) = &config.cluster.listener else {
+        return Ok(Vec::new());
+    };
+    let listeners = Listener::bind(listen, false).await?;
+    for listener in &listeners {
+        info!(listener = %listener.local_addr()?, "accepting peer links");
     }
+    Ok(listeners)
+}
 
-    {
-        let mut clients = clients.lock().await;
-        clients.remove(&framed.get_ref().peer_addr()?);
-        println!("this client is disconnected.");
+/// Starts the metrics and admin endpoints, if configured. Statistics and
+/// clients are shared by every core, so any core's broker can serve them.
+async fn serve_http(config: &Config, broker: &Broker) -> io::Result<()> {
+    if let Some(address) = &config.metrics.address {
+        let listener = bind_http("metrics", address).await?;
+        tokio::spawn(metrics::serve(listener, broker.stats().clone()));
+    }
+    if let Some(address) = &config.admin.address {
+        let listener = bind_http("admin", address).await?;
+        tokio::spawn(admin::serve(listener, broker.clone()));
     }
     Ok(())
 }
 
-#[derive(Debug, Clone)]
-enum Command {
-    Subscribe {
-        packet: MqttSubscribePacket,
-        client: SocketAddr,
-    },
-    Publish {
-        packet: MqttPublishPacket,
-    },
+async fn bind_http(name: &str, address: &str) -> io::Result<TcpListener> {
+    let listener = TcpListener::bind(address)
+        .await
+        .map_err(|e| io::Error::new(e.kind(), format!("{} {}: {}", name, address, e)))?;
+    info!(listener = %listener.local_addr()?, "serving {}", name);
+    Ok(listener)
 }
 
-type Tx = mpsc::UnboundedSender<Command>;
-type Rx = mpsc::UnboundedReceiver<Command>;
+fn serve_per_core(
+    reloader: Reloader,
+    builder: BrokerBuilder,
+    cores: usize,
+) -> Result<(), Box<dyn Error>> {
+    let (peers, rxs): (Vec<Tx>, Vec<Rx>) = (0..cores).map(|_| mpsc::unbounded_channel()).unzip();
+    let core_ids = core_affinity::get_core_ids().unwrap_or_default();
+
+    let mut handles = Vec::new();
+    for (core, rx) in rxs.into_iter().enumerate() {
+        let peers = peers.clone();
+        let reloader = reloader.clone();
+        let builder = builder.clone();
+        let core_id = core_ids.get(core % core_ids.len().max(1)).copied();
+
+        handles.push(thread::spawn(move || -> std::io::Result<()> {
+            if let Some(core_id) = core_id {
+                core_affinity::set_for_current(core_id);
+            }
+
+            let rt = runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()?;
+            rt.block_on(async move {
+                // A Unix socket path can only be bound once, so core 0 serves it alone.
+                let listen: Vec<_> = reloader
+                    .running
+                    .listeners
+                    .iter()
+                    .filter(|listen| core == 0 || !listen.is_unix())
+                    .cloned()
+                    .collect();
+                let listeners = Listener::bind_all(&listen, true).await?;
+                if core == 0 {
+                    for listener in &listeners {
+                        info!(listener = %listener.local_addr()?, "listening");
+                    }
+                }
+                // Core 0 also accepts the links of the other cluster nodes.
+                let peer_listeners = match core {
+                    0 => bind_peers(&reloader.running).await?,
+                    _ => Vec::new(),
+                };
+                let broker = builder.spawn_core(core, peers[core].clone(), rx, peers.clone());
+                if core == 0 {
+                    serve_http(&reloader.running, &broker).await?;
+                    tokio::spawn(reloader.run(peers));
+                }
+                try_join(
+                    try_join_all(listeners.iter().map(|listener| broker.serve(listener))),
+                    try_join_all(
+                        peer_listeners
+                            .iter()
+                            .map(|listener| broker.serve_peers(listener)),
+                    ),
+                )
+                .await?;
+                Ok(())
+            })
+        }));
+    }
+    info!(cores, "running thread-per-core");
+
+    for handle in handles {
+        handle.join().expect("broker core panicked")?;
+    }
+
+    Ok(())
+}
@@ -0,0 +1,316 @@
+//! Bridges to remote MQTiny brokers. A bridge connects to the remote broker
+//! as a client, and to this one in process, and forwards the publishes on
+//! its topics between them.
+//!
+//! The bridge subscribes on both sides with
+//! [`no_local`](crate::MqttSubscribePacket::no_local), so a publish it
+//! forwards to one broker is not sent back to it and forwarded again. Two
+//! bridges between the same pair of brokers must not both forward a topic,
+//! or each would forward what the other did.
+//!
+//! A QoS 1 or 2 publish is acknowledged to the broker it came from only once
+//! the other broker has acknowledged it, so neither loses a publish that was
+//! on its way over the bridge when it dropped.
+
+use std::{
+    collections::{BTreeSet, VecDeque},
+    io,
+    ops::RangeInclusive,
+    time::Duration,
+};
+
+use serde::Deserialize;
+use tokio::time::sleep;
+use tracing::{info, info_span, trace, warn, Instrument};
+
+use crate::{
+    broker::Broker,
+    client::{Client, ConnectOptions},
+    MqttPacket, MqttPublishPacket, QoS,
+};
+
+/// Which way publishes are forwarded.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Direction {
+    /// From this broker to the remote one.
+    #[default]
+    Out,
+    /// From the remote broker to this one.
+    In,
+    Both,
+}
+
+impl Direction {
+    fn outgoing(self) -> bool {
+        self != Direction::In
+    }
+
+    fn incoming(self) -> bool {
+        self != Direction::Out
+    }
+}
+
+/// Topics a bridge forwards, and how.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BridgeTopics {
+    /// The topics on this broker.
+    pub topics: RangeInclusive<u16>,
+    pub direction: Direction,
+    /// Local topic `t` is remote topic `t + offset`.
+    pub offset: i32,
+    /// QoS of the forwarded publishes; by default, that of each publish.
+    pub qos: Option<QoS>,
+}
+
+impl BridgeTopics {
+    /// The topics on the remote broker, or `None` if the offset moves some
+    /// of them out of the `u16` range.
+    pub fn remote_topics(&self) -> Option<RangeInclusive<u16>> {
+        Some(self.remote(*self.topics.start())?..=self.remote(*self.topics.end())?)
+    }
+
+    fn remote(&self, local: u16) -> Option<u16> {
+        u16::try_from(i32::from(local) + self.offset).ok()
+    }
+
+    fn local(&self, remote: u16) -> Option<u16> {
+        let local = u16::try_from(i32::from(remote) - self.offset).ok()?;
+        self.topics.contains(&local).then_some(local)
+    }
+}
+
+/// A connection to a remote broker and the topics forwarded over it.
+#[derive(Clone, Debug)]
+pub struct Bridge {
+    /// Names the bridge in logs; it logs in to this broker as this client id.
+    pub name: String,
+    /// The remote broker, as for [`Client::connect`].
+    pub address: String,
+    /// How to log in to the remote broker. With `persistent`, the remote
+    /// broker keeps the publishes for the bridge while it is disconnected.
+    pub options: ConnectOptions,
+    /// The first rule covering a topic applies.
+    pub topics: Vec<BridgeTopics>,
+    /// Wait before reconnecting, doubled after each failed attempt up to
+    /// `max_backoff`.
+    pub min_backoff: Duration,
+    pub max_backoff: Duration,
+}
+
+impl Bridge {
+    /// A bridge to `address` that forwards nothing yet, reconnecting after 1s
+    /// backing off to 60s.
+    pub fn new(name: impl Into<String>, address: impl Into<String>) -> Bridge {
+        Bridge {
+            name: name.into(),
+            address: address.into(),
+            options: ConnectOptions::default(),
+            topics: Vec::new(),
+            min_backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(60),
+        }
+    }
+
+    /// The remote topic and QoS to forward a local publish on `topic` with,
+    /// if it is forwarded.
+    fn outgoing(&self, topic: u16) -> Option<(u16, Option<QoS>)> {
+        let rule = self
+            .topics
+            .iter()
+            .find(|rule| rule.topics.contains(&topic))?;
+        Some((
+            rule.remote(topic).filter(|_| rule.direction.outgoing())?,
+            rule.qos,
+        ))
+    }
+
+    /// The local topic and QoS to forward a remote publish on `topic` with,
+    /// if it is forwarded.
+    fn incoming(&self, topic: u16) -> Option<(u16, Option<QoS>)> {
+        let (rule, local) = self
+            .topics
+            .iter()
+            .find_map(|rule| Some((rule, rule.local(topic)?)))?;
+        rule.direction.incoming().then_some((local, rule.qos))
+    }
+
+    /// The local topics forwarded to the remote broker.
+    fn outgoing_topics(&self) -> BTreeSet<u16> {
+        let rules = self.topics.iter().filter(|rule| rule.direction.outgoing());
+        rules
+            .flat_map(|rule| rule.topics.clone())
+            .filter(|&topic| self.outgoing(topic).is_some())
+            .collect()
+    }
+
+    /// The remote topics forwarded to this broker.
+    fn incoming_topics(&self) -> BTreeSet<u16> {
+        let rules = self.topics.iter().filter(|rule| rule.direction.incoming());
+        rules
+            .filter_map(BridgeTopics::remote_topics)
+            .flatten()
+            .filter(|&topic| self.incoming(topic).is_some())
+            .collect()
+    }
+}
+
+/// Runs `bridge` for `broker`, reconnecting whenever either side drops.
+pub(crate) async fn run(bridge: Bridge, broker: Broker) {
+    let span = info_span!("bridge", name = %bridge.name, remote = %bridge.address);
+    async move {
+        let mut backoff = bridge.min_backoff;
+        loop {
+            match connect(&bridge, &broker).await {
+                Ok((local, remote)) => {
+                    info!("bridge connected");
+                    backoff = bridge.min_backoff;
+                    let e = forward(&bridge, local, remote).await;
+                    warn!(error = %e, retry_in = ?backoff, "bridge disconnected");
+                }
+                Err(e) => warn!(error = %e, retry_in = ?backoff, "bridge connection failed"),
+            }
+            sleep(backoff).await;
+            backoff = (backoff * 2).min(bridge.max_backoff);
+        }
+    }
+    .instrument(span)
+    .await
+}
+
+/// Connects to both brokers and subscribes to the forwarded topics.
+async fn connect(bridge: &Bridge, broker: &Broker) -> io::Result<(Client, Client)> {
+    let mut remote = Client::connect_with(&bridge.address, &bridge.options).await?;
+    let login = ConnectOptions {
+        client_id: Some(bridge.name.clone()),
+        ..Default::default()
+    };
+    let mut local = broker.connect_local(&login).await?;
+    for topic in bridge.outgoing_topics() {
+        local.subscribe_no_local(topic).await?;
+    }
+    for topic in bridge.incoming_topics() {
+        remote.subscribe_no_local(topic).await?;
+    }
+    Ok((local, remote))
+}
+
+/// The acknowledgements between the bridge and one of the brokers. PUBACKs
+/// carry no packet id, so each side acknowledges in the order it received.
+#[derive(Debug, Default)]
+struct Acks {
+    /// One per QoS 1 or 2 publish received from the broker and not yet
+    /// acknowledged to it, oldest first: whether it may be.
+    owed: VecDeque<bool>,
+    /// One per QoS 1 or 2 publish forwarded to the broker and not yet
+    /// acknowledged by it, oldest first: whether its PUBACK releases one owed
+    /// to the other broker.
+    awaited: VecDeque<bool>,
+}
+
+impl Acks {
+    /// Releases the oldest acknowledgement held for the other broker.
+    fn release(&mut self) {
+        if let Some(held) = self.owed.iter_mut().find(|ready| !**ready) {
+            *held = true;
+        }
+    }
+
+    /// Acknowledges to `client` the publishes that may be, in order.
+    async fn flush(&mut self, client: &mut Client) -> io::Result<()> {
+        while self.owed.front() == Some(&true) {
+            self.owed.pop_front();
+            client.ack().await?;
+        }
+        Ok(())
+    }
+}
+
+/// Forwards publishes until either connection fails, and returns why.
+async fn forward(bridge: &Bridge, mut local: Client, mut remote: Client) -> io::Error {
+    let (mut local_acks, mut remote_acks) = (Acks::default(), Acks::default());
+    loop {
+        let (packet, incoming) = tokio::select! {
+            packet = local.recv() => (packet, false),
+            packet = remote.recv() => (packet, true),
+        };
+        let side = if incoming { "remote" } else { "local" };
+        let publish = match packet {
+            Some(Ok(MqttPacket::Publish(publish))) => publish,
+            Some(Ok(MqttPacket::Suback(suback))) => {
+                warn!(side, topic = suback.topic_name, "bridge subscribe refused");
+                continue;
+            }
+            Some(Ok(MqttPacket::Puback(_))) => {
+                let (acks, other_acks, other) = if incoming {
+                    (&mut remote_acks, &mut local_acks, &mut local)
+                } else {
+                    (&mut local_acks, &mut remote_acks, &mut remote)
+                };
+                if acks.awaited.pop_front() == Some(true) {
+                    other_acks.release();
+                    if let Err(e) = other_acks.flush(other).await {
+                        return e;
+                    }
+                }
+                continue;
+            }
+            Some(Ok(_)) => continue,
+            Some(Err(e)) => return e,
+            None => {
+                let message = format!("{} broker closed the connection", side);
+                return io::Error::new(io::ErrorKind::UnexpectedEof, message);
+            }
+        };
+
+        let qos = publish.qos;
+        let (route, to, to_acks, from, from_acks) = if incoming {
+            let route = bridge.incoming(publish.topic_name);
+            (
+                route,
+                &mut local,
+                &mut local_acks,
+                &mut remote,
+                &mut remote_acks,
+            )
+        } else {
+            let route = bridge.outgoing(publish.topic_name);
+            (
+                route,
+                &mut remote,
+                &mut remote_acks,
+                &mut local,
+                &mut local_acks,
+            )
+        };
+        // Acknowledged right away unless forwarded at QoS 1 or 2.
+        let mut held = false;
+        if let Some((topic_name, forward_qos)) = route {
+            trace!(
+                from = side,
+                topic = publish.topic_name,
+                to = topic_name,
+                "forwarding"
+            );
+            let packet = MqttPublishPacket {
+                topic_name,
+                qos: forward_qos.unwrap_or(qos),
+                seq: None,
+                ..publish
+            };
+            if packet.qos != QoS::AtMostOnce {
+                held = qos != QoS::AtMostOnce;
+                to_acks.awaited.push_back(held);
+            }
+            if let Err(e) = to.publish_packet(packet).await {
+                return e;
+            }
+        }
+        if qos != QoS::AtMostOnce {
+            from_acks.owed.push_back(!held);
+            if let Err(e) = from_acks.flush(from).await {
+                return e;
+            }
+        }
+    }
+}
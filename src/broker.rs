@@ -0,0 +1,1747 @@
+use std::{
+    collections::{hash_map::Entry, BTreeSet, HashMap, HashSet, VecDeque},
+    error::Error,
+    fmt, io,
+    net::IpAddr,
+    ops::RangeInclusive,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex as StdMutex, RwLock,
+    },
+    time::{Duration, Instant},
+};
+
+use futures::SinkExt;
+use serde::Deserialize;
+use tokio::sync::{
+    mpsc::{self, error::SendError},
+    Mutex,
+};
+use tokio_stream::StreamExt;
+use tokio_util::codec::Framed;
+use tracing::{debug, field, info, info_span, trace, warn, Instrument, Span};
+
+use crate::{
+    auth::{Access, Authenticator, Authorizer},
+    bridge::{self, Bridge},
+    client::{Client, ConnectOptions},
+    cluster::{self, Cluster, Interest},
+    deadletter::{DeadLetter, DropReason},
+    history::{self, History, HistoryLimit},
+    hook::{BrokerHook, ClientInfo, Hooks},
+    listener::{Listener, Slot},
+    ratelimit::{self, Limiter, RateAction, RateLimit, TopicLimits, TopicRules},
+    schedule::{self, Scheduled, Scheduler},
+    shared::{self, Groups},
+    stats::{self, Stats, PAYLOAD_SIZE_BUCKETS, ROUTING_LATENCY_BUCKETS, SYS_TOPICS},
+    store::{self, Fsync, Store},
+    transport::{PeerAddr, Stream},
+    *,
+};
+
+#[derive(Debug, Clone)]
+pub enum Command {
+    Subscribe {
+        packet: MqttSubscribePacket,
+        client: ClientId,
+    },
+    /// `received` is when the broker got the publish, to measure routing
+    /// latency, `stored` the sequence number it is queued under for
+    /// persistent sessions, if it is, and `origin` the client that sent it,
+    /// unless the broker did. `from_peer` publishes came from another node of
+    /// the cluster and are not passed on to the nodes.
+    Publish {
+        packet: MqttPublishPacket,
+        received: Instant,
+        stored: Option<u64>,
+        origin: Option<ClientId>,
+        from_peer: bool,
+    },
+    /// Publish received on another core, delivered to local subscribers only.
+    Forward {
+        packet: MqttPublishPacket,
+        received: Instant,
+        stored: Option<u64>,
+        origin: Option<ClientId>,
+        from_peer: bool,
+    },
+    /// Publish for the client as the member of the shared subscription
+    /// `group` picked for it. `publisher` is the key sticky groups pick by,
+    /// kept so that another member can be picked the same way.
+    Shared {
+        packet: MqttPublishPacket,
+        received: Instant,
+        group: Arc<str>,
+        publisher: u64,
+        origin: Option<ClientId>,
+    },
+    /// `core` got its first local subscriber on `topic`, or lost its last
+    /// one if not `subscribed`.
+    Interest {
+        core: usize,
+        topic: u16,
+        subscribed: bool,
+    },
+    /// `client` lost its permission to subscribe to `topic`.
+    Unsubscribe { topic: u16, client: ClientId },
+    /// Replaces the settings. Each core passes them on to its clients, which
+    /// check their login and subscriptions against it.
+    Reload { settings: Settings },
+    /// Closes the connection of a client, as asked through [`Broker::disconnect`].
+    Disconnect,
+}
+
+pub type Tx = mpsc::UnboundedSender<Command>;
+pub type Rx = mpsc::UnboundedReceiver<Command>;
+
+/// Identifies a connection within one broker instance.
+pub type ClientId = u64;
+
+/// Bytes buffered each way between a [`Broker::connect_local`] client and
+/// the broker.
+const LOCAL_BUFFER: usize = 64 * 1024;
+
+type Clients = Arc<Mutex<HashMap<ClientId, Arc<Session>>>>;
+
+/// Every connected client of a broker, across all its cores.
+pub(crate) type Sessions = Arc<RwLock<HashMap<ClientId, Arc<Session>>>>;
+
+/// A connected client, as the admin API shows it.
+pub(crate) struct Session {
+    pub(crate) id: ClientId,
+    pub(crate) peer: String,
+    pub(crate) core: usize,
+    /// Set once the client logs in.
+    pub(crate) client_id: RwLock<String>,
+    pub(crate) username: RwLock<Option<String>>,
+    pub(crate) subscriptions: RwLock<BTreeSet<u16>>,
+    /// Publishes waiting to be sent to the client.
+    pub(crate) queued: AtomicU64,
+    /// Payload bytes received from and sent to the client.
+    pub(crate) bytes_in: AtomicU64,
+    pub(crate) bytes_out: AtomicU64,
+    /// Publishes of the client that were over a rate limit.
+    pub(crate) throttled: AtomicU64,
+    /// A link from another node of the cluster rather than a client.
+    pub(crate) link: bool,
+    tx: Tx,
+}
+
+impl Session {
+    /// Queues a publish for the client and counts it, or returns it if the
+    /// client is gone. It is counted before it is sent, as the client may take
+    /// it off the queue before `send` returns.
+    fn enqueue(&self, stats: &Stats, publish: Command) -> Option<Command> {
+        self.queued.fetch_add(1, Ordering::Relaxed);
+        stats.queued.fetch_add(1, Ordering::Relaxed);
+        let SendError(returned) = self.tx.send(publish).err()?;
+        self.queued.fetch_sub(1, Ordering::Relaxed);
+        stats.queued.fetch_sub(1, Ordering::Relaxed);
+        Some(returned)
+    }
+}
+
+/// Handle to one broker instance: its routing task and connected clients.
+///
+/// In thread-per-core mode every core runs its own `Broker`, and the routing
+/// tasks exchange subscription interest and publishes over their channels.
+#[derive(Clone)]
+pub struct Broker {
+    core: usize,
+    tx: Tx,
+    clients: Clients,
+    next_id: Arc<AtomicU64>,
+    sessions: Sessions,
+    settings: Arc<RwLock<Settings>>,
+    topic_limits: TopicLimits,
+    per_ip: PerIp,
+    store: Store,
+    history: History,
+    interest: Interest,
+    groups: Groups,
+    scheduler: Scheduler,
+    dead_letter_topic: Option<u16>,
+    hooks: Hooks,
+    stats: Arc<Stats>,
+}
+
+/// Caps on the connections and subscriptions of a broker, on top of the
+/// `max-connections` of each listener.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Limits {
+    /// Clients connected at once, across every listener and core. Further
+    /// clients get a CONNACK with [`ConnectReturnCode::ServerUnavailable`].
+    pub max_connections: Option<usize>,
+    /// Clients connected at once from one IP address, refused the same way.
+    /// Unix socket clients are not counted.
+    pub max_connections_per_ip: Option<usize>,
+    /// Largest packet a client may send, headers included, in bytes. A larger
+    /// packet closes the connection.
+    pub max_packet_size: Option<usize>,
+    /// Topics one client may be subscribed to. Further subscribes get a
+    /// SUBACK with [`SubackReturnCode::Failure`].
+    pub max_subscriptions: Option<usize>,
+}
+
+/// Connections per IP address, shared by the cores.
+type PerIp = Arc<StdMutex<HashMap<IpAddr, usize>>>;
+
+/// Counts a connection against the [`Limits`] until dropped.
+struct ConnectionSlot {
+    stats: Arc<Stats>,
+    per_ip: PerIp,
+    ip: Option<IpAddr>,
+}
+
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        self.stats.clients.fetch_sub(1, Ordering::Relaxed);
+        if let Some(ip) = self.ip {
+            if let Entry::Occupied(mut count) = self.per_ip.lock().unwrap().entry(ip) {
+                *count.get_mut() -= 1;
+                if *count.get() == 0 {
+                    count.remove();
+                }
+            }
+        }
+    }
+}
+
+/// Who may log in and which topics they may use.
+#[derive(Clone)]
+pub struct Policy {
+    authenticator: Option<Arc<dyn Authenticator>>,
+    authorizer: Option<Arc<dyn Authorizer>>,
+    allow_anonymous: bool,
+}
+
+impl Default for Policy {
+    fn default() -> Self {
+        Policy {
+            authenticator: None,
+            authorizer: None,
+            allow_anonymous: true,
+        }
+    }
+}
+
+impl fmt::Debug for Policy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Policy")
+            .field("authenticator", &self.authenticator.is_some())
+            .field("authorizer", &self.authorizer.is_some())
+            .field("allow_anonymous", &self.allow_anonymous)
+            .finish()
+    }
+}
+
+/// The settings of a broker that a reload can change while it runs.
+#[derive(Clone, Debug, Default)]
+pub struct Settings {
+    pub policy: Policy,
+    pub limits: Limits,
+    /// Applies to the publishes of each client separately.
+    pub client_limit: Option<RateLimit>,
+    /// Apply to the publishes on each topic separately.
+    pub topic_limits: TopicRules,
+}
+
+/// Settings shared by every core of a broker.
+#[derive(Clone, Default)]
+pub struct BrokerBuilder {
+    settings: Settings,
+    stats: Arc<Stats>,
+    sys_interval: Option<Duration>,
+    /// Shared by the cores so that client ids are unique across them.
+    next_id: Arc<AtomicU64>,
+    sessions: Sessions,
+    /// Shared by the cores so that each topic has one set of buckets.
+    topic_limits: TopicLimits,
+    per_ip: PerIp,
+    store: Store,
+    history: History,
+    interest: Interest,
+    groups: Groups,
+    scheduler: Scheduler,
+    dead_letter_topic: Option<u16>,
+    hooks: Vec<Arc<dyn BrokerHook>>,
+    bridges: Vec<Bridge>,
+    cluster: Option<Cluster>,
+}
+
+impl BrokerBuilder {
+    /// Verifies the credentials of clients that log in with a username.
+    pub fn authenticator(mut self, authenticator: Arc<dyn Authenticator>) -> Self {
+        self.settings.policy.authenticator = Some(authenticator);
+        self
+    }
+
+    /// Decides which topics each client may publish or subscribe to. Without
+    /// one, every client may use every topic.
+    pub fn authorizer(mut self, authorizer: Arc<dyn Authorizer>) -> Self {
+        self.settings.policy.authorizer = Some(authorizer);
+        self
+    }
+
+    /// Whether clients may skip the login, either by connecting without a
+    /// username or by never sending a connect packet. Enabled by default.
+    pub fn allow_anonymous(mut self, allow: bool) -> Self {
+        self.settings.policy.allow_anonymous = allow;
+        self
+    }
+
+    /// Publishes the broker statistics on the [`SYS_TOPICS`] every `interval`.
+    /// Off by default.
+    pub fn sys_interval(mut self, interval: Duration) -> Self {
+        self.sys_interval = Some(interval);
+        self
+    }
+
+    /// Caps connections, packet sizes and subscriptions. Unlimited by default.
+    pub fn limits(mut self, limits: Limits) -> Self {
+        self.settings.limits = limits;
+        self
+    }
+
+    /// Keeps retained publishes and persistent sessions in `store`. By default
+    /// they are kept in memory only.
+    pub fn store(mut self, store: Store) -> Self {
+        self.store = store;
+        self
+    }
+
+    /// Limits the publishes of each client separately.
+    pub fn client_limit(mut self, limit: RateLimit) -> Self {
+        self.settings.client_limit = Some(limit);
+        self
+    }
+
+    /// Limits the publishes on each topic in `topics` separately, whoever the
+    /// publishers. The first matching rule applies.
+    pub fn topic_limit(mut self, topics: RangeInclusive<u16>, limit: RateLimit) -> Self {
+        self.settings.topic_limits.push((topics, limit));
+        self
+    }
+
+    /// Keeps the recent publishes of each topic in `topics`, numbered, for
+    /// subscribers to replay. The first matching rule applies.
+    pub fn history(mut self, topics: RangeInclusive<u16>, limit: HistoryLimit) -> Self {
+        self.history.push(topics, limit);
+        self
+    }
+
+    /// Republishes the publishes the broker drops on `topic`, each wrapped in
+    /// a [`DeadLetter`] envelope. Off by default.
+    pub fn dead_letter_topic(mut self, topic: u16) -> Self {
+        self.dead_letter_topic = Some(topic);
+        self
+    }
+
+    /// Adds `hook` to the hooks of the broker, called after those added
+    /// before it.
+    pub fn hook(mut self, hook: Arc<dyn BrokerHook>) -> Self {
+        self.hooks.push(hook);
+        self
+    }
+
+    /// Runs `bridge` alongside the broker, on the first core.
+    pub fn bridge(mut self, bridge: Bridge) -> Self {
+        self.bridges.push(bridge);
+        self
+    }
+
+    /// Makes the broker a node of `cluster`, linking to each of its peers
+    /// from the first core. The links of the peers are accepted by
+    /// [`Broker::serve_peers`].
+    pub fn cluster(mut self, cluster: Cluster) -> Self {
+        self.cluster = Some(cluster);
+        self
+    }
+
+    /// The settings configured so far, to send in a [`Command::Reload`].
+    pub fn settings(&self) -> &Settings {
+        &self.settings
+    }
+
+    /// Spawns a standalone broker on the current runtime.
+    pub fn spawn(self) -> Broker {
+        let (tx, rx) = mpsc::unbounded_channel();
+        self.spawn_core(0, tx.clone(), rx, vec![tx])
+    }
+
+    /// Spawns the broker of `core`. `peers[i]` is the routing channel of core `i`,
+    /// and `tx`/`rx` must be the pair for `peers[core]`.
+    pub fn spawn_core(self, core: usize, tx: Tx, rx: Rx, peers: Vec<Tx>) -> Broker {
+        // Statistics are shared by every core, so one core publishes them.
+        if let (0, Some(interval)) = (core, self.sys_interval) {
+            tokio::spawn(stats::publish_sys(self.stats.clone(), tx.clone(), interval));
+        }
+        if let (0, Some(Fsync::Interval)) = (core, self.store.fsync()) {
+            tokio::spawn(store::sync_every_interval(self.store.clone()));
+        }
+
+        self.topic_limits.set(self.settings.topic_limits.clone());
+        let broker = Broker {
+            core,
+            tx,
+            clients: Clients::default(),
+            next_id: self.next_id,
+            sessions: self.sessions,
+            settings: Arc::new(RwLock::new(self.settings)),
+            topic_limits: self.topic_limits,
+            per_ip: self.per_ip,
+            store: self.store,
+            history: self.history,
+            interest: self.interest,
+            groups: self.groups,
+            scheduler: self.scheduler,
+            dead_letter_topic: self.dead_letter_topic,
+            hooks: self.hooks.into(),
+            stats: self.stats,
+        };
+        tokio::spawn(manage(broker.clone(), rx, peers));
+        if core == 0 {
+            tokio::spawn(store::expire_every_interval(broker.clone()));
+            broker.scheduler.load(&broker.store);
+            let scheduled = broker.scheduler.len() as u64;
+            broker.stats.scheduled.store(scheduled, Ordering::Relaxed);
+            broker.count_retained();
+            tokio::spawn(schedule::run(broker.clone()));
+            for bridge in self.bridges {
+                tokio::spawn(bridge::run(bridge, broker.clone()));
+            }
+            if let Some(cluster) = self.cluster {
+                for peer in &cluster.peers {
+                    tokio::spawn(cluster::link(cluster.clone(), peer.clone(), broker.clone()));
+                }
+            }
+        }
+        broker
+    }
+}
+
+impl Broker {
+    pub fn builder() -> BrokerBuilder {
+        BrokerBuilder::default()
+    }
+
+    /// Spawns a standalone broker with default settings on the current runtime.
+    pub fn spawn() -> Broker {
+        Broker::builder().spawn()
+    }
+
+    pub fn stats(&self) -> &Arc<Stats> {
+        &self.stats
+    }
+
+    /// Replaces the access policy and limits of this broker, as on SIGHUP.
+    /// Existing clients are disconnected only if their login is no longer
+    /// accepted, and lose only the subscriptions they are no longer allowed;
+    /// new limits apply to what they do next.
+    pub fn reload(&self, settings: Settings) {
+        let _ = self.tx.send(Command::Reload { settings });
+    }
+
+    /// Closes the connection of client `id`, on whichever core it is
+    /// connected. Returns whether such a client was connected.
+    pub fn disconnect(&self, id: ClientId) -> bool {
+        match self.sessions.read().unwrap().get(&id) {
+            Some(session) => session.tx.send(Command::Disconnect).is_ok(),
+            None => false,
+        }
+    }
+
+    /// The connected clients of every core, in no particular order.
+    pub(crate) fn sessions(&self) -> Vec<Arc<Session>> {
+        self.sessions.read().unwrap().values().cloned().collect()
+    }
+
+    /// Counts a connection from `peer` against the connection limits, or
+    /// returns the limit it is over.
+    fn admit(&self, peer: &PeerAddr) -> Result<ConnectionSlot, &'static str> {
+        let limits = self.limits();
+        let clients = self.stats.clients.fetch_add(1, Ordering::Relaxed) + 1;
+        let mut slot = ConnectionSlot {
+            stats: self.stats.clone(),
+            per_ip: self.per_ip.clone(),
+            ip: None,
+        };
+        if limits
+            .max_connections
+            .is_some_and(|max| clients > max as u64)
+        {
+            return Err("max-connections");
+        }
+        if let (PeerAddr::Tcp(addr), Some(max)) = (peer, limits.max_connections_per_ip) {
+            let mut per_ip = self.per_ip.lock().unwrap();
+            let count = per_ip.entry(addr.ip()).or_default();
+            if *count >= max {
+                return Err("max-connections-per-ip");
+            }
+            *count += 1;
+            slot.ip = Some(addr.ip());
+        }
+        Ok(slot)
+    }
+
+    fn policy(&self) -> Policy {
+        self.settings.read().unwrap().policy.clone()
+    }
+
+    fn limits(&self) -> Limits {
+        self.settings.read().unwrap().limits
+    }
+
+    /// The topics the clients of this node are subscribed to.
+    pub(crate) fn interest(&self) -> &Interest {
+        &self.interest
+    }
+
+    /// Delivers a publish that arrived from another node of the cluster to
+    /// the clients of this one.
+    pub(crate) fn publish_from_peer(&self, mut packet: MqttPublishPacket) -> io::Result<()> {
+        packet.retain = false;
+        packet.seq = None;
+        self.inject(packet, true)
+    }
+
+    pub(crate) fn store(&self) -> &Store {
+        &self.store
+    }
+
+    pub(crate) fn scheduler(&self) -> &Scheduler {
+        &self.scheduler
+    }
+
+    /// Routes a scheduled publish that is due and forgets it.
+    pub(crate) fn publish_scheduled(&self, scheduled: Scheduled) -> io::Result<()> {
+        self.stats.scheduled.fetch_sub(1, Ordering::Relaxed);
+        self.inject(scheduled.packet, false)?;
+        self.store.unschedule(scheduled.id)
+    }
+
+    /// The publishes waiting for their time, soonest first.
+    pub(crate) fn scheduled(&self) -> Vec<Scheduled> {
+        self.scheduler.list()
+    }
+
+    /// Cancels scheduled publish `id`. Returns whether it was still waiting.
+    pub(crate) fn cancel_scheduled(&self, id: u64) -> io::Result<bool> {
+        let cancelled = self.scheduler.cancel(&self.store, id)?;
+        if cancelled {
+            self.stats.scheduled.fetch_sub(1, Ordering::Relaxed);
+        }
+        Ok(cancelled)
+    }
+
+    /// Republishes `packet`, dropped for `reason`, on the dead-letter topic,
+    /// if there is one. `origin` is the client that sent it, unless the broker
+    /// did or does not know.
+    pub(crate) fn dead_letter(
+        &self,
+        packet: &MqttPublishPacket,
+        reason: DropReason,
+        origin: Option<ClientId>,
+    ) {
+        let Some(topic) = self.dead_letter_topic else {
+            return;
+        };
+        if packet.topic_name == topic || SYS_TOPICS.contains(&packet.topic_name) {
+            return;
+        }
+        let client_id = origin
+            .and_then(|id| {
+                let sessions = self.sessions.read().unwrap();
+                let client_id = sessions.get(&id)?.client_id.read().unwrap().clone();
+                Some(client_id)
+            })
+            .unwrap_or_default();
+        let envelope = DeadLetter {
+            topic: packet.topic_name,
+            reason,
+            dropped_at: history::now(),
+            client_id,
+            size: packet.payload.len(),
+            payload: packet.payload.clone(),
+        };
+        let dead_letter = MqttPublishPacket {
+            topic_name: topic,
+            qos: packet.qos,
+            retain: false,
+            seq: None,
+            expiry: None,
+            delay: None,
+            response_topic: None,
+            correlation_data: None,
+            payload: envelope.encode(),
+        };
+        if let Err(e) = self.inject(dead_letter, false) {
+            warn!(topic = packet.topic_name, error = %e, "cannot publish dead letter");
+        }
+    }
+
+    /// Records a publish in the store, see [`Store::publish`], and counts the
+    /// retained publishes if it is one.
+    fn persist(&self, packet: &MqttPublishPacket) -> io::Result<Option<u64>> {
+        let stored = self.store.publish(packet)?;
+        if packet.retain {
+            self.count_retained();
+        }
+        Ok(stored)
+    }
+
+    /// Updates [`Stats::retained`] from the store.
+    pub(crate) fn count_retained(&self) {
+        let retained = self.store.retained_count() as u64;
+        self.stats.retained.store(retained, Ordering::Relaxed);
+    }
+
+    /// Routes a publish that no client of this node sent just now: one from
+    /// another node, a scheduled one or a dead letter.
+    fn inject(&self, mut packet: MqttPublishPacket, from_peer: bool) -> io::Result<()> {
+        self.history.record(&mut packet);
+        let stored = self.persist(&packet)?;
+        self.send(Command::Publish {
+            packet: MqttPublishPacket {
+                retain: false,
+                ..packet
+            },
+            received: Instant::now(),
+            stored,
+            origin: None,
+            from_peer,
+        })
+    }
+
+    /// Sends `command` to the routing task of this core.
+    fn send(&self, command: Command) -> io::Result<()> {
+        self.tx
+            .send(command)
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "broker stopped"))
+    }
+
+    /// Adds `topic` to the subscriptions of `session`. Returns whether it was
+    /// not there yet.
+    fn add_subscription(&self, session: &Session, topic: u16) -> io::Result<bool> {
+        if !session.subscriptions.write().unwrap().insert(topic) {
+            return Ok(false);
+        }
+        self.stats.subscriptions.fetch_add(1, Ordering::Relaxed);
+        if !session.link && !SYS_TOPICS.contains(&topic) {
+            self.interest.add(topic);
+        }
+        self.send(Command::Subscribe {
+            packet: MqttSubscribePacket {
+                topic_name: topic,
+                ..Default::default()
+            },
+            client: session.id,
+        })?;
+        Ok(true)
+    }
+
+    /// Removes `topic` from the subscriptions of `session`. Returns whether it
+    /// was there.
+    fn remove_subscription(&self, session: &Session, topic: u16) -> io::Result<bool> {
+        if !session.subscriptions.write().unwrap().remove(&topic) {
+            return Ok(false);
+        }
+        self.stats.subscriptions.fetch_sub(1, Ordering::Relaxed);
+        if !session.link && !SYS_TOPICS.contains(&topic) {
+            self.interest.remove(topic);
+        }
+        self.send(Command::Unsubscribe {
+            topic,
+            client: session.id,
+        })?;
+        Ok(true)
+    }
+
+    /// Adds `session` to the shared subscription `group` on `topic`, created
+    /// with `strategy` if it has no members. Returns whether it was not a
+    /// member yet.
+    fn join_group(
+        &self,
+        session: &Arc<Session>,
+        topic: u16,
+        group: &Arc<str>,
+        strategy: ShareStrategy,
+    ) -> bool {
+        if !self.groups.join(topic, group, strategy, session) {
+            return false;
+        }
+        self.stats.subscriptions.fetch_add(1, Ordering::Relaxed);
+        if !session.link && !SYS_TOPICS.contains(&topic) {
+            self.interest.add(topic);
+        }
+        true
+    }
+
+    /// Takes `session` out of the shared subscription `group` on `topic`,
+    /// which it joined.
+    fn leave_group(&self, session: &Session, topic: u16, group: &str) {
+        self.groups.leave(topic, group, session.id);
+        self.stats.subscriptions.fetch_sub(1, Ordering::Relaxed);
+        if !session.link && !SYS_TOPICS.contains(&topic) {
+            self.interest.remove(topic);
+        }
+    }
+
+    /// Accepts connections on `listener` until it fails.
+    pub async fn serve(&self, listener: &Listener) -> io::Result<()> {
+        loop {
+            let (stream, addr, slot) = listener.accept().await?;
+            self.spawn_connection(stream, addr, Some(slot), false);
+        }
+    }
+
+    /// Accepts the links of the other nodes of the cluster on `listener`
+    /// until it fails. They are sent the publishes of this node's clients
+    /// on the topics they subscribe to, without access checks or
+    /// subscription limits, so the listener must only be reachable by them.
+    pub async fn serve_peers(&self, listener: &Listener) -> io::Result<()> {
+        loop {
+            let (stream, addr, slot) = listener.accept().await?;
+            self.spawn_connection(stream, addr, Some(slot), true);
+        }
+    }
+
+    /// Connects a client running in the same process, logged in as `options`
+    /// say. It is served like any other, limits and access policy included.
+    pub async fn connect_local(&self, options: &ConnectOptions) -> io::Result<Client> {
+        let (client, server) = tokio::io::duplex(LOCAL_BUFFER);
+        self.spawn_connection(Box::new(server), PeerAddr::Local, None, false);
+        Client::over(Box::new(client), options).await
+    }
+
+    /// Serves a client, or a `link` from another node, on its own task.
+    /// `slot` is held until it disconnects.
+    fn spawn_connection(&self, stream: Stream, addr: PeerAddr, slot: Option<Slot>, link: bool) {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        self.stats.connections.fetch_add(1, Ordering::Relaxed);
+        let broker = self.clone();
+        let span = info_span!(
+            "connection",
+            peer = %addr,
+            conn = id,
+            client_id = field::Empty,
+            username = field::Empty,
+        );
+
+        tokio::spawn(
+            async move {
+                if let Err(e) = process(stream, id, addr, slot.as_ref(), link, broker).await {
+                    warn!(error = %e, "connection failed");
+                }
+                drop(slot);
+            }
+            .instrument(span),
+        );
+    }
+
+    async fn authenticate(&self, connect: &MqttConnectPacket) -> ConnectReturnCode {
+        let policy = self.policy();
+        match (&connect.username, &policy.authenticator) {
+            (Some(username), Some(authenticator)) => {
+                let password = connect.password.as_deref().unwrap_or_default();
+                if authenticator
+                    .authenticate(&connect.client_id, username, password)
+                    .await
+                {
+                    ConnectReturnCode::Accepted
+                } else {
+                    ConnectReturnCode::BadUsernameOrPassword
+                }
+            }
+            _ if policy.allow_anonymous => ConnectReturnCode::Accepted,
+            _ => ConnectReturnCode::NotAuthorized,
+        }
+    }
+
+    /// The fingerprint of the credentials `login` logs in with, if an
+    /// authenticator checks them.
+    async fn fingerprint(&self, login: &MqttConnectPacket) -> Option<u64> {
+        match (&login.username, &self.policy().authenticator) {
+            (Some(username), Some(authenticator)) => authenticator.fingerprint(username).await,
+            _ => None,
+        }
+    }
+
+    /// Checks a login accepted before a reload against the current policy.
+    /// Its password is not kept: `fingerprint` stands for the credentials it
+    /// was accepted with.
+    async fn reauthenticate(
+        &self,
+        login: &MqttConnectPacket,
+        fingerprint: Option<u64>,
+    ) -> ConnectReturnCode {
+        let policy = self.policy();
+        match (&login.username, &policy.authenticator) {
+            (Some(username), Some(authenticator)) => {
+                if fingerprint.is_some() && authenticator.fingerprint(username).await == fingerprint
+                {
+                    ConnectReturnCode::Accepted
+                } else {
+                    ConnectReturnCode::BadUsernameOrPassword
+                }
+            }
+            _ if policy.allow_anonymous => ConnectReturnCode::Accepted,
+            _ => ConnectReturnCode::NotAuthorized,
+        }
+    }
+
+    async fn authorize(&self, login: &MqttConnectPacket, access: Access, topic: u16) -> bool {
+        match &self.policy().authorizer {
+            Some(authorizer) => {
+                authorizer
+                    .authorize(&login.client_id, login.username.as_deref(), access, topic)
+                    .await
+            }
+            None => true,
+        }
+    }
+}
+
+async fn manage(broker: Broker, mut rx: Rx, peers: Vec<Tx>) {
+    let mut subscription_table = HashMap::<u16, Vec<ClientId>>::new();
+    let mut remote_interest = HashMap::<u16, HashSet<usize>>::new();
+
+    while let Some(cmd) = rx.recv().await {
+        match cmd {
+            Command::Publish {
+                packet,
+                received,
+                stored,
+                origin,
+                from_peer,
+            } => {
+                // Shared subscriptions span the cores, so each publish is
+                // shared out by the core it was sent to only.
+                let groups_on_topic = broker.groups.on(packet.topic_name);
+                if !groups_on_topic.is_empty() {
+                    // Clients send their publishes to the core they are
+                    // connected to.
+                    let publisher = match origin {
+                        Some(id) => broker
+                            .clients
+                            .lock()
+                            .await
+                            .get(&id)
+                            .map_or(0, |s| shared::publisher(s)),
+                        None => 0,
+                    };
+                    for group in groups_on_topic {
+                        share(&broker, packet.clone(), received, group, publisher, origin);
+                    }
+                }
+                if let Some(cores) = remote_interest.get(&packet.topic_name) {
+                    for &peer in cores {
+                        let _ = peers[peer].send(Command::Forward {
+                            packet: packet.clone(),
+                            received,
+                            stored,
+                            origin,
+                            from_peer,
+                        });
+                    }
+                }
+                let publish = Command::Publish {
+                    packet,
+                    received,
+                    stored,
+                    origin,
+                    from_peer,
+                };
+                deliver(&subscription_table, &broker, publish).await;
+            }
+            Command::Forward {
+                packet,
+                received,
+                stored,
+                origin,
+                from_peer,
+            } => {
+                let publish = Command::Publish {
+                    packet,
+                    received,
+                    stored,
+                    origin,
+                    from_peer,
+                };
+                deliver(&subscription_table, &broker, publish).await;
+            }
+            Command::Subscribe { packet, client } => {
+                let subscriptions = subscription_table.entry(packet.topic_name).or_default();
+                if subscriptions.is_empty() {
+                    announce(&broker, &peers, packet.topic_name, true);
+                }
+                subscriptions.push(client);
+            }
+            Command::Interest {
+                core,
+                topic,
+                subscribed: true,
+            } => {
+                remote_interest.entry(topic).or_default().insert(core);
+            }
+            Command::Interest {
+                core,
+                topic,
+                subscribed: false,
+            } => {
+                if let Some(cores) = remote_interest.get_mut(&topic) {
+                    cores.remove(&core);
+                    if cores.is_empty() {
+                        remote_interest.remove(&topic);
+                    }
+                }
+            }
+            Command::Unsubscribe { topic, client } => {
+                if let Some(subscriptions) = subscription_table.get_mut(&topic) {
+                    subscriptions.retain(|&subscriber| subscriber != client);
+                    if subscriptions.is_empty() {
+                        subscription_table.remove(&topic);
+                        announce(&broker, &peers, topic, false);
+                    }
+                }
+            }
+            Command::Reload { settings } => {
+                broker.topic_limits.set(settings.topic_limits.clone());
+                *broker.settings.write().unwrap() = settings.clone();
+                for client in broker.clients.lock().await.values() {
+                    let _ = client.tx.send(Command::Reload {
+                        settings: settings.clone(),
+                    });
+                }
+            }
+            // Only sent to clients.
+            Command::Shared { .. } | Command::Disconnect => {}
+        }
+    }
+}
+
+/// Tells the other cores whether this one has local subscribers on `topic`,
+/// so that they forward its publishes here only while it does.
+fn announce(broker: &Broker, peers: &[Tx], topic: u16, subscribed: bool) {
+    for (peer, tx) in peers.iter().enumerate() {
+        if peer != broker.core {
+            let _ = tx.send(Command::Interest {
+                core: broker.core,
+                topic,
+                subscribed,
+            });
+        }
+    }
+}
+
+/// Passes `publish`, a [`Command::Publish`], on to the local subscribers of
+/// its topic.
+async fn deliver(
+    subscription_table: &HashMap<u16, Vec<ClientId>>,
+    broker: &Broker,
+    publish: Command,
+) {
+    let Command::Publish { packet, origin, .. } = &publish else {
+        return;
+    };
+    let stats = &broker.stats;
+    if let Some(subscriptions) = subscription_table.get(&packet.topic_name) {
+        let clients = broker.clients.lock().await;
+        for subscriber in subscriptions {
+            let delivered = clients
+                .get(subscriber)
+                .is_some_and(|subscriber| subscriber.enqueue(stats, publish.clone()).is_none());
+            if !delivered {
+                stats.undeliverable.fetch_add(1, Ordering::Relaxed);
+                broker.dead_letter(packet, DropReason::Undeliverable, *origin);
+            }
+        }
+    }
+}
+
+/// Sends a publish to the member of the shared subscription `group` that
+/// the group picks, or drops it as undeliverable if the group has none left.
+fn share(
+    broker: &Broker,
+    packet: MqttPublishPacket,
+    received: Instant,
+    group: Arc<str>,
+    publisher: u64,
+    origin: Option<ClientId>,
+) {
+    let (groups, stats) = (&broker.groups, &broker.stats);
+    let topic = packet.topic_name;
+    let mut publish = Command::Shared {
+        packet,
+        received,
+        group: group.clone(),
+        publisher,
+        origin,
+    };
+    while let Some(member) = groups.pick(topic, &group, publisher) {
+        match member.enqueue(stats, publish) {
+            None => return,
+            Some(returned) => {
+                // The member is disconnecting; it leaves the group itself too.
+                groups.leave(topic, &group, member.id);
+                publish = returned;
+            }
+        }
+    }
+    stats.undeliverable.fetch_add(1, Ordering::Relaxed);
+    if let Command::Shared { packet, .. } = &publish {
+        broker.dead_letter(packet, DropReason::Undeliverable, origin);
+    }
+}
+
+/// Counts the expiry interval of `packet` down by the time since `received`,
+/// rounding up. Returns `false`, dropping the publish `origin` sent, if it has
+/// expired.
+fn count_down(
+    packet: &mut MqttPublishPacket,
+    received: Instant,
+    origin: Option<ClientId>,
+    broker: &Broker,
+) -> bool {
+    let Some(expiry) = packet.expiry else {
+        return true;
+    };
+    let elapsed = received.elapsed();
+    if elapsed >= Duration::from_secs(expiry.into()) {
+        broker.stats.expired.fetch_add(1, Ordering::Relaxed);
+        trace!(topic = packet.topic_name, "publish expired");
+        broker.dead_letter(packet, DropReason::Expired, origin);
+        return false;
+    }
+    packet.expiry = Some(expiry - elapsed.as_secs() as u32);
+    true
+}
+
+/// A QoS 1 or 2 publish sent to the client and not yet acknowledged.
+struct Inflight {
+    /// The sequence number it is stored under for the persistent session.
+    stored: Option<u64>,
+    /// The shared subscription group it was sent to the client through, the
+    /// publisher key, the publisher and the publish, to send to another member
+    /// of the group if the client leaves without acknowledging it.
+    shared: Option<(Arc<str>, u64, Option<ClientId>, MqttPublishPacket)>,
+}
+
+impl Inflight {
+    fn stored(stored: Option<u64>) -> Inflight {
+        Inflight {
+            stored,
+            shared: None,
+        }
+    }
+}
+
+/// Serves one client. Runs inside its `connection` span, which carries the
+/// peer address and, once the client logs in, its client id and username.
+async fn process(
+    stream: Stream,
+    id: ClientId,
+    peer: PeerAddr,
+    slot: Option<&Slot>,
+    link: bool,
+    broker: Broker,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let codec = MQTinyCodec {
+        max_packet_size: broker.limits().max_packet_size,
+    };
+    let mut framed = Framed::new(stream, codec);
+    let _slot = match broker.admit(&peer) {
+        Ok(slot) => slot,
+        Err(limit) => {
+            broker
+                .stats
+                .connections_rejected
+                .fetch_add(1, Ordering::Relaxed);
+            warn!(limit, "connection refused: limit reached");
+            let return_code = ConnectReturnCode::ServerUnavailable;
+            framed
+                .send(MqttPacket::Connack(MqttConnackPacket { return_code }))
+                .await?;
+            return Ok(());
+        }
+    };
+    let (tx, mut rx) = mpsc::unbounded_channel();
+    let session = Arc::new(Session {
+        id,
+        peer: peer.to_string(),
+        core: broker.core,
+        client_id: RwLock::default(),
+        username: RwLock::default(),
+        subscriptions: RwLock::default(),
+        queued: AtomicU64::new(0),
+        bytes_in: AtomicU64::new(0),
+        bytes_out: AtomicU64::new(0),
+        throttled: AtomicU64::new(0),
+        link,
+        tx,
+    });
+    broker.clients.lock().await.insert(id, session.clone());
+    broker.sessions.write().unwrap().insert(id, session.clone());
+    info!("client connected");
+    let client_limit = broker.settings.read().unwrap().client_limit;
+    let mut client_limiter = client_limit.map(Limiter::new);
+    // QoS 1 and 2 publishes sent to a persistent session or a member of a
+    // shared subscription and not yet acknowledged, oldest first.
+    let mut inflight = VecDeque::<Inflight>::new();
+    // The shared subscriptions the client is a member of.
+    let mut groups = HashSet::<(u16, Arc<str>)>::new();
+    // Links run no hooks: their node ran them for its own clients.
+    let hooks = if link {
+        Hooks::default()
+    } else {
+        broker.hooks.clone()
+    };
+    // The client as hooks see it, and whether they let it in.
+    let mut info = ClientInfo {
+        conn: id,
+        peer: session.peer.clone(),
+        ..Default::default()
+    };
+    let mut connected = false;
+
+    let result: Result<(), Box<dyn Error + Send + Sync>> = async {
+        // Set by a successful connect, or by the first packet of a client that
+        // skips the handshake.
+        let mut logged_in = false;
+        // Who the client logged in as, without the password; all empty for
+        // anonymous clients.
+        let mut login = MqttConnectPacket::default();
+        // The fingerprint of the credentials of the login, to check it again
+        // when the policy is reloaded.
+        let mut fingerprint = None;
+        // The last sequence number replayed from the history of each topic,
+        // so that live publishes up to it are not sent twice.
+        let mut replayed = HashMap::<u16, u64>::new();
+        // The stored publishes sent or dropped when the session was resumed,
+        // which may also be on their way live.
+        let mut resumed = HashSet::<u64>::new();
+        // Topics subscribed to with `no_local`.
+        let mut no_local = HashSet::<u16>::new();
+
+        loop {
+            tokio::select! {
+                Some(msg) = rx.recv() => match msg {
+                    Command::Publish { packet, received, stored, origin, from_peer } => {
+                        let stats = &broker.stats;
+                        stats.queued.fetch_sub(1, Ordering::Relaxed);
+                        session.queued.fetch_sub(1, Ordering::Relaxed);
+                        if link && from_peer {
+                            // The node it came from links to every other.
+                            continue;
+                        }
+                        if stored.is_some_and(|seq| resumed.remove(&seq)) {
+                            // Already taken care of with the stored session.
+                            continue;
+                        }
+                        if origin == Some(id) && no_local.contains(&packet.topic_name) {
+                            // The client's own publish; it is not sent later
+                            // either.
+                            if let (true, Some(seq)) = (login.persistent, stored) {
+                                broker.store.ack(&login.client_id, seq)?;
+                            }
+                            continue;
+                        }
+                        let last = replayed.get(&packet.topic_name);
+                        if packet.seq.is_some_and(|seq| last.is_some_and(|&last| seq <= last)) {
+                            // Already sent from the history.
+                            continue;
+                        }
+                        let mut packet = packet;
+                        if !count_down(&mut packet, received, origin, &broker) {
+                            if let (true, Some(seq)) = (login.persistent, stored) {
+                                broker.store.ack(&login.client_id, seq)?;
+                            }
+                            continue;
+                        }
+                        let Some(packet) = hooks.deliver(&info, packet).await else {
+                            if let (true, Some(seq)) = (login.persistent, stored) {
+                                broker.store.ack(&login.client_id, seq)?;
+                            }
+                            continue;
+                        };
+                        let qos = packet.qos;
+                        if !send_publish(&mut framed, &broker, &session, packet).await? {
+                            if let (true, Some(seq)) = (login.persistent, stored) {
+                                broker.store.ack(&login.client_id, seq)?;
+                            }
+                            continue;
+                        }
+                        if qos != QoS::AtMostOnce {
+                            track(&mut inflight, &broker.stats, Inflight::stored(stored));
+                        }
+                        let latency = received.elapsed().as_micros() as u64;
+                        stats.routing_latency.observe(&ROUTING_LATENCY_BUCKETS, latency);
+                    }
+                    Command::Shared { packet, received, group, publisher, origin } => {
+                        let stats = &broker.stats;
+                        stats.queued.fetch_sub(1, Ordering::Relaxed);
+                        session.queued.fetch_sub(1, Ordering::Relaxed);
+                        if !groups.contains(&(packet.topic_name, group.clone())) {
+                            // Left the group since it was picked.
+                            share(&broker, packet, received, group, publisher, origin);
+                            continue;
+                        }
+                        let mut packet = packet;
+                        if !count_down(&mut packet, received, origin, &broker) {
+                            continue;
+                        }
+                        let Some(packet) = hooks.deliver(&info, packet).await else {
+                            continue;
+                        };
+                        let unacked = (packet.qos != QoS::AtMostOnce).then(|| packet.clone());
+                        if !send_publish(&mut framed, &broker, &session, packet).await? {
+                            continue;
+                        }
+                        if let Some(packet) = unacked {
+                            track(&mut inflight, &broker.stats, Inflight {
+                                stored: None,
+                                shared: Some((group, publisher, origin, packet)),
+                            });
+                        }
+                        let latency = received.elapsed().as_micros() as u64;
+                        stats.routing_latency.observe(&ROUTING_LATENCY_BUCKETS, latency);
+                    }
+                    Command::Reload { settings } => {
+                        framed.codec_mut().max_packet_size = settings.limits.max_packet_size;
+                        if client_limiter.as_ref().map(Limiter::limit) != settings.client_limit {
+                            client_limiter = settings.client_limit.map(Limiter::new);
+                        }
+                        let return_code = broker.reauthenticate(&login, fingerprint).await;
+                        if return_code != ConnectReturnCode::Accepted {
+                            warn!(?return_code, "login revoked by reload");
+                            break;
+                        }
+                        for (topic, group) in groups.clone() {
+                            if !link && !broker.authorize(&login, Access::Subscribe, topic).await {
+                                info!(topic, group = &*group, "shared subscription revoked by reload");
+                                broker.leave_group(&session, topic, &group);
+                                groups.remove(&(topic, group));
+                                framed
+                                    .send(MqttPacket::Suback(MqttSubackPacket {
+                                        topic_name: topic,
+                                        return_code: SubackReturnCode::Failure,
+                                    }))
+                                    .await?;
+                            }
+                        }
+                        let topics = session.subscriptions.read().unwrap().clone();
+                        for topic in topics {
+                            if !link && !broker.authorize(&login, Access::Subscribe, topic).await {
+                                info!(topic, "subscription revoked by reload");
+                                broker.remove_subscription(&session, topic)?;
+                                if login.persistent {
+                                    broker.store.unsubscribe(&login.client_id, topic)?;
+                                }
+                                framed
+                                    .send(MqttPacket::Suback(MqttSubackPacket {
+                                        topic_name: topic,
+                                        return_code: SubackReturnCode::Failure,
+                                    }))
+                                    .await?;
+                            }
+                        }
+                    }
+                    Command::Disconnect => {
+                        info!("disconnected by admin");
+                        break;
+                    }
+                    _ => {}
+                },
+                result = framed.next() => match result {
+                    Some(Ok(MqttPacket::Connect(connect))) if !logged_in => {
+                        let mut return_code = if connect.persistent && connect.client_id.is_empty() {
+                            ConnectReturnCode::IdentifierRejected
+                        } else {
+                            broker.authenticate(&connect).await
+                        };
+                        let username = connect.username.as_deref();
+                        if return_code == ConnectReturnCode::Accepted
+                            && !broker.store.owned_by(&connect.client_id, username)
+                        {
+                            info!(client_id = connect.client_id, "session belongs to another user");
+                            return_code = ConnectReturnCode::NotAuthorized;
+                        }
+                        if return_code == ConnectReturnCode::Accepted {
+                            info.client_id = connect.client_id.clone();
+                            info.username = connect.username.clone();
+                            if !hooks.connect(&info).await {
+                                return_code = ConnectReturnCode::NotAuthorized;
+                            }
+                        }
+                        framed
+                            .send(MqttPacket::Connack(MqttConnackPacket { return_code }))
+                            .await?;
+                        if return_code != ConnectReturnCode::Accepted {
+                            broker.stats().auth_failures.fetch_add(1, Ordering::Relaxed);
+                            warn!(
+                                client_id = connect.client_id,
+                                username = connect.username,
+                                ?return_code,
+                                "login refused"
+                            );
+                            break;
+                        }
+                        let span = Span::current();
+                        span.record("client_id", connect.client_id.as_str());
+                        if let Some(username) = &connect.username {
+                            span.record("username", username.as_str());
+                        }
+                        info!("logged in");
+                        connected = true;
+                        *session.client_id.write().unwrap() = connect.client_id.clone();
+                        *session.username.write().unwrap() = connect.username.clone();
+                        logged_in = true;
+                        fingerprint = broker.fingerprint(&connect).await;
+                        login = MqttConnectPacket {
+                            password: None,
+                            ..connect
+                        };
+                        if login.persistent {
+                            resumed = resume(&mut framed, &broker, &session, &login, &hooks, &info, &mut inflight)
+                                .await?;
+                        } else if !login.client_id.is_empty() {
+                            broker.store.forget(&login.client_id)?;
+                        }
+                    }
+                    Some(Ok(_)) if !logged_in && !broker.policy().allow_anonymous => {
+                        broker.stats().auth_failures.fetch_add(1, Ordering::Relaxed);
+                        warn!("login refused: anonymous clients are not allowed");
+                        let return_code = ConnectReturnCode::NotAuthorized;
+                        framed
+                            .send(MqttPacket::Connack(MqttConnackPacket { return_code }))
+                            .await?;
+                        break;
+                    }
+                    Some(Ok(packet)) => {
+                        if !logged_in {
+                            if !hooks.connect(&info).await {
+                                broker.stats().auth_failures.fetch_add(1, Ordering::Relaxed);
+                                warn!("login refused by a hook");
+                                let return_code = ConnectReturnCode::NotAuthorized;
+                                framed
+                                    .send(MqttPacket::Connack(MqttConnackPacket { return_code }))
+                                    .await?;
+                                break;
+                            }
+                            connected = true;
+                        }
+                        logged_in = true;
+                        match packet {
+                            MqttPacket::Publish(mut publish) => {
+                                trace!(
+                                    topic = publish.topic_name,
+                                    qos = ?publish.qos,
+                                    size = publish.payload.len(),
+                                    "publish"
+                                );
+                                let received = Instant::now();
+                                let stats = &broker.stats;
+                                let size = publish.payload.len() as u64;
+                                stats.messages_in[publish.qos as usize]
+                                    .fetch_add(1, Ordering::Relaxed);
+                                stats.bytes_in.fetch_add(size, Ordering::Relaxed);
+                                session.bytes_in.fetch_add(size, Ordering::Relaxed);
+                                stats.payload_size.observe(&PAYLOAD_SIZE_BUCKETS, size);
+                                let listener_limiter = slot.and_then(Slot::limiter);
+                                let topic_limiter = broker.topic_limits.get(publish.topic_name);
+                                let limiters = [
+                                    client_limiter.as_ref(),
+                                    listener_limiter.as_deref(),
+                                    topic_limiter.as_deref(),
+                                ];
+                                let throttled =
+                                    ratelimit::admit(limiters.into_iter().flatten(), size as usize)
+                                        .await;
+                                if let Some(action) = throttled {
+                                    stats.throttled[action as usize]
+                                        .fetch_add(1, Ordering::Relaxed);
+                                    session.throttled.fetch_add(1, Ordering::Relaxed);
+                                    let topic = publish.topic_name;
+                                    match action {
+                                        RateAction::Delay => debug!(topic, "publish delayed"),
+                                        RateAction::Drop => {
+                                            debug!(topic, "publish dropped by rate limit");
+                                            broker.dead_letter(&publish, DropReason::Throttled, Some(id));
+                                            ack_publish(&mut framed, &broker.store, publish.qos).await?;
+                                            continue;
+                                        }
+                                        RateAction::Disconnect => {
+                                            warn!(topic, "rate limit exceeded, disconnecting");
+                                            break;
+                                        }
+                                    }
+                                }
+                                if SYS_TOPICS.contains(&publish.topic_name)
+                                    || !broker
+                                        .authorize(&login, Access::Publish, publish.topic_name)
+                                        .await
+                                {
+                                    broker.stats().publishes_denied.fetch_add(1, Ordering::Relaxed);
+                                    debug!(topic = publish.topic_name, "publish denied");
+                                    broker.dead_letter(&publish, DropReason::Denied, Some(id));
+                                    ack_publish(&mut framed, &broker.store, publish.qos).await?;
+                                    continue;
+                                }
+                                if !hooks.publish(&info, &mut publish).await {
+                                    stats.rejected.fetch_add(1, Ordering::Relaxed);
+                                    debug!(topic = publish.topic_name, "publish rejected by a hook");
+                                    broker.dead_letter(&publish, DropReason::Rejected, Some(id));
+                                    ack_publish(&mut framed, &broker.store, publish.qos).await?;
+                                    continue;
+                                }
+                                if let Some(delay) = publish.delay.take() {
+                                    let topic = publish.topic_name;
+                                    let qos = publish.qos;
+                                    let id = broker.scheduler.schedule(&broker.store, publish, delay)?;
+                                    stats.scheduled.fetch_add(1, Ordering::Relaxed);
+                                    debug!(topic, id, delay, "publish scheduled");
+                                    ack_publish(&mut framed, &broker.store, qos).await?;
+                                    continue;
+                                }
+                                broker.history.record(&mut publish);
+                                let stored = broker.persist(&publish)?;
+                                let qos = publish.qos;
+                                broker.tx.send(Command::Publish {
+                                    packet: MqttPublishPacket {
+                                        retain: false,
+                                        ..publish
+                                    },
+                                    received,
+                                    stored,
+                                    origin: Some(id),
+                                    from_peer: link,
+                                })?;
+                                ack_publish(&mut framed, &broker.store, qos).await?;
+                            }
+                            MqttPacket::Subscribe(subscribe) => {
+                                if !link
+                                    && !broker
+                                        .authorize(&login, Access::Subscribe, subscribe.topic_name)
+                                        .await
+                                {
+                                    broker.stats().subscribes_denied.fetch_add(1, Ordering::Relaxed);
+                                    info!(topic = subscribe.topic_name, "subscribe denied");
+                                    framed
+                                        .send(MqttPacket::Suback(MqttSubackPacket {
+                                            topic_name: subscribe.topic_name,
+                                            return_code: SubackReturnCode::Failure,
+                                        }))
+                                        .await?;
+                                    continue;
+                                }
+                                let topic = subscribe.topic_name;
+                                let share = subscribe
+                                    .share
+                                    .map(|share| (Arc::<str>::from(share.group), share.strategy));
+                                // Shared subscriptions count against the limit too.
+                                let over_limit = !link
+                                    && broker.limits().max_subscriptions.is_some_and(|max| {
+                                        let subscriptions = session.subscriptions.read().unwrap();
+                                        let held = match &share {
+                                            Some((group, _)) => groups.contains(&(topic, group.clone())),
+                                            None => subscriptions.contains(&topic),
+                                        };
+                                        !held && subscriptions.len() + groups.len() >= max
+                                    });
+                                if over_limit {
+                                    broker
+                                        .stats
+                                        .subscriptions_rejected
+                                        .fetch_add(1, Ordering::Relaxed);
+                                    warn!(topic, "subscribe refused: subscription limit reached");
+                                    framed
+                                        .send(MqttPacket::Suback(MqttSubackPacket {
+                                            topic_name: topic,
+                                            return_code: SubackReturnCode::Failure,
+                                        }))
+                                        .await?;
+                                    continue;
+                                }
+                                if !hooks.subscribe(&info, topic).await {
+                                    info!(topic, "subscribe refused by a hook");
+                                    framed
+                                        .send(MqttPacket::Suback(MqttSubackPacket {
+                                            topic_name: topic,
+                                            return_code: SubackReturnCode::Failure,
+                                        }))
+                                        .await?;
+                                    continue;
+                                }
+                                if let Some((group, strategy)) = share {
+                                    if broker.join_group(&session, topic, &group, strategy) {
+                                        info!(topic, group = &*group, "joined shared subscription");
+                                        groups.insert((topic, group));
+                                    }
+                                    continue;
+                                }
+                                info!(topic = subscribe.topic_name, "subscribed");
+                                if subscribe.no_local {
+                                    no_local.insert(topic);
+                                } else {
+                                    no_local.remove(&topic);
+                                }
+                                broker.add_subscription(&session, topic)?;
+                                if login.persistent {
+                                    broker.store.subscribe(&login.client_id, topic)?;
+                                }
+                                let retained = match broker.store.retained(topic) {
+                                    Some(retained) => {
+                                        let packet = MqttPublishPacket {
+                                            retain: true,
+                                            ..retained
+                                        };
+                                        hooks.deliver(&info, packet).await
+                                    }
+                                    None => None,
+                                };
+                                if let Some(packet) = retained {
+                                    let qos = packet.qos;
+                                    if send_publish(&mut framed, &broker, &session, packet).await?
+                                        && qos != QoS::AtMostOnce
+                                    {
+                                        track(&mut inflight, &broker.stats, Inflight::stored(None));
+                                    }
+                                }
+                                if let Some(replay) = subscribe.replay {
+                                    let packets = broker.history.replay(topic, replay);
+                                    debug!(topic, ?replay, count = packets.len(), "replaying history");
+                                    if let Some(last) = packets.last().and_then(|packet| packet.seq) {
+                                        replayed.insert(topic, last);
+                                    }
+                                    for packet in packets {
+                                        let Some(packet) = hooks.deliver(&info, packet).await else {
+                                            continue;
+                                        };
+                                        let qos = packet.qos;
+                                        if send_publish(&mut framed, &broker, &session, packet).await?
+                                            && qos != QoS::AtMostOnce
+                                        {
+                                            track(&mut inflight, &broker.stats, Inflight::stored(None));
+                                        }
+                                    }
+                                }
+                            }
+                            MqttPacket::Unsubscribe(unsubscribe) => {
+                                let topic = unsubscribe.topic_name;
+                                if let Some(group) = unsubscribe.group {
+                                    let group = Arc::<str>::from(group);
+                                    if groups.remove(&(topic, group.clone())) {
+                                        info!(topic, group = &*group, "left shared subscription");
+                                        broker.leave_group(&session, topic, &group);
+                                    }
+                                } else if broker.remove_subscription(&session, topic)? {
+                                    info!(topic, "unsubscribed");
+                                    no_local.remove(&topic);
+                                    replayed.remove(&topic);
+                                    if login.persistent {
+                                        broker.store.unsubscribe(&login.client_id, topic)?;
+                                    }
+                                }
+                            }
+                            MqttPacket::Puback(_) => {
+                                if let Some(acked) = inflight.pop_front() {
+                                    broker.stats.inflight.fetch_sub(1, Ordering::Relaxed);
+                                    if let Some(seq) = acked.stored {
+                                        broker.store.ack(&login.client_id, seq)?;
+                                    }
+                                }
+                            }
+                            MqttPacket::Connect(_) => {
+                                warn!("second connect, closing");
+                                break;
+                            }
+                            _ => {}
+                        }
+                    }
+                    Some(Err(e)) => {
+                        broker.stats.invalid_packets.fetch_add(1, Ordering::Relaxed);
+                        warn!(error = %e, "invalid packet");
+                    }
+                    None => break,
+                }
+            }
+        }
+        Ok(())
+    }
+    .await;
+
+    broker.clients.lock().await.remove(&id);
+    broker.sessions.write().unwrap().remove(&id);
+    for (topic, group) in &groups {
+        broker.leave_group(&session, *topic, group);
+    }
+    // Nothing is queued for the client once it is removed. What was queued or
+    // is unacknowledged from shared subscriptions goes to other members.
+    rx.close();
+    while let Ok(msg) = rx.try_recv() {
+        match msg {
+            Command::Publish { packet, origin, .. } => {
+                broker.stats.queued.fetch_sub(1, Ordering::Relaxed);
+                broker.stats.undeliverable.fetch_add(1, Ordering::Relaxed);
+                broker.dead_letter(&packet, DropReason::Undeliverable, origin);
+            }
+            Command::Shared {
+                packet,
+                received,
+                group,
+                publisher,
+                origin,
+            } => {
+                broker.stats.queued.fetch_sub(1, Ordering::Relaxed);
+                share(&broker, packet, received, group, publisher, origin);
+            }
+            _ => {}
+        }
+    }
+    broker
+        .stats
+        .inflight
+        .fetch_sub(inflight.len() as u64, Ordering::Relaxed);
+    let unacked: Vec<_> = inflight.into_iter().filter_map(|i| i.shared).collect();
+    if !unacked.is_empty() {
+        debug!(
+            count = unacked.len(),
+            "redistributing unacknowledged shared publishes"
+        );
+    }
+    for (group, publisher, origin, packet) in unacked {
+        share(&broker, packet, Instant::now(), group, publisher, origin);
+    }
+    let topics = session.subscriptions.read().unwrap().clone();
+    for topic in topics {
+        let _ = broker.remove_subscription(&session, topic);
+    }
+    if connected {
+        hooks.disconnect(&info).await;
+    }
+    info!("client disconnected");
+
+    result
+}
+
+/// Restores the subscriptions of the persistent session of `login`, as far as
+/// the client is still allowed them, and sends the publishes queued for it.
+/// Another connection holding the session is closed.
+///
+/// The queued publishes are read once the subscriptions are restored, so
+/// those stored since are sent live. Those read may also still be on their
+/// way live; their sequence numbers are returned so that they are not sent
+/// twice.
+async fn resume(
+    framed: &mut Framed<Stream, MQTinyCodec>,
+    broker: &Broker,
+    session: &Session,
+    login: &MqttConnectPacket,
+    hooks: &Hooks,
+    info: &ClientInfo,
+    inflight: &mut VecDeque<Inflight>,
+) -> Result<HashSet<u64>, Box<dyn Error + Send + Sync>> {
+    // Checked before the login was accepted, but another user may have
+    // claimed a new session since.
+    if !broker
+        .store
+        .claim(&login.client_id, login.username.as_deref())?
+    {
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            "session claimed by another user",
+        )
+        .into());
+    }
+    for other in broker.sessions() {
+        if other.id != session.id
+            && *other.client_id.read().unwrap() == login.client_id
+            && *other.username.read().unwrap() == login.username
+        {
+            info!(conn = other.id, "session taken over");
+            broker.disconnect(other.id);
+        }
+    }
+
+    for topic in broker.store.subscriptions(&login.client_id) {
+        if !broker.authorize(login, Access::Subscribe, topic).await {
+            info!(topic, "stored subscription no longer allowed");
+            broker.store.unsubscribe(&login.client_id, topic)?;
+            continue;
+        }
+        broker.add_subscription(session, topic)?;
+    }
+    let queued = broker.store.queued(&login.client_id);
+    let subscriptions = session.subscriptions.read().unwrap().len();
+    info!(subscriptions, queued = queued.len(), "session resumed");
+    let resumed = queued.iter().map(|(seq, _)| *seq).collect();
+    for (seq, packet) in queued {
+        if !broker
+            .authorize(login, Access::Subscribe, packet.topic_name)
+            .await
+        {
+            debug!(
+                topic = packet.topic_name,
+                "queued publish no longer allowed"
+            );
+            broker.store.ack(&login.client_id, seq)?;
+            continue;
+        }
+        let Some(packet) = hooks.deliver(info, packet).await else {
+            broker.store.ack(&login.client_id, seq)?;
+            continue;
+        };
+        if send_publish(framed, broker, session, packet).await? {
+            track(inflight, &broker.stats, Inflight::stored(Some(seq)));
+        } else {
+            broker.store.ack(&login.client_id, seq)?;
+        }
+    }
+    Ok(resumed)
+}
+
+/// Keeps `entry` until the client acknowledges the publish it stands for.
+fn track(inflight: &mut VecDeque<Inflight>, stats: &Stats, entry: Inflight) {
+    inflight.push_back(entry);
+    stats.inflight.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Sends a publish to the client and counts it. Returns `false`, without
+/// sending it, if it is too large for a packet.
+async fn send_publish(
+    framed: &mut Framed<Stream, MQTinyCodec>,
+    broker: &Broker,
+    session: &Session,
+    packet: MqttPublishPacket,
+) -> io::Result<bool> {
+    if packet.encoded_len() > u8::MAX as usize {
+        warn!(
+            topic = packet.topic_name,
+            "publish too large to send, skipping it"
+        );
+        return Ok(false);
+    }
+    let (qos, size) = (packet.qos as usize, packet.payload.len() as u64);
+    framed.send(MqttPacket::Publish(packet)).await?;
+    broker.stats.messages_out[qos].fetch_add(1, Ordering::Relaxed);
+    broker.stats.bytes_out.fetch_add(size, Ordering::Relaxed);
+    session.bytes_out.fetch_add(size, Ordering::Relaxed);
+    Ok(true)
+}
+
+/// Acknowledges a publish of the client, if its QoS asks for it, once
+/// `store` has written what the publish changed.
+async fn ack_publish(
+    framed: &mut Framed<Stream, MQTinyCodec>,
+    store: &Store,
+    qos: QoS,
+) -> io::Result<()> {
+    if qos == QoS::AtMostOnce {
+        return Ok(());
+    }
+    store.written().await?;
+    framed
+        .send(MqttPacket::Puback(MqttPubackPacket::default()))
+        .await
+}
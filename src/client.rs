@@ -0,0 +1,471 @@
+use std::{
+    collections::{hash_map::RandomState, VecDeque},
+    future::Future,
+    hash::BuildHasher,
+    io,
+    path::PathBuf,
+    time::{Duration, SystemTime},
+};
+
+use futures::SinkExt;
+use tokio::net::{TcpStream, UnixStream};
+use tokio_rustls::{rustls::pki_types::ServerName, TlsConnector};
+use tokio_stream::StreamExt;
+use tokio_util::codec::Framed;
+
+use crate::{
+    tls,
+    transport::{Endpoint, Stream},
+    websocket::{ws_error, WsStream},
+    *,
+};
+
+/// Builds a client address from the `--ip` and `--port` arguments of the tools.
+/// `unix://` paths are used as they are; anything else gets the port appended,
+/// so `-i tls://broker -p 8883` connects to `tls://broker:8883`.
+pub fn address(ip: &str, port: u16) -> String {
+    if ip.starts_with("unix://") {
+        ip.to_string()
+    } else {
+        format!("{}:{}", ip, port)
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct ConnectOptions {
+    /// PEM CA certificates to verify a `tls://` broker with, instead of the system roots.
+    pub ca: Option<PathBuf>,
+    /// PEM client certificate, for brokers that require client authentication.
+    pub cert: Option<PathBuf>,
+    /// PEM private key of `cert`.
+    pub key: Option<PathBuf>,
+    /// Identifies the client to the broker. Setting it or `username` makes the
+    /// client log in with a connect packet; otherwise it connects anonymously.
+    pub client_id: Option<String>,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    /// Asks the broker to keep the session of `client_id` across connects,
+    /// see [`MqttConnectPacket::persistent`]. The client must then [`ack`]
+    /// every QoS 1 and 2 publish it receives.
+    ///
+    /// [`ack`]: Client::ack
+    pub persistent: bool,
+}
+
+/// A connection to an MQTiny broker.
+pub struct Client {
+    framed: Framed<Stream, MQTinyCodec>,
+    /// Packets received while waiting for a response, for [`Client::recv`].
+    pending: VecDeque<MqttPacket>,
+    /// Set by [`Client::subscribe_responses`].
+    response_topic: Option<u16>,
+    /// Correlation data of the next request, starting at a random number so
+    /// that clients sharing a response topic do not take each other's
+    /// responses.
+    next_correlation: u64,
+}
+
+impl Client {
+    /// Connects to `addr`: `HOST:PORT`, `tls://HOST:PORT`, `ws://HOST:PORT` or
+    /// `unix:///path/to/socket`.
+    pub async fn connect(addr: &str) -> io::Result<Client> {
+        Client::connect_with(addr, &ConnectOptions::default()).await
+    }
+
+    pub async fn connect_with(addr: &str, options: &ConnectOptions) -> io::Result<Client> {
+        let stream: Stream = match Endpoint::parse(addr) {
+            Endpoint::Tcp(addr) => Box::new(TcpStream::connect(addr).await?),
+            Endpoint::Tls(addr) => {
+                let identity = match (&options.cert, &options.key) {
+                    (Some(cert), Some(key)) => Some((cert.as_path(), key.as_path())),
+                    (None, None) => None,
+                    _ => {
+                        return Err(io::Error::new(
+                            io::ErrorKind::InvalidInput,
+                            "a client certificate needs both cert and key",
+                        ))
+                    }
+                };
+                let config = tls::client_config(options.ca.as_deref(), identity)?;
+                let stream = TcpStream::connect(&addr).await?;
+                Box::new(
+                    TlsConnector::from(config)
+                        .connect(server_name(&addr)?, stream)
+                        .await?,
+                )
+            }
+            Endpoint::Ws(host) => {
+                let stream = TcpStream::connect(&host).await?;
+                let (ws, _) = tokio_tungstenite::client_async(format!("ws://{}/", host), stream)
+                    .await
+                    .map_err(ws_error)?;
+                Box::new(WsStream::new(ws))
+            }
+            Endpoint::Unix(path) => Box::new(UnixStream::connect(path).await?),
+        };
+
+        Client::over(stream, options).await
+    }
+
+    /// Talks to a broker over an established `stream`, logging in as
+    /// `options` say. Only the login options are used.
+    pub(crate) async fn over(stream: Stream, options: &ConnectOptions) -> io::Result<Client> {
+        let mut client = Client {
+            framed: Framed::new(stream, MQTinyCodec::default()),
+            pending: VecDeque::new(),
+            response_topic: None,
+            next_correlation: RandomState::new().hash_one(0),
+        };
+        if options.client_id.is_some() || options.username.is_some() {
+            client.login(options).await?;
+        }
+
+        Ok(client)
+    }
+
+    async fn login(&mut self, options: &ConnectOptions) -> io::Result<()> {
+        self.framed
+            .send(MqttPacket::Connect(MqttConnectPacket {
+                client_id: options.client_id.clone().unwrap_or_default(),
+                username: options.username.clone(),
+                password: options.password.clone().map(String::into_bytes),
+                persistent: options.persistent,
+            }))
+            .await?;
+
+        match self.recv().await {
+            Some(Ok(MqttPacket::Connack(connack)))
+                if connack.return_code == ConnectReturnCode::Accepted =>
+            {
+                Ok(())
+            }
+            Some(Ok(MqttPacket::Connack(connack))) => Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                format!("connection refused: {:?}", connack.return_code),
+            )),
+            Some(Ok(packet)) => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("expected connack, got {:?}", packet),
+            )),
+            Some(Err(e)) => Err(e),
+            None => Err(io::ErrorKind::UnexpectedEof.into()),
+        }
+    }
+
+    pub async fn subscribe(&mut self, topic_name: u16) -> io::Result<()> {
+        self.framed
+            .send(MqttPacket::Subscribe(MqttSubscribePacket {
+                topic_name,
+                ..Default::default()
+            }))
+            .await
+    }
+
+    /// Subscribes to the publishes of other clients only: those this client
+    /// sends on the topic are not sent back to it.
+    pub async fn subscribe_no_local(&mut self, topic_name: u16) -> io::Result<()> {
+        self.framed
+            .send(MqttPacket::Subscribe(MqttSubscribePacket {
+                topic_name,
+                no_local: true,
+                ..Default::default()
+            }))
+            .await
+    }
+
+    /// Ends a subscription. Publishes already on their way may still arrive.
+    pub async fn unsubscribe(&mut self, topic_name: u16) -> io::Result<()> {
+        self.framed
+            .send(MqttPacket::Unsubscribe(MqttUnsubscribePacket {
+                topic_name,
+                group: None,
+            }))
+            .await
+    }
+
+    /// Joins the shared subscription `group` on the topic: each publish goes
+    /// to one member of the group, chosen by `strategy`. Members must
+    /// [`ack`](Client::ack) every QoS 1 and 2 publish they receive; those not
+    /// yet acknowledged when a member leaves are sent to another member.
+    pub async fn subscribe_shared(
+        &mut self,
+        topic_name: u16,
+        group: &str,
+        strategy: ShareStrategy,
+    ) -> io::Result<()> {
+        self.framed
+            .send(MqttPacket::Subscribe(MqttSubscribePacket {
+                topic_name,
+                share: Some(Share {
+                    group: group.to_string(),
+                    strategy,
+                }),
+                ..Default::default()
+            }))
+            .await
+    }
+
+    /// Leaves the shared subscription `group` on the topic. Publishes already
+    /// received must still be acknowledged.
+    pub async fn unsubscribe_shared(&mut self, topic_name: u16, group: &str) -> io::Result<()> {
+        self.framed
+            .send(MqttPacket::Unsubscribe(MqttUnsubscribePacket {
+                topic_name,
+                group: Some(group.to_string()),
+            }))
+            .await
+    }
+
+    /// Subscribes, asking for the publishes kept in the history of the topic
+    /// from `replay` on before live ones. Topics without a history replay
+    /// nothing.
+    pub async fn subscribe_from(&mut self, topic_name: u16, replay: Replay) -> io::Result<()> {
+        self.framed
+            .send(MqttPacket::Subscribe(MqttSubscribePacket {
+                topic_name,
+                replay: Some(replay),
+                ..Default::default()
+            }))
+            .await
+    }
+
+    /// The broker answers a QoS 1 or 2 publish with a PUBACK once it has
+    /// taken responsibility for it.
+    pub async fn publish(&mut self, topic_name: u16, qos: QoS, payload: Vec<u8>) -> io::Result<()> {
+        self.publish_packet(MqttPublishPacket {
+            topic_name,
+            qos,
+            retain: false,
+            seq: None,
+            expiry: None,
+            delay: None,
+            response_topic: None,
+            correlation_data: None,
+            payload,
+        })
+        .await
+    }
+
+    /// Publishes, to be dropped if no subscriber has received it within
+    /// `expiry`, rounded down to whole seconds. Subscribers get the time left
+    /// in [`MqttPublishPacket::expiry`].
+    pub async fn publish_expiring(
+        &mut self,
+        topic_name: u16,
+        qos: QoS,
+        payload: Vec<u8>,
+        expiry: Duration,
+    ) -> io::Result<()> {
+        self.publish_packet(MqttPublishPacket {
+            topic_name,
+            qos,
+            retain: false,
+            seq: None,
+            expiry: Some(expiry.as_secs().try_into().unwrap_or(u32::MAX)),
+            delay: None,
+            response_topic: None,
+            correlation_data: None,
+            payload,
+        })
+        .await
+    }
+
+    /// Publishes and asks the broker to keep the publish for future
+    /// subscribers of the topic. An empty payload clears the kept publish.
+    pub async fn publish_retained(
+        &mut self,
+        topic_name: u16,
+        qos: QoS,
+        payload: Vec<u8>,
+    ) -> io::Result<()> {
+        self.publish_packet(MqttPublishPacket {
+            topic_name,
+            qos,
+            retain: true,
+            seq: None,
+            expiry: None,
+            delay: None,
+            response_topic: None,
+            correlation_data: None,
+            payload,
+        })
+        .await
+    }
+
+    /// Publishes, for the broker to route only once `delay` has passed,
+    /// rounded up to whole seconds. Until then it can be listed and cancelled
+    /// through the admin API.
+    pub async fn publish_delayed(
+        &mut self,
+        topic_name: u16,
+        qos: QoS,
+        payload: Vec<u8>,
+        delay: Duration,
+    ) -> io::Result<()> {
+        let secs = delay.as_secs() + u64::from(delay.subsec_nanos() > 0);
+        self.publish_packet(MqttPublishPacket {
+            topic_name,
+            qos,
+            retain: false,
+            seq: None,
+            expiry: None,
+            delay: Some(secs.try_into().unwrap_or(u32::MAX)),
+            response_topic: None,
+            correlation_data: None,
+            payload,
+        })
+        .await
+    }
+
+    /// Publishes, for the broker to route at `time`, to the second. A time in
+    /// the past routes it right away.
+    pub async fn publish_at(
+        &mut self,
+        topic_name: u16,
+        qos: QoS,
+        payload: Vec<u8>,
+        time: SystemTime,
+    ) -> io::Result<()> {
+        let delay = time.duration_since(SystemTime::now()).unwrap_or_default();
+        self.publish_delayed(topic_name, qos, payload, delay).await
+    }
+
+    /// Sends `packet` as it is, for combinations of options the methods above
+    /// do not cover, such as a retained publish with an expiry.
+    pub async fn publish_packet(&mut self, packet: MqttPublishPacket) -> io::Result<()> {
+        self.framed.send(MqttPacket::Publish(packet)).await
+    }
+
+    /// Subscribes to `topic_name` to receive the responses to
+    /// [`request`](Client::request)s on. Responses on it to other clients'
+    /// requests are skipped.
+    pub async fn subscribe_responses(&mut self, topic_name: u16) -> io::Result<()> {
+        self.subscribe(topic_name).await?;
+        self.response_topic = Some(topic_name);
+        Ok(())
+    }
+
+    /// Publishes `payload` on the topic as a request and waits up to
+    /// `timeout` for the response with its correlation data. Other packets
+    /// received meanwhile are kept for [`recv`](Client::recv). Needs a
+    /// [`subscribe_responses`](Client::subscribe_responses) first.
+    pub async fn request(
+        &mut self,
+        topic_name: u16,
+        payload: Vec<u8>,
+        timeout: Duration,
+    ) -> io::Result<MqttPublishPacket> {
+        let Some(response_topic) = self.response_topic else {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "a request needs a response topic",
+            ));
+        };
+        let correlation_data = self.next_correlation.to_be_bytes().to_vec();
+        self.next_correlation = self.next_correlation.wrapping_add(1);
+        self.publish_packet(MqttPublishPacket {
+            topic_name,
+            qos: QoS::AtMostOnce,
+            retain: false,
+            seq: None,
+            expiry: None,
+            delay: None,
+            response_topic: Some(response_topic),
+            correlation_data: Some(correlation_data.clone()),
+            payload,
+        })
+        .await?;
+
+        let deadline = tokio::time::Instant::now() + timeout;
+        loop {
+            let packet = match tokio::time::timeout_at(deadline, self.framed.next()).await {
+                Ok(Some(packet)) => packet?,
+                Ok(None) => return Err(io::ErrorKind::UnexpectedEof.into()),
+                Err(_) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::TimedOut,
+                        format!("no response to the request on topic {}", topic_name),
+                    ))
+                }
+            };
+            match packet {
+                MqttPacket::Publish(publish) if publish.topic_name == response_topic => {
+                    if publish.correlation_data.as_ref() == Some(&correlation_data) {
+                        return Ok(publish);
+                    }
+                    // A late response to an earlier request, or a response to
+                    // another client.
+                }
+                packet => self.pending.push_back(packet),
+            }
+        }
+    }
+
+    /// Subscribes to `topic_name` and answers each request on it with the
+    /// payload `handler` returns, on its response topic and with its
+    /// correlation data, until the broker disconnects. Other publishes on
+    /// the topic are skipped.
+    pub async fn serve<F, Fut>(&mut self, topic_name: u16, mut handler: F) -> io::Result<()>
+    where
+        F: FnMut(MqttPublishPacket) -> Fut,
+        Fut: Future<Output = Vec<u8>>,
+    {
+        self.subscribe(topic_name).await?;
+        while let Some(packet) = self.recv().await {
+            let MqttPacket::Publish(request) = packet? else {
+                continue;
+            };
+            let Some(response_topic) = request.response_topic else {
+                continue;
+            };
+            if request.topic_name != topic_name {
+                continue;
+            }
+            let qos = request.qos;
+            let correlation_data = request.correlation_data.clone();
+            let payload = handler(request).await;
+            self.publish_packet(MqttPublishPacket {
+                topic_name: response_topic,
+                qos,
+                retain: false,
+                seq: None,
+                expiry: None,
+                delay: None,
+                response_topic: None,
+                correlation_data,
+                payload,
+            })
+            .await?;
+        }
+        Ok(())
+    }
+
+    /// Acknowledges the oldest QoS 1 or 2 publish received and not yet
+    /// acknowledged.
+    pub async fn ack(&mut self) -> io::Result<()> {
+        self.framed
+            .send(MqttPacket::Puback(MqttPubackPacket::default()))
+            .await
+    }
+
+    /// Waits for the next packet from the broker, or `None` once it disconnects.
+    pub async fn recv(&mut self) -> Option<io::Result<MqttPacket>> {
+        if let Some(packet) = self.pending.pop_front() {
+            return Some(Ok(packet));
+        }
+        self.framed.next().await
+    }
+
+    /// The underlying stream, for writing bytes outside the MQTiny framing.
+    pub fn get_mut(&mut self) -> &mut Stream {
+        self.framed.get_mut()
+    }
+}
+
+/// The name to verify the broker certificate against: the host part of `addr`.
+fn server_name(addr: &str) -> io::Result<ServerName<'static>> {
+    let host = addr.rsplit_once(':').map_or(addr, |(host, _)| host);
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    ServerName::try_from(host.to_string())
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))
+}
@@ -0,0 +1,158 @@
+//! Clusters of broker nodes, so that clients can connect to any node. Each
+//! node links to every other one as a client, subscribed to the topics its
+//! own clients are subscribed to, and delivers the publishes that arrive
+//! over the links to them.
+//!
+//! Nodes accept the links of the others on a listener of their own, see
+//! [`Broker::serve_peers`]. A link is only sent the publishes of the node's
+//! own clients, so a publish crosses one link at most and every node must
+//! list every other as a peer. Retained publishes, histories and persistent
+//! sessions stay on the node that has them, and QoS 1 and 2 publishes in
+//! flight on a link that drops are lost.
+
+use std::{
+    collections::{hash_map::Entry, BTreeSet, HashMap},
+    io,
+    sync::Arc,
+    time::Duration,
+};
+
+use tokio::{sync::watch, time::sleep};
+use tracing::{info, info_span, trace, warn, Instrument};
+
+use crate::{
+    broker::Broker,
+    client::{Client, ConnectOptions},
+    MqttPacket, QoS,
+};
+
+/// The other nodes of a cluster, and how to link to them.
+#[derive(Clone, Debug)]
+pub struct Cluster {
+    /// Addresses of their peer listeners, as for [`Client::connect`].
+    pub peers: Vec<String>,
+    /// How to log in to them.
+    pub options: ConnectOptions,
+    /// Wait before linking again, doubled after each failed attempt up to
+    /// `max_backoff`.
+    pub min_backoff: Duration,
+    pub max_backoff: Duration,
+}
+
+impl Cluster {
+    /// Links to `peers`, again after 1s backing off to 30s when a link drops.
+    pub fn new(peers: Vec<String>) -> Cluster {
+        Cluster {
+            peers,
+            options: ConnectOptions::default(),
+            min_backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(30),
+        }
+    }
+}
+
+/// The topics the clients of a node are subscribed to, with how many of them
+/// on each, shared by its cores. Links are notified when the topics change.
+#[derive(Clone)]
+pub(crate) struct Interest(Arc<watch::Sender<HashMap<u16, usize>>>);
+
+impl Default for Interest {
+    fn default() -> Self {
+        Interest(Arc::new(watch::channel(HashMap::new()).0))
+    }
+}
+
+impl Interest {
+    pub(crate) fn add(&self, topic: u16) {
+        self.0.send_if_modified(|topics| {
+            let subscribers = topics.entry(topic).or_default();
+            *subscribers += 1;
+            *subscribers == 1
+        });
+    }
+
+    pub(crate) fn remove(&self, topic: u16) {
+        self.0.send_if_modified(|topics| match topics.entry(topic) {
+            Entry::Occupied(mut subscribers) => {
+                *subscribers.get_mut() -= 1;
+                if *subscribers.get() == 0 {
+                    subscribers.remove();
+                    return true;
+                }
+                false
+            }
+            Entry::Vacant(_) => false,
+        });
+    }
+}
+
+/// Keeps a link from `broker` to `peer`, linking again whenever it drops.
+pub(crate) async fn link(cluster: Cluster, peer: String, broker: Broker) {
+    let span = info_span!("link", peer = %peer);
+    async move {
+        let mut backoff = cluster.min_backoff;
+        loop {
+            match Client::connect_with(&peer, &cluster.options).await {
+                Ok(client) => {
+                    info!("linked to peer");
+                    backoff = cluster.min_backoff;
+                    let e = relay(client, &broker).await;
+                    warn!(error = %e, retry_in = ?backoff, "link to peer lost");
+                }
+                Err(e) => warn!(error = %e, retry_in = ?backoff, "cannot link to peer"),
+            }
+            sleep(backoff).await;
+            backoff = (backoff * 2).min(cluster.max_backoff);
+        }
+    }
+    .instrument(span)
+    .await
+}
+
+/// Keeps the subscriptions of the link `client` to the interest of `broker`
+/// and delivers the publishes that arrive over it, until it fails.
+async fn relay(mut client: Client, broker: &Broker) -> io::Error {
+    let mut interest = broker.interest().0.subscribe();
+    let mut subscribed = BTreeSet::new();
+    loop {
+        let wanted: BTreeSet<u16> = interest.borrow_and_update().keys().copied().collect();
+        for &topic in wanted.difference(&subscribed) {
+            if let Err(e) = client.subscribe(topic).await {
+                return e;
+            }
+        }
+        for &topic in subscribed.difference(&wanted) {
+            if let Err(e) = client.unsubscribe(topic).await {
+                return e;
+            }
+        }
+        subscribed = wanted;
+
+        tokio::select! {
+            // The broker holds the sender, so this never fails.
+            _ = interest.changed() => {}
+            packet = client.recv() => match packet {
+                Some(Ok(MqttPacket::Publish(publish))) => {
+                    trace!(topic = publish.topic_name, "publish from peer");
+                    let qos = publish.qos;
+                    if let Err(e) = broker.publish_from_peer(publish) {
+                        return e;
+                    }
+                    if qos != QoS::AtMostOnce {
+                        if let Err(e) = client.ack().await {
+                            return e;
+                        }
+                    }
+                }
+                Some(Ok(MqttPacket::Suback(suback))) => {
+                    warn!(topic = suback.topic_name, "peer refused subscribe");
+                }
+                Some(Ok(_)) => {}
+                Some(Err(e)) => return e,
+                None => {
+                    return io::Error::new(io::ErrorKind::UnexpectedEof, "peer closed the link");
+                }
+            },
+        }
+    }
+}
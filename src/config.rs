@@ -0,0 +1,640 @@
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+
+use serde::Deserialize;
+
+use crate::{
+    auth::{self, AclFile, PasswordFile},
+    bridge::{Bridge, BridgeTopics, Direction},
+    broker::{Broker, BrokerBuilder, Limits},
+    client::ConnectOptions,
+    cluster::Cluster,
+    history::HistoryLimit,
+    listener::ListenAddr,
+    logging::{self, LogFormat},
+    ratelimit::{RateAction, RateLimit},
+    stats::SYS_TOPICS,
+    store::{Fsync, Store},
+    QoS,
+};
+
+/// Broker settings read from a TOML file:
+///
+/// ```toml
+/// runtime = "thread-per-core"
+/// threads = 8
+///
+/// [[listener]]
+/// address = "0.0.0.0:7001"
+/// max-connections = 200
+/// rate-messages = 5000
+///
+/// [[listener]]
+/// address = "tls://0.0.0.0:8883"
+/// cert = "server.pem"
+/// key = "server.key"
+///
+/// [auth]
+/// password-file = "passwd"
+/// allow-anonymous = false
+/// acl-file = "acl"
+///
+/// [log]
+/// level = "info"
+/// format = "json"
+///
+/// [stats]
+/// interval = 10
+///
+/// [metrics]
+/// address = "127.0.0.1:9100"
+///
+/// [admin]
+/// address = "127.0.0.1:9101"
+///
+/// [dead-letter]
+/// topic = 900
+///
+/// [rate-limit.client]
+/// messages = 100
+/// bytes = 16384
+/// action = "delay"
+///
+/// [[rate-limit.topic]]
+/// topics = "10-19"
+/// messages = 50
+///
+/// [[history]]
+/// topics = "100-199"
+/// max-messages = 1000
+/// max-age = 600
+///
+/// [store]
+/// path = "mqtiny.wal"
+/// fsync = "always"
+///
+/// [limits]
+/// max-connections = 10000
+/// max-connections-per-ip = 100
+/// max-packet-size = 128
+/// max-subscriptions = 64
+///
+/// [[bridge]]
+/// name = "uplink"
+/// address = "tls://central:8883"
+/// client-id = "site-a"
+/// persistent = true
+///
+/// [[bridge.topics]]
+/// topics = "100-199"
+/// direction = "both"
+/// offset = 1000
+/// qos = 1
+///
+/// [cluster]
+/// peers = ["10.0.0.2:7101", "10.0.0.3:7101"]
+///
+/// [cluster.listener]
+/// address = "0.0.0.0:7101"
+/// ```
+///
+/// Every key is optional. Relative paths are resolved against the directory
+/// of the file, and unknown keys are rejected so that typos do not pass
+/// silently.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Config {
+    pub runtime: Option<Runtime>,
+    /// Worker threads (multi-thread) or cores (thread-per-core).
+    pub threads: Option<usize>,
+    #[serde(rename = "listener")]
+    pub listeners: Vec<ListenAddr>,
+    pub auth: AuthConfig,
+    pub log: LogConfig,
+    pub stats: StatsConfig,
+    pub metrics: MetricsConfig,
+    pub admin: AdminConfig,
+    pub dead_letter: DeadLetterConfig,
+    pub rate_limit: RateLimitConfig,
+    pub limits: Limits,
+    pub store: StoreConfig,
+    pub history: Vec<TopicHistory>,
+    #[serde(rename = "bridge")]
+    pub bridges: Vec<BridgeConfig>,
+    pub cluster: ClusterConfig,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum Runtime {
+    /// A single thread serving every connection
+    #[default]
+    CurrentThread,
+    /// Tokio's multi-threaded work-stealing runtime
+    MultiThread,
+    /// One single-threaded runtime and SO_REUSEPORT listener per core
+    ThreadPerCore,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct AuthConfig {
+    /// File of `username:hash` lines, see [`PasswordFile`].
+    pub password_file: Option<PathBuf>,
+    pub allow_anonymous: bool,
+    /// ACL file, see [`AclFile`].
+    pub acl_file: Option<PathBuf>,
+}
+
+impl Default for AuthConfig {
+    fn default() -> Self {
+        AuthConfig {
+            password_file: None,
+            allow_anonymous: true,
+            acl_file: None,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LogConfig {
+    /// Log filter, such as `debug` or `info,mqtiny::broker=trace`.
+    pub level: String,
+    pub format: LogFormat,
+}
+
+impl Default for LogConfig {
+    fn default() -> Self {
+        LogConfig {
+            level: logging::DEFAULT_LEVEL.to_string(),
+            format: LogFormat::default(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct StatsConfig {
+    /// Seconds between two publishes of the statistics topics; 0 disables them.
+    pub interval: u64,
+}
+
+impl Default for StatsConfig {
+    fn default() -> Self {
+        StatsConfig { interval: 10 }
+    }
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MetricsConfig {
+    /// `ADDR:PORT` to serve Prometheus metrics on at `/metrics`; off if unset.
+    pub address: Option<String>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AdminConfig {
+    /// `ADDR:PORT` to serve the admin API on, see [`crate::admin`]; off if unset.
+    pub address: Option<String>,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DeadLetterConfig {
+    /// Topic to republish dropped publishes on, see [`crate::deadletter`];
+    /// off if unset.
+    pub topic: Option<u16>,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RateLimitConfig {
+    /// Applies to each client separately.
+    pub client: Option<RateLimit>,
+    /// Applies to each topic separately; the first rule covering a topic wins.
+    pub topic: Vec<TopicRateLimit>,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct StoreConfig {
+    /// Write-ahead log of retained publishes and persistent sessions, see
+    /// [`crate::store`]; they are kept in memory only if unset.
+    pub path: Option<PathBuf>,
+    pub fsync: Fsync,
+}
+
+/// A `[[rate-limit.topic]]` table: a [`RateLimit`] for each of `topics`.
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TopicRateLimit {
+    /// A topic `N` or an inclusive range `LO-HI`.
+    pub topics: String,
+    pub messages: Option<u32>,
+    pub bytes: Option<u32>,
+    #[serde(default)]
+    pub action: RateAction,
+}
+
+impl TopicRateLimit {
+    fn limit(&self) -> RateLimit {
+        RateLimit {
+            messages: self.messages,
+            bytes: self.bytes,
+            action: self.action,
+        }
+    }
+}
+
+/// A `[[history]]` table: a [`HistoryLimit`] for each of `topics`. At least
+/// one bound must be set.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct TopicHistory {
+    /// A topic `N` or an inclusive range `LO-HI`.
+    pub topics: String,
+    pub max_messages: Option<usize>,
+    pub max_bytes: Option<usize>,
+    /// Seconds.
+    pub max_age: Option<u64>,
+}
+
+impl TopicHistory {
+    fn limit(&self) -> HistoryLimit {
+        HistoryLimit {
+            max_messages: self.max_messages,
+            max_bytes: self.max_bytes,
+            max_age: self.max_age.map(Duration::from_secs),
+        }
+    }
+}
+
+/// A `[[bridge]]` table, see [`Bridge`].
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct BridgeConfig {
+    pub name: String,
+    pub address: String,
+    pub client_id: Option<String>,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub ca: Option<PathBuf>,
+    pub cert: Option<PathBuf>,
+    pub key: Option<PathBuf>,
+    #[serde(default)]
+    pub persistent: bool,
+    /// Seconds.
+    pub min_backoff: Option<u64>,
+    pub max_backoff: Option<u64>,
+    #[serde(default)]
+    pub topics: Vec<BridgeTopicsConfig>,
+}
+
+/// A `[[bridge.topics]]` table, see [`BridgeTopics`].
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct BridgeTopicsConfig {
+    /// A topic `N` or an inclusive range `LO-HI`.
+    pub topics: String,
+    #[serde(default)]
+    pub direction: Direction,
+    #[serde(default)]
+    pub offset: i32,
+    pub qos: Option<usize>,
+}
+
+/// The `[cluster]` table, see [`Cluster`]. The broker joins a cluster if it
+/// has peers.
+#[derive(Clone, Debug, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct ClusterConfig {
+    /// Where the links of the other nodes are accepted.
+    pub listener: Option<ListenAddr>,
+    /// The peer listeners of the other nodes.
+    pub peers: Vec<String>,
+    pub client_id: Option<String>,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub ca: Option<PathBuf>,
+    pub cert: Option<PathBuf>,
+    pub key: Option<PathBuf>,
+    /// Seconds.
+    pub min_backoff: Option<u64>,
+    pub max_backoff: Option<u64>,
+}
+
+impl ClusterConfig {
+    fn cluster(&self) -> Cluster {
+        let mut cluster = Cluster::new(self.peers.clone());
+        cluster.options = ConnectOptions {
+            ca: self.ca.clone(),
+            cert: self.cert.clone(),
+            key: self.key.clone(),
+            client_id: self.client_id.clone(),
+            username: self.username.clone(),
+            password: self.password.clone(),
+            persistent: false,
+        };
+        if let Some(secs) = self.min_backoff {
+            cluster.min_backoff = Duration::from_secs(secs);
+        }
+        if let Some(secs) = self.max_backoff {
+            cluster.max_backoff = Duration::from_secs(secs);
+        }
+        cluster
+    }
+}
+
+impl BridgeConfig {
+    fn bridge(&self) -> Result<Bridge, String> {
+        let mut bridge = Bridge::new(&self.name, &self.address);
+        bridge.options = ConnectOptions {
+            ca: self.ca.clone(),
+            cert: self.cert.clone(),
+            key: self.key.clone(),
+            client_id: self.client_id.clone(),
+            username: self.username.clone(),
+            password: self.password.clone(),
+            persistent: self.persistent,
+        };
+        if let Some(secs) = self.min_backoff {
+            bridge.min_backoff = Duration::from_secs(secs);
+        }
+        if let Some(secs) = self.max_backoff {
+            bridge.max_backoff = Duration::from_secs(secs);
+        }
+        for rule in &self.topics {
+            let invalid = |what: &str| format!("bridge {}: {} {}", self.name, what, rule.topics);
+            let topics =
+                auth::parse_topics(&rule.topics).ok_or_else(|| invalid("invalid topics"))?;
+            let qos = match rule.qos {
+                Some(qos) => Some(QoS::from_usize(qos).ok_or_else(|| invalid("invalid qos for"))?),
+                None => None,
+            };
+            let topics = BridgeTopics {
+                topics,
+                direction: rule.direction,
+                offset: rule.offset,
+                qos,
+            };
+            topics
+                .remote_topics()
+                .ok_or_else(|| invalid("offset out of range for"))?;
+            bridge.topics.push(topics);
+        }
+        Ok(bridge)
+    }
+}
+
+impl Config {
+    pub fn load(path: &Path) -> io::Result<Config> {
+        let contents = fs::read_to_string(path)
+            .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path.display(), e)))?;
+        let mut config = Config::parse(&contents).map_err(|e| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{}: {}", path.display(), e),
+            )
+        })?;
+        if let Some(dir) = path.parent() {
+            config.resolve_paths(dir);
+        }
+        Ok(config)
+    }
+
+    /// Parses config file contents. Errors give the line and column.
+    pub fn parse(contents: &str) -> Result<Config, String> {
+        let config: Config = toml::from_str(contents).map_err(|e| e.to_string())?;
+        logging::parse_filter(&config.log.level).map_err(|e| format!("log.level: {}", e))?;
+        if let Some(limit) = &config.rate_limit.client {
+            limit
+                .check()
+                .map_err(|e| format!("rate-limit.client: {}", e))?;
+        }
+        for rule in &config.rate_limit.topic {
+            auth::parse_topics(&rule.topics)
+                .ok_or_else(|| format!("rate-limit.topic: invalid topics {}", rule.topics))?;
+            rule.limit()
+                .check()
+                .map_err(|e| format!("rate-limit.topic: topics {}: {}", rule.topics, e))?;
+        }
+        for rule in &config.history {
+            auth::parse_topics(&rule.topics)
+                .ok_or_else(|| format!("history: invalid topics {}", rule.topics))?;
+            if rule.limit() == HistoryLimit::default() {
+                return Err(format!(
+                    "history: topics {} need max-messages, max-bytes or max-age",
+                    rule.topics
+                ));
+            }
+        }
+        for bridge in &config.bridges {
+            bridge.bridge()?;
+        }
+        if let Some(topic) = config.dead_letter.topic {
+            if SYS_TOPICS.contains(&topic) {
+                return Err(format!(
+                    "dead-letter: topic {} is a statistics topic",
+                    topic
+                ));
+            }
+        }
+        Ok(config)
+    }
+
+    fn resolve_paths(&mut self, dir: &Path) {
+        let resolve = |path: &mut Option<PathBuf>| {
+            if let Some(path) = path {
+                *path = dir.join(&*path);
+            }
+        };
+        for listen in &mut self.listeners {
+            resolve(&mut listen.cert);
+            resolve(&mut listen.key);
+            resolve(&mut listen.client_ca);
+        }
+        resolve(&mut self.auth.password_file);
+        resolve(&mut self.auth.acl_file);
+        resolve(&mut self.store.path);
+        for bridge in &mut self.bridges {
+            resolve(&mut bridge.ca);
+            resolve(&mut bridge.cert);
+            resolve(&mut bridge.key);
+        }
+        let cluster = &mut self.cluster;
+        resolve(&mut cluster.ca);
+        resolve(&mut cluster.cert);
+        resolve(&mut cluster.key);
+        if let Some(listen) = &mut cluster.listener {
+            resolve(&mut listen.cert);
+            resolve(&mut listen.key);
+            resolve(&mut listen.client_ca);
+        }
+    }
+
+    /// The settings that differ from `running` but only take effect on restart.
+    /// Everything else is applied by [`Broker::reload`].
+    pub fn restart_required(&self, running: &Config) -> Vec<&'static str> {
+        let mut changed = Vec::new();
+        if self.runtime != running.runtime {
+            changed.push("runtime");
+        }
+        if self.threads != running.threads {
+            changed.push("threads");
+        }
+        let rebind = self.listeners.len() != running.listeners.len()
+            || (self.listeners.iter())
+                .zip(&running.listeners)
+                .any(|(listen, running)| listen.rebind(running));
+        if rebind {
+            changed.push("listener");
+        }
+        if self.log.format != running.log.format {
+            changed.push("log.format");
+        }
+        if self.stats.interval != running.stats.interval {
+            changed.push("stats.interval");
+        }
+        if self.metrics.address != running.metrics.address {
+            changed.push("metrics.address");
+        }
+        if self.admin.address != running.admin.address {
+            changed.push("admin.address");
+        }
+        if self.dead_letter != running.dead_letter {
+            changed.push("dead-letter");
+        }
+        if self.store != running.store {
+            changed.push("store");
+        }
+        if self.history != running.history {
+            changed.push("history");
+        }
+        if self.bridges != running.bridges {
+            changed.push("bridge");
+        }
+        if self.cluster != running.cluster {
+            changed.push("cluster");
+        }
+        changed
+    }
+
+    /// Opens the store, recovering what it recorded, if one is configured. Kept
+    /// out of [`Config::broker`] since a reload must not open it again.
+    pub fn store(&self) -> io::Result<Option<Store>> {
+        match &self.store.path {
+            Some(path) => Store::open(path, self.store.fsync).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// Loads the files the broker depends on and returns a builder for it.
+    pub fn broker(&self) -> io::Result<BrokerBuilder> {
+        let auth = &self.auth;
+        let mut builder = Broker::builder().allow_anonymous(auth.allow_anonymous);
+        if let Some(path) = &auth.password_file {
+            builder = builder.authenticator(Arc::new(PasswordFile::load(path)?));
+        } else if !auth.allow_anonymous {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "allow-anonymous = false needs a password-file",
+            ));
+        }
+        if let Some(path) = &auth.acl_file {
+            builder = builder.authorizer(Arc::new(AclFile::load(path)?));
+        }
+        if let Some(limit) = self.rate_limit.client {
+            builder = builder.client_limit(limit);
+        }
+        for rule in &self.rate_limit.topic {
+            let topics = auth::parse_topics(&rule.topics).ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("rate-limit.topic: invalid topics {}", rule.topics),
+                )
+            })?;
+            builder = builder.topic_limit(topics, rule.limit());
+        }
+        for rule in &self.history {
+            let topics = auth::parse_topics(&rule.topics).ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("history: invalid topics {}", rule.topics),
+                )
+            })?;
+            builder = builder.history(topics, rule.limit());
+        }
+        for bridge in &self.bridges {
+            let bridge = bridge
+                .bridge()
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+            builder = builder.bridge(bridge);
+        }
+        if !self.cluster.peers.is_empty() {
+            builder = builder.cluster(self.cluster.cluster());
+        }
+        if let Some(topic) = self.dead_letter.topic {
+            builder = builder.dead_letter_topic(topic);
+        }
+        builder = builder.limits(self.limits);
+        if self.stats.interval > 0 {
+            builder = builder.sys_interval(Duration::from_secs(self.stats.interval));
+        }
+        Ok(builder)
+    }
+}
+
+/// A `[[listener]]` table: `address` plus the options of a `--listen` argument.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+struct ListenerConfig {
+    address: String,
+    max_connections: Option<usize>,
+    mode: Option<String>,
+    cert: Option<String>,
+    key: Option<String>,
+    client_ca: Option<String>,
+    rate_messages: Option<u32>,
+    rate_bytes: Option<u32>,
+    rate_action: Option<String>,
+}
+
+impl TryFrom<ListenerConfig> for ListenAddr {
+    type Error = String;
+
+    fn try_from(config: ListenerConfig) -> Result<Self, Self::Error> {
+        let mut listen = ListenAddr::new(&config.address);
+        let max_connections = config.max_connections.map(|n| n.to_string());
+        let options = [
+            ("max-connections", max_connections),
+            ("rate-messages", config.rate_messages.map(|n| n.to_string())),
+            ("rate-bytes", config.rate_bytes.map(|n| n.to_string())),
+            ("rate-action", config.rate_action),
+            ("mode", config.mode),
+            ("cert", config.cert),
+            ("key", config.key),
+            ("client-ca", config.client_ca),
+        ];
+        for (key, value) in options {
+            if let Some(value) = value {
+                listen.set_option(key, &value)?;
+            }
+        }
+        listen.check()?;
+        Ok(listen)
+    }
+}
+
+impl<'de> Deserialize<'de> for ListenAddr {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        ListenerConfig::deserialize(deserializer)?
+            .try_into()
+            .map_err(serde::de::Error::custom)
+    }
+}
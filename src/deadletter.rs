@@ -0,0 +1,140 @@
+//! Publishes the broker drops, republished on a dead-letter topic so that
+//! losses can be audited.
+//!
+//! A broker given a dead-letter topic wraps each publish it drops in an
+//! envelope and publishes it there, with the QoS of the original. The
+//! envelope is, big-endian:
+//!
+//! | Bytes | Field                                                      |
+//! |-------|------------------------------------------------------------|
+//! | 2     | topic of the publish                                       |
+//! | 1     | [`DropReason`]                                             |
+//! | 8     | when it was dropped, in milliseconds since the Unix epoch  |
+//! | 1     | length of the client id of the publisher                   |
+//! | n     | client id, empty if the broker does not know it            |
+//! | 1     | length of the payload                                      |
+//! | rest  | payload, cut short to fit in a packet                      |
+//!
+//! Publishes dropped on the dead-letter topic itself or on the statistics
+//! topics are not republished.
+
+use std::{fmt, io};
+
+use bytes::{Buf, BufMut};
+
+/// Why a publish was dropped.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum DropReason {
+    /// The ACL does not allow the publisher the topic.
+    Denied = 1,
+    /// The publisher was over a rate limit with `action=drop`.
+    Throttled = 2,
+    /// A subscriber or shared subscription group went away before the
+    /// publish could be sent to it.
+    Undeliverable = 3,
+    /// Its expiry interval passed before it reached a subscriber.
+    Expired = 4,
+    /// A [`BrokerHook`](crate::hook::BrokerHook) rejected it.
+    Rejected = 5,
+}
+
+impl TryFrom<u8> for DropReason {
+    type Error = io::Error;
+
+    fn try_from(value: u8) -> io::Result<Self> {
+        match value {
+            1 => Ok(DropReason::Denied),
+            2 => Ok(DropReason::Throttled),
+            3 => Ok(DropReason::Undeliverable),
+            4 => Ok(DropReason::Expired),
+            5 => Ok(DropReason::Rejected),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("unknown drop reason {}", value),
+            )),
+        }
+    }
+}
+
+impl fmt::Display for DropReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            DropReason::Denied => "denied",
+            DropReason::Throttled => "throttled",
+            DropReason::Undeliverable => "undeliverable",
+            DropReason::Expired => "expired",
+            DropReason::Rejected => "rejected",
+        })
+    }
+}
+
+/// Longest envelope: what is left of a packet after the topic and the
+/// properties the broker may add.
+const MAX_ENVELOPE: usize = 255 - 2 - 9;
+
+/// Longest client id kept in an envelope, in bytes.
+const MAX_CLIENT_ID: usize = 64;
+
+/// A dropped publish, as published on the dead-letter topic.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DeadLetter {
+    pub topic: u16,
+    pub reason: DropReason,
+    /// In milliseconds since the Unix epoch.
+    pub dropped_at: u64,
+    pub client_id: String,
+    /// Bytes of the original payload, more than `payload` holds if it was
+    /// cut short.
+    pub size: usize,
+    pub payload: Vec<u8>,
+}
+
+impl DeadLetter {
+    /// The envelope, with the client id and payload cut short as needed.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut len = self.client_id.len().min(MAX_CLIENT_ID);
+        while !self.client_id.is_char_boundary(len) {
+            len -= 1;
+        }
+        let client_id = &self.client_id.as_bytes()[..len];
+        let room = MAX_ENVELOPE - 13 - client_id.len();
+        let payload = &self.payload[..self.payload.len().min(room)];
+
+        let mut envelope = Vec::with_capacity(13 + client_id.len() + payload.len());
+        envelope.put_u16(self.topic);
+        envelope.put_u8(self.reason as u8);
+        envelope.put_u64(self.dropped_at);
+        envelope.put_u8(client_id.len() as u8);
+        envelope.put(client_id);
+        envelope.put_u8(self.size.min(u8::MAX as usize) as u8);
+        envelope.put(payload);
+        envelope
+    }
+
+    pub fn decode(mut envelope: &[u8]) -> io::Result<DeadLetter> {
+        let truncated = || io::Error::new(io::ErrorKind::UnexpectedEof, "envelope cut short");
+        if envelope.remaining() < 12 {
+            return Err(truncated());
+        }
+        let topic = envelope.get_u16();
+        let reason = DropReason::try_from(envelope.get_u8())?;
+        let dropped_at = envelope.get_u64();
+        let len = envelope.get_u8() as usize;
+        if envelope.remaining() < len + 1 {
+            return Err(truncated());
+        }
+        let client_id = String::from_utf8(envelope[..len].to_vec())
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        envelope.advance(len);
+        let size = envelope.get_u8() as usize;
+        Ok(DeadLetter {
+            topic,
+            reason,
+            dropped_at,
+            client_id,
+            size,
+            payload: envelope.to_vec(),
+        })
+    }
+}
@@ -0,0 +1,140 @@
+//! Recent publishes of chosen topics, kept in memory for subscribers that
+//! join late and ask for a [`Replay`].
+
+use std::{
+    collections::{HashMap, VecDeque},
+    ops::RangeInclusive,
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use tracing::debug;
+
+use crate::{MqttPublishPacket, Replay};
+
+/// How much of a topic's history to keep. Publishes past any of the bounds
+/// are forgotten, oldest first; unset bounds do not apply.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct HistoryLimit {
+    pub max_messages: Option<usize>,
+    /// Payload bytes.
+    pub max_bytes: Option<usize>,
+    pub max_age: Option<Duration>,
+}
+
+/// The histories of the topics that keep one. Each topic numbers its
+/// publishes from 1, shared by every core; clones share them too.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct History {
+    rules: Vec<(RangeInclusive<u16>, HistoryLimit)>,
+    topics: Arc<Mutex<HashMap<u16, Ring>>>,
+}
+
+#[derive(Debug)]
+struct Ring {
+    limit: HistoryLimit,
+    next_seq: u64,
+    /// Publishes with the time they were received, in milliseconds since the
+    /// Unix epoch, oldest first.
+    entries: VecDeque<(u64, MqttPublishPacket)>,
+    bytes: usize,
+}
+
+impl History {
+    /// Keeps the history of each topic in `topics`, unless an earlier rule
+    /// covers it.
+    pub(crate) fn push(&mut self, topics: RangeInclusive<u16>, limit: HistoryLimit) {
+        self.rules.push((topics, limit));
+    }
+
+    /// Numbers `packet` and keeps it, if its topic keeps a history. A publish
+    /// too large to be sent with its sequence number is routed unnumbered and
+    /// left out of the history.
+    pub(crate) fn record(&self, packet: &mut MqttPublishPacket) {
+        let topic = packet.topic_name;
+        let Some((_, limit)) = self
+            .rules
+            .iter()
+            .find(|(topics, _)| topics.contains(&topic))
+        else {
+            return;
+        };
+        packet.seq = Some(0);
+        if packet.encoded_len() > u8::MAX as usize {
+            packet.seq = None;
+            debug!(topic, "publish too large for the history");
+            return;
+        }
+        let mut topics = self.topics.lock().unwrap();
+        let ring = topics.entry(topic).or_insert_with(|| Ring {
+            limit: *limit,
+            next_seq: 1,
+            entries: VecDeque::new(),
+            bytes: 0,
+        });
+        packet.seq = Some(ring.next_seq);
+        ring.next_seq += 1;
+        ring.bytes += packet.payload.len();
+        ring.entries.push_back((now(), packet.clone()));
+        ring.trim();
+    }
+
+    /// The kept publishes of `topic` that `replay` asks for and that have not
+    /// expired, oldest first, with the seconds left of their expiry.
+    pub(crate) fn replay(&self, topic: u16, replay: Replay) -> Vec<MqttPublishPacket> {
+        let mut topics = self.topics.lock().unwrap();
+        let Some(ring) = topics.get_mut(&topic) else {
+            return Vec::new();
+        };
+        ring.trim();
+        let now = now();
+        ring.entries
+            .iter()
+            .filter(|(received, packet)| match replay {
+                Replay::FromSeq(seq) => packet.seq >= Some(seq),
+                Replay::Since(time) => *received >= time,
+            })
+            .filter_map(|(received, packet)| {
+                let mut packet = packet.clone();
+                if let Some(expiry) = packet.expiry {
+                    let expires = received + u64::from(expiry) * 1000;
+                    if expires <= now {
+                        return None;
+                    }
+                    packet.expiry = Some((expires - now).div_ceil(1000) as u32);
+                }
+                Some(packet)
+            })
+            .collect()
+    }
+}
+
+impl Ring {
+    /// Forgets the oldest publishes until the history is within its limit.
+    fn trim(&mut self) {
+        let limit = self.limit;
+        let oldest = limit
+            .max_age
+            .map(|age| now().saturating_sub(age.as_millis() as u64));
+        while let Some((received, packet)) = self.entries.front() {
+            let over = limit
+                .max_messages
+                .is_some_and(|max| self.entries.len() > max)
+                || limit.max_bytes.is_some_and(|max| self.bytes > max)
+                || oldest.is_some_and(|oldest| *received < oldest);
+            if !over {
+                break;
+            }
+            self.bytes -= packet.payload.len();
+            self.entries.pop_front();
+        }
+    }
+}
+
+/// Milliseconds since the Unix epoch.
+pub(crate) fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
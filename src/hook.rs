@@ -0,0 +1,125 @@
+//! Custom logic run inside the broker at points in the life of a client:
+//! its login, its subscribes, its publishes, the publishes sent to it and its
+//! disconnect.
+//!
+//! Hooks are registered with [`BrokerBuilder::hook`](crate::broker::BrokerBuilder::hook)
+//! and called in the order they were registered. A hook that refuses
+//! something stops the hooks after it from being called for it. They are not
+//! called for the links of other cluster nodes, whose clients their own node
+//! calls them for.
+
+use std::sync::Arc;
+
+use async_trait::async_trait;
+
+use crate::{broker::ClientId, MqttPublishPacket};
+
+/// A connected client, as hooks see it.
+#[derive(Clone, Debug, Default)]
+pub struct ClientInfo {
+    /// The connection id, as the admin API shows it.
+    pub conn: ClientId,
+    pub peer: String,
+    /// Empty until the client logs in, and for anonymous clients.
+    pub client_id: String,
+    pub username: Option<String>,
+}
+
+/// Callbacks into the broker. Each has a default that lets everything through,
+/// so a hook only implements those it needs.
+#[async_trait]
+pub trait BrokerHook: Send + Sync {
+    /// A client logged in, or sent its first packet without logging in.
+    /// Returning `false` refuses it with [`ConnectReturnCode::NotAuthorized`].
+    ///
+    /// [`ConnectReturnCode::NotAuthorized`]: crate::ConnectReturnCode::NotAuthorized
+    async fn on_connect(&self, _client: &ClientInfo) -> bool {
+        true
+    }
+
+    /// A client subscribes to `topic`, as the ACL and limits allow it.
+    /// Returning `false` refuses it with a failure SUBACK.
+    async fn on_subscribe(&self, _client: &ClientInfo, _topic: u16) -> bool {
+        true
+    }
+
+    /// A client published `packet`, as the ACL and rate limits allow it. The
+    /// hook may change it, its topic included to route it elsewhere without
+    /// further access checks. Returning `false` drops it as rejected.
+    async fn on_publish(&self, _client: &ClientInfo, _packet: &mut MqttPublishPacket) -> bool {
+        true
+    }
+
+    /// `packet` is about to be sent to a client. The hook may change it.
+    /// Returning `false` skips the client, which then never gets the publish.
+    async fn on_deliver(&self, _client: &ClientInfo, _packet: &mut MqttPublishPacket) -> bool {
+        true
+    }
+
+    /// A client that was let in disconnected.
+    async fn on_disconnect(&self, _client: &ClientInfo) {}
+}
+
+/// The hooks of a broker, in the order they are called.
+#[derive(Clone, Default)]
+pub(crate) struct Hooks(Arc<[Arc<dyn BrokerHook>]>);
+
+impl From<Vec<Arc<dyn BrokerHook>>> for Hooks {
+    fn from(hooks: Vec<Arc<dyn BrokerHook>>) -> Self {
+        Hooks(hooks.into())
+    }
+}
+
+impl Hooks {
+    pub(crate) async fn connect(&self, client: &ClientInfo) -> bool {
+        for hook in self.0.iter() {
+            if !hook.on_connect(client).await {
+                return false;
+            }
+        }
+        true
+    }
+
+    pub(crate) async fn subscribe(&self, client: &ClientInfo, topic: u16) -> bool {
+        for hook in self.0.iter() {
+            if !hook.on_subscribe(client, topic).await {
+                return false;
+            }
+        }
+        true
+    }
+
+    pub(crate) async fn publish(
+        &self,
+        client: &ClientInfo,
+        packet: &mut MqttPublishPacket,
+    ) -> bool {
+        for hook in self.0.iter() {
+            if !hook.on_publish(client, packet).await {
+                return false;
+            }
+        }
+        true
+    }
+
+    /// `packet` as the hooks would have it sent to `client`, or `None` if one
+    /// of them skips the client.
+    pub(crate) async fn deliver(
+        &self,
+        client: &ClientInfo,
+        mut packet: MqttPublishPacket,
+    ) -> Option<MqttPublishPacket> {
+        for hook in self.0.iter() {
+            if !hook.on_deliver(client, &mut packet).await {
+                return None;
+            }
+        }
+        Some(packet)
+    }
+
+    pub(crate) async fn disconnect(&self, client: &ClientInfo) {
+        for hook in self.0.iter() {
+            hook.on_disconnect(client).await;
+        }
+    }
+}
@@ -0,0 +1,99 @@
+//! Just enough HTTP/1.1 for the metrics and admin endpoints: one request per
+//! connection, no request bodies.
+
+use std::io;
+
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::TcpStream,
+};
+
+/// Longest request head accepted; scrapers and the admin CLI send far less.
+const MAX_REQUEST: usize = 8192;
+
+pub(crate) struct Request {
+    pub(crate) method: String,
+    pub(crate) path: String,
+}
+
+pub(crate) struct Response {
+    pub(crate) status: &'static str,
+    pub(crate) content_type: &'static str,
+    pub(crate) body: String,
+}
+
+impl Response {
+    pub(crate) fn text(status: &'static str, body: &str) -> Response {
+        Response {
+            status,
+            content_type: "text/plain; charset=utf-8",
+            body: format!("{}\n", body),
+        }
+    }
+}
+
+/// Reads a request head. `None` if the peer closed the connection or sent
+/// more than [`MAX_REQUEST`] bytes before the end of it.
+pub(crate) async fn read_request(
+    stream: &mut (impl AsyncRead + Unpin),
+) -> io::Result<Option<Request>> {
+    let mut head = Vec::new();
+    let mut buf = [0; 1024];
+    while !head.windows(4).any(|w| w == b"\r\n\r\n") {
+        let n = stream.read(&mut buf).await?;
+        if n == 0 || head.len() + n > MAX_REQUEST {
+            return Ok(None);
+        }
+        head.extend_from_slice(&buf[..n]);
+    }
+
+    let head = String::from_utf8_lossy(&head);
+    let mut request_line = head.lines().next().unwrap_or_default().split(' ');
+    Ok(Some(Request {
+        method: request_line.next().unwrap_or_default().to_string(),
+        path: request_line.next().unwrap_or_default().to_string(),
+    }))
+}
+
+/// Writes `response` and closes the connection.
+pub(crate) async fn write_response(
+    stream: &mut (impl AsyncWrite + Unpin),
+    response: Response,
+) -> io::Result<()> {
+    let head = format!(
+        "HTTP/1.1 {}\r\n\
+         Content-Type: {}\r\n\
+         Content-Length: {}\r\n\
+         Connection: close\r\n\r\n",
+        response.status,
+        response.content_type,
+        response.body.len(),
+    );
+    stream.write_all(head.as_bytes()).await?;
+    stream.write_all(response.body.as_bytes()).await?;
+    stream.shutdown().await
+}
+
+/// Sends a request without a body to `addr` and returns the status code and
+/// body of the response.
+pub(crate) async fn call(addr: &str, method: &str, path: &str) -> io::Result<(u16, String)> {
+    let mut stream = TcpStream::connect(addr)
+        .await
+        .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", addr, e)))?;
+    let request = format!(
+        "{} {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n\r\n",
+        method, path, addr
+    );
+    stream.write_all(request.as_bytes()).await?;
+    let mut response = String::new();
+    stream.read_to_string(&mut response).await?;
+
+    let invalid = || io::Error::new(io::ErrorKind::InvalidData, "invalid HTTP response");
+    let (head, body) = response.split_once("\r\n\r\n").ok_or_else(invalid)?;
+    let status = head
+        .split(' ')
+        .nth(1)
+        .and_then(|code| code.parse().ok())
+        .ok_or_else(invalid)?;
+    Ok((status, body.to_string()))
+}
@@ -1,3 +1,26 @@
+pub mod admin;
+pub mod auth;
+pub mod bridge;
+pub mod broker;
+pub mod client;
+pub mod cluster;
+pub mod config;
+pub mod deadletter;
+pub mod history;
+pub mod hook;
+mod http;
+pub mod listener;
+pub mod logging;
+pub mod metrics;
+pub mod ratelimit;
+mod schedule;
+mod shared;
+pub mod stats;
+pub mod store;
+pub mod tls;
+pub mod transport;
+pub mod websocket;
+
 use std::io::Cursor;
 
 use bytes::{Buf, BufMut, Bytes};
@@ -5,9 +28,29 @@ use tokio_util::codec::{Decoder, Encoder};
 
 #[derive(Debug)]
 pub enum MqttPacket {
+    Connect(MqttConnectPacket),
+    Connack(MqttConnackPacket),
     Publish(MqttPublishPacket),
     Puback(MqttPubackPacket),
     Subscribe(MqttSubscribePacket),
+    Suback(MqttSubackPacket),
+    Unsubscribe(MqttUnsubscribePacket),
+}
+
+/// Opens a session. Clients that never send one are anonymous.
+#[derive(Debug, Clone, Default)]
+pub struct MqttConnectPacket {
+    pub client_id: String,
+    pub username: Option<String>,
+    pub password: Option<Vec<u8>>,
+    /// Keeps the subscriptions of `client_id`, and the QoS 1 and 2 publishes
+    /// to it that are not yet acknowledged, for its next connect. A connect
+    /// without it discards any such session.
+    pub persistent: bool,
+}
+#[derive(Debug, Clone, Copy)]
+pub struct MqttConnackPacket {
+    pub return_code: ConnectReturnCode,
 }
 
 #[allow(unused)]
@@ -15,44 +58,299 @@ pub enum MqttPacket {
 pub struct MqttPublishPacket {
     pub topic_name: u16,
     pub qos: QoS,
+    /// Sent to a broker: keep the publish for future subscribers of the topic;
+    /// an empty payload clears it. Sent by a broker: the publish was kept.
+    pub retain: bool,
+    /// Position of the publish in the history of its topic, set by the broker
+    /// on topics that keep one. Subscribers resume from it with
+    /// [`Replay::FromSeq`].
+    pub seq: Option<u64>,
+    /// Seconds after which the publish is dropped if it has not reached a
+    /// subscriber yet. Sent by a broker: the seconds left.
+    pub expiry: Option<u32>,
+    /// Seconds for the broker to hold the publish back before routing it, for
+    /// delayed and scheduled publishes. Never set by a broker.
+    pub delay: Option<u32>,
+    /// Makes the publish a request: the topic its responder publishes the
+    /// response on. See [`Client::request`](client::Client::request).
+    pub response_topic: Option<u16>,
+    /// Matches a response to its request: a responder copies it from the
+    /// request to the response.
+    pub correlation_data: Option<Vec<u8>>,
     pub payload: Vec<u8>,
 }
+
+impl MqttPublishPacket {
+    /// The length of the packet after its fixed header, which must be at most
+    /// 255 bytes for the packet to be sent.
+    pub fn encoded_len(&self) -> usize {
+        let properties = [
+            self.seq.map(|_| 8),
+            self.expiry.map(|_| 4),
+            self.delay.map(|_| 4),
+            self.response_topic.map(|_| 2),
+            self.correlation_data.as_ref().map(|data| 2 + data.len()),
+        ];
+        let properties_len = match properties.iter().flatten().sum() {
+            0 => 0,
+            len => 1 + len,
+        };
+        2 + properties_len + self.payload.len()
+    }
+}
+/// Acknowledges the oldest QoS 1 or 2 publish sent the other way on the
+/// connection that is not yet acknowledged. The payload is unused.
 #[allow(unused)]
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Default)]
 pub struct MqttPubackPacket {
     pub payload: Vec<u8>,
 }
 #[allow(unused)]
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Default)]
 pub struct MqttSubscribePacket {
     pub topic_name: u16,
+    /// Publishes from the history of the topic to send before live ones.
+    pub replay: Option<Replay>,
+    /// Publishes of this same connection on the topic are not sent back to it.
+    pub no_local: bool,
+    /// Joins a shared subscription instead, see [`Share`]. Replay and
+    /// no-local do not apply to it.
+    pub share: Option<Share>,
+}
+/// A shared subscription: each publish on the topic goes to one member of
+/// the group only.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Share {
+    pub group: String,
+    /// How the member is chosen. The group keeps the strategy of its first
+    /// member for as long as it has members.
+    pub strategy: ShareStrategy,
+}
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum ShareStrategy {
+    /// Each member in turn.
+    #[default]
+    RoundRobin = 0,
+    /// The member with the fewest publishes waiting to be sent to it.
+    LeastQueued = 1,
+    /// The same member for every publish of a client, as long as it stays in
+    /// the group.
+    Sticky = 2,
+}
+
+impl ShareStrategy {
+    pub fn from_u8(n: u8) -> Option<ShareStrategy> {
+        match n {
+            0 => Some(ShareStrategy::RoundRobin),
+            1 => Some(ShareStrategy::LeastQueued),
+            2 => Some(ShareStrategy::Sticky),
+            _ => None,
+        }
+    }
+}
+/// Where a subscription starts in the history of its topic.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Replay {
+    /// The publish with this sequence number and those after it.
+    FromSeq(u64),
+    /// Publishes received at or after this time, in milliseconds since the
+    /// Unix epoch.
+    Since(u64),
+}
+/// Ends a subscription, or leaves the shared subscription `group`. Not
+/// acknowledged.
+#[derive(Debug, Clone)]
+pub struct MqttUnsubscribePacket {
+    pub topic_name: u16,
+    pub group: Option<String>,
+}
+/// Sent only for a refused subscribe; accepted subscribes are not acknowledged.
+#[derive(Debug, Clone, Copy)]
+pub struct MqttSubackPacket {
+    pub topic_name: u16,
+    pub return_code: SubackReturnCode,
 }
 
-pub fn parse_publish_packet(flags: u8, data: &[u8]) -> MqttPublishPacket {
+pub fn parse_publish_packet(flags: u8, data: &[u8]) -> Result<MqttPublishPacket, std::io::Error> {
     let mut cursor = Cursor::new(data);
     cursor.advance(2); // fixed header
 
-    let qos = QoS::from_usize(((flags & 0x06) >> 1).into()).unwrap();
-    let topic_name = cursor.get_u16();
-    let payload = cursor.into_inner();
+    let qos =
+        QoS::from_usize(((flags & 0x06) >> 1).into()).ok_or_else(|| invalid_data("invalid QoS"))?;
+    let topic_name = get_u16(&mut cursor)?;
+    let properties = match flags & PUBLISH_FLAG_PROPERTIES {
+        0 => 0,
+        _ => get_u8(&mut cursor)?,
+    };
+    let seq = match properties & PUBLISH_PROPERTY_SEQ {
+        0 => None,
+        _ => Some(get_u64(&mut cursor)?),
+    };
+    let expiry = match properties & PUBLISH_PROPERTY_EXPIRY {
+        0 => None,
+        _ => Some(get_u32(&mut cursor)?),
+    };
+    let delay = match properties & PUBLISH_PROPERTY_DELAY {
+        0 => None,
+        _ => Some(get_u32(&mut cursor)?),
+    };
+    let response_topic = match properties & PUBLISH_PROPERTY_RESPONSE_TOPIC {
+        0 => None,
+        _ => Some(get_u16(&mut cursor)?),
+    };
+    let correlation_data = match properties & PUBLISH_PROPERTY_CORRELATION_DATA {
+        0 => None,
+        _ => Some(get_bytes(&mut cursor)?.to_vec()),
+    };
+    let payload = &data[cursor.position() as usize..];
 
-    MqttPublishPacket {
+    Ok(MqttPublishPacket {
         topic_name,
         qos,
+        retain: flags & PUBLISH_FLAG_RETAIN != 0,
+        seq,
+        expiry,
+        delay,
+        response_topic,
+        correlation_data,
         payload: payload.to_vec(),
+    })
+}
+
+pub fn parse_puback_packet(_flags: u8, data: &[u8]) -> MqttPubackPacket {
+    MqttPubackPacket {
+        payload: data[2..].to_vec(), // after the fixed header
     }
 }
 
-pub fn parse_subscribe_packet(_flags: u8, data: &[u8]) -> MqttSubscribePacket {
-    let mut cursor = Cursor::new(data);
-    cursor.advance(2); // fixed header
+const PUBLISH_FLAG_RETAIN: u8 = 0x01;
+/// A byte of `PUBLISH_PROPERTY_*` flags follows the topic, then the
+/// properties it flags, in the order below.
+const PUBLISH_FLAG_PROPERTIES: u8 = 0x08;
+/// A `u64` sequence number.
+const PUBLISH_PROPERTY_SEQ: u8 = 0x01;
+/// A `u32` expiry interval, in seconds.
+const PUBLISH_PROPERTY_EXPIRY: u8 = 0x02;
+/// A `u32` delay, in seconds.
+const PUBLISH_PROPERTY_DELAY: u8 = 0x04;
+/// A `u16` response topic.
+const PUBLISH_PROPERTY_RESPONSE_TOPIC: u8 = 0x08;
+/// Correlation data, prefixed by its `u16` length.
+const PUBLISH_PROPERTY_CORRELATION_DATA: u8 = 0x10;
+
+const CONNECT_FLAG_USERNAME: u8 = 0x80;
+const CONNECT_FLAG_PASSWORD: u8 = 0x40;
+const CONNECT_FLAG_PERSISTENT: u8 = 0x02;
+
+/// Connect: `flags`, then the client id, username and password, each prefixed
+/// with a `u16` length. Username and password are present only when flagged.
+pub fn parse_connect_packet(_flags: u8, data: &[u8]) -> Result<MqttConnectPacket, std::io::Error> {
+    let mut cursor = Cursor::new(&data[2..]); // fixed header
+
+    let connect_flags = get_u8(&mut cursor)?;
+    let client_id = get_string(&mut cursor)?;
+    let username = match connect_flags & CONNECT_FLAG_USERNAME {
+        0 => None,
+        _ => Some(get_string(&mut cursor)?),
+    };
+    let password = match connect_flags & CONNECT_FLAG_PASSWORD {
+        0 => None,
+        _ => Some(get_bytes(&mut cursor)?.to_vec()),
+    };
+
+    Ok(MqttConnectPacket {
+        client_id,
+        username,
+        password,
+        persistent: connect_flags & CONNECT_FLAG_PERSISTENT != 0,
+    })
+}
 
-    let topic_name = cursor.get_u16();
+pub fn parse_connack_packet(_flags: u8, data: &[u8]) -> Result<MqttConnackPacket, std::io::Error> {
+    let mut cursor = Cursor::new(&data[2..]); // fixed header
 
-    MqttSubscribePacket { topic_name }
+    let _session_present = get_u8(&mut cursor)?;
+    let return_code = ConnectReturnCode::from_u8(get_u8(&mut cursor)?)
+        .ok_or_else(|| invalid_data("unknown connack return code"))?;
+
+    Ok(MqttConnackPacket { return_code })
+}
+
+/// Subscribe: the topic, then for a replay a `u64` sequence number or
+/// timestamp, then for a shared subscription the strategy and the group name
+/// prefixed with a `u16` length, as the flags say.
+pub fn parse_subscribe_packet(
+    flags: u8,
+    data: &[u8],
+) -> Result<MqttSubscribePacket, std::io::Error> {
+    let mut cursor = Cursor::new(&data[2..]); // fixed header
+
+    let topic_name = get_u16(&mut cursor)?;
+    let replay = match flags & (SUBSCRIBE_FLAG_FROM_SEQ | SUBSCRIBE_FLAG_SINCE) {
+        0 => None,
+        SUBSCRIBE_FLAG_FROM_SEQ => Some(Replay::FromSeq(get_u64(&mut cursor)?)),
+        SUBSCRIBE_FLAG_SINCE => Some(Replay::Since(get_u64(&mut cursor)?)),
+        _ => return Err(invalid_data("conflicting replay flags")),
+    };
+    let share = match flags & SUBSCRIBE_FLAG_SHARED {
+        0 => None,
+        _ => {
+            let strategy = ShareStrategy::from_u8(get_u8(&mut cursor)?)
+                .ok_or_else(|| invalid_data("unknown share strategy"))?;
+            let group = get_string(&mut cursor)?;
+            Some(Share { group, strategy })
+        }
+    };
+
+    Ok(MqttSubscribePacket {
+        topic_name,
+        replay,
+        no_local: flags & SUBSCRIBE_FLAG_NO_LOCAL != 0,
+        share,
+    })
+}
+
+const SUBSCRIBE_FLAG_FROM_SEQ: u8 = 0x01;
+const SUBSCRIBE_FLAG_SHARED: u8 = 0x02;
+const SUBSCRIBE_FLAG_SINCE: u8 = 0x04;
+const SUBSCRIBE_FLAG_NO_LOCAL: u8 = 0x08;
+
+/// Unsubscribe: the topic, then the group name for a shared subscription,
+/// flagged as in a subscribe.
+pub fn parse_unsubscribe_packet(
+    flags: u8,
+    data: &[u8],
+) -> Result<MqttUnsubscribePacket, std::io::Error> {
+    let mut cursor = Cursor::new(&data[2..]); // fixed header
+
+    let topic_name = get_u16(&mut cursor)?;
+    let group = match flags & SUBSCRIBE_FLAG_SHARED {
+        0 => None,
+        _ => Some(get_string(&mut cursor)?),
+    };
+
+    Ok(MqttUnsubscribePacket { topic_name, group })
+}
+
+pub fn parse_suback_packet(_flags: u8, data: &[u8]) -> Result<MqttSubackPacket, std::io::Error> {
+    let mut cursor = Cursor::new(&data[2..]); // fixed header
+
+    let topic_name = get_u16(&mut cursor)?;
+    let return_code = SubackReturnCode::from_u8(get_u8(&mut cursor)?)
+        .ok_or_else(|| invalid_data("unknown suback return code"))?;
+
+    Ok(MqttSubackPacket {
+        topic_name,
+        return_code,
+    })
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct MQTinyCodec {
+    /// Packets larger than this many bytes, headers included, fail to decode.
+    pub max_packet_size: Option<usize>,
 }
 
-pub struct MQTinyCodec {}
 impl Decoder for MQTinyCodec {
     type Item = MqttPacket;
     type Error = std::io::Error;
@@ -67,6 +365,18 @@ impl Decoder for MQTinyCodec {
         let packet_flags = src[0] & 0x0F;
         let remaining_length = src[1] as usize;
 
+        if let Some(max) = self.max_packet_size {
+            if remaining_length + 2 > max {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!(
+                        "packet of {} bytes exceeds the maximum of {}",
+                        remaining_length + 2,
+                        max
+                    ),
+                ));
+            }
+        }
         if src.len() < remaining_length + 2 {
             src.reserve(remaining_length + 2 - src.len());
 
@@ -78,8 +388,13 @@ impl Decoder for MQTinyCodec {
         // println!("packet_flags: {}", packet_flags);
         // println!("remaining_length: {}", remaining_length);
         let packet = match packet_type {
-            3 => MqttPacket::Publish(parse_publish_packet(packet_flags, &packet_data)),
-            8 => MqttPacket::Subscribe(parse_subscribe_packet(packet_flags, &packet_data)),
+            1 => MqttPacket::Connect(parse_connect_packet(packet_flags, &packet_data)?),
+            2 => MqttPacket::Connack(parse_connack_packet(packet_flags, &packet_data)?),
+            3 => MqttPacket::Publish(parse_publish_packet(packet_flags, &packet_data)?),
+            4 => MqttPacket::Puback(parse_puback_packet(packet_flags, &packet_data)),
+            8 => MqttPacket::Subscribe(parse_subscribe_packet(packet_flags, &packet_data)?),
+            9 => MqttPacket::Suback(parse_suback_packet(packet_flags, &packet_data)?),
+            10 => MqttPacket::Unsubscribe(parse_unsubscribe_packet(packet_flags, &packet_data)?),
             _ => {
                 return Err(std::io::Error::new(
                     std::io::ErrorKind::InvalidData,
@@ -88,7 +403,7 @@ impl Decoder for MQTinyCodec {
             }
         };
 
-        return Ok(Some(packet));
+        Ok(Some(packet))
     }
 }
 impl Encoder<MqttPacket> for MQTinyCodec {
@@ -96,17 +411,225 @@ impl Encoder<MqttPacket> for MQTinyCodec {
 
     fn encode(&mut self, item: MqttPacket, dst: &mut bytes::BytesMut) -> Result<(), Self::Error> {
         match item {
+            MqttPacket::Connect(connect) => {
+                let mut body = Vec::new();
+                let mut connect_flags = 0;
+                if connect.username.is_some() {
+                    connect_flags |= CONNECT_FLAG_USERNAME;
+                }
+                if connect.password.is_some() {
+                    connect_flags |= CONNECT_FLAG_PASSWORD;
+                }
+                if connect.persistent {
+                    connect_flags |= CONNECT_FLAG_PERSISTENT;
+                }
+                body.put_u8(connect_flags);
+                put_bytes(&mut body, connect.client_id.as_bytes())?;
+                if let Some(username) = &connect.username {
+                    put_bytes(&mut body, username.as_bytes())?;
+                }
+                if let Some(password) = &connect.password {
+                    put_bytes(&mut body, password)?;
+                }
+
+                let remaining_length = remaining_length(body.len())?;
+                dst.reserve(2 + remaining_length as usize);
+                dst.put_u8((PacketType::Connect as u8) << 4);
+                dst.put_u8(remaining_length);
+                dst.put(&body[..]);
+            }
+            MqttPacket::Connack(connack) => {
+                dst.reserve(4);
+                dst.put_u8((PacketType::Connack as u8) << 4);
+                dst.put_u8(2);
+                dst.put_u8(0); // session present
+                dst.put_u8(connack.return_code as u8);
+            }
             MqttPacket::Publish(publish) => {
+                let mut properties = Vec::new();
+                if let Some(seq) = publish.seq {
+                    properties.put_u64(seq);
+                }
+                if let Some(expiry) = publish.expiry {
+                    properties.put_u32(expiry);
+                }
+                if let Some(delay) = publish.delay {
+                    properties.put_u32(delay);
+                }
+                if let Some(topic) = publish.response_topic {
+                    properties.put_u16(topic);
+                }
+                if let Some(data) = &publish.correlation_data {
+                    put_bytes(&mut properties, data)?;
+                }
+                let properties_len = match properties.len() {
+                    0 => 0,
+                    len => 1 + len,
+                };
+                let remaining_length = remaining_length(publish.encoded_len())?;
+                dst.reserve(2 + remaining_length as usize);
+                let mut flags = (publish.qos as u8) << 1;
+                if publish.retain {
+                    flags |= PUBLISH_FLAG_RETAIN;
+                }
+                if properties_len > 0 {
+                    flags |= PUBLISH_FLAG_PROPERTIES;
+                }
+                dst.put_u8(((PacketType::Publish as u8) << 4) + flags);
+                dst.put_u8(remaining_length);
+                dst.put_u16(publish.topic_name);
+                if properties_len > 0 {
+                    let mut present = 0;
+                    if publish.seq.is_some() {
+                        present |= PUBLISH_PROPERTY_SEQ;
+                    }
+                    if publish.expiry.is_some() {
+                        present |= PUBLISH_PROPERTY_EXPIRY;
+                    }
+                    if publish.delay.is_some() {
+                        present |= PUBLISH_PROPERTY_DELAY;
+                    }
+                    if publish.response_topic.is_some() {
+                        present |= PUBLISH_PROPERTY_RESPONSE_TOPIC;
+                    }
+                    if publish.correlation_data.is_some() {
+                        present |= PUBLISH_PROPERTY_CORRELATION_DATA;
+                    }
+                    dst.put_u8(present);
+                    dst.put(&properties[..]);
+                }
                 dst.put(&publish.payload[..]);
-                Ok(())
             }
-            MqttPacket::Subscribe(_) => todo!(),
-            MqttPacket::Puback(_) => todo!(),
+            MqttPacket::Subscribe(subscribe) => {
+                let (mut flags, from) = match subscribe.replay {
+                    None => (0, None),
+                    Some(Replay::FromSeq(seq)) => (SUBSCRIBE_FLAG_FROM_SEQ, Some(seq)),
+                    Some(Replay::Since(time)) => (SUBSCRIBE_FLAG_SINCE, Some(time)),
+                };
+                if subscribe.no_local {
+                    flags |= SUBSCRIBE_FLAG_NO_LOCAL;
+                }
+                let mut body = Vec::new();
+                body.put_u16(subscribe.topic_name);
+                if let Some(from) = from {
+                    body.put_u64(from);
+                }
+                if let Some(share) = &subscribe.share {
+                    flags |= SUBSCRIBE_FLAG_SHARED;
+                    body.put_u8(share.strategy as u8);
+                    put_bytes(&mut body, share.group.as_bytes())?;
+                }
+
+                let remaining_length = remaining_length(body.len())?;
+                dst.reserve(2 + remaining_length as usize);
+                dst.put_u8(((PacketType::Subscribe as u8) << 4) + flags);
+                dst.put_u8(remaining_length);
+                dst.put(&body[..]);
+            }
+            MqttPacket::Suback(suback) => {
+                dst.reserve(5);
+                dst.put_u8((PacketType::Suback as u8) << 4);
+                dst.put_u8(3);
+                dst.put_u16(suback.topic_name);
+                dst.put_u8(suback.return_code as u8);
+            }
+            MqttPacket::Unsubscribe(unsubscribe) => {
+                let mut flags = 0;
+                let mut body = Vec::new();
+                body.put_u16(unsubscribe.topic_name);
+                if let Some(group) = &unsubscribe.group {
+                    flags |= SUBSCRIBE_FLAG_SHARED;
+                    put_bytes(&mut body, group.as_bytes())?;
+                }
+
+                let remaining_length = remaining_length(body.len())?;
+                dst.reserve(2 + remaining_length as usize);
+                dst.put_u8(((PacketType::Unsubscribe as u8) << 4) + flags);
+                dst.put_u8(remaining_length);
+                dst.put(&body[..]);
+            }
+            MqttPacket::Puback(puback) => {
+                let remaining_length = remaining_length(puback.payload.len())?;
+                dst.reserve(2 + remaining_length as usize);
+                dst.put_u8((PacketType::Puback as u8) << 4);
+                dst.put_u8(remaining_length);
+                dst.put(&puback.payload[..]);
+            }
         }
+
+        Ok(())
     }
 }
 
-#[derive(Debug)]
+/// The remaining length is a single byte, so a packet carries at most 255 bytes
+/// after the fixed header.
+fn remaining_length(len: usize) -> Result<u8, std::io::Error> {
+    u8::try_from(len).map_err(|_| {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("packet too large: {} bytes", len),
+        )
+    })
+}
+
+fn invalid_data(msg: &str) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, msg.to_string())
+}
+
+fn get_u8(cursor: &mut Cursor<&[u8]>) -> Result<u8, std::io::Error> {
+    if !cursor.has_remaining() {
+        return Err(invalid_data("truncated packet"));
+    }
+    Ok(cursor.get_u8())
+}
+
+fn get_u16(cursor: &mut Cursor<&[u8]>) -> Result<u16, std::io::Error> {
+    if cursor.remaining() < 2 {
+        return Err(invalid_data("truncated packet"));
+    }
+    Ok(cursor.get_u16())
+}
+
+fn get_u32(cursor: &mut Cursor<&[u8]>) -> Result<u32, std::io::Error> {
+    if cursor.remaining() < 4 {
+        return Err(invalid_data("truncated packet"));
+    }
+    Ok(cursor.get_u32())
+}
+
+fn get_u64(cursor: &mut Cursor<&[u8]>) -> Result<u64, std::io::Error> {
+    if cursor.remaining() < 8 {
+        return Err(invalid_data("truncated packet"));
+    }
+    Ok(cursor.get_u64())
+}
+
+fn get_bytes<'a>(cursor: &mut Cursor<&'a [u8]>) -> Result<&'a [u8], std::io::Error> {
+    if cursor.remaining() < 2 {
+        return Err(invalid_data("truncated packet"));
+    }
+    let len = cursor.get_u16() as usize;
+    if cursor.remaining() < len {
+        return Err(invalid_data("truncated packet"));
+    }
+    let start = cursor.position() as usize;
+    cursor.advance(len);
+    Ok(&cursor.get_ref()[start..start + len])
+}
+
+fn get_string(cursor: &mut Cursor<&[u8]>) -> Result<String, std::io::Error> {
+    let bytes = get_bytes(cursor)?;
+    String::from_utf8(bytes.to_vec()).map_err(|_| invalid_data("invalid UTF-8 string"))
+}
+
+fn put_bytes(dst: &mut Vec<u8>, bytes: &[u8]) -> Result<(), std::io::Error> {
+    let len = u16::try_from(bytes.len()).map_err(|_| invalid_data("field too long"))?;
+    dst.put_u16(len);
+    dst.put(bytes);
+    Ok(())
+}
+
+#[derive(Debug, Clone, Copy)]
 pub enum PacketType {
     Unknown = 0,
     Connect = 1,
@@ -150,7 +673,53 @@ impl PacketType {
     }
 }
 
-#[derive(Clone, Copy, Debug)]
+/// Result of a connect, as in MQTT 3.1.1.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ConnectReturnCode {
+    Accepted = 0,
+    UnacceptableProtocolVersion = 1,
+    IdentifierRejected = 2,
+    ServerUnavailable = 3,
+    BadUsernameOrPassword = 4,
+    NotAuthorized = 5,
+}
+
+impl ConnectReturnCode {
+    pub fn from_u8(n: u8) -> Option<ConnectReturnCode> {
+        match n {
+            0 => Some(ConnectReturnCode::Accepted),
+            1 => Some(ConnectReturnCode::UnacceptableProtocolVersion),
+            2 => Some(ConnectReturnCode::IdentifierRejected),
+            3 => Some(ConnectReturnCode::ServerUnavailable),
+            4 => Some(ConnectReturnCode::BadUsernameOrPassword),
+            5 => Some(ConnectReturnCode::NotAuthorized),
+            _ => None,
+        }
+    }
+}
+
+/// Result of a subscribe, as in MQTT 3.1.1: the granted QoS, or a failure.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SubackReturnCode {
+    MaximumQoS0 = 0x00,
+    MaximumQoS1 = 0x01,
+    MaximumQoS2 = 0x02,
+    Failure = 0x80,
+}
+
+impl SubackReturnCode {
+    pub fn from_u8(n: u8) -> Option<SubackReturnCode> {
+        match n {
+            0x00 => Some(SubackReturnCode::MaximumQoS0),
+            0x01 => Some(SubackReturnCode::MaximumQoS1),
+            0x02 => Some(SubackReturnCode::MaximumQoS2),
+            0x80 => Some(SubackReturnCode::Failure),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum QoS {
     AtMostOnce = 0,
     AtLeastOnce = 1,
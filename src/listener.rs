@@ -0,0 +1,402 @@
+use std::{
+    fmt, fs, io,
+    net::SocketAddr,
+    os::unix::{
+        fs::{DirBuilderExt, FileTypeExt, PermissionsExt},
+        net::UnixStream,
+    },
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::{Arc, Mutex, RwLock},
+};
+
+use tokio::net::{lookup_host, TcpListener, TcpSocket, UnixListener};
+
+use tokio_rustls::TlsAcceptor;
+use tracing::warn;
+
+use crate::{
+    ratelimit::{Limiter, RateLimit},
+    tls,
+    transport::{Endpoint, Handshake, PeerAddr, Stream},
+    websocket::{ws_error, WsStream},
+};
+
+/// A `--listen` argument: `ENDPOINT[,OPTION=VALUE]...`.
+///
+/// `ENDPOINT` is `ADDR:PORT`, where `ADDR` may be an IPv4 address, a bracketed
+/// IPv6 address or a hostname, `tls://ADDR:PORT` for TLS, `ws://ADDR:PORT` for
+/// WebSocket clients, or `unix:///path` for a Unix domain socket.
+///
+/// Options are `max-connections=N`; `rate-messages=N`, `rate-bytes=N` and
+/// `rate-action=drop|delay|disconnect` for a [`RateLimit`] on the publishes
+/// of all its clients together; for TLS, `cert=PATH` and `key=PATH` (PEM,
+/// required) and `client-ca=PATH` to require client certificates signed by
+/// that CA; and for Unix sockets, `mode=OCTAL` for the socket file permissions.
+///
+/// Clones share the connection and rate limits, so a listener bound once per
+/// core still admits at most `max_connections` clients in total, and
+/// [`ListenAddr::reload`] changes them for every listener bound from it.
+#[derive(Clone, Debug)]
+pub struct ListenAddr {
+    pub endpoint: Endpoint,
+    pub max_connections: Option<usize>,
+    pub mode: Option<u32>,
+    pub cert: Option<PathBuf>,
+    pub key: Option<PathBuf>,
+    pub client_ca: Option<PathBuf>,
+    pub rate_limit: Option<RateLimit>,
+    limits: Arc<Limits>,
+}
+
+/// The limits of a listener as they are enforced.
+#[derive(Debug, Default)]
+struct Limits {
+    /// Connections open right now, and the most allowed.
+    connections: Mutex<(usize, Option<usize>)>,
+    limiter: RwLock<Option<Arc<Limiter>>>,
+}
+
+impl ListenAddr {
+    pub fn new(addr: &str) -> ListenAddr {
+        ListenAddr {
+            endpoint: Endpoint::parse(addr),
+            max_connections: None,
+            mode: None,
+            cert: None,
+            key: None,
+            client_ca: None,
+            rate_limit: None,
+            limits: Arc::default(),
+        }
+    }
+
+    pub fn max_connections(mut self, max: usize) -> ListenAddr {
+        self.max_connections = Some(max);
+        self.limits.connections.lock().unwrap().1 = Some(max);
+        self
+    }
+
+    pub fn rate_limit(mut self, limit: RateLimit) -> ListenAddr {
+        self.rate_limit = Some(limit);
+        *self.limits.limiter.write().unwrap() = Some(Arc::new(Limiter::new(limit)));
+        self
+    }
+
+    /// Takes the connection and rate limits of `new`, for the listeners
+    /// already bound from this address. Clients connected beyond a lowered
+    /// `max_connections` stay connected.
+    pub fn reload(&mut self, new: &ListenAddr) {
+        self.max_connections = new.max_connections;
+        self.limits.connections.lock().unwrap().1 = new.max_connections;
+        if self.rate_limit != new.rate_limit {
+            self.rate_limit = new.rate_limit;
+            *self.limits.limiter.write().unwrap() =
+                new.rate_limit.map(|limit| Arc::new(Limiter::new(limit)));
+        }
+    }
+
+    /// Whether `other` differs from this address in more than the limits
+    /// [`ListenAddr::reload`] can change, so that it must be bound again.
+    pub fn rebind(&self, other: &ListenAddr) -> bool {
+        self.endpoint != other.endpoint
+            || self.mode != other.mode
+            || self.cert != other.cert
+            || self.key != other.key
+            || self.client_ca != other.client_ca
+    }
+
+    pub fn is_unix(&self) -> bool {
+        matches!(self.endpoint, Endpoint::Unix(_))
+    }
+
+    pub fn is_tls(&self) -> bool {
+        matches!(self.endpoint, Endpoint::Tls(_))
+    }
+}
+
+impl FromStr for ListenAddr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.split(',');
+        let addr = parts.next().unwrap_or_default();
+        if addr.is_empty() {
+            return Err("missing listen address".to_string());
+        }
+
+        let mut listen = ListenAddr::new(addr);
+        for option in parts {
+            match option.split_once('=') {
+                Some((key, value)) => listen.set_option(key, value)?,
+                None => return Err(format!("unknown listen option: {}", option)),
+            }
+        }
+        listen.check()?;
+
+        Ok(listen)
+    }
+}
+
+impl ListenAddr {
+    /// Applies one `KEY=VALUE` option of a `--listen` argument.
+    pub(crate) fn set_option(&mut self, key: &str, value: &str) -> Result<(), String> {
+        match key {
+            "max-connections" => {
+                let n = value
+                    .parse()
+                    .map_err(|_| format!("invalid max-connections: {}", value))?;
+                *self = self.clone().max_connections(n);
+            }
+            "mode" if self.is_unix() => {
+                let mode = u32::from_str_radix(value, 8)
+                    .map_err(|_| format!("invalid mode: {}", value))?;
+                self.mode = Some(mode);
+            }
+            "rate-messages" | "rate-bytes" | "rate-action" => {
+                let mut limit = self.rate_limit.unwrap_or_default();
+                limit.set_option(&key["rate-".len()..], value)?;
+                *self = self.clone().rate_limit(limit);
+            }
+            "cert" if self.is_tls() => self.cert = Some(value.into()),
+            "key" if self.is_tls() => self.key = Some(value.into()),
+            "client-ca" if self.is_tls() => self.client_ca = Some(value.into()),
+            _ => return Err(format!("unknown listen option: {}={}", key, value)),
+        }
+        Ok(())
+    }
+
+    /// Checks that the options given so far are complete.
+    pub(crate) fn check(&self) -> Result<(), String> {
+        if self.is_tls() && (self.cert.is_none() || self.key.is_none()) {
+            return Err(format!("{} needs a cert and a key", self.endpoint));
+        }
+        Ok(())
+    }
+}
+
+/// Listen addresses are equal if they have the same endpoint and options.
+impl PartialEq for ListenAddr {
+    fn eq(&self, other: &Self) -> bool {
+        !self.rebind(other)
+            && self.max_connections == other.max_connections
+            && self.rate_limit == other.rate_limit
+    }
+}
+
+impl fmt::Display for ListenAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.endpoint)
+    }
+}
+
+/// A bound listener that enforces the limits of its [`ListenAddr`].
+pub struct Listener {
+    inner: Inner,
+    limits: Arc<Limits>,
+}
+
+enum Inner {
+    Tcp(TcpListener),
+    Tls(TcpListener, TlsAcceptor),
+    Ws(TcpListener),
+    Unix(UnixListener, PathBuf),
+}
+
+/// Keeps a connection counted against its listener's limit until dropped.
+pub struct Slot {
+    limits: Arc<Limits>,
+}
+
+impl Slot {
+    /// The buckets of the listener's rate limit, shared by all its clients.
+    pub(crate) fn limiter(&self) -> Option<Arc<Limiter>> {
+        self.limits.limiter.read().unwrap().clone()
+    }
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        self.limits.connections.lock().unwrap().0 -= 1;
+    }
+}
+
+impl Listener {
+    /// Binds every address `listen` resolves to. With `reuseport`, TCP sockets
+    /// are bound with `SO_REUSEPORT` so that several cores can share the port.
+    /// A Unix socket path can only be bound once.
+    pub async fn bind(listen: &ListenAddr, reuseport: bool) -> io::Result<Vec<Listener>> {
+        let mut listeners = Vec::new();
+        match &listen.endpoint {
+            Endpoint::Tcp(addr) | Endpoint::Tls(addr) | Endpoint::Ws(addr) => {
+                let acceptor = if listen.is_tls() {
+                    let (Some(cert), Some(key)) = (&listen.cert, &listen.key) else {
+                        return Err(io::Error::new(
+                            io::ErrorKind::InvalidInput,
+                            format!("{} needs a certificate and key", listen.endpoint),
+                        ));
+                    };
+                    let config = tls::server_config(cert, key, listen.client_ca.as_deref())?;
+                    Some(TlsAcceptor::from(config))
+                } else {
+                    None
+                };
+
+                for addr in lookup_host(addr).await? {
+                    let listener = if reuseport {
+                        bind_reuseport(addr)?
+                    } else {
+                        TcpListener::bind(addr).await?
+                    };
+                    let inner = match (&listen.endpoint, &acceptor) {
+                        (Endpoint::Ws(_), _) => Inner::Ws(listener),
+                        (_, Some(acceptor)) => Inner::Tls(listener, acceptor.clone()),
+                        _ => Inner::Tcp(listener),
+                    };
+                    listeners.push(Listener {
+                        inner,
+                        limits: listen.limits.clone(),
+                    });
+                }
+            }
+            Endpoint::Unix(path) => {
+                let inner = bind_unix(path, listen.mode)?;
+                listeners.push(Listener {
+                    inner: Inner::Unix(inner, path.clone()),
+                    limits: listen.limits.clone(),
+                });
+            }
+        }
+
+        Ok(listeners)
+    }
+
+    /// Binds every address in `listen`.
+    pub async fn bind_all(listen: &[ListenAddr], reuseport: bool) -> io::Result<Vec<Listener>> {
+        let mut listeners = Vec::new();
+        for listen in listen {
+            listeners.extend(Listener::bind(listen, reuseport).await?);
+        }
+
+        Ok(listeners)
+    }
+
+    pub fn local_addr(&self) -> io::Result<Endpoint> {
+        match &self.inner {
+            Inner::Tcp(listener) => Ok(Endpoint::Tcp(listener.local_addr()?.to_string())),
+            Inner::Tls(listener, _) => Ok(Endpoint::Tls(listener.local_addr()?.to_string())),
+            Inner::Ws(listener) => Ok(Endpoint::Ws(listener.local_addr()?.to_string())),
+            Inner::Unix(_, path) => Ok(Endpoint::Unix(path.clone())),
+        }
+    }
+
+    /// Accepts the next connection, closing any that arrive while the
+    /// listener is at its connection limit.
+    pub async fn accept(&self) -> io::Result<(Stream, PeerAddr, Slot)> {
+        loop {
+            let (stream, addr): (Stream, _) = match &self.inner {
+                Inner::Tcp(listener) => {
+                    let (stream, addr) = listener.accept().await?;
+                    (Box::new(stream), PeerAddr::Tcp(addr))
+                }
+                Inner::Tls(listener, acceptor) => {
+                    let (stream, addr) = listener.accept().await?;
+                    let acceptor = acceptor.clone();
+                    let handshake = Handshake::new(async move {
+                        Ok(Box::new(acceptor.accept(stream).await?) as Stream)
+                    });
+                    (Box::new(handshake), PeerAddr::Tcp(addr))
+                }
+                Inner::Ws(listener) => {
+                    let (stream, addr) = listener.accept().await?;
+                    let handshake = Handshake::new(async move {
+                        let ws = tokio_tungstenite::accept_async(stream)
+                            .await
+                            .map_err(ws_error)?;
+                        Ok(Box::new(WsStream::new(ws)) as Stream)
+                    });
+                    (Box::new(handshake), PeerAddr::Tcp(addr))
+                }
+                Inner::Unix(listener, path) => {
+                    let (stream, _) = listener.accept().await?;
+                    (Box::new(stream), PeerAddr::Unix(path.clone()))
+                }
+            };
+            {
+                let mut connections = self.limits.connections.lock().unwrap();
+                let (open, max) = &mut *connections;
+                if max.is_none_or(|max| *open < max) {
+                    *open += 1;
+                    let limits = self.limits.clone();
+                    return Ok((stream, addr, Slot { limits }));
+                }
+            }
+            warn!(
+                listener = %self.local_addr()?,
+                peer = %addr,
+                "connection limit reached, rejecting"
+            );
+        }
+    }
+}
+
+impl Drop for Listener {
+    fn drop(&mut self) {
+        if let Inner::Unix(_, path) = &self.inner {
+            let _ = fs::remove_file(path);
+        }
+    }
+}
+
+fn bind_reuseport(addr: SocketAddr) -> io::Result<TcpListener> {
+    let socket = match addr {
+        SocketAddr::V4(_) => TcpSocket::new_v4()?,
+        SocketAddr::V6(_) => TcpSocket::new_v6()?,
+    };
+    socket.set_reuseaddr(true)?;
+    socket.set_reuseport(true)?;
+    socket.bind(addr)?;
+    socket.listen(1024)
+}
+
+/// Binds a Unix socket at `path`, replacing a stale socket file left behind by
+/// a previous broker, but not one another process still listens on. `mode`
+/// restricts which local users may connect.
+fn bind_unix(path: &Path, mode: Option<u32>) -> io::Result<UnixListener> {
+    if let Ok(metadata) = fs::symlink_metadata(path) {
+        if metadata.file_type().is_socket() {
+            if UnixStream::connect(path).is_ok() {
+                return Err(io::Error::new(
+                    io::ErrorKind::AddrInUse,
+                    format!("{}: another process is listening on it", path.display()),
+                ));
+            }
+            fs::remove_file(path)?;
+        }
+    }
+
+    let Some(mode) = mode else {
+        return UnixListener::bind(path);
+    };
+    // The socket is bound in a directory only the broker may enter and moved
+    // into place once it has its mode, so it is never reachable without it.
+    let name = path.file_name().ok_or_else(|| {
+        io::Error::new(io::ErrorKind::InvalidInput, "socket path has no file name")
+    })?;
+    let mut private = std::ffi::OsString::from(".");
+    private.push(name);
+    private.push(format!(".{}", std::process::id()));
+    let dir = path.with_file_name(private);
+    fs::DirBuilder::new().mode(0o700).create(&dir)?;
+
+    let bound = dir.join(name);
+    let listener = UnixListener::bind(&bound).and_then(|listener| {
+        fs::set_permissions(&bound, fs::Permissions::from_mode(mode))?;
+        fs::rename(&bound, path)?;
+        Ok(listener)
+    });
+    // Only left behind if binding or moving the socket failed.
+    let _ = fs::remove_file(&bound);
+    let _ = fs::remove_dir(&dir);
+    listener
+}
@@ -0,0 +1,88 @@
+use std::io;
+
+use serde::Deserialize;
+use tracing_subscriber::{
+    layer::SubscriberExt, reload, util::SubscriberInitExt, EnvFilter, Layer, Registry,
+};
+
+/// How log events are written to stderr.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum LogFormat {
+    /// One line per event
+    #[default]
+    Text,
+    /// Multi-line events with their fields and spans laid out for reading
+    Pretty,
+    /// One JSON object per event, for log collectors
+    Json,
+}
+
+/// `--log-level` and `--log-format`, shared by the broker and the tools.
+#[derive(clap::Args, Clone, Debug, Default)]
+// Without this the doc comment above becomes the about text of every binary.
+#[command(about = None, long_about = None)]
+pub struct LogArgs {
+    /// Log filter: a level such as `debug`, or directives such as `info,mqtiny::broker=trace` [default: info]
+    #[arg(long)]
+    pub log_level: Option<String>,
+
+    /// Log output format [default: text]
+    #[arg(long, value_enum)]
+    pub log_format: Option<LogFormat>,
+}
+
+impl LogArgs {
+    /// Installs the global logger from the command line alone.
+    pub fn init(&self) -> io::Result<LogHandle> {
+        init(
+            self.log_level.as_deref().unwrap_or(DEFAULT_LEVEL),
+            self.log_format.unwrap_or_default(),
+        )
+    }
+}
+
+/// The level used when none is configured. Per-publish events are logged at
+/// `trace`, so they stay off unless asked for.
+pub const DEFAULT_LEVEL: &str = "info";
+
+/// Changes the log filter of the running process.
+#[derive(Clone)]
+pub struct LogHandle {
+    filter: reload::Handle<EnvFilter, Registry>,
+}
+
+impl LogHandle {
+    pub fn set_level(&self, level: &str) -> io::Result<()> {
+        let filter = parse_filter(level)?;
+        self.filter.reload(filter).map_err(io::Error::other)
+    }
+}
+
+/// Checks a log filter without installing it.
+pub fn parse_filter(level: &str) -> io::Result<EnvFilter> {
+    EnvFilter::try_new(level).map_err(|e| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("invalid log level {:?}: {}", level, e),
+        )
+    })
+}
+
+/// Installs the global logger, writing to stderr.
+pub fn init(level: &str, format: LogFormat) -> io::Result<LogHandle> {
+    let (filter, handle) = reload::Layer::new(parse_filter(level)?);
+    let output = tracing_subscriber::fmt::layer().with_writer(io::stderr);
+    let output = match format {
+        LogFormat::Text => output.boxed(),
+        LogFormat::Pretty => output.pretty().boxed(),
+        LogFormat::Json => output.json().boxed(),
+    };
+    tracing_subscriber::registry()
+        .with(filter)
+        .with(output)
+        .try_init()
+        .map_err(|e| io::Error::new(io::ErrorKind::AlreadyExists, e))?;
+
+    Ok(LogHandle { filter: handle })
+}
@@ -0,0 +1,244 @@
+use std::{
+    fmt::Write as _,
+    io,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
+
+use tokio::net::{TcpListener, TcpStream};
+use tracing::debug;
+
+use crate::{
+    http::{self, Response},
+    stats::{Histogram, Stats, PAYLOAD_SIZE_BUCKETS, ROUTING_LATENCY_BUCKETS},
+};
+
+/// Serves `GET /metrics` over HTTP on `listener` until it fails.
+pub async fn serve(listener: TcpListener, stats: Arc<Stats>) -> io::Result<()> {
+    loop {
+        let (stream, addr) = listener.accept().await?;
+        let stats = stats.clone();
+        tokio::spawn(async move {
+            if let Err(e) = respond(stream, &stats).await {
+                debug!(peer = %addr, error = %e, "metrics request failed");
+            }
+        });
+    }
+}
+
+/// Answers one request and closes the connection.
+async fn respond(mut stream: TcpStream, stats: &Stats) -> io::Result<()> {
+    let Some(request) = http::read_request(&mut stream).await? else {
+        return Ok(());
+    };
+    let response = match (request.method.as_str(), request.path.as_str()) {
+        ("GET", "/metrics") => Response {
+            status: "200 OK",
+            content_type: "text/plain; version=0.0.4; charset=utf-8",
+            body: render(stats),
+        },
+        ("GET", _) => Response::text("404 Not Found", "not found"),
+        _ => Response::text("405 Method Not Allowed", "method not allowed"),
+    };
+    http::write_response(&mut stream, response).await
+}
+
+/// The statistics in the Prometheus text exposition format.
+pub fn render(stats: &Stats) -> String {
+    let mut out = String::new();
+    let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+
+    let single = [
+        (
+            "mqtiny_connections_total",
+            "counter",
+            "Connections accepted.",
+            &stats.connections,
+        ),
+        (
+            "mqtiny_connections_rejected_total",
+            "counter",
+            "Connections refused by the connection limits.",
+            &stats.connections_rejected,
+        ),
+        (
+            "mqtiny_clients",
+            "gauge",
+            "Clients connected.",
+            &stats.clients,
+        ),
+        (
+            "mqtiny_subscriptions",
+            "gauge",
+            "Subscriptions held by connected clients.",
+            &stats.subscriptions,
+        ),
+        (
+            "mqtiny_subscriptions_rejected_total",
+            "counter",
+            "Subscribes refused by the subscription limit.",
+            &stats.subscriptions_rejected,
+        ),
+        (
+            "mqtiny_received_bytes_total",
+            "counter",
+            "Payload bytes received from clients.",
+            &stats.bytes_in,
+        ),
+        (
+            "mqtiny_sent_bytes_total",
+            "counter",
+            "Payload bytes sent to subscribers.",
+            &stats.bytes_out,
+        ),
+        (
+            "mqtiny_queued_messages",
+            "gauge",
+            "Publishes waiting in subscriber queues.",
+            &stats.queued,
+        ),
+        (
+            "mqtiny_inflight_messages",
+            "gauge",
+            "QoS 1 and 2 publishes sent and not yet acknowledged.",
+            &stats.inflight,
+        ),
+        (
+            "mqtiny_retained_messages",
+            "gauge",
+            "Retained publishes kept.",
+            &stats.retained,
+        ),
+        (
+            "mqtiny_scheduled_messages",
+            "gauge",
+            "Delayed publishes waiting for their time.",
+            &stats.scheduled,
+        ),
+        (
+            "mqtiny_auth_failures_total",
+            "counter",
+            "Logins refused.",
+            &stats.auth_failures,
+        ),
+        (
+            "mqtiny_subscribes_denied_total",
+            "counter",
+            "Subscribes refused by the ACL.",
+            &stats.subscribes_denied,
+        ),
+    ];
+    for (name, kind, help, value) in single {
+        metric(&mut out, name, kind, help);
+        sample(&mut out, name, "", load(value));
+    }
+
+    let per_qos = [
+        (
+            "mqtiny_messages_received_total",
+            "Publishes received from clients, by QoS.",
+            &stats.messages_in,
+        ),
+        (
+            "mqtiny_messages_sent_total",
+            "Publishes sent to subscribers, by QoS.",
+            &stats.messages_out,
+        ),
+    ];
+    for (name, help, counters) in per_qos {
+        metric(&mut out, name, "counter", help);
+        for (qos, counter) in counters.iter().enumerate() {
+            sample(&mut out, name, &format!("qos=\"{}\"", qos), load(counter));
+        }
+    }
+
+    let name = "mqtiny_dropped_total";
+    metric(&mut out, name, "counter", "Packets dropped, by reason.");
+    let drops = [
+        ("denied", &stats.publishes_denied),
+        ("invalid", &stats.invalid_packets),
+        ("undeliverable", &stats.undeliverable),
+        ("expired", &stats.expired),
+        ("rejected", &stats.rejected),
+    ];
+    for (reason, counter) in drops {
+        sample(
+            &mut out,
+            name,
+            &format!("reason=\"{}\"", reason),
+            load(counter),
+        );
+    }
+
+    let name = "mqtiny_throttled_total";
+    let help = "Publishes over a rate limit, by the action taken.";
+    metric(&mut out, name, "counter", help);
+    let actions = ["drop", "delay", "disconnect"];
+    for (action, counter) in actions.into_iter().zip(&stats.throttled) {
+        sample(
+            &mut out,
+            name,
+            &format!("action=\"{}\"", action),
+            load(counter),
+        );
+    }
+
+    histogram(
+        &mut out,
+        "mqtiny_payload_size_bytes",
+        "Payload sizes of received publishes.",
+        &stats.payload_size,
+        &PAYLOAD_SIZE_BUCKETS,
+        1.0,
+    );
+    histogram(
+        &mut out,
+        "mqtiny_routing_latency_seconds",
+        "Time from receiving a publish to sending it to a subscriber.",
+        &stats.routing_latency,
+        &ROUTING_LATENCY_BUCKETS,
+        1e6,
+    );
+    out
+}
+
+fn metric(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+fn sample(out: &mut String, name: &str, labels: &str, value: impl std::fmt::Display) {
+    if labels.is_empty() {
+        let _ = writeln!(out, "{} {}", name, value);
+    } else {
+        let _ = writeln!(out, "{}{{{}}} {}", name, labels, value);
+    }
+}
+
+/// Writes `histogram`, whose observations are `per_unit` times finer than the
+/// unit in `name`, such as microseconds for seconds.
+fn histogram<const N: usize>(
+    out: &mut String,
+    name: &str,
+    help: &str,
+    histogram: &Histogram<N>,
+    bounds: &[u64; N],
+    per_unit: f64,
+) {
+    metric(out, name, "histogram", help);
+    let bucket = format!("{}_bucket", name);
+    for (bound, count) in bounds.iter().zip(histogram.cumulative()) {
+        let labels = format!("le=\"{}\"", *bound as f64 / per_unit);
+        sample(out, &bucket, &labels, count);
+    }
+    sample(out, &bucket, "le=\"+Inf\"", histogram.count());
+    sample(
+        out,
+        &format!("{}_sum", name),
+        "",
+        histogram.sum() as f64 / per_unit,
+    );
+    sample(out, &format!("{}_count", name), "", histogram.count());
+}
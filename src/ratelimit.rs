@@ -0,0 +1,211 @@
+use std::{
+    collections::HashMap,
+    ops::RangeInclusive,
+    str::FromStr,
+    sync::{Arc, Mutex, RwLock},
+    time::Duration,
+};
+
+use serde::Deserialize;
+use tokio::time::{self, Instant};
+
+/// What happens to a publish over its rate limit.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum RateAction {
+    /// The publish is discarded.
+    #[default]
+    Drop,
+    /// The broker stops reading from the client until the publish fits.
+    Delay,
+    /// The client is disconnected.
+    Disconnect,
+}
+
+impl FromStr for RateAction {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "drop" => Ok(RateAction::Drop),
+            "delay" => Ok(RateAction::Delay),
+            "disconnect" => Ok(RateAction::Disconnect),
+            _ => Err(format!("unknown rate limit action: {}", s)),
+        }
+    }
+}
+
+/// Publishes and payload bytes per second allowed to a client, a listener or
+/// a topic, with bursts of up to one second's worth. `messages` and `bytes`
+/// are each optional.
+///
+/// Parsed from `messages=N,bytes=N,action=drop|delay|disconnect`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RateLimit {
+    pub messages: Option<u32>,
+    pub bytes: Option<u32>,
+    pub action: RateAction,
+}
+
+impl RateLimit {
+    /// Applies one `KEY=VALUE` option: `messages`, `bytes` or `action`.
+    pub(crate) fn set_option(&mut self, key: &str, value: &str) -> Result<(), String> {
+        let rate = || match value.parse() {
+            Ok(0) | Err(_) => Err(format!("invalid rate limit {}: {}", key, value)),
+            Ok(rate) => Ok(rate),
+        };
+        match key {
+            "messages" => self.messages = Some(rate()?),
+            "bytes" => self.bytes = Some(rate()?),
+            "action" => self.action = value.parse()?,
+            _ => return Err(format!("unknown rate limit option: {}", key)),
+        }
+        Ok(())
+    }
+
+    /// Fails if a rate is zero: no publish would ever fit a message rate of
+    /// zero, and a byte rate of zero would turn the limit off.
+    pub(crate) fn check(&self) -> Result<(), String> {
+        if self.messages == Some(0) {
+            return Err("rate limit messages must be at least 1".to_string());
+        }
+        if self.bytes == Some(0) {
+            return Err("rate limit bytes must be at least 1".to_string());
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for RateLimit {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut limit = RateLimit::default();
+        for option in s.split(',') {
+            let (key, value) = option
+                .split_once('=')
+                .ok_or_else(|| format!("expected KEY=VALUE: {}", option))?;
+            limit.set_option(key, value)?;
+        }
+        Ok(limit)
+    }
+}
+
+/// The token buckets enforcing one [`RateLimit`].
+#[derive(Debug)]
+pub(crate) struct Limiter {
+    limit: RateLimit,
+    buckets: Mutex<Buckets>,
+}
+
+#[derive(Debug)]
+struct Buckets {
+    messages: f64,
+    bytes: f64,
+    updated: Instant,
+}
+
+impl Limiter {
+    pub(crate) fn new(limit: RateLimit) -> Limiter {
+        Limiter {
+            limit,
+            buckets: Mutex::new(Buckets {
+                messages: limit.messages.unwrap_or_default().into(),
+                bytes: limit.bytes.unwrap_or_default().into(),
+                updated: Instant::now(),
+            }),
+        }
+    }
+
+    pub(crate) fn limit(&self) -> RateLimit {
+        self.limit
+    }
+
+    /// Takes a publish of `size` payload bytes from the buckets, or returns how
+    /// long until the buckets hold enough for it.
+    fn try_take(&self, size: usize) -> Result<(), Duration> {
+        let mut buckets = self.buckets.lock().unwrap();
+        let now = Instant::now();
+        let elapsed = (now - buckets.updated).as_secs_f64();
+        buckets.updated = now;
+
+        let mut wait: f64 = 0.0;
+        if let Some(rate) = self.limit.messages {
+            let rate = f64::from(rate);
+            buckets.messages = (buckets.messages + elapsed * rate).min(rate);
+            wait = wait.max((1.0 - buckets.messages) / rate);
+        }
+        // A payload larger than the bucket only needs a full one.
+        let mut bytes = size as f64;
+        if let Some(rate) = self.limit.bytes {
+            let rate = f64::from(rate);
+            bytes = bytes.min(rate);
+            buckets.bytes = (buckets.bytes + elapsed * rate).min(rate);
+            wait = wait.max((bytes - buckets.bytes) / rate);
+        }
+        if wait > 0.0 {
+            return Err(Duration::from_secs_f64(wait));
+        }
+
+        buckets.messages -= 1.0;
+        buckets.bytes -= bytes;
+        Ok(())
+    }
+}
+
+/// Admits a publish of `size` payload bytes through every limiter, waiting on
+/// those whose action is [`RateAction::Delay`]. Returns the action taken if
+/// the publish was over a limit; unless it is `Delay`, the publish must not
+/// be routed.
+pub(crate) async fn admit<'a>(
+    limiters: impl IntoIterator<Item = &'a Limiter>,
+    size: usize,
+) -> Option<RateAction> {
+    let mut throttled = None;
+    for limiter in limiters {
+        while let Err(wait) = limiter.try_take(size) {
+            match limiter.limit.action {
+                RateAction::Delay => {
+                    throttled = Some(RateAction::Delay);
+                    time::sleep(wait).await;
+                }
+                action => return Some(action),
+            }
+        }
+    }
+    throttled
+}
+
+/// Rules limiting ranges of topics; the first rule covering a topic applies.
+pub type TopicRules = Vec<(RangeInclusive<u16>, RateLimit)>;
+
+/// Rate limits on ranges of topics. Each topic gets its own buckets, shared
+/// by every publisher on every core; clones share them and the rules too.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct TopicLimits {
+    rules: Arc<RwLock<TopicRules>>,
+    limiters: Arc<Mutex<HashMap<u16, Arc<Limiter>>>>,
+}
+
+impl TopicLimits {
+    /// Replaces the rules. The buckets start over if they changed.
+    pub(crate) fn set(&self, rules: TopicRules) {
+        let mut current = self.rules.write().unwrap();
+        if *current != rules {
+            *current = rules;
+            self.limiters.lock().unwrap().clear();
+        }
+    }
+
+    /// The limiter of `topic`, if a rule covers it.
+    pub(crate) fn get(&self, topic: u16) -> Option<Arc<Limiter>> {
+        let rules = self.rules.read().unwrap();
+        let (_, limit) = rules.iter().find(|(topics, _)| topics.contains(&topic))?;
+        let mut limiters = self.limiters.lock().unwrap();
+        let limiter = limiters
+            .entry(topic)
+            .or_insert_with(|| Arc::new(Limiter::new(*limit)));
+        Some(limiter.clone())
+    }
+}
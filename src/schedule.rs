@@ -0,0 +1,163 @@
+//! Publishes the broker holds back until they are due, on a timer wheel.
+//!
+//! The wheel has a slot per second for an hour. A publish goes in the slot of
+//! the second it is due in, and each tick empties the slots of the seconds
+//! that went by, routing the publishes that are due and leaving those due in
+//! a later turn of the wheel. Scheduled publishes are also kept in the
+//! [`Store`], so a broker with a log routes them after a restart.
+
+use std::{
+    collections::HashMap,
+    io,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use tracing::{debug, warn};
+
+use crate::{broker::Broker, history::now, store::Store, MqttPublishPacket};
+
+/// Slots of the wheel, one per second.
+const SLOTS: u64 = 3600;
+
+/// How often the wheel turns.
+const TICK: Duration = Duration::from_secs(1);
+
+/// The scheduled publishes of a broker, shared by its cores.
+#[derive(Clone)]
+pub(crate) struct Scheduler(Arc<Mutex<Wheel>>);
+
+struct Wheel {
+    /// Ids of the publishes due in each second, by the second modulo [`SLOTS`].
+    /// Cancelled ones are left behind and skipped.
+    slots: Vec<Vec<u64>>,
+    /// The last second emptied, since the Unix epoch.
+    cursor: u64,
+    entries: HashMap<u64, Scheduled>,
+    next_id: u64,
+}
+
+/// A publish waiting for its time.
+#[derive(Clone, Debug)]
+pub(crate) struct Scheduled {
+    pub(crate) id: u64,
+    /// In milliseconds since the Unix epoch.
+    pub(crate) due: u64,
+    pub(crate) packet: MqttPublishPacket,
+}
+
+impl Default for Scheduler {
+    fn default() -> Self {
+        Scheduler(Arc::new(Mutex::new(Wheel {
+            slots: vec![Vec::new(); SLOTS as usize],
+            cursor: now() / 1000,
+            entries: HashMap::new(),
+            next_id: 1,
+        })))
+    }
+}
+
+impl Scheduler {
+    /// Takes over the scheduled publishes kept in `store`.
+    pub(crate) fn load(&self, store: &Store) {
+        let mut wheel = self.0.lock().unwrap();
+        for (id, due, packet) in store.scheduled() {
+            wheel.next_id = wheel.next_id.max(id + 1);
+            wheel.insert(Scheduled { id, due, packet });
+        }
+    }
+
+    /// Schedules `packet` to be routed `delay` seconds from now, keeping it in
+    /// `store`. Returns its id.
+    pub(crate) fn schedule(
+        &self,
+        store: &Store,
+        packet: MqttPublishPacket,
+        delay: u32,
+    ) -> io::Result<u64> {
+        let mut wheel = self.0.lock().unwrap();
+        let id = wheel.next_id;
+        let due = now() + u64::from(delay) * 1000;
+        store.schedule(id, due, &packet)?;
+        wheel.next_id += 1;
+        wheel.insert(Scheduled { id, due, packet });
+        Ok(id)
+    }
+
+    /// Cancels scheduled publish `id`. Returns whether it was still waiting.
+    pub(crate) fn cancel(&self, store: &Store, id: u64) -> io::Result<bool> {
+        let mut wheel = self.0.lock().unwrap();
+        if !wheel.entries.contains_key(&id) {
+            return Ok(false);
+        }
+        store.unschedule(id)?;
+        wheel.entries.remove(&id);
+        Ok(true)
+    }
+
+    /// The publishes waiting, soonest first.
+    pub(crate) fn list(&self) -> Vec<Scheduled> {
+        let wheel = self.0.lock().unwrap();
+        let mut list: Vec<Scheduled> = wheel.entries.values().cloned().collect();
+        list.sort_by_key(|scheduled| (scheduled.due, scheduled.id));
+        list
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.0.lock().unwrap().entries.len()
+    }
+
+    /// Turns the wheel to now and takes the publishes that are due, soonest
+    /// first.
+    fn take_due(&self) -> Vec<Scheduled> {
+        let mut wheel = self.0.lock().unwrap();
+        let now = now();
+        let second = now / 1000;
+        // After a pause longer than a turn every slot is due for a look.
+        let first = (wheel.cursor + 1).max(second.saturating_sub(SLOTS - 1));
+        let mut due = Vec::new();
+        for s in first..=second {
+            let slot = std::mem::take(&mut wheel.slots[(s % SLOTS) as usize]);
+            let mut later = Vec::new();
+            for id in slot {
+                match wheel.entries.get(&id) {
+                    Some(scheduled) if scheduled.due <= now => {
+                        due.extend(wheel.entries.remove(&id));
+                    }
+                    Some(_) => later.push(id),
+                    None => {}
+                }
+            }
+            wheel.slots[(s % SLOTS) as usize] = later;
+        }
+        wheel.cursor = wheel.cursor.max(second);
+        due.sort_by_key(|scheduled| (scheduled.due, scheduled.id));
+        due
+    }
+}
+
+impl Wheel {
+    fn insert(&mut self, scheduled: Scheduled) {
+        // The second the publish is due by the end of, and never one already
+        // emptied.
+        let second = scheduled.due.div_ceil(1000).max(self.cursor + 1);
+        self.slots[(second % SLOTS) as usize].push(scheduled.id);
+        self.entries.insert(scheduled.id, scheduled);
+    }
+}
+
+/// Turns the wheel of `broker` every [`TICK`], routing the publishes that
+/// are due as if their publisher had sent them just then.
+pub(crate) async fn run(broker: Broker) {
+    let mut interval = tokio::time::interval(TICK);
+    loop {
+        interval.tick().await;
+        for scheduled in broker.scheduler().take_due() {
+            let topic = scheduled.packet.topic_name;
+            debug!(id = scheduled.id, topic, "scheduled publish due");
+            if let Err(e) = broker.publish_scheduled(scheduled) {
+                warn!(topic, error = %e, "cannot route scheduled publish");
+            }
+        }
+    }
+}
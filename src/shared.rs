@@ -0,0 +1,130 @@
+//! Shared subscriptions: groups of clients subscribed to a topic together,
+//! each publish on it going to one member of each group only. Groups span
+//! the cores of a broker but not the nodes of a cluster.
+
+use std::{
+    collections::{hash_map::DefaultHasher, HashMap},
+    hash::{Hash, Hasher},
+    sync::{atomic::Ordering, Arc, Mutex},
+};
+
+use crate::{
+    broker::{ClientId, Session},
+    ShareStrategy,
+};
+
+/// The shared subscriptions of a broker by topic, shared by its cores.
+#[derive(Clone, Default)]
+pub(crate) struct Groups(Arc<Mutex<HashMap<u16, Vec<Group>>>>);
+
+struct Group {
+    name: Arc<str>,
+    strategy: ShareStrategy,
+    members: Vec<Arc<Session>>,
+    /// Where round robin carries on.
+    next: usize,
+}
+
+impl Groups {
+    /// Adds `session` to `group` on `topic`, creating the group with
+    /// `strategy` if it has no members. Returns whether it was not a member
+    /// yet.
+    pub(crate) fn join(
+        &self,
+        topic: u16,
+        group: &Arc<str>,
+        strategy: ShareStrategy,
+        session: &Arc<Session>,
+    ) -> bool {
+        let mut topics = self.0.lock().unwrap();
+        let groups = topics.entry(topic).or_default();
+        let index = match groups.iter().position(|g| g.name == *group) {
+            Some(index) => index,
+            None => {
+                groups.push(Group {
+                    name: group.clone(),
+                    strategy,
+                    members: Vec::new(),
+                    next: 0,
+                });
+                groups.len() - 1
+            }
+        };
+        let members = &mut groups[index].members;
+        if members.iter().any(|member| member.id == session.id) {
+            return false;
+        }
+        members.push(session.clone());
+        true
+    }
+
+    /// Takes client `id` out of `group` on `topic`. Groups without members
+    /// are dropped.
+    pub(crate) fn leave(&self, topic: u16, group: &str, id: ClientId) {
+        let mut topics = self.0.lock().unwrap();
+        let Some(groups) = topics.get_mut(&topic) else {
+            return;
+        };
+        for g in groups.iter_mut().filter(|g| &*g.name == group) {
+            g.members.retain(|member| member.id != id);
+        }
+        groups.retain(|g| !g.members.is_empty());
+        if groups.is_empty() {
+            topics.remove(&topic);
+        }
+    }
+
+    /// The groups on `topic`.
+    pub(crate) fn on(&self, topic: u16) -> Vec<Arc<str>> {
+        let topics = self.0.lock().unwrap();
+        topics
+            .get(&topic)
+            .map(|groups| groups.iter().map(|g| g.name.clone()).collect())
+            .unwrap_or_default()
+    }
+
+    /// The member of `group` on `topic` to send the next publish of
+    /// `publisher` to, see [`publisher`].
+    pub(crate) fn pick(&self, topic: u16, group: &str, publisher: u64) -> Option<Arc<Session>> {
+        let mut topics = self.0.lock().unwrap();
+        let g = topics
+            .get_mut(&topic)?
+            .iter_mut()
+            .find(|g| &*g.name == group)?;
+        let count = g.members.len();
+        let start = g.next % count.max(1);
+        let member = match g.strategy {
+            ShareStrategy::RoundRobin => g.members.get(start),
+            // Ties go round robin.
+            ShareStrategy::LeastQueued => (0..count)
+                .map(|i| &g.members[(start + i) % count])
+                .min_by_key(|member| member.queued.load(Ordering::Relaxed)),
+            // Rendezvous hashing: only the publishers of a member that leaves
+            // move to another.
+            ShareStrategy::Sticky => g
+                .members
+                .iter()
+                .max_by_key(|member| hash((publisher, member.id))),
+        };
+        g.next = start + 1;
+        member.cloned()
+    }
+}
+
+/// Identifies the publisher of a publish to sticky groups: its client id if
+/// it logged in with one, so that it keeps its member across reconnects, or
+/// else its connection.
+pub(crate) fn publisher(session: &Session) -> u64 {
+    let client_id = session.client_id.read().unwrap();
+    if client_id.is_empty() {
+        hash(session.id)
+    } else {
+        hash(&*client_id)
+    }
+}
+
+fn hash(value: impl Hash) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    value.hash(&mut hasher);
+    hasher.finish()
+}
@@ -0,0 +1,252 @@
+use std::{
+    ops::RangeInclusive,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use tokio::time::{self, MissedTickBehavior};
+
+use crate::{
+    broker::{Command, Tx},
+    MqttPublishPacket, QoS,
+};
+
+/// Broker counters, shared by every core of a broker.
+#[derive(Debug, Default)]
+pub struct Stats {
+    /// Connections refused because of bad credentials or a missing login.
+    pub auth_failures: AtomicU64,
+    /// Publishes dropped because the client may not publish to their topic.
+    pub publishes_denied: AtomicU64,
+    /// Subscribes refused because the client may not subscribe to their topic.
+    pub subscribes_denied: AtomicU64,
+    /// Connections accepted since startup.
+    pub connections: AtomicU64,
+    /// Connections refused because of the broker's connection limits.
+    pub connections_rejected: AtomicU64,
+    /// Clients connected right now.
+    pub clients: AtomicU64,
+    /// Subscriptions held by connected clients right now.
+    pub subscriptions: AtomicU64,
+    /// Subscribes refused because the client had too many subscriptions.
+    pub subscriptions_rejected: AtomicU64,
+    /// Publishes received from clients, indexed by QoS.
+    pub messages_in: [AtomicU64; 3],
+    /// Publishes sent to subscribers, indexed by QoS.
+    pub messages_out: [AtomicU64; 3],
+    /// Payload bytes of `messages_in`.
+    pub bytes_in: AtomicU64,
+    /// Payload bytes of `messages_out`.
+    pub bytes_out: AtomicU64,
+    /// Packets that could not be decoded.
+    pub invalid_packets: AtomicU64,
+    /// Publishes for a subscriber that disconnected before they were handed over.
+    pub undeliverable: AtomicU64,
+    /// Publishes dropped from the queues of subscribers, persistent sessions
+    /// or retained storage because their expiry interval passed.
+    pub expired: AtomicU64,
+    /// Publishes a [`BrokerHook`](crate::hook::BrokerHook) rejected.
+    pub rejected: AtomicU64,
+    /// Delayed publishes waiting for their time right now.
+    pub scheduled: AtomicU64,
+    /// Publishes over a rate limit, indexed by the
+    /// [`RateAction`](crate::ratelimit::RateAction) taken.
+    pub throttled: [AtomicU64; 3],
+    /// Publishes waiting in the queues of subscribers right now.
+    pub queued: AtomicU64,
+    /// QoS 1 and 2 publishes sent to clients and not yet acknowledged right now.
+    pub inflight: AtomicU64,
+    /// Retained publishes kept right now.
+    pub retained: AtomicU64,
+    /// Payload sizes of `messages_in`, in bytes, over [`PAYLOAD_SIZE_BUCKETS`].
+    pub payload_size: Histogram<{ PAYLOAD_SIZE_BUCKETS.len() }>,
+    /// Time from receiving a publish to sending it to a subscriber, in
+    /// microseconds, over [`ROUTING_LATENCY_BUCKETS`].
+    pub routing_latency: Histogram<{ ROUTING_LATENCY_BUCKETS.len() }>,
+}
+
+/// Upper bounds of the [`Stats::payload_size`] buckets, in bytes.
+pub const PAYLOAD_SIZE_BUCKETS: [u64; 6] = [8, 16, 32, 64, 128, 256];
+/// Upper bounds of the [`Stats::routing_latency`] buckets, in microseconds.
+pub const ROUTING_LATENCY_BUCKETS: [u64; 12] = [
+    10, 25, 50, 100, 250, 500, 1_000, 2_500, 5_000, 10_000, 25_000, 100_000,
+];
+
+/// Counts of observations per bucket, plus their sum. The bucket bounds are
+/// kept by the caller and passed to [`Histogram::observe`].
+#[derive(Debug)]
+pub struct Histogram<const N: usize> {
+    /// Observations that fall in each bucket but not the ones before it.
+    buckets: [AtomicU64; N],
+    sum: AtomicU64,
+    count: AtomicU64,
+}
+
+impl<const N: usize> Default for Histogram<N> {
+    fn default() -> Self {
+        Histogram {
+            buckets: std::array::from_fn(|_| AtomicU64::new(0)),
+            sum: AtomicU64::new(0),
+            count: AtomicU64::new(0),
+        }
+    }
+}
+
+impl<const N: usize> Histogram<N> {
+    pub fn observe(&self, bounds: &[u64; N], value: u64) {
+        let bucket = bounds.partition_point(|&bound| bound < value);
+        if let Some(bucket) = self.buckets.get(bucket) {
+            bucket.fetch_add(1, Ordering::Relaxed);
+        }
+        self.sum.fetch_add(value, Ordering::Relaxed);
+        self.count.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Observations at or below each bound, as Prometheus buckets count them.
+    pub fn cumulative(&self) -> [u64; N] {
+        let mut total = 0;
+        std::array::from_fn(|i| {
+            total += self.buckets[i].load(Ordering::Relaxed);
+            total
+        })
+    }
+
+    pub fn sum(&self) -> u64 {
+        self.sum.load(Ordering::Relaxed)
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count.load(Ordering::Relaxed)
+    }
+}
+
+impl Stats {
+    /// Publishes received from clients, at any QoS.
+    pub fn messages_in(&self) -> u64 {
+        self.messages_in
+            .iter()
+            .map(|n| n.load(Ordering::Relaxed))
+            .sum()
+    }
+
+    /// Publishes sent to subscribers, at any QoS.
+    pub fn messages_out(&self) -> u64 {
+        self.messages_out
+            .iter()
+            .map(|n| n.load(Ordering::Relaxed))
+            .sum()
+    }
+
+    /// Publishes over a rate limit, whatever the action taken.
+    pub fn throttled(&self) -> u64 {
+        self.throttled
+            .iter()
+            .map(|n| n.load(Ordering::Relaxed))
+            .sum()
+    }
+}
+
+/// Topics reserved for broker statistics. Clients may subscribe to them, as
+/// far as the ACL allows, but publishes to them are denied.
+pub const SYS_TOPICS: RangeInclusive<u16> = 0xFF00..=0xFFFF;
+
+/// Clients connected.
+pub const SYS_CLIENTS: u16 = 0xFF00;
+/// Subscriptions held.
+pub const SYS_SUBSCRIPTIONS: u16 = 0xFF01;
+/// Publishes received per second over the last interval.
+pub const SYS_MESSAGES_IN_RATE: u16 = 0xFF02;
+/// Publishes sent per second over the last interval.
+pub const SYS_MESSAGES_OUT_RATE: u16 = 0xFF03;
+/// Payload bytes received since startup.
+pub const SYS_BYTES_IN: u16 = 0xFF04;
+/// Payload bytes sent since startup.
+pub const SYS_BYTES_OUT: u16 = 0xFF05;
+/// Publishes dropped by the ACL since startup.
+pub const SYS_DROPPED_DENIED: u16 = 0xFF06;
+/// Invalid packets dropped since startup.
+pub const SYS_DROPPED_INVALID: u16 = 0xFF07;
+/// Publishes dropped because their subscriber had disconnected, since startup.
+pub const SYS_DROPPED_UNDELIVERABLE: u16 = 0xFF08;
+/// Refused logins since startup.
+pub const SYS_AUTH_FAILURES: u16 = 0xFF09;
+/// Refused subscribes since startup.
+pub const SYS_SUBSCRIBES_DENIED: u16 = 0xFF0A;
+/// Publishes over a rate limit since startup, whether dropped, delayed or
+/// answered with a disconnect.
+pub const SYS_THROTTLED: u16 = 0xFF0B;
+/// Publishes dropped because their expiry interval passed, since startup.
+pub const SYS_DROPPED_EXPIRED: u16 = 0xFF0C;
+/// Publishes rejected by a hook since startup.
+pub const SYS_DROPPED_REJECTED: u16 = 0xFF0D;
+/// QoS 1 and 2 publishes sent and not yet acknowledged.
+pub const SYS_INFLIGHT: u16 = 0xFF0E;
+/// Retained publishes kept.
+pub const SYS_RETAINED: u16 = 0xFF0F;
+
+/// Publishes the statistics every `interval` through the routing channel `tx`,
+/// one topic per value with the value as a big-endian `u64` payload.
+pub(crate) async fn publish_sys(stats: Arc<Stats>, tx: Tx, interval: Duration) {
+    let mut ticks = time::interval(interval);
+    ticks.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    ticks.tick().await;
+
+    let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+    let mut last_in = stats.messages_in();
+    let mut last_out = stats.messages_out();
+    loop {
+        ticks.tick().await;
+        let (messages_in, messages_out) = (stats.messages_in(), stats.messages_out());
+        let rate = |now: u64, last: u64| ((now - last) as f64 / interval.as_secs_f64()) as u64;
+
+        let values = [
+            (SYS_CLIENTS, load(&stats.clients)),
+            (SYS_SUBSCRIPTIONS, load(&stats.subscriptions)),
+            (SYS_MESSAGES_IN_RATE, rate(messages_in, last_in)),
+            (SYS_MESSAGES_OUT_RATE, rate(messages_out, last_out)),
+            (SYS_BYTES_IN, load(&stats.bytes_in)),
+            (SYS_BYTES_OUT, load(&stats.bytes_out)),
+            (SYS_DROPPED_DENIED, load(&stats.publishes_denied)),
+            (SYS_DROPPED_INVALID, load(&stats.invalid_packets)),
+            (SYS_DROPPED_UNDELIVERABLE, load(&stats.undeliverable)),
+            (SYS_AUTH_FAILURES, load(&stats.auth_failures)),
+            (SYS_SUBSCRIBES_DENIED, load(&stats.subscribes_denied)),
+            (SYS_THROTTLED, stats.throttled()),
+            (SYS_DROPPED_EXPIRED, load(&stats.expired)),
+            (SYS_DROPPED_REJECTED, load(&stats.rejected)),
+            (SYS_INFLIGHT, load(&stats.inflight)),
+            (SYS_RETAINED, load(&stats.retained)),
+        ];
+        for (topic_name, value) in values {
+            let packet = MqttPublishPacket {
+                topic_name,
+                qos: QoS::AtMostOnce,
+                retain: false,
+                seq: None,
+                expiry: None,
+                delay: None,
+                response_topic: None,
+                correlation_data: None,
+                payload: value.to_be_bytes().to_vec(),
+            };
+            let received = Instant::now();
+            let stored = None;
+            if tx
+                .send(Command::Publish {
+                    packet,
+                    received,
+                    stored,
+                    origin: None,
+                    from_peer: false,
+                })
+                .is_err()
+            {
+                return;
+            }
+        }
+        (last_in, last_out) = (messages_in, messages_out);
+    }
+}
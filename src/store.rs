@@ -0,0 +1,1149 @@
+//! Retained publishes, persistent sessions and scheduled publishes, kept in
+//! memory and, if the broker is given a path, in a write-ahead log on disk.
+//!
+//! The log is a sequence of records, each preceded by its length and CRC-32 as
+//! big-endian `u32`s. Every change is appended before it takes effect, and
+//! before the broker acknowledges the publish or subscribe behind it. When the
+//! store is opened the log is replayed; a record cut short or failing its
+//! checksum, as left by a crash in the middle of a write, ends the log and is
+//! discarded. The log is then compacted: the live state is written to a new
+//! file that replaces the old one. It is compacted again whenever it grows to
+//! twice its compacted size, and at least [`COMPACT_MIN`] bytes.
+//!
+//! The records are written, synced and compacted by a thread of the store's
+//! own, so that file I/O never blocks the runtime. A change takes effect in
+//! memory as soon as it is handed to it; [`Store::written`] waits for the
+//! thread to catch up.
+//!
+//! Publishes with an expiry interval are kept with the time they expire, in
+//! milliseconds since the Unix epoch, so that a restart does not extend it.
+
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc, Arc, Mutex,
+    },
+    thread,
+    time::Duration,
+};
+
+use bytes::{Buf, BufMut};
+use serde::Deserialize;
+use tokio::sync::watch;
+use tracing::{debug, error, info, warn};
+
+use crate::{broker::Broker, deadletter::DropReason, history::now, MqttPublishPacket, QoS};
+
+/// Log size below which the log is not compacted, in bytes.
+pub const COMPACT_MIN: u64 = 1 << 20;
+
+/// How often the log is flushed to disk with [`Fsync::Interval`].
+pub const FSYNC_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How often expired publishes are dropped from the store.
+pub const EXPIRE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// When appends to the log are flushed to disk. Whatever the policy, nothing
+/// acknowledged is lost if only the broker crashes; the policy decides what
+/// survives a crash of the machine.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum Fsync {
+    /// Before anything is acknowledged, so nothing acknowledged is lost
+    Always,
+    /// Every second, so the last second of acknowledged changes may be lost
+    #[default]
+    Interval,
+    /// Whenever the operating system writes them back
+    Never,
+}
+
+/// The retained publishes and persistent sessions of a broker. Clones share
+/// the same store.
+#[derive(Clone, Default)]
+pub struct Store {
+    inner: Arc<Mutex<Inner>>,
+    /// Whether any persistent session is subscribed to a topic, so that
+    /// publishes with nothing to keep skip the lock.
+    subscribed: Arc<AtomicBool>,
+    /// How far the writer thread has got, if the store has a log.
+    written: Option<watch::Receiver<Written>>,
+}
+
+#[derive(Default)]
+struct Inner {
+    state: State,
+    /// `None` for a store kept in memory only.
+    log: Option<Log>,
+}
+
+impl Store {
+    /// A store that is lost when the broker stops, as is the default.
+    pub fn memory() -> Store {
+        Store::default()
+    }
+
+    /// Opens the log at `path`, creating it if it does not exist, and
+    /// recovers the state it records.
+    pub fn open(path: &Path, fsync: Fsync) -> io::Result<Store> {
+        let with_path =
+            |e: io::Error| io::Error::new(e.kind(), format!("{}: {}", path.display(), e));
+        let contents = match fs::read(path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(with_path(e)),
+        };
+
+        let mut state = State::default();
+        let mut buf = &contents[..];
+        let mut records = 0;
+        while let Some(record) = read_record(&mut buf) {
+            state.apply(record);
+            records += 1;
+        }
+        if !buf.is_empty() {
+            warn!(
+                path = %path.display(),
+                bytes = buf.len(),
+                "discarding the torn end of the store log"
+            );
+        }
+
+        let mut writer = Writer {
+            path: path.to_path_buf(),
+            file: None,
+            fsync,
+            dirty: false,
+        };
+        let snapshot = encode(&state.snapshot());
+        writer.compact(&snapshot).map_err(with_path)?;
+        let (tx, rx) = mpsc::channel();
+        let (written_tx, written) = watch::channel(Written::default());
+        thread::Builder::new()
+            .name("mqtiny-store".to_string())
+            .spawn(move || writer.run(rx, written_tx))?;
+        let log = Log {
+            tx,
+            fsync,
+            len: snapshot.len() as u64,
+            compacted: snapshot.len() as u64,
+            sent: 0,
+        };
+        info!(
+            path = %path.display(),
+            records,
+            sessions = state.sessions.len(),
+            retained = state.retained.len(),
+            scheduled = state.scheduled.len(),
+            "store recovered"
+        );
+        Ok(Store {
+            subscribed: Arc::new(AtomicBool::new(!state.subscribers.is_empty())),
+            written: Some(written),
+            inner: Arc::new(Mutex::new(Inner {
+                state,
+                log: Some(log),
+            })),
+        })
+    }
+
+    /// The fsync policy of the log, if the store has one.
+    pub(crate) fn fsync(&self) -> Option<Fsync> {
+        let inner = self.inner.lock().unwrap();
+        inner.log.as_ref().map(|log| log.fsync)
+    }
+
+    /// Has the log flushed to disk, if anything was appended since the last
+    /// time.
+    pub(crate) fn sync(&self) -> io::Result<()> {
+        match &mut self.inner.lock().unwrap().log {
+            Some(log) => log.send(LogWrite::Sync),
+            None => Ok(()),
+        }
+    }
+
+    /// Waits until the log holds every change made so far, flushed to disk
+    /// with [`Fsync::Always`]. Nothing may be acknowledged before.
+    pub(crate) async fn written(&self) -> io::Result<()> {
+        let Some(written) = &self.written else {
+            return Ok(());
+        };
+        let sent = match &self.inner.lock().unwrap().log {
+            Some(log) => log.sent,
+            None => return Ok(()),
+        };
+        let mut written = written.clone();
+        loop {
+            {
+                let written = written.borrow();
+                if let Some((kind, message)) = &written.error {
+                    return Err(io::Error::new(*kind, message.clone()));
+                }
+                if written.count >= sent {
+                    return Ok(());
+                }
+            }
+            written.changed().await.map_err(|_| writer_stopped())?;
+        }
+    }
+
+    /// Records a publish: keeps it if it is retained, and queues it for the
+    /// persistent sessions subscribed to its topic if its QoS is 1 or 2.
+    /// Returns the sequence number it is queued under, if it is.
+    pub(crate) fn publish(&self, packet: &MqttPublishPacket) -> io::Result<Option<u64>> {
+        if !packet.retain
+            && (packet.qos == QoS::AtMostOnce || !self.subscribed.load(Ordering::Acquire))
+        {
+            return Ok(None);
+        }
+        let expires = packet.expiry.map(|expiry| now() + u64::from(expiry) * 1000);
+        let packet = &MqttPublishPacket {
+            expiry: None,
+            ..packet.clone()
+        };
+        let mut inner = self.inner.lock().unwrap();
+        let mut records = Vec::new();
+        if packet.retain {
+            records.push(Record::Retain {
+                packet: packet.clone(),
+                expires,
+            });
+        }
+        let subscribers = inner.state.subscribers.get(&packet.topic_name);
+        let seq = match subscribers {
+            Some(subscribers) if packet.qos != QoS::AtMostOnce => {
+                let seq = inner.state.next_seq;
+                records.push(Record::Message {
+                    seq,
+                    packet: MqttPublishPacket {
+                        retain: false,
+                        ..packet.clone()
+                    },
+                    expires,
+                });
+                for client_id in subscribers {
+                    records.push(Record::Enqueue {
+                        client_id: client_id.clone(),
+                        seq,
+                    });
+                }
+                Some(seq)
+            }
+            _ => None,
+        };
+        inner.commit(records)?;
+        Ok(seq)
+    }
+
+    /// The publish kept for `topic`, if any and unexpired.
+    pub(crate) fn retained(&self, topic: u16) -> Option<MqttPublishPacket> {
+        let inner = self.inner.lock().unwrap();
+        let (packet, expires) = inner.state.retained.get(&topic)?;
+        unexpired(packet, *expires, now())
+    }
+
+    /// The number of retained publishes, expired or not.
+    pub(crate) fn retained_count(&self) -> usize {
+        self.inner.lock().unwrap().state.retained.len()
+    }
+
+    /// The subscriptions of the persistent session of `client_id`.
+    pub(crate) fn subscriptions(&self, client_id: &str) -> Vec<u16> {
+        let inner = self.inner.lock().unwrap();
+        match inner.state.sessions.get(client_id) {
+            Some(session) => session.subscriptions.iter().copied().collect(),
+            None => Vec::new(),
+        }
+    }
+
+    /// The unexpired publishes queued for the persistent session of
+    /// `client_id`, with their sequence numbers, oldest first.
+    pub(crate) fn queued(&self, client_id: &str) -> Vec<(u64, MqttPublishPacket)> {
+        let inner = self.inner.lock().unwrap();
+        let state = &inner.state;
+        let now = now();
+        match state.sessions.get(client_id) {
+            Some(session) => session
+                .pending
+                .iter()
+                .filter_map(|seq| {
+                    let message = &state.messages[seq];
+                    Some((*seq, unexpired(&message.packet, message.expires, now)?))
+                })
+                .collect(),
+            None => Vec::new(),
+        }
+    }
+
+    /// Drops the expired publishes: retained ones, and those queued for
+    /// persistent sessions. Returns them, a publish once per session it was
+    /// queued for.
+    pub(crate) fn expire(&self) -> io::Result<Vec<MqttPublishPacket>> {
+        let now = now();
+        let mut inner = self.inner.lock().unwrap();
+        let state = &inner.state;
+        let is_expired = |expires: Option<u64>| expires.is_some_and(|expires| expires <= now);
+        let mut records = Vec::new();
+        let mut dropped = Vec::new();
+        let expired: BTreeSet<u64> = state
+            .messages
+            .iter()
+            .filter(|(_, message)| is_expired(message.expires))
+            .map(|(seq, _)| *seq)
+            .collect();
+        if !expired.is_empty() {
+            for (client_id, session) in &state.sessions {
+                for &seq in session.pending.intersection(&expired) {
+                    let client_id = client_id.clone();
+                    records.push(Record::Ack { client_id, seq });
+                    dropped.push(state.messages[&seq].packet.clone());
+                }
+            }
+        }
+        for (packet, expires) in state.retained.values() {
+            if is_expired(*expires) {
+                dropped.push(packet.clone());
+                // An empty payload clears the topic.
+                let packet = MqttPublishPacket {
+                    payload: Vec::new(),
+                    ..packet.clone()
+                };
+                records.push(Record::Retain {
+                    packet,
+                    expires: None,
+                });
+            }
+        }
+        inner.commit(records)?;
+        Ok(dropped)
+    }
+
+    /// Whether the persistent session of `client_id`, if it has one, belongs
+    /// to `username`, `None` for anonymous clients.
+    pub(crate) fn owned_by(&self, client_id: &str, username: Option<&str>) -> bool {
+        let inner = self.inner.lock().unwrap();
+        inner
+            .state
+            .sessions
+            .get(client_id)
+            .is_none_or(|session| session.owner.as_deref() == username)
+    }
+
+    /// Claims the persistent session of `client_id` for `username`, creating
+    /// it if it does not exist. Returns `false`, leaving the session alone, if
+    /// it belongs to another user.
+    pub(crate) fn claim(&self, client_id: &str, username: Option<&str>) -> io::Result<bool> {
+        let mut inner = self.inner.lock().unwrap();
+        match inner.state.sessions.get(client_id) {
+            Some(session) => Ok(session.owner.as_deref() == username),
+            None => {
+                let client_id = client_id.to_string();
+                let owner = username.map(str::to_string);
+                inner.commit(vec![Record::Claim { client_id, owner }])?;
+                Ok(true)
+            }
+        }
+    }
+
+    pub(crate) fn subscribe(&self, client_id: &str, topic: u16) -> io::Result<()> {
+        let client_id = client_id.to_string();
+        let mut inner = self.inner.lock().unwrap();
+        inner.commit(vec![Record::Subscribe { client_id, topic }])?;
+        self.update_subscribed(&inner);
+        Ok(())
+    }
+
+    pub(crate) fn unsubscribe(&self, client_id: &str, topic: u16) -> io::Result<()> {
+        let client_id = client_id.to_string();
+        let mut inner = self.inner.lock().unwrap();
+        inner.commit(vec![Record::Unsubscribe { client_id, topic }])?;
+        self.update_subscribed(&inner);
+        Ok(())
+    }
+
+    /// Removes publish `seq` from the queue of `client_id`, once the client
+    /// has acknowledged it.
+    pub(crate) fn ack(&self, client_id: &str, seq: u64) -> io::Result<()> {
+        let mut inner = self.inner.lock().unwrap();
+        let pending = inner
+            .state
+            .sessions
+            .get(client_id)
+            .is_some_and(|session| session.pending.contains(&seq));
+        if !pending {
+            return Ok(());
+        }
+        let client_id = client_id.to_string();
+        inner.commit(vec![Record::Ack { client_id, seq }])
+    }
+
+    /// Keeps `packet` as scheduled publish `id`, due at `due` milliseconds
+    /// since the Unix epoch.
+    pub(crate) fn schedule(&self, id: u64, due: u64, packet: &MqttPublishPacket) -> io::Result<()> {
+        let packet = packet.clone();
+        let mut inner = self.inner.lock().unwrap();
+        inner.commit(vec![Record::Schedule { id, due, packet }])
+    }
+
+    /// Forgets scheduled publish `id`, once routed or cancelled.
+    pub(crate) fn unschedule(&self, id: u64) -> io::Result<()> {
+        let mut inner = self.inner.lock().unwrap();
+        if !inner.state.scheduled.contains_key(&id) {
+            return Ok(());
+        }
+        inner.commit(vec![Record::Unschedule { id }])
+    }
+
+    /// The scheduled publishes, with their ids and due times.
+    pub(crate) fn scheduled(&self) -> Vec<(u64, u64, MqttPublishPacket)> {
+        let inner = self.inner.lock().unwrap();
+        inner
+            .state
+            .scheduled
+            .iter()
+            .map(|(id, (due, packet))| (*id, *due, packet.clone()))
+            .collect()
+    }
+
+    /// Discards the persistent session of `client_id`, if it has one.
+    pub(crate) fn forget(&self, client_id: &str) -> io::Result<()> {
+        let mut inner = self.inner.lock().unwrap();
+        if !inner.state.sessions.contains_key(client_id) {
+            return Ok(());
+        }
+        let client_id = client_id.to_string();
+        inner.commit(vec![Record::Forget { client_id }])?;
+        self.update_subscribed(&inner);
+        Ok(())
+    }
+
+    /// Updates the flag read by [`Store::publish`] after subscriptions of
+    /// persistent sessions changed, under the lock of `inner`.
+    fn update_subscribed(&self, inner: &Inner) {
+        let subscribed = !inner.state.subscribers.is_empty();
+        self.subscribed.store(subscribed, Ordering::Release);
+    }
+}
+
+/// Flushes the log of `store` every [`FSYNC_INTERVAL`].
+pub(crate) async fn sync_every_interval(store: Store) {
+    let mut interval = tokio::time::interval(FSYNC_INTERVAL);
+    loop {
+        interval.tick().await;
+        if let Err(e) = store.sync() {
+            warn!(error = %e, "store fsync failed");
+        }
+    }
+}
+
+/// Drops the expired publishes of the store of `broker` every
+/// [`EXPIRE_INTERVAL`], counting them and passing them to its dead-letter
+/// topic.
+pub(crate) async fn expire_every_interval(broker: Broker) {
+    let mut interval = tokio::time::interval(EXPIRE_INTERVAL);
+    loop {
+        interval.tick().await;
+        match broker.store().expire() {
+            Ok(dropped) if dropped.is_empty() => {}
+            Ok(dropped) => {
+                let count = dropped.len() as u64;
+                debug!(count, "stored publishes expired");
+                let stats = broker.stats();
+                stats.expired.fetch_add(count, Ordering::Relaxed);
+                broker.count_retained();
+                for packet in &dropped {
+                    broker.dead_letter(packet, DropReason::Expired, None);
+                }
+            }
+            Err(e) => warn!(error = %e, "cannot drop expired publishes"),
+        }
+    }
+}
+
+/// `packet` with the seconds left until `expires`, rounded up, or `None` if
+/// it has expired by `now`.
+fn unexpired(
+    packet: &MqttPublishPacket,
+    expires: Option<u64>,
+    now: u64,
+) -> Option<MqttPublishPacket> {
+    let mut packet = packet.clone();
+    if let Some(expires) = expires {
+        if expires <= now {
+            return None;
+        }
+        packet.expiry = Some(
+            (expires - now)
+                .div_ceil(1000)
+                .try_into()
+                .unwrap_or(u32::MAX),
+        );
+    }
+    Some(packet)
+}
+
+impl Inner {
+    /// Appends `records` to the log, then applies them.
+    fn commit(&mut self, records: Vec<Record>) -> io::Result<()> {
+        if records.is_empty() {
+            return Ok(());
+        }
+        if let Some(log) = &mut self.log {
+            log.append(&records)?;
+        }
+        for record in records {
+            self.state.apply(record);
+        }
+        if let Some(log) = &mut self.log {
+            if log.len >= COMPACT_MIN.max(2 * log.compacted) {
+                log.compact(&self.state)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Default)]
+struct State {
+    next_seq: u64,
+    messages: HashMap<u64, Message>,
+    /// With the time they expire, if they do.
+    retained: BTreeMap<u16, (MqttPublishPacket, Option<u64>)>,
+    sessions: HashMap<String, Session>,
+    /// The persistent sessions subscribed to each topic.
+    subscribers: HashMap<u16, BTreeSet<String>>,
+    /// Publishes to route later, by id, with the time they are due.
+    scheduled: BTreeMap<u64, (u64, MqttPublishPacket)>,
+}
+
+/// A publish queued for persistent sessions.
+struct Message {
+    packet: MqttPublishPacket,
+    expires: Option<u64>,
+    /// Sessions it is queued for.
+    refs: usize,
+}
+
+#[derive(Default)]
+struct Session {
+    /// The user the session belongs to, `None` for anonymous clients.
+    owner: Option<String>,
+    subscriptions: BTreeSet<u16>,
+    /// Sequence numbers of the publishes queued for the session.
+    pending: BTreeSet<u64>,
+}
+
+impl State {
+    fn apply(&mut self, record: Record) {
+        match record {
+            Record::Message {
+                seq,
+                packet,
+                expires,
+            } => {
+                let message = Message {
+                    packet,
+                    expires,
+                    refs: 0,
+                };
+                self.messages.insert(seq, message);
+                self.next_seq = self.next_seq.max(seq + 1);
+            }
+            Record::Enqueue { client_id, seq } => {
+                if let Some(message) = self.messages.get_mut(&seq) {
+                    let session = self.sessions.entry(client_id).or_default();
+                    if session.pending.insert(seq) {
+                        message.refs += 1;
+                    }
+                }
+            }
+            Record::Ack { client_id, seq } => {
+                let acked = self
+                    .sessions
+                    .get_mut(&client_id)
+                    .is_some_and(|session| session.pending.remove(&seq));
+                if acked {
+                    self.release(seq);
+                }
+            }
+            Record::Claim { client_id, owner } => {
+                self.sessions.entry(client_id).or_default().owner = owner;
+            }
+            Record::Subscribe { client_id, topic } => {
+                self.subscribers
+                    .entry(topic)
+                    .or_default()
+                    .insert(client_id.clone());
+                let session = self.sessions.entry(client_id).or_default();
+                session.subscriptions.insert(topic);
+            }
+            Record::Unsubscribe { client_id, topic } => {
+                if let Some(session) = self.sessions.get_mut(&client_id) {
+                    session.subscriptions.remove(&topic);
+                }
+                self.remove_subscriber(topic, &client_id);
+            }
+            Record::Forget { client_id } => {
+                if let Some(session) = self.sessions.remove(&client_id) {
+                    for topic in session.subscriptions {
+                        self.remove_subscriber(topic, &client_id);
+                    }
+                    for seq in session.pending {
+                        self.release(seq);
+                    }
+                }
+            }
+            Record::Schedule { id, due, packet } => {
+                self.scheduled.insert(id, (due, packet));
+            }
+            Record::Unschedule { id } => {
+                self.scheduled.remove(&id);
+            }
+            Record::Retain { packet, expires } => {
+                if packet.payload.is_empty() {
+                    self.retained.remove(&packet.topic_name);
+                } else {
+                    self.retained.insert(packet.topic_name, (packet, expires));
+                }
+            }
+        }
+    }
+
+    fn release(&mut self, seq: u64) {
+        if let Some(message) = self.messages.get_mut(&seq) {
+            message.refs -= 1;
+            if message.refs == 0 {
+                self.messages.remove(&seq);
+            }
+        }
+    }
+
+    fn remove_subscriber(&mut self, topic: u16, client_id: &str) {
+        if let Some(subscribers) = self.subscribers.get_mut(&topic) {
+            subscribers.remove(client_id);
+            if subscribers.is_empty() {
+                self.subscribers.remove(&topic);
+            }
+        }
+    }
+
+    /// Records that rebuild the state from scratch.
+    fn snapshot(&self) -> Vec<Record> {
+        let mut records = Vec::new();
+        let mut seqs: Vec<_> = self.messages.keys().copied().collect();
+        seqs.sort_unstable();
+        for seq in seqs {
+            let message = &self.messages[&seq];
+            records.push(Record::Message {
+                seq,
+                packet: message.packet.clone(),
+                expires: message.expires,
+            });
+        }
+        for (client_id, session) in &self.sessions {
+            records.push(Record::Claim {
+                client_id: client_id.clone(),
+                owner: session.owner.clone(),
+            });
+            for &topic in &session.subscriptions {
+                let client_id = client_id.clone();
+                records.push(Record::Subscribe { client_id, topic });
+            }
+            for &seq in &session.pending {
+                let client_id = client_id.clone();
+                records.push(Record::Enqueue { client_id, seq });
+            }
+        }
+        for (packet, expires) in self.retained.values() {
+            let packet = packet.clone();
+            let expires = *expires;
+            records.push(Record::Retain { packet, expires });
+        }
+        for (&id, (due, packet)) in &self.scheduled {
+            let due = *due;
+            let packet = packet.clone();
+            records.push(Record::Schedule { id, due, packet });
+        }
+        records
+    }
+}
+
+/// The log as the broker sees it: writes are handed to the writer thread.
+struct Log {
+    tx: mpsc::Sender<LogWrite>,
+    fsync: Fsync,
+    len: u64,
+    /// Length right after the last compaction.
+    compacted: u64,
+    /// Writes handed to the writer thread so far.
+    sent: u64,
+}
+
+/// A write for the writer thread.
+enum LogWrite {
+    /// Encoded records to append.
+    Append(Vec<u8>),
+    /// Flush the appends to disk.
+    Sync,
+    /// An encoded snapshot to replace the log with.
+    Compact(Vec<u8>),
+}
+
+/// How far the writer thread has got.
+#[derive(Debug, Default)]
+struct Written {
+    /// Writes done, out of [`Log::sent`].
+    count: u64,
+    /// Why the writer thread stopped, if it did.
+    error: Option<(io::ErrorKind, String)>,
+}
+
+impl Log {
+    fn send(&mut self, write: LogWrite) -> io::Result<()> {
+        self.tx.send(write).map_err(|_| writer_stopped())?;
+        self.sent += 1;
+        Ok(())
+    }
+
+    fn append(&mut self, records: &[Record]) -> io::Result<()> {
+        let buf = encode(records);
+        self.len += buf.len() as u64;
+        self.send(LogWrite::Append(buf))
+    }
+
+    fn compact(&mut self, state: &State) -> io::Result<()> {
+        let buf = encode(&state.snapshot());
+        self.len = buf.len() as u64;
+        self.compacted = self.len;
+        self.send(LogWrite::Compact(buf))
+    }
+}
+
+fn writer_stopped() -> io::Error {
+    io::Error::other("the store writer stopped")
+}
+
+fn encode(records: &[Record]) -> Vec<u8> {
+    let mut buf = Vec::new();
+    for record in records {
+        write_record(&mut buf, record);
+    }
+    buf
+}
+
+/// The log file, owned by the writer thread.
+struct Writer {
+    path: PathBuf,
+    /// Open for appending; `None` until the first compaction.
+    file: Option<File>,
+    fsync: Fsync,
+    /// Whether anything was appended since the last fsync.
+    dirty: bool,
+}
+
+impl Writer {
+    /// Does the writes from `rx` until the store is dropped or one fails. With
+    /// [`Fsync::Always`], the writes waiting together are flushed together.
+    fn run(mut self, rx: mpsc::Receiver<LogWrite>, written: watch::Sender<Written>) {
+        while let Ok(write) = rx.recv() {
+            let writes: Vec<_> = std::iter::once(write).chain(rx.try_iter()).collect();
+            let count = writes.len() as u64;
+            let mut result = writes.into_iter().try_for_each(|write| match write {
+                LogWrite::Append(buf) => self.append(&buf),
+                LogWrite::Sync => self.sync(),
+                LogWrite::Compact(buf) => self.compact(&buf),
+            });
+            if self.fsync == Fsync::Always {
+                result = result.and_then(|()| self.sync());
+            }
+            match result {
+                Ok(()) => written.send_modify(|written| written.count += count),
+                Err(e) => {
+                    error!(path = %self.path.display(), error = %e, "store write failed");
+                    let error = Some((e.kind(), e.to_string()));
+                    written.send_modify(|written| written.error = error);
+                    return;
+                }
+            }
+        }
+    }
+
+    fn append(&mut self, buf: &[u8]) -> io::Result<()> {
+        let file = self
+            .file
+            .as_mut()
+            .expect("the log is compacted when opened");
+        file.write_all(buf)?;
+        self.dirty = true;
+        Ok(())
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        if let (true, Some(file)) = (self.dirty, &self.file) {
+            file.sync_data()?;
+            self.dirty = false;
+        }
+        Ok(())
+    }
+
+    /// Replaces the log with a snapshot of `state`. The snapshot is written to
+    /// a temporary file and synced before it is renamed over the log, so a
+    /// crash leaves either the old log or the new one.
+    fn compact(&mut self, buf: &[u8]) -> io::Result<()> {
+        let mut name = self.path.file_name().unwrap_or_default().to_owned();
+        name.push(".compact");
+        let tmp = self.path.with_file_name(name);
+        let mut file = File::create(&tmp)?;
+        file.write_all(buf)?;
+        file.sync_all()?;
+        fs::rename(&tmp, &self.path)?;
+        if let Some(dir) = self.path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            File::open(dir)?.sync_all()?;
+        }
+
+        self.file = Some(OpenOptions::new().append(true).open(&self.path)?);
+        self.dirty = false;
+        Ok(())
+    }
+}
+
+enum Record {
+    /// A publish queued for persistent sessions under `seq`. Followed by its
+    /// `Enqueue`s.
+    Message {
+        seq: u64,
+        packet: MqttPublishPacket,
+        expires: Option<u64>,
+    },
+    Enqueue {
+        client_id: String,
+        seq: u64,
+    },
+    /// Also written when the publish expires.
+    Ack {
+        client_id: String,
+        seq: u64,
+    },
+    /// Creates the session of `client_id` for the user `owner`, `None` for
+    /// anonymous clients.
+    Claim {
+        client_id: String,
+        owner: Option<String>,
+    },
+    Subscribe {
+        client_id: String,
+        topic: u16,
+    },
+    Unsubscribe {
+        client_id: String,
+        topic: u16,
+    },
+    Forget {
+        client_id: String,
+    },
+    /// Keeps `packet` for its topic, or clears the topic if its payload is empty.
+    Retain {
+        packet: MqttPublishPacket,
+        expires: Option<u64>,
+    },
+    /// A publish to route at `due`, in milliseconds since the Unix epoch, with
+    /// its retain flag and expiry interval.
+    Schedule {
+        id: u64,
+        due: u64,
+        packet: MqttPublishPacket,
+    },
+    Unschedule {
+        id: u64,
+    },
+}
+
+const MESSAGE: u8 = 1;
+const ENQUEUE: u8 = 2;
+const ACK: u8 = 3;
+const SUBSCRIBE: u8 = 4;
+const UNSUBSCRIBE: u8 = 5;
+const FORGET: u8 = 6;
+const RETAIN: u8 = 7;
+const SCHEDULE: u8 = 8;
+const UNSCHEDULE: u8 = 9;
+const CLAIM: u8 = 10;
+
+/// The publish of a `SCHEDULE` record is retained.
+const SCHEDULE_RETAIN: u8 = 0x01;
+/// A `u32` expiry interval follows the flags of a `SCHEDULE` record.
+const SCHEDULE_EXPIRY: u8 = 0x02;
+
+fn write_record(dst: &mut Vec<u8>, record: &Record) {
+    let mut body = Vec::new();
+    match record {
+        Record::Message {
+            seq,
+            packet,
+            expires,
+        } => {
+            body.put_u8(MESSAGE);
+            body.put_u64(*seq);
+            put_packet(&mut body, packet, *expires);
+        }
+        Record::Enqueue { client_id, seq } => {
+            body.put_u8(ENQUEUE);
+            put_string(&mut body, client_id);
+            body.put_u64(*seq);
+        }
+        Record::Ack { client_id, seq } => {
+            body.put_u8(ACK);
+            put_string(&mut body, client_id);
+            body.put_u64(*seq);
+        }
+        Record::Claim { client_id, owner } => {
+            body.put_u8(CLAIM);
+            put_string(&mut body, client_id);
+            if let Some(owner) = owner {
+                put_string(&mut body, owner);
+            }
+        }
+        Record::Subscribe { client_id, topic } => {
+            body.put_u8(SUBSCRIBE);
+            put_string(&mut body, client_id);
+            body.put_u16(*topic);
+        }
+        Record::Unsubscribe { client_id, topic } => {
+            body.put_u8(UNSUBSCRIBE);
+            put_string(&mut body, client_id);
+            body.put_u16(*topic);
+        }
+        Record::Forget { client_id } => {
+            body.put_u8(FORGET);
+            put_string(&mut body, client_id);
+        }
+        Record::Retain { packet, expires } => {
+            body.put_u8(RETAIN);
+            put_packet(&mut body, packet, *expires);
+        }
+        Record::Schedule { id, due, packet } => {
+            body.put_u8(SCHEDULE);
+            body.put_u64(*id);
+            body.put_u64(*due);
+            let mut flags = 0;
+            if packet.retain {
+                flags |= SCHEDULE_RETAIN;
+            }
+            if packet.expiry.is_some() {
+                flags |= SCHEDULE_EXPIRY;
+            }
+            body.put_u8(flags);
+            if let Some(expiry) = packet.expiry {
+                body.put_u32(expiry);
+            }
+            put_packet(&mut body, packet, None);
+        }
+        Record::Unschedule { id } => {
+            body.put_u8(UNSCHEDULE);
+            body.put_u64(*id);
+        }
+    }
+    dst.put_u32(body.len() as u32);
+    dst.put_u32(crc32fast::hash(&body));
+    dst.put(&body[..]);
+}
+
+fn put_string(dst: &mut Vec<u8>, s: &str) {
+    dst.put_u16(s.len() as u16);
+    dst.put(s.as_bytes());
+}
+
+/// A `u64` sequence number follows the flags of a stored packet.
+const PACKET_SEQ: u8 = 0x01;
+/// A `u64` expiry time follows, after the sequence number if any.
+const PACKET_EXPIRES: u8 = 0x02;
+/// A `u16` response topic follows, after the expiry time if any.
+const PACKET_RESPONSE_TOPIC: u8 = 0x04;
+/// Correlation data prefixed by its `u16` length follows, last.
+const PACKET_CORRELATION_DATA: u8 = 0x08;
+
+fn put_packet(dst: &mut Vec<u8>, packet: &MqttPublishPacket, expires: Option<u64>) {
+    dst.put_u16(packet.topic_name);
+    dst.put_u8(packet.qos as u8);
+    let mut flags = 0;
+    if packet.seq.is_some() {
+        flags |= PACKET_SEQ;
+    }
+    if expires.is_some() {
+        flags |= PACKET_EXPIRES;
+    }
+    if packet.response_topic.is_some() {
+        flags |= PACKET_RESPONSE_TOPIC;
+    }
+    if packet.correlation_data.is_some() {
+        flags |= PACKET_CORRELATION_DATA;
+    }
+    dst.put_u8(flags);
+    if let Some(seq) = packet.seq {
+        dst.put_u64(seq);
+    }
+    if let Some(expires) = expires {
+        dst.put_u64(expires);
+    }
+    if let Some(topic) = packet.response_topic {
+        dst.put_u16(topic);
+    }
+    if let Some(data) = &packet.correlation_data {
+        dst.put_u16(data.len() as u16);
+        dst.put(&data[..]);
+    }
+    dst.put_u32(packet.payload.len() as u32);
+    dst.put(&packet.payload[..]);
+}
+
+/// Reads the next record off `src`, or returns `None` at the end of the log or
+/// at a torn or corrupt record, leaving `src` at its start.
+fn read_record(src: &mut &[u8]) -> Option<Record> {
+    let mut header = *src;
+    if header.remaining() < 8 {
+        return None;
+    }
+    let len = header.get_u32() as usize;
+    let crc = header.get_u32();
+    if header.remaining() < len || crc32fast::hash(&header[..len]) != crc {
+        return None;
+    }
+    let record = parse_record(&header[..len])?;
+    *src = &header[len..];
+    Some(record)
+}
+
+fn parse_record(mut body: &[u8]) -> Option<Record> {
+    let buf = &mut body;
+    let record = match get_u8(buf)? {
+        MESSAGE => {
+            let seq = get_u64(buf)?;
+            let (packet, expires) = get_packet(buf)?;
+            Record::Message {
+                seq,
+                packet,
+                expires,
+            }
+        }
+        ENQUEUE => Record::Enqueue {
+            client_id: get_string(buf)?,
+            seq: get_u64(buf)?,
+        },
+        ACK => Record::Ack {
+            client_id: get_string(buf)?,
+            seq: get_u64(buf)?,
+        },
+        CLAIM => Record::Claim {
+            client_id: get_string(buf)?,
+            // Anonymous sessions have no owner after the client id.
+            owner: match buf.remaining() {
+                0 => None,
+                _ => Some(get_string(buf)?),
+            },
+        },
+        SUBSCRIBE => Record::Subscribe {
+            client_id: get_string(buf)?,
+            topic: get_u16(buf)?,
+        },
+        UNSUBSCRIBE => Record::Unsubscribe {
+            client_id: get_string(buf)?,
+            topic: get_u16(buf)?,
+        },
+        FORGET => Record::Forget {
+            client_id: get_string(buf)?,
+        },
+        RETAIN => {
+            let (packet, expires) = get_packet(buf)?;
+            Record::Retain { packet, expires }
+        }
+        SCHEDULE => {
+            let id = get_u64(buf)?;
+            let due = get_u64(buf)?;
+            let flags = get_u8(buf)?;
+            let expiry = match flags & SCHEDULE_EXPIRY {
+                0 => None,
+                _ => Some(get_u32(buf)?),
+            };
+            let (packet, _) = get_packet(buf)?;
+            let packet = MqttPublishPacket {
+                retain: flags & SCHEDULE_RETAIN != 0,
+                expiry,
+                ..packet
+            };
+            Record::Schedule { id, due, packet }
+        }
+        UNSCHEDULE => Record::Unschedule { id: get_u64(buf)? },
+        _ => return None,
+    };
+    Some(record)
+}
+
+fn get_u8(buf: &mut &[u8]) -> Option<u8> {
+    (buf.remaining() >= 1).then(|| buf.get_u8())
+}
+
+fn get_u16(buf: &mut &[u8]) -> Option<u16> {
+    (buf.remaining() >= 2).then(|| buf.get_u16())
+}
+
+fn get_u32(buf: &mut &[u8]) -> Option<u32> {
+    (buf.remaining() >= 4).then(|| buf.get_u32())
+}
+
+fn get_u64(buf: &mut &[u8]) -> Option<u64> {
+    (buf.remaining() >= 8).then(|| buf.get_u64())
+}
+
+fn get_bytes(buf: &mut &[u8], len: usize) -> Option<Vec<u8>> {
+    (buf.remaining() >= len).then(|| buf.copy_to_bytes(len).to_vec())
+}
+
+fn get_string(buf: &mut &[u8]) -> Option<String> {
+    let len = get_u16(buf)?.into();
+    String::from_utf8(get_bytes(buf, len)?).ok()
+}
+
+fn get_packet(buf: &mut &[u8]) -> Option<(MqttPublishPacket, Option<u64>)> {
+    let topic_name = get_u16(buf)?;
+    let qos = QoS::from_usize(get_u8(buf)?.into())?;
+    let flags = get_u8(buf)?;
+    let seq = match flags & PACKET_SEQ {
+        0 => None,
+        _ => Some(get_u64(buf)?),
+    };
+    let expires = match flags & PACKET_EXPIRES {
+        0 => None,
+        _ => Some(get_u64(buf)?),
+    };
+    let response_topic = match flags & PACKET_RESPONSE_TOPIC {
+        0 => None,
+        _ => Some(get_u16(buf)?),
+    };
+    let correlation_data = match flags & PACKET_CORRELATION_DATA {
+        0 => None,
+        _ => {
+            let len = get_u16(buf)?.into();
+            Some(get_bytes(buf, len)?)
+        }
+    };
+    if buf.remaining() < 4 {
+        return None;
+    }
+    let len = buf.get_u32() as usize;
+    let packet = MqttPublishPacket {
+        topic_name,
+        qos,
+        retain: false,
+        seq,
+        expiry: None,
+        delay: None,
+        response_topic,
+        correlation_data,
+        payload: get_bytes(buf, len)?,
+    };
+    Some((packet, expires))
+}
@@ -0,0 +1,138 @@
+use std::{
+    fmt,
+    future::Future,
+    io,
+    net::SocketAddr,
+    path::PathBuf,
+    pin::Pin,
+    task::{ready, Context, Poll},
+};
+
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+/// Byte stream of any transport MQTiny runs over.
+pub trait AsyncStream: AsyncRead + AsyncWrite + Unpin + Send {}
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> AsyncStream for T {}
+
+pub type Stream = Box<dyn AsyncStream>;
+
+/// Where a broker listens or a client connects: `HOST:PORT`, `tls://HOST:PORT`,
+/// `ws://HOST:PORT` or `unix:///path`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Endpoint {
+    Tcp(String),
+    Tls(String),
+    Ws(String),
+    Unix(PathBuf),
+}
+
+impl Endpoint {
+    pub fn parse(addr: &str) -> Endpoint {
+        if let Some(path) = addr.strip_prefix("unix://") {
+            Endpoint::Unix(PathBuf::from(path))
+        } else if let Some(addr) = addr.strip_prefix("tls://") {
+            Endpoint::Tls(addr.to_string())
+        } else if let Some(addr) = addr.strip_prefix("ws://") {
+            Endpoint::Ws(addr.to_string())
+        } else {
+            Endpoint::Tcp(addr.to_string())
+        }
+    }
+}
+
+impl fmt::Display for Endpoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Endpoint::Tcp(addr) => write!(f, "{}", addr),
+            Endpoint::Tls(addr) => write!(f, "tls://{}", addr),
+            Endpoint::Ws(addr) => write!(f, "ws://{}", addr),
+            Endpoint::Unix(path) => write!(f, "unix://{}", path.display()),
+        }
+    }
+}
+
+/// Address of a connected peer. Unix domain peers are usually unnamed, so they
+/// are identified by the socket they connected to.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum PeerAddr {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+    /// A client in the same process, see [`crate::broker::Broker::connect_local`].
+    Local,
+}
+
+impl fmt::Display for PeerAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PeerAddr::Tcp(addr) => write!(f, "{}", addr),
+            PeerAddr::Unix(path) => write!(f, "unix://{}", path.display()),
+            PeerAddr::Local => write!(f, "local"),
+        }
+    }
+}
+
+/// A stream whose handshake (WebSocket upgrade, TLS) has not completed yet.
+///
+/// Listeners hand these out immediately so that a slow handshake only stalls
+/// its own connection task, not the accept loop. The first read or write
+/// drives the handshake to completion.
+pub struct Handshake {
+    state: HandshakeState,
+}
+
+enum HandshakeState {
+    Pending(Pin<Box<dyn Future<Output = io::Result<Stream>> + Send>>),
+    Ready(Stream),
+}
+
+impl Handshake {
+    pub fn new(handshake: impl Future<Output = io::Result<Stream>> + Send + 'static) -> Handshake {
+        Handshake {
+            state: HandshakeState::Pending(Box::pin(handshake)),
+        }
+    }
+
+    fn poll_stream(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<&mut Stream>> {
+        if let HandshakeState::Pending(handshake) = &mut self.state {
+            let stream = ready!(handshake.as_mut().poll(cx))?;
+            self.state = HandshakeState::Ready(stream);
+        }
+
+        match &mut self.state {
+            HandshakeState::Ready(stream) => Poll::Ready(Ok(stream)),
+            HandshakeState::Pending(_) => unreachable!(),
+        }
+    }
+}
+
+impl AsyncRead for Handshake {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let stream = ready!(self.poll_stream(cx))?;
+        Pin::new(stream).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for Handshake {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let stream = ready!(self.poll_stream(cx))?;
+        Pin::new(stream).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let stream = ready!(self.poll_stream(cx))?;
+        Pin::new(stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let stream = ready!(self.poll_stream(cx))?;
+        Pin::new(stream).poll_shutdown(cx)
+    }
+}
@@ -0,0 +1,99 @@
+use std::{
+    io,
+    pin::Pin,
+    task::{ready, Context, Poll},
+};
+
+use bytes::{Buf, Bytes};
+use futures::{Sink, Stream};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio_tungstenite::{
+    tungstenite::{Error as WsError, Message},
+    WebSocketStream,
+};
+
+/// Byte stream over a WebSocket, so that `MQTinyCodec` can frame it like TCP.
+///
+/// Incoming binary messages are concatenated, so a message may carry one or
+/// more packets, or part of one. Everything written between two flushes is
+/// sent as a single binary message.
+pub struct WsStream<S> {
+    inner: WebSocketStream<S>,
+    read_buf: Bytes,
+    write_buf: Vec<u8>,
+}
+
+impl<S> WsStream<S> {
+    pub fn new(inner: WebSocketStream<S>) -> WsStream<S> {
+        WsStream {
+            inner,
+            read_buf: Bytes::new(),
+            write_buf: Vec::new(),
+        }
+    }
+}
+
+pub fn ws_error(e: WsError) -> io::Error {
+    match e {
+        WsError::Io(e) => e,
+        WsError::ConnectionClosed | WsError::AlreadyClosed => io::ErrorKind::NotConnected.into(),
+        e => io::Error::other(e),
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncRead for WsStream<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        while self.read_buf.is_empty() {
+            match ready!(Pin::new(&mut self.inner).poll_next(cx)) {
+                Some(Ok(Message::Binary(data))) => self.read_buf = data.into(),
+                Some(Ok(Message::Close(_))) | None => return Poll::Ready(Ok(())),
+                Some(Ok(Message::Text(_))) => {
+                    return Poll::Ready(Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "MQTiny packets must be sent in binary messages",
+                    )))
+                }
+                // Pings are answered by tungstenite itself.
+                Some(Ok(_)) => {}
+                Some(Err(e)) => return Poll::Ready(Err(ws_error(e))),
+            }
+        }
+
+        let n = self.read_buf.len().min(buf.remaining());
+        buf.put_slice(&self.read_buf[..n]);
+        self.read_buf.advance(n);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncWrite for WsStream<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        self.write_buf.extend_from_slice(buf);
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        if !self.write_buf.is_empty() {
+            ready!(Pin::new(&mut self.inner).poll_ready(cx)).map_err(ws_error)?;
+            let message = Message::Binary(std::mem::take(&mut self.write_buf));
+            Pin::new(&mut self.inner)
+                .start_send(message)
+                .map_err(ws_error)?;
+        }
+
+        Pin::new(&mut self.inner).poll_flush(cx).map_err(ws_error)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        ready!(self.as_mut().poll_flush(cx))?;
+        Pin::new(&mut self.inner).poll_close(cx).map_err(ws_error)
+    }
+}
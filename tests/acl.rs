@@ -0,0 +1,158 @@
+use async_trait::async_trait;
+use mqtiny::{
+    auth::{AclFile, Authenticator},
+    broker::Broker,
+    client::{Client, ConnectOptions},
+    listener::{ListenAddr, Listener},
+    MqttPacket, QoS, SubackReturnCode,
+};
+use std::{
+    sync::{atomic::Ordering, Arc},
+    time::Duration,
+};
+use tokio::time::timeout;
+
+/// Lets everyone in, so the tests only exercise the ACL.
+struct AcceptAll;
+
+#[async_trait]
+impl Authenticator for AcceptAll {
+    async fn authenticate(&self, _client_id: &str, _username: &str, _password: &[u8]) -> bool {
+        true
+    }
+}
+
+const ACL: &str = "
+# Everyone may read the announcements.
+topic read 1
+
+user alice
+topic readwrite 10-19
+topic write 1
+";
+
+async fn start_broker() -> (String, Broker) {
+    let broker = Broker::builder()
+        .authenticator(Arc::new(AcceptAll))
+        .authorizer(Arc::new(AclFile::parse(ACL).unwrap()))
+        .spawn();
+    let listener = Listener::bind(&ListenAddr::new("127.0.0.1:0"), false)
+        .await
+        .unwrap()
+        .remove(0);
+    let addr = listener.local_addr().unwrap().to_string();
+    let serving = broker.clone();
+    tokio::spawn(async move { serving.serve(&listener).await });
+
+    (addr, broker)
+}
+
+async fn connect(addr: &str, username: Option<&str>) -> Client {
+    let options = ConnectOptions {
+        username: username.map(str::to_string),
+        ..Default::default()
+    };
+    Client::connect_with(addr, &options).await.unwrap()
+}
+
+async fn recv(client: &mut Client) -> Option<MqttPacket> {
+    match timeout(Duration::from_millis(500), client.recv()).await {
+        Ok(Some(Ok(packet))) => Some(packet),
+        Ok(other) => panic!("connection failed: {:?}", other),
+        Err(_) => None,
+    }
+}
+
+#[tokio::test]
+async fn denied_subscribe_gets_a_failure_suback() {
+    let (addr, broker) = start_broker().await;
+
+    let mut bob = connect(&addr, Some("bob")).await;
+    bob.subscribe(10).await.unwrap();
+    match recv(&mut bob).await {
+        Some(MqttPacket::Suback(suback)) => {
+            assert_eq!(suback.topic_name, 10);
+            assert_eq!(suback.return_code, SubackReturnCode::Failure);
+        }
+        other => panic!("expected a suback, got {:?}", other),
+    }
+    assert_eq!(broker.stats().subscribes_denied.load(Ordering::Relaxed), 1);
+
+    let mut alice = connect(&addr, Some("alice")).await;
+    alice.subscribe(10).await.unwrap();
+    assert!(recv(&mut alice).await.is_none());
+}
+
+#[tokio::test]
+async fn denied_publish_is_dropped_and_counted() {
+    let (addr, broker) = start_broker().await;
+
+    let mut anonymous = connect(&addr, None).await;
+    anonymous.subscribe(1).await.unwrap();
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    let mut bob = connect(&addr, Some("bob")).await;
+    bob.publish(1, QoS::AtMostOnce, b"spoofed".to_vec())
+        .await
+        .unwrap();
+    let mut alice = connect(&addr, Some("alice")).await;
+    alice
+        .publish(1, QoS::AtMostOnce, b"hello".to_vec())
+        .await
+        .unwrap();
+
+    match recv(&mut anonymous).await {
+        Some(MqttPacket::Publish(publish)) => assert_eq!(publish.payload, b"hello"),
+        other => panic!("expected a publish, got {:?}", other),
+    }
+    assert!(recv(&mut anonymous).await.is_none());
+    assert_eq!(broker.stats().publishes_denied.load(Ordering::Relaxed), 1);
+}
+
+#[test]
+fn acl_file_errors_name_the_line() {
+    let err = AclFile::parse("user alice\ntopic write 9-3\n")
+        .err()
+        .unwrap();
+    assert!(err.starts_with("2: "), "{}", err);
+}
+
+#[tokio::test]
+async fn persistent_sessions_belong_to_their_user() {
+    let (addr, _broker) = start_broker().await;
+    let options = |username: &str, persistent| ConnectOptions {
+        client_id: Some("sensor".to_string()),
+        username: Some(username.to_string()),
+        persistent,
+        ..Default::default()
+    };
+
+    let mut alice = Client::connect_with(&addr, &options("alice", true))
+        .await
+        .unwrap();
+    alice.subscribe(10).await.unwrap();
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    drop(alice);
+    let mut publisher = connect(&addr, Some("alice")).await;
+    publisher
+        .publish(10, QoS::AtLeastOnce, b"queued".to_vec())
+        .await
+        .unwrap();
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    // Bob can neither take the session over nor discard it.
+    assert!(Client::connect_with(&addr, &options("bob", true))
+        .await
+        .is_err());
+    assert!(Client::connect_with(&addr, &options("bob", false))
+        .await
+        .is_err());
+
+    let mut alice = Client::connect_with(&addr, &options("alice", true))
+        .await
+        .unwrap();
+    match recv(&mut alice).await {
+        Some(MqttPacket::Publish(publish)) => assert_eq!(publish.payload, b"queued"),
+        other => panic!("expected the queued publish, got {:?}", other),
+    }
+}
@@ -0,0 +1,102 @@
+use mqtiny::{
+    admin::{self, AdminClient, TopicInfo},
+    broker::Broker,
+    client::{Client, ConnectOptions},
+    listener::{ListenAddr, Listener},
+    QoS,
+};
+use std::{io, time::Duration};
+use tokio::{net::TcpListener, time::timeout};
+
+/// Starts a broker and its admin API, returning the broker address and an
+/// admin client.
+async fn start_broker() -> (String, AdminClient) {
+    let broker = Broker::spawn();
+    let listener = Listener::bind(&ListenAddr::new("127.0.0.1:0"), false)
+        .await
+        .unwrap()
+        .remove(0);
+    let addr = listener.local_addr().unwrap().to_string();
+    let admin_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let admin = AdminClient::new(&admin_listener.local_addr().unwrap().to_string());
+    tokio::spawn(admin::serve(admin_listener, broker.clone()));
+    tokio::spawn(async move { broker.serve(&listener).await });
+
+    (addr, admin)
+}
+
+async fn connect(addr: &str, client_id: &str) -> Client {
+    let options = ConnectOptions {
+        client_id: Some(client_id.to_string()),
+        ..Default::default()
+    };
+    Client::connect_with(addr, &options).await.unwrap()
+}
+
+#[tokio::test]
+async fn lists_clients_and_subscribers() {
+    let (addr, admin) = start_broker().await;
+
+    let mut sensor = connect(&addr, "sensor").await;
+    let mut display = connect(&addr, "display").await;
+    display.subscribe(1).await.unwrap();
+    display.subscribe(2).await.unwrap();
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    sensor
+        .publish(1, QoS::AtMostOnce, b"21.5".to_vec())
+        .await
+        .unwrap();
+    display.recv().await.unwrap().unwrap();
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    let clients = admin.clients().await.unwrap();
+    assert_eq!(clients.len(), 2);
+    let sensor_info = clients.iter().find(|c| c.client_id == "sensor").unwrap();
+    assert_eq!(sensor_info.bytes_in, 4);
+    assert!(sensor_info.subscriptions.is_empty());
+    let display_info = clients.iter().find(|c| c.client_id == "display").unwrap();
+    assert_eq!(display_info.subscriptions, vec![1, 2]);
+    assert_eq!(display_info.bytes_out, 4);
+    assert_eq!(display_info.queued, 0);
+    assert!(display_info.peer.starts_with("127.0.0.1:"));
+    assert_eq!(&admin.client(display_info.id).await.unwrap(), display_info);
+
+    let topics = admin.topics().await.unwrap();
+    let subscribers = vec![display_info.id];
+    assert_eq!(
+        topics,
+        vec![
+            TopicInfo {
+                topic: 1,
+                subscribers: subscribers.clone()
+            },
+            TopicInfo {
+                topic: 2,
+                subscribers
+            },
+        ]
+    );
+    assert!(admin.topic(3).await.unwrap().subscribers.is_empty());
+}
+
+#[tokio::test]
+async fn kicks_a_client() {
+    let (addr, admin) = start_broker().await;
+
+    let mut client = connect(&addr, "noisy").await;
+    client.subscribe(1).await.unwrap();
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    let id = admin.clients().await.unwrap()[0].id;
+
+    admin.disconnect(id).await.unwrap();
+    let closed = timeout(Duration::from_secs(2), client.recv())
+        .await
+        .unwrap();
+    assert!(closed.is_none(), "expected the connection to close");
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    assert!(admin.clients().await.unwrap().is_empty());
+    assert!(admin.topics().await.unwrap().is_empty());
+
+    let e = admin.disconnect(id).await.unwrap_err();
+    assert_eq!(e.kind(), io::ErrorKind::NotFound);
+}
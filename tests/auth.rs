@@ -0,0 +1,138 @@
+use mqtiny::{
+    auth::{Authenticator, PasswordFile},
+    broker::Broker,
+    client::{Client, ConnectOptions},
+    listener::{ListenAddr, Listener},
+    ConnectReturnCode, MqttPacket, QoS,
+};
+use std::{
+    io::{ErrorKind, Write},
+    sync::{atomic::Ordering, Arc},
+    time::Duration,
+};
+use tokio::{io::AsyncReadExt, time::timeout};
+
+/// Starts a broker that knows `alice` (bcrypt) and `bob` (argon2), both with
+/// password `secret`.
+async fn start_broker(allow_anonymous: bool) -> (String, Broker) {
+    let argon2 = {
+        use argon2::{
+            password_hash::{rand_core::OsRng, SaltString},
+            Argon2, PasswordHasher,
+        };
+        let salt = SaltString::generate(&mut OsRng);
+        Argon2::default()
+            .hash_password(b"secret", &salt)
+            .unwrap()
+            .to_string()
+    };
+    let mut file = tempfile::NamedTempFile::new().unwrap();
+    writeln!(file, "# test users").unwrap();
+    writeln!(file, "alice:{}", bcrypt::hash("secret", 4).unwrap()).unwrap();
+    writeln!(file, "bob:{}", argon2).unwrap();
+
+    let broker = Broker::builder()
+        .authenticator(Arc::new(PasswordFile::load(file.path()).unwrap()))
+        .allow_anonymous(allow_anonymous)
+        .spawn();
+    let listener = Listener::bind(&ListenAddr::new("127.0.0.1:0"), false)
+        .await
+        .unwrap()
+        .remove(0);
+    let addr = listener.local_addr().unwrap().to_string();
+    let serving = broker.clone();
+    tokio::spawn(async move { serving.serve(&listener).await });
+
+    (addr, broker)
+}
+
+fn login(username: &str, password: &str) -> ConnectOptions {
+    ConnectOptions {
+        username: Some(username.to_string()),
+        password: Some(password.to_string()),
+        ..Default::default()
+    }
+}
+
+#[tokio::test]
+async fn valid_credentials_are_accepted() {
+    let (addr, broker) = start_broker(false).await;
+
+    let mut subscriber = Client::connect_with(&addr, &login("alice", "secret"))
+        .await
+        .unwrap();
+    subscriber.subscribe(1).await.unwrap();
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    let mut publisher = Client::connect_with(&addr, &login("bob", "secret"))
+        .await
+        .unwrap();
+    publisher
+        .publish(1, QoS::AtMostOnce, b"hi".to_vec())
+        .await
+        .unwrap();
+
+    match timeout(Duration::from_secs(5), subscriber.recv()).await {
+        Ok(Some(Ok(MqttPacket::Publish(publish)))) => assert_eq!(publish.payload, b"hi"),
+        other => panic!("expected a publish, got {:?}", other),
+    }
+    assert_eq!(broker.stats().auth_failures.load(Ordering::Relaxed), 0);
+}
+
+#[tokio::test]
+async fn bad_credentials_are_refused_and_counted() {
+    let (addr, broker) = start_broker(true).await;
+
+    for options in [
+        login("alice", "wrong"),
+        login("bob", "wrong"),
+        login("eve", "secret"),
+    ] {
+        let err = Client::connect_with(&addr, &options).await.err().unwrap();
+        assert_eq!(err.kind(), ErrorKind::PermissionDenied);
+    }
+    assert_eq!(broker.stats().auth_failures.load(Ordering::Relaxed), 3);
+}
+
+#[tokio::test]
+async fn anonymous_clients_are_closed_when_not_allowed() {
+    let (addr, broker) = start_broker(false).await;
+
+    let mut client = Client::connect(&addr).await.unwrap();
+    client.subscribe(1).await.unwrap();
+    match timeout(Duration::from_secs(5), client.recv()).await {
+        Ok(Some(Ok(MqttPacket::Connack(connack)))) => {
+            assert_eq!(connack.return_code, ConnectReturnCode::NotAuthorized)
+        }
+        other => panic!("expected a connack, got {:?}", other),
+    }
+    let mut rest = Vec::new();
+    timeout(
+        Duration::from_secs(5),
+        client.get_mut().read_to_end(&mut rest),
+    )
+    .await
+    .unwrap()
+    .unwrap();
+    assert!(rest.is_empty());
+    assert_eq!(broker.stats().auth_failures.load(Ordering::Relaxed), 1);
+}
+
+#[tokio::test]
+async fn fingerprints_change_with_the_password_hash() {
+    let load = |lines: &str| {
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        write!(file, "{}", lines).unwrap();
+        PasswordFile::load(file.path()).unwrap()
+    };
+    let hash = bcrypt::hash("secret", 4).unwrap();
+    let before = load(&format!("alice:{}\n", hash));
+    let same = load(&format!("alice:{}\nbob:{}\n", hash, hash));
+    let changed = load(&format!("alice:{}\n", bcrypt::hash("other", 4).unwrap()));
+
+    let fingerprint = before.fingerprint("alice").await;
+    assert!(fingerprint.is_some());
+    assert_eq!(same.fingerprint("alice").await, fingerprint);
+    assert_ne!(changed.fingerprint("alice").await, fingerprint);
+    assert_eq!(before.fingerprint("bob").await, None);
+}
@@ -0,0 +1,204 @@
+use futures::{SinkExt, StreamExt};
+use mqtiny::{
+    bridge::{Bridge, BridgeTopics, Direction},
+    broker::{Broker, BrokerBuilder},
+    client::Client,
+    listener::{ListenAddr, Listener},
+    MQTinyCodec, MqttPacket, MqttPubackPacket, QoS,
+};
+use std::{sync::atomic::Ordering, time::Duration};
+use tokio::{
+    net::TcpListener,
+    time::{sleep, timeout, Instant},
+};
+use tokio_util::codec::Framed;
+
+async fn start_broker(builder: BrokerBuilder, addr: &str) -> (String, Broker) {
+    let broker = builder.spawn();
+    let listener = Listener::bind(&ListenAddr::new(addr), false)
+        .await
+        .unwrap()
+        .remove(0);
+    let addr = listener.local_addr().unwrap().to_string();
+    let serving = broker.clone();
+    tokio::spawn(async move { serving.serve(&listener).await });
+    (addr, broker)
+}
+
+/// Waits until `broker` has `count` subscriptions, as once the bridge has
+/// subscribed.
+async fn wait_for_subscriptions(broker: &Broker, count: u64) {
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while broker.stats().subscriptions.load(Ordering::Relaxed) < count {
+        assert!(Instant::now() < deadline, "the bridge did not subscribe");
+        sleep(Duration::from_millis(20)).await;
+    }
+}
+
+/// The topics, QoS and payloads received until none arrives for 300ms.
+async fn received(client: &mut Client) -> Vec<(u16, QoS, String)> {
+    let mut received = Vec::new();
+    while let Ok(Some(Ok(MqttPacket::Publish(publish)))) =
+        timeout(Duration::from_millis(300), client.recv()).await
+    {
+        let payload = String::from_utf8(publish.payload).unwrap();
+        received.push((publish.topic_name, publish.qos, payload));
+    }
+    received
+}
+
+fn rule(topics: std::ops::RangeInclusive<u16>, direction: Direction, offset: i32) -> BridgeTopics {
+    BridgeTopics {
+        topics,
+        direction,
+        offset,
+        qos: None,
+    }
+}
+
+#[tokio::test]
+async fn publishes_cross_both_ways_without_looping() {
+    let (remote_addr, remote) = start_broker(Broker::builder(), "127.0.0.1:0").await;
+    let mut bridge = Bridge::new("bridge", &remote_addr);
+    bridge.topics = vec![
+        rule(10..=11, Direction::Both, 1000),
+        BridgeTopics {
+            qos: Some(QoS::AtLeastOnce),
+            ..rule(20..=20, Direction::Out, -10)
+        },
+        rule(30..=30, Direction::In, 0),
+    ];
+    let (local_addr, local) = start_broker(Broker::builder().bridge(bridge), "127.0.0.1:0").await;
+    // 10, 11, 20 locally; 1010, 1011, 30 remotely.
+    wait_for_subscriptions(&local, 3).await;
+    wait_for_subscriptions(&remote, 3).await;
+
+    let mut here = Client::connect(&local_addr).await.unwrap();
+    let mut there = Client::connect(&remote_addr).await.unwrap();
+    for topic in [10, 11, 30] {
+        here.subscribe(topic).await.unwrap();
+    }
+    for topic in [10, 1010, 1011] {
+        there.subscribe(topic).await.unwrap();
+    }
+    sleep(Duration::from_millis(100)).await;
+
+    let mut publisher = Client::connect(&local_addr).await.unwrap();
+    publisher
+        .publish(10, QoS::AtMostOnce, b"out".to_vec())
+        .await
+        .unwrap();
+    publisher
+        .publish(20, QoS::AtMostOnce, b"upgraded".to_vec())
+        .await
+        .unwrap();
+    assert_eq!(
+        received(&mut there).await,
+        vec![
+            (1010, QoS::AtMostOnce, "out".to_string()),
+            (10, QoS::AtLeastOnce, "upgraded".to_string()),
+        ]
+    );
+    // Delivered here once, not echoed back over the bridge.
+    assert_eq!(
+        received(&mut here).await,
+        vec![(10, QoS::AtMostOnce, "out".to_string())]
+    );
+
+    let mut publisher = Client::connect(&remote_addr).await.unwrap();
+    for (topic, payload) in [(1011, "in"), (30, "unmapped"), (10, "not bridged")] {
+        publisher
+            .publish(topic, QoS::AtMostOnce, payload.as_bytes().to_vec())
+            .await
+            .unwrap();
+    }
+    assert_eq!(
+        received(&mut here).await,
+        vec![
+            (11, QoS::AtMostOnce, "in".to_string()),
+            (30, QoS::AtMostOnce, "unmapped".to_string()),
+        ]
+    );
+    assert_eq!(
+        received(&mut there).await,
+        vec![
+            (1011, QoS::AtMostOnce, "in".to_string()),
+            (10, QoS::AtMostOnce, "not bridged".to_string()),
+        ]
+    );
+}
+
+#[tokio::test]
+async fn the_bridge_reconnects_with_backoff() {
+    let remote_addr = std::net::TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .to_string();
+    let mut bridge = Bridge::new("bridge", &remote_addr);
+    bridge.topics = vec![rule(1..=1, Direction::In, 0)];
+    bridge.min_backoff = Duration::from_millis(50);
+    bridge.max_backoff = Duration::from_millis(200);
+    let (local_addr, _local) = start_broker(Broker::builder().bridge(bridge), "127.0.0.1:0").await;
+
+    // Nothing listens there yet.
+    sleep(Duration::from_millis(500)).await;
+    let (_, remote) = start_broker(Broker::builder(), &remote_addr).await;
+    wait_for_subscriptions(&remote, 1).await;
+
+    let mut here = Client::connect(&local_addr).await.unwrap();
+    here.subscribe(1).await.unwrap();
+    sleep(Duration::from_millis(100)).await;
+    let mut publisher = Client::connect(&remote_addr).await.unwrap();
+    publisher
+        .publish(1, QoS::AtLeastOnce, b"late".to_vec())
+        .await
+        .unwrap();
+    assert_eq!(
+        received(&mut here).await,
+        vec![(1, QoS::AtLeastOnce, "late".to_string())]
+    );
+}
+
+#[tokio::test]
+async fn publishes_are_acknowledged_once_the_other_broker_has_them() {
+    // A remote broker that only answers when told to.
+    let remote = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let mut bridge = Bridge::new("bridge", remote.local_addr().unwrap().to_string());
+    bridge.topics = vec![rule(5..=6, Direction::Both, 0)];
+    let (local_addr, local) = start_broker(Broker::builder().bridge(bridge), "127.0.0.1:0").await;
+    let (stream, _) = remote.accept().await.unwrap();
+    let mut remote = Framed::new(stream, MQTinyCodec::default());
+
+    // Only the bridged topics are subscribed to.
+    for topic in [5, 6] {
+        match remote.next().await {
+            Some(Ok(MqttPacket::Subscribe(subscribe))) => assert_eq!(subscribe.topic_name, topic),
+            other => panic!("expected a subscribe, got {:?}", other),
+        }
+    }
+    wait_for_subscriptions(&local, 2).await;
+
+    let mut publisher = Client::connect(&local_addr).await.unwrap();
+    publisher
+        .publish(5, QoS::AtLeastOnce, b"held".to_vec())
+        .await
+        .unwrap();
+    match remote.next().await {
+        Some(Ok(MqttPacket::Publish(publish))) => assert_eq!(publish.payload, b"held"),
+        other => panic!("expected a publish, got {:?}", other),
+    }
+    // Still in flight to the bridge until the remote broker acknowledges it.
+    sleep(Duration::from_millis(200)).await;
+    assert_eq!(local.stats().inflight.load(Ordering::Relaxed), 1);
+
+    remote
+        .send(MqttPacket::Puback(MqttPubackPacket::default()))
+        .await
+        .unwrap();
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while local.stats().inflight.load(Ordering::Relaxed) > 0 {
+        assert!(Instant::now() < deadline, "the bridge did not acknowledge");
+        sleep(Duration::from_millis(20)).await;
+    }
+}
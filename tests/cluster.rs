@@ -0,0 +1,147 @@
+use mqtiny::{client::Client, MqttPacket, QoS};
+use std::{
+    process::{Child, Command, Stdio},
+    time::Duration,
+};
+use tokio::time::{sleep, timeout, Instant};
+
+/// A broker process that is a node of a cluster, killed when dropped.
+struct Node {
+    child: Child,
+    addr: String,
+}
+
+impl Node {
+    /// Starts the broker binary on `addr`, accepting links on `peer_addr` and
+    /// linking to `peers`, and waits until it accepts connections.
+    async fn start(addr: &str, peer_addr: &str, peers: &[&str]) -> Node {
+        let mut command = Command::new(env!("CARGO_BIN_EXE_test"));
+        command.args([
+            "--listen",
+            addr,
+            "--peer-listen",
+            peer_addr,
+            "--log-level",
+            "warn",
+        ]);
+        for peer in peers {
+            command.args(["--peer", peer]);
+        }
+        let child = command
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+            .unwrap();
+        let node = Node {
+            child,
+            addr: addr.to_string(),
+        };
+
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while tokio::net::TcpStream::connect(&node.addr).await.is_err() {
+            assert!(Instant::now() < deadline, "the node did not start");
+            sleep(Duration::from_millis(20)).await;
+        }
+        node
+    }
+}
+
+impl Drop for Node {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+fn free_addr() -> String {
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    listener.local_addr().unwrap().to_string()
+}
+
+/// Publishes on `topic` until `subscriber` receives one, as once the nodes
+/// have linked.
+async fn wait_for_route(publisher: &mut Client, subscriber: &mut Client, topic: u16) {
+    let deadline = Instant::now() + Duration::from_secs(10);
+    loop {
+        assert!(Instant::now() < deadline, "publishes did not get through");
+        publisher
+            .publish(topic, QoS::AtMostOnce, b"ping".to_vec())
+            .await
+            .unwrap();
+        if let Ok(Some(Ok(MqttPacket::Publish(_)))) =
+            timeout(Duration::from_millis(200), subscriber.recv()).await
+        {
+            return;
+        }
+    }
+}
+
+/// The payloads received until none arrives for 300ms, pings left out.
+async fn received(client: &mut Client) -> Vec<String> {
+    let mut received = Vec::new();
+    while let Ok(Some(Ok(MqttPacket::Publish(publish)))) =
+        timeout(Duration::from_millis(300), client.recv()).await
+    {
+        if publish.payload != b"ping" {
+            received.push(String::from_utf8(publish.payload).unwrap());
+        }
+    }
+    received
+}
+
+#[tokio::test]
+async fn publishes_cross_nodes_and_survive_a_node_failure() {
+    let addrs: Vec<String> = (0..3).map(|_| free_addr()).collect();
+    let peer_addrs: Vec<String> = (0..3).map(|_| free_addr()).collect();
+    let mut nodes = Vec::new();
+    for node in 0..3 {
+        let peers: Vec<&str> = (0..3)
+            .filter(|&peer| peer != node)
+            .map(|peer| peer_addrs[peer].as_str())
+            .collect();
+        nodes.push(Node::start(&addrs[node], &peer_addrs[node], &peers).await);
+    }
+
+    let mut a = Client::connect(&nodes[0].addr).await.unwrap();
+    let mut b = Client::connect(&nodes[1].addr).await.unwrap();
+    let mut c = Client::connect(&nodes[2].addr).await.unwrap();
+    b.subscribe(7).await.unwrap();
+    c.subscribe(7).await.unwrap();
+    wait_for_route(&mut a, &mut b, 7).await;
+    wait_for_route(&mut a, &mut c, 7).await;
+    received(&mut b).await;
+
+    // Each subscriber gets a publish once, from whichever node it was sent to,
+    // though not necessarily in the order they were sent.
+    a.publish(7, QoS::AtLeastOnce, b"from a".to_vec())
+        .await
+        .unwrap();
+    let mut publisher = Client::connect(&nodes[1].addr).await.unwrap();
+    publisher
+        .publish(7, QoS::AtMostOnce, b"from b".to_vec())
+        .await
+        .unwrap();
+    for subscriber in [&mut b, &mut c] {
+        let mut payloads = received(subscriber).await;
+        payloads.sort();
+        assert_eq!(payloads, ["from a", "from b"]);
+    }
+
+    // The other nodes carry on without the third.
+    drop(nodes.pop());
+    drop(c);
+    a.publish(7, QoS::AtMostOnce, b"after".to_vec())
+        .await
+        .unwrap();
+    assert_eq!(received(&mut b).await, ["after"]);
+
+    // New subscriptions still reach the surviving nodes.
+    let mut subscriber = Client::connect(&nodes[0].addr).await.unwrap();
+    subscriber.subscribe(8).await.unwrap();
+    wait_for_route(&mut publisher, &mut subscriber, 8).await;
+    publisher
+        .publish(8, QoS::AtMostOnce, b"back".to_vec())
+        .await
+        .unwrap();
+    assert_eq!(received(&mut subscriber).await, ["back"]);
+}
@@ -0,0 +1,183 @@
+use mqtiny::{
+    bridge::Direction,
+    config::{Config, Runtime},
+    logging::LogFormat,
+    ratelimit::RateAction,
+    store::Fsync,
+};
+use std::{fs, io::ErrorKind};
+
+#[test]
+fn full_config_is_parsed() {
+    let config = Config::parse(
+        r#"
+        runtime = "thread-per-core"
+        threads = 4
+
+        [[listener]]
+        address = "0.0.0.0:7001"
+        max-connections = 200
+
+        [[listener]]
+        address = "unix:///run/mqtiny.sock"
+        mode = "660"
+
+        [auth]
+        allow-anonymous = false
+        password-file = "passwd"
+
+        [limits]
+        max-connections-per-ip = 10
+        max-packet-size = 128
+
+        [store]
+        path = "/var/lib/mqtiny/mqtiny.wal"
+        fsync = "always"
+
+        [cluster]
+        peers = ["10.0.0.2:7101"]
+
+        [cluster.listener]
+        address = "0.0.0.0:7101"
+        "#,
+    )
+    .unwrap();
+
+    assert!(matches!(config.runtime, Some(Runtime::ThreadPerCore)));
+    assert_eq!(config.threads, Some(4));
+    assert_eq!(config.listeners.len(), 2);
+    assert_eq!(config.listeners[0].max_connections, Some(200));
+    assert_eq!(config.listeners[1].mode, Some(0o660));
+    assert!(!config.auth.allow_anonymous);
+    assert_eq!(config.limits.max_connections_per_ip, Some(10));
+    assert_eq!(config.limits.max_packet_size, Some(128));
+    assert_eq!(config.limits.max_connections, None);
+    assert_eq!(config.store.fsync, Fsync::Always);
+    assert_eq!(config.cluster.peers, ["10.0.0.2:7101"]);
+    assert!(config.cluster.listener.is_some());
+}
+
+#[test]
+fn errors_point_at_the_offending_line() {
+    let err =
+        Config::parse("threads = 2\n\n[[listener]]\naddress = \"0.0.0.0:1\"\nmax-conections = 1\n")
+            .err()
+            .unwrap();
+    assert!(err.contains("line 5"), "{}", err);
+    assert!(err.contains("max-conections"), "{}", err);
+
+    let err = Config::parse("[[listener]]\naddress = \"tls://0.0.0.0:8883\"\ncert = \"a.pem\"\n")
+        .err()
+        .unwrap();
+    assert!(err.contains("line 1"), "{}", err);
+    assert!(err.contains("needs a cert and a key"), "{}", err);
+}
+
+#[test]
+fn paths_are_relative_to_the_config_file() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("mqtiny.toml");
+    fs::write(&path, "[auth]\nacl-file = \"acl\"\n").unwrap();
+
+    let config = Config::load(&path).unwrap();
+    assert_eq!(config.auth.acl_file, Some(dir.path().join("acl")));
+
+    // The ACL file itself is missing, which is caught before the broker starts.
+    let err = config.broker().err().unwrap();
+    assert_eq!(err.kind(), ErrorKind::NotFound);
+}
+
+#[test]
+fn log_settings_are_validated() {
+    let config =
+        Config::parse("[log]\nlevel = \"info,mqtiny::broker=trace\"\nformat = \"json\"\n").unwrap();
+    assert_eq!(config.log.format, LogFormat::Json);
+
+    let err = Config::parse("[log]\nlevel = \"mqtiny=loud\"\n")
+        .err()
+        .unwrap();
+    assert!(err.starts_with("log.level: "), "{}", err);
+}
+
+#[test]
+fn rate_limits_are_parsed() {
+    let config = Config::parse(
+        r#"
+        [[listener]]
+        address = "0.0.0.0:7001"
+        rate-messages = 1000
+        rate-action = "disconnect"
+
+        [rate-limit.client]
+        messages = 100
+        bytes = 16384
+        action = "delay"
+
+        [[rate-limit.topic]]
+        topics = "10-19"
+        messages = 5
+        "#,
+    )
+    .unwrap();
+
+    let listener = config.listeners[0].rate_limit.unwrap();
+    assert_eq!(listener.messages, Some(1000));
+    assert_eq!(listener.action, RateAction::Disconnect);
+    let client = config.rate_limit.client.unwrap();
+    assert_eq!((client.messages, client.bytes), (Some(100), Some(16384)));
+    assert_eq!(client.action, RateAction::Delay);
+    assert_eq!(config.rate_limit.topic[0].action, RateAction::Drop);
+
+    let err = Config::parse("[[history]]\ntopics = \"10-19\"\n")
+        .err()
+        .unwrap();
+    assert!(err.contains("need max-messages"), "{}", err);
+
+    let err = Config::parse("[[rate-limit.topic]]\ntopics = \"19-10\"\n")
+        .err()
+        .unwrap();
+    assert!(err.starts_with("rate-limit.topic: "), "{}", err);
+}
+
+#[test]
+fn zero_rates_are_refused() {
+    for config in [
+        "[rate-limit.client]\nmessages = 0\n",
+        "[rate-limit.client]\nbytes = 0\n",
+        "[[rate-limit.topic]]\ntopics = \"1\"\nmessages = 0\n",
+        "[[listener]]\naddress = \"0.0.0.0:7001\"\nrate-bytes = 0\n",
+    ] {
+        assert!(Config::parse(config).is_err(), "{}", config);
+    }
+}
+
+#[test]
+fn bridges_are_parsed() {
+    let config = Config::parse(
+        r#"
+        [[bridge]]
+        name = "uplink"
+        address = "tls://central:8883"
+        client-id = "site-a"
+        persistent = true
+
+        [[bridge.topics]]
+        topics = "100-199"
+        direction = "both"
+        offset = 1000
+        qos = 1
+        "#,
+    )
+    .unwrap();
+    let bridge = &config.bridges[0];
+    assert_eq!(bridge.client_id.as_deref(), Some("site-a"));
+    assert!(bridge.persistent);
+    assert_eq!(bridge.topics[0].direction, Direction::Both);
+
+    let err = Config::parse(
+        "[[bridge]]\nname = \"b\"\naddress = \"x:1\"\n[[bridge.topics]]\ntopics = \"65000\"\noffset = 1000\n",
+    )
+    .err()
+    .unwrap();
+    assert!(err.contains("offset out of range"), "{}", err);
+}
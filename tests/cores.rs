@@ -0,0 +1,112 @@
+use mqtiny::{
+    broker::{Broker, Rx, Tx},
+    client::Client,
+    listener::{ListenAddr, Listener},
+    MqttPacket, QoS,
+};
+use std::time::Duration;
+use tokio::{
+    sync::mpsc,
+    time::{sleep, timeout},
+};
+
+/// Spawns the brokers of `cores` cores, as `test -r thread-per-core` does
+/// but on the runtime of the test.
+fn spawn_cores(cores: usize) -> Vec<Broker> {
+    let (peers, rxs): (Vec<Tx>, Vec<Rx>) = (0..cores).map(|_| mpsc::unbounded_channel()).unzip();
+    rxs.into_iter()
+        .enumerate()
+        .map(|(core, rx)| {
+            Broker::builder().spawn_core(core, peers[core].clone(), rx, peers.clone())
+        })
+        .collect()
+}
+
+/// Serves `broker` on `addr`, returning the address it listens on.
+async fn serve(broker: &Broker, addr: &str, reuseport: bool) -> String {
+    let listener = Listener::bind(&ListenAddr::new(addr), reuseport)
+        .await
+        .unwrap()
+        .remove(0);
+    let addr = listener.local_addr().unwrap().to_string();
+    let broker = broker.clone();
+    tokio::spawn(async move { broker.serve(&listener).await });
+    addr
+}
+
+async fn recv(client: &mut Client) -> Option<MqttPacket> {
+    match timeout(Duration::from_millis(500), client.recv()).await {
+        Ok(Some(Ok(packet))) => Some(packet),
+        Ok(other) => panic!("connection failed: {:?}", other),
+        Err(_) => None,
+    }
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn publishes_cross_cores_while_they_have_subscribers() {
+    let cores = spawn_cores(2);
+    let first = serve(&cores[0], "127.0.0.1:0", false).await;
+    let second = serve(&cores[1], "127.0.0.1:0", false).await;
+
+    let mut subscriber = Client::connect(&first).await.unwrap();
+    subscriber.subscribe(1).await.unwrap();
+    sleep(Duration::from_millis(100)).await;
+
+    let mut publisher = Client::connect(&second).await.unwrap();
+    publisher
+        .publish(1, QoS::AtMostOnce, b"across".to_vec())
+        .await
+        .unwrap();
+    let Some(MqttPacket::Publish(publish)) = recv(&mut subscriber).await else {
+        panic!("the publish did not reach the other core");
+    };
+    assert_eq!(publish.payload, b"across");
+
+    // The first core withdraws its interest, then announces it again.
+    subscriber.unsubscribe(1).await.unwrap();
+    sleep(Duration::from_millis(100)).await;
+    publisher
+        .publish(1, QoS::AtMostOnce, b"nobody".to_vec())
+        .await
+        .unwrap();
+    assert!(recv(&mut subscriber).await.is_none());
+
+    subscriber.subscribe(1).await.unwrap();
+    sleep(Duration::from_millis(100)).await;
+    publisher
+        .publish(1, QoS::AtMostOnce, b"again".to_vec())
+        .await
+        .unwrap();
+    let Some(MqttPacket::Publish(publish)) = recv(&mut subscriber).await else {
+        panic!("the publish did not reach the other core after resubscribing");
+    };
+    assert_eq!(publish.payload, b"again");
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn cores_share_a_port_with_so_reuseport() {
+    let cores = spawn_cores(2);
+    let addr = serve(&cores[0], "127.0.0.1:0", true).await;
+    assert_eq!(serve(&cores[1], &addr, true).await, addr);
+
+    // The kernel spreads the connections over both cores.
+    let mut subscribers = Vec::new();
+    for _ in 0..8 {
+        let mut subscriber = Client::connect(&addr).await.unwrap();
+        subscriber.subscribe(1).await.unwrap();
+        subscribers.push(subscriber);
+    }
+    sleep(Duration::from_millis(100)).await;
+
+    let mut publisher = Client::connect(&addr).await.unwrap();
+    publisher
+        .publish(1, QoS::AtMostOnce, b"everyone".to_vec())
+        .await
+        .unwrap();
+    for subscriber in &mut subscribers {
+        let Some(MqttPacket::Publish(publish)) = recv(subscriber).await else {
+            panic!("a subscriber missed the publish");
+        };
+        assert_eq!(publish.payload, b"everyone");
+    }
+}
@@ -0,0 +1,156 @@
+use async_trait::async_trait;
+use mqtiny::{
+    auth::{AclFile, Authenticator},
+    broker::{Broker, BrokerBuilder},
+    client::{Client, ConnectOptions},
+    deadletter::{DeadLetter, DropReason},
+    listener::{ListenAddr, Listener},
+    ratelimit::{RateAction, RateLimit},
+    MqttPacket, MqttPublishPacket, QoS,
+};
+use std::{sync::Arc, time::Duration};
+use tokio::time::{sleep, timeout};
+
+/// Lets everyone in, so the tests only exercise the ACL.
+struct AcceptAll;
+
+#[async_trait]
+impl Authenticator for AcceptAll {
+    async fn authenticate(&self, _client_id: &str, _username: &str, _password: &[u8]) -> bool {
+        true
+    }
+}
+
+const ACL: &str = "
+topic read 900
+topic readwrite 10-19
+";
+
+async fn start_broker(builder: BrokerBuilder) -> String {
+    let broker = builder.dead_letter_topic(900).spawn();
+    let listener = Listener::bind(&ListenAddr::new("127.0.0.1:0"), false)
+        .await
+        .unwrap()
+        .remove(0);
+    let addr = listener.local_addr().unwrap().to_string();
+    tokio::spawn(async move { broker.serve(&listener).await });
+    addr
+}
+
+async fn auditor(addr: &str) -> Client {
+    let mut auditor = Client::connect(addr).await.unwrap();
+    auditor.subscribe(900).await.unwrap();
+    sleep(Duration::from_millis(100)).await;
+    auditor
+}
+
+async fn recv(client: &mut Client, wait: Duration) -> MqttPublishPacket {
+    match timeout(wait, client.recv()).await {
+        Ok(Some(Ok(MqttPacket::Publish(publish)))) => publish,
+        other => panic!("expected a dead letter, got {:?}", other),
+    }
+}
+
+#[tokio::test]
+async fn denied_and_throttled_publishes_are_dead_lettered() {
+    let limit = RateLimit {
+        messages: Some(1),
+        bytes: None,
+        action: RateAction::Drop,
+    };
+    let addr = start_broker(
+        Broker::builder()
+            .authenticator(Arc::new(AcceptAll))
+            .authorizer(Arc::new(AclFile::parse(ACL).unwrap()))
+            .client_limit(limit),
+    )
+    .await;
+    let mut auditor = auditor(&addr).await;
+
+    let options = ConnectOptions {
+        client_id: Some("alice-1".to_string()),
+        username: Some("alice".to_string()),
+        ..Default::default()
+    };
+    let mut publisher = Client::connect_with(&addr, &options).await.unwrap();
+    publisher
+        .publish(5, QoS::AtMostOnce, b"secret".to_vec())
+        .await
+        .unwrap();
+    publisher
+        .publish(10, QoS::AtMostOnce, b"burst".to_vec())
+        .await
+        .unwrap();
+
+    let publish = recv(&mut auditor, Duration::from_millis(500)).await;
+    assert_eq!(publish.topic_name, 900);
+    let denied = DeadLetter::decode(&publish.payload).unwrap();
+    assert_eq!(denied.topic, 5);
+    assert_eq!(denied.reason, DropReason::Denied);
+    assert_eq!(denied.client_id, "alice-1");
+    assert_eq!(denied.payload, b"secret");
+    assert!(denied.dropped_at > 0);
+
+    let publish = recv(&mut auditor, Duration::from_millis(500)).await;
+    let throttled = DeadLetter::decode(&publish.payload).unwrap();
+    assert_eq!(throttled.topic, 10);
+    assert_eq!(throttled.reason, DropReason::Throttled);
+    assert_eq!(throttled.payload, b"burst");
+}
+
+#[tokio::test]
+async fn expired_stored_publishes_are_dead_lettered() {
+    let addr = start_broker(Broker::builder()).await;
+    let options = ConnectOptions {
+        client_id: Some("offline".to_string()),
+        persistent: true,
+        ..Default::default()
+    };
+    let mut offline = Client::connect_with(&addr, &options).await.unwrap();
+    offline.subscribe(2).await.unwrap();
+    sleep(Duration::from_millis(100)).await;
+    drop(offline);
+    let mut auditor = auditor(&addr).await;
+
+    let mut publisher = Client::connect(&addr).await.unwrap();
+    publisher
+        .publish_expiring(
+            2,
+            QoS::AtLeastOnce,
+            b"stale".to_vec(),
+            Duration::from_secs(1),
+        )
+        .await
+        .unwrap();
+
+    let publish = recv(&mut auditor, Duration::from_secs(3)).await;
+    assert_eq!(publish.qos, QoS::AtLeastOnce);
+    let expired = DeadLetter::decode(&publish.payload).unwrap();
+    assert_eq!(expired.topic, 2);
+    assert_eq!(expired.reason, DropReason::Expired);
+    assert_eq!(expired.client_id, "");
+    assert_eq!(expired.payload, b"stale");
+}
+
+#[test]
+fn long_payloads_are_cut_short_to_fit_a_packet() {
+    let dead_letter = DeadLetter {
+        topic: 7,
+        reason: DropReason::Undeliverable,
+        dropped_at: 1_700_000_000_000,
+        client_id: "é".repeat(40),
+        size: 240,
+        payload: vec![1; 240],
+    };
+    let envelope = dead_letter.encode();
+    assert!(envelope.len() <= 244);
+    let decoded = DeadLetter::decode(&envelope).unwrap();
+    // Cut at a character boundary.
+    assert_eq!(decoded.client_id, "é".repeat(32));
+    assert_eq!(decoded.size, 240);
+    assert_eq!(
+        decoded.payload,
+        &dead_letter.payload[..decoded.payload.len()]
+    );
+    assert!(DeadLetter::decode(&envelope[..20]).is_err());
+}
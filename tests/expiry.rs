@@ -0,0 +1,122 @@
+use mqtiny::{
+    broker::Broker,
+    client::{Client, ConnectOptions},
+    listener::{ListenAddr, Listener},
+    MqttPacket, MqttPublishPacket, QoS,
+};
+use std::{sync::atomic::Ordering, time::Duration};
+use tokio::time::{sleep, timeout};
+
+async fn start_broker() -> (String, Broker) {
+    let broker = Broker::spawn();
+    let listener = Listener::bind(&ListenAddr::new("127.0.0.1:0"), false)
+        .await
+        .unwrap()
+        .remove(0);
+    let addr = listener.local_addr().unwrap().to_string();
+    let serving = broker.clone();
+    tokio::spawn(async move { serving.serve(&listener).await });
+    (addr, broker)
+}
+
+/// The publishes received until none arrives for 300ms.
+async fn received(client: &mut Client) -> Vec<MqttPublishPacket> {
+    let mut received = Vec::new();
+    while let Ok(Some(Ok(MqttPacket::Publish(publish)))) =
+        timeout(Duration::from_millis(300), client.recv()).await
+    {
+        received.push(publish);
+    }
+    received
+}
+
+#[tokio::test]
+async fn expired_publishes_leave_the_queue_of_a_slow_subscriber() {
+    let (addr, broker) = start_broker().await;
+    // Stops reading, so publishes queue up for it once its 64KiB buffer fills.
+    let mut slow = broker
+        .connect_local(&ConnectOptions::default())
+        .await
+        .unwrap();
+    slow.subscribe(1).await.unwrap();
+    sleep(Duration::from_millis(100)).await;
+
+    let mut publisher = Client::connect(&addr).await.unwrap();
+    for _ in 0..1000 {
+        publisher
+            .publish_expiring(1, QoS::AtMostOnce, vec![0; 200], Duration::from_secs(1))
+            .await
+            .unwrap();
+    }
+    sleep(Duration::from_millis(1500)).await;
+
+    let got = received(&mut slow).await;
+    let expired = broker.stats().expired.load(Ordering::Relaxed);
+    assert!(expired > 0, "nothing expired");
+    assert_eq!(got.len() as u64 + expired, 1000);
+    assert!(got.iter().all(|publish| publish.expiry == Some(1)));
+}
+
+#[tokio::test]
+async fn expired_publishes_leave_sessions_and_retained_storage() {
+    let (addr, broker) = start_broker().await;
+    let options = ConnectOptions {
+        client_id: Some("offline".to_string()),
+        persistent: true,
+        ..Default::default()
+    };
+    let mut offline = Client::connect_with(&addr, &options).await.unwrap();
+    offline.subscribe(2).await.unwrap();
+    sleep(Duration::from_millis(100)).await;
+    drop(offline);
+    sleep(Duration::from_millis(100)).await;
+
+    let mut publisher = Client::connect(&addr).await.unwrap();
+    publisher
+        .publish_expiring(
+            2,
+            QoS::AtLeastOnce,
+            b"stale".to_vec(),
+            Duration::from_secs(1),
+        )
+        .await
+        .unwrap();
+    publisher
+        .publish(2, QoS::AtLeastOnce, b"kept".to_vec())
+        .await
+        .unwrap();
+    publisher
+        .publish_packet(MqttPublishPacket {
+            topic_name: 3,
+            qos: QoS::AtMostOnce,
+            retain: true,
+            seq: None,
+            expiry: Some(1),
+            delay: None,
+            response_topic: None,
+            correlation_data: None,
+            payload: b"retained".to_vec(),
+        })
+        .await
+        .unwrap();
+    sleep(Duration::from_millis(200)).await;
+
+    // Before it expires, a subscriber gets the retained publish with the
+    // time it has left.
+    let mut early = Client::connect(&addr).await.unwrap();
+    early.subscribe(3).await.unwrap();
+    let got = received(&mut early).await;
+    assert_eq!(got.len(), 1);
+    assert_eq!(got[0].expiry, Some(1));
+
+    sleep(Duration::from_millis(2000)).await;
+    let mut late = Client::connect(&addr).await.unwrap();
+    late.subscribe(3).await.unwrap();
+    assert!(received(&mut late).await.is_empty());
+
+    let mut offline = Client::connect_with(&addr, &options).await.unwrap();
+    let got = received(&mut offline).await;
+    let payloads: Vec<&[u8]> = got.iter().map(|p| &p.payload[..]).collect();
+    assert_eq!(payloads, [b"kept"]);
+    assert_eq!(broker.stats().expired.load(Ordering::Relaxed), 2);
+}
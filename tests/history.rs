@@ -0,0 +1,138 @@
+use mqtiny::{
+    broker::{Broker, BrokerBuilder},
+    client::Client,
+    history::HistoryLimit,
+    listener::{ListenAddr, Listener},
+    MqttPacket, QoS, Replay,
+};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::time::{sleep, timeout};
+
+async fn start_broker(builder: BrokerBuilder) -> String {
+    let broker = builder.spawn();
+    let listener = Listener::bind(&ListenAddr::new("127.0.0.1:0"), false)
+        .await
+        .unwrap()
+        .remove(0);
+    let addr = listener.local_addr().unwrap().to_string();
+    tokio::spawn(async move { broker.serve(&listener).await });
+    addr
+}
+
+async fn publish(client: &mut Client, topic: u16, payloads: &[&str]) {
+    for payload in payloads {
+        client
+            .publish(topic, QoS::AtMostOnce, payload.as_bytes().to_vec())
+            .await
+            .unwrap();
+    }
+    sleep(Duration::from_millis(100)).await;
+}
+
+/// The sequence numbers and payloads received until none arrives for 300ms.
+async fn received(client: &mut Client) -> Vec<(Option<u64>, String)> {
+    let mut received = Vec::new();
+    while let Ok(Some(Ok(MqttPacket::Publish(publish)))) =
+        timeout(Duration::from_millis(300), client.recv()).await
+    {
+        let payload = String::from_utf8(publish.payload).unwrap();
+        received.push((publish.seq, payload));
+    }
+    received
+}
+
+fn numbered(from: u64, payloads: &[&str]) -> Vec<(Option<u64>, String)> {
+    (from..)
+        .zip(payloads)
+        .map(|(seq, payload)| (Some(seq), payload.to_string()))
+        .collect()
+}
+
+#[tokio::test]
+async fn late_subscribers_replay_from_a_sequence_number() {
+    let limit = HistoryLimit {
+        max_messages: Some(3),
+        ..Default::default()
+    };
+    let addr = start_broker(Broker::builder().history(10..=19, limit)).await;
+
+    let mut live = Client::connect(&addr).await.unwrap();
+    live.subscribe(10).await.unwrap();
+    sleep(Duration::from_millis(100)).await;
+    let mut publisher = Client::connect(&addr).await.unwrap();
+    publish(&mut publisher, 10, &["a", "b", "c", "d", "e"]).await;
+    assert_eq!(
+        received(&mut live).await,
+        numbered(1, &["a", "b", "c", "d", "e"])
+    );
+
+    // Only the last three are kept.
+    let mut late = Client::connect(&addr).await.unwrap();
+    late.subscribe_from(10, Replay::FromSeq(0)).await.unwrap();
+    assert_eq!(received(&mut late).await, numbered(3, &["c", "d", "e"]));
+
+    // Resuming after the last one seen, then carrying on live.
+    let mut resumed = Client::connect(&addr).await.unwrap();
+    resumed
+        .subscribe_from(10, Replay::FromSeq(5))
+        .await
+        .unwrap();
+    sleep(Duration::from_millis(100)).await;
+    publish(&mut publisher, 10, &["f"]).await;
+    assert_eq!(received(&mut resumed).await, numbered(5, &["e", "f"]));
+}
+
+#[tokio::test]
+async fn subscribers_replay_since_a_time() {
+    let limit = HistoryLimit {
+        max_bytes: Some(1024),
+        ..Default::default()
+    };
+    let addr = start_broker(Broker::builder().history(1..=1, limit)).await;
+
+    let mut publisher = Client::connect(&addr).await.unwrap();
+    publish(&mut publisher, 1, &["old"]).await;
+    let since = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as u64;
+    publish(&mut publisher, 1, &["new", "newer"]).await;
+    publish(&mut publisher, 2, &["elsewhere"]).await;
+
+    let mut late = Client::connect(&addr).await.unwrap();
+    late.subscribe_from(1, Replay::Since(since)).await.unwrap();
+    assert_eq!(received(&mut late).await, numbered(2, &["new", "newer"]));
+
+    // Topics without a history are not numbered and replay nothing.
+    let mut other = Client::connect(&addr).await.unwrap();
+    other.subscribe_from(2, Replay::FromSeq(0)).await.unwrap();
+    sleep(Duration::from_millis(100)).await;
+    publish(&mut publisher, 2, &["live"]).await;
+    assert_eq!(received(&mut other).await, vec![(None, "live".to_string())]);
+}
+
+#[tokio::test]
+async fn publishes_too_large_to_number_are_left_out_of_the_history() {
+    let addr = start_broker(Broker::builder().history(1..=1, HistoryLimit::default())).await;
+
+    let mut live = Client::connect(&addr).await.unwrap();
+    live.subscribe(1).await.unwrap();
+    sleep(Duration::from_millis(100)).await;
+    // With its sequence number, the first fills a packet to its 255 bytes and
+    // the second would overflow it.
+    let (fits, too_large) = ("x".repeat(244), "y".repeat(245));
+    let mut publisher = Client::connect(&addr).await.unwrap();
+    publish(&mut publisher, 1, &[&fits, &too_large, "after"]).await;
+    assert_eq!(
+        received(&mut live).await,
+        vec![
+            (Some(1), fits.clone()),
+            (None, too_large),
+            (Some(2), "after".to_string()),
+        ]
+    );
+
+    let mut late = Client::connect(&addr).await.unwrap();
+    late.subscribe_from(1, Replay::FromSeq(0)).await.unwrap();
+    assert_eq!(received(&mut late).await, numbered(1, &[&fits, "after"]));
+}
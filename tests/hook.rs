@@ -0,0 +1,201 @@
+use async_trait::async_trait;
+use mqtiny::{
+    broker::{Broker, BrokerBuilder},
+    client::{Client, ConnectOptions},
+    deadletter::{DeadLetter, DropReason},
+    hook::{BrokerHook, ClientInfo},
+    listener::{ListenAddr, Listener},
+    MqttPacket, MqttPublishPacket, QoS, SubackReturnCode,
+};
+use std::{
+    io,
+    sync::{atomic::Ordering, Arc, Mutex},
+    time::Duration,
+};
+use tokio::time::{sleep, timeout};
+
+async fn start_broker(builder: BrokerBuilder) -> (String, Broker) {
+    let broker = builder.spawn();
+    let listener = Listener::bind(&ListenAddr::new("127.0.0.1:0"), false)
+        .await
+        .unwrap()
+        .remove(0);
+    let addr = listener.local_addr().unwrap().to_string();
+    let serving = broker.clone();
+    tokio::spawn(async move { serving.serve(&listener).await });
+    (addr, broker)
+}
+
+async fn connect(addr: &str, client_id: &str) -> io::Result<Client> {
+    let options = ConnectOptions {
+        client_id: Some(client_id.to_string()),
+        ..Default::default()
+    };
+    Client::connect_with(addr, &options).await
+}
+
+async fn recv(client: &mut Client) -> Option<MqttPacket> {
+    match timeout(Duration::from_millis(500), client.recv()).await {
+        Ok(Some(Ok(packet))) => Some(packet),
+        Ok(other) => panic!("connection failed: {:?}", other),
+        Err(_) => None,
+    }
+}
+
+/// Reroutes publishes on topic 10 to topic 20 in upper case, and rejects
+/// those saying "spam".
+struct Router;
+
+#[async_trait]
+impl BrokerHook for Router {
+    async fn on_publish(&self, _client: &ClientInfo, packet: &mut MqttPublishPacket) -> bool {
+        if packet.payload == b"spam" {
+            return false;
+        }
+        if packet.topic_name == 10 {
+            packet.topic_name = 20;
+            packet.payload.make_ascii_uppercase();
+        }
+        true
+    }
+}
+
+#[tokio::test]
+async fn publishes_are_rewritten_and_rejected() {
+    let builder = Broker::builder()
+        .dead_letter_topic(900)
+        .hook(Arc::new(Router));
+    let (addr, broker) = start_broker(builder).await;
+
+    let mut subscriber = Client::connect(&addr).await.unwrap();
+    subscriber.subscribe(10).await.unwrap();
+    subscriber.subscribe(20).await.unwrap();
+    subscriber.subscribe(900).await.unwrap();
+    sleep(Duration::from_millis(100)).await;
+
+    let mut publisher = connect(&addr, "publisher").await.unwrap();
+    publisher
+        .publish(10, QoS::AtLeastOnce, b"hello".to_vec())
+        .await
+        .unwrap();
+    publisher
+        .publish(10, QoS::AtMostOnce, b"spam".to_vec())
+        .await
+        .unwrap();
+
+    let Some(MqttPacket::Publish(publish)) = recv(&mut subscriber).await else {
+        panic!("the rerouted publish was lost");
+    };
+    assert_eq!(publish.topic_name, 20);
+    assert_eq!(publish.payload, b"HELLO");
+
+    let Some(MqttPacket::Publish(publish)) = recv(&mut subscriber).await else {
+        panic!("the rejected publish was not dead-lettered");
+    };
+    assert_eq!(publish.topic_name, 900);
+    let letter = DeadLetter::decode(&publish.payload).unwrap();
+    assert_eq!(letter.topic, 10);
+    assert_eq!(letter.reason, DropReason::Rejected);
+    assert_eq!(letter.client_id, "publisher");
+    assert_eq!(letter.payload, b"spam");
+
+    assert!(recv(&mut subscriber).await.is_none());
+    assert_eq!(broker.stats().rejected.load(Ordering::Relaxed), 1);
+}
+
+/// Keeps "mallory" out and off topic 13, hides publishes saying "secret"
+/// from everyone but "admin", tags the others with the client they go to,
+/// and records who came and went.
+#[derive(Default)]
+struct Gatekeeper {
+    events: Mutex<Vec<String>>,
+}
+
+#[async_trait]
+impl BrokerHook for Gatekeeper {
+    async fn on_connect(&self, client: &ClientInfo) -> bool {
+        if client.client_id == "mallory" {
+            return false;
+        }
+        let event = format!("connect {}", client.client_id);
+        self.events.lock().unwrap().push(event);
+        true
+    }
+
+    async fn on_subscribe(&self, _client: &ClientInfo, topic: u16) -> bool {
+        topic != 13
+    }
+
+    async fn on_deliver(&self, client: &ClientInfo, packet: &mut MqttPublishPacket) -> bool {
+        if packet.payload == b"secret" {
+            return client.client_id == "admin";
+        }
+        packet.payload.extend_from_slice(b" for ");
+        packet
+            .payload
+            .extend_from_slice(client.client_id.as_bytes());
+        true
+    }
+
+    async fn on_disconnect(&self, client: &ClientInfo) {
+        let event = format!("disconnect {}", client.client_id);
+        self.events.lock().unwrap().push(event);
+    }
+}
+
+#[tokio::test]
+async fn clients_are_gated_and_deliveries_changed() {
+    let gatekeeper = Arc::new(Gatekeeper::default());
+    let (addr, _broker) = start_broker(Broker::builder().hook(gatekeeper.clone())).await;
+
+    let e = connect(&addr, "mallory").await.err().unwrap();
+    assert_eq!(e.kind(), io::ErrorKind::PermissionDenied);
+
+    let mut alice = connect(&addr, "alice").await.unwrap();
+    alice.subscribe(13).await.unwrap();
+    match recv(&mut alice).await {
+        Some(MqttPacket::Suback(suback)) => {
+            assert_eq!(suback.topic_name, 13);
+            assert_eq!(suback.return_code, SubackReturnCode::Failure);
+        }
+        other => panic!("expected a suback, got {:?}", other),
+    }
+    alice.subscribe(12).await.unwrap();
+    let mut admin = connect(&addr, "admin").await.unwrap();
+    admin.subscribe(12).await.unwrap();
+    sleep(Duration::from_millis(100)).await;
+
+    let mut publisher = connect(&addr, "bob").await.unwrap();
+    for payload in ["secret", "news"] {
+        publisher
+            .publish(12, QoS::AtMostOnce, payload.as_bytes().to_vec())
+            .await
+            .unwrap();
+    }
+
+    let Some(MqttPacket::Publish(publish)) = recv(&mut alice).await else {
+        panic!("alice got nothing");
+    };
+    assert_eq!(publish.payload, b"news for alice");
+    assert!(recv(&mut alice).await.is_none());
+    let Some(MqttPacket::Publish(publish)) = recv(&mut admin).await else {
+        panic!("admin got nothing");
+    };
+    assert_eq!(publish.payload, b"secret");
+    let Some(MqttPacket::Publish(publish)) = recv(&mut admin).await else {
+        panic!("admin missed the news");
+    };
+    assert_eq!(publish.payload, b"news for admin");
+
+    drop(publisher);
+    sleep(Duration::from_millis(100)).await;
+    assert_eq!(
+        *gatekeeper.events.lock().unwrap(),
+        [
+            "connect alice",
+            "connect admin",
+            "connect bob",
+            "disconnect bob"
+        ]
+    );
+}
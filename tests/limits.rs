@@ -0,0 +1,165 @@
+use mqtiny::{
+    broker::{Broker, BrokerBuilder, Limits},
+    client::{Client, ConnectOptions},
+    listener::{ListenAddr, Listener},
+    MqttPacket, QoS, ShareStrategy, SubackReturnCode,
+};
+use std::{io, sync::atomic::Ordering, time::Duration};
+use tokio::time::timeout;
+
+async fn start_broker(builder: BrokerBuilder) -> (String, Broker) {
+    let broker = builder.spawn();
+    let listener = Listener::bind(&ListenAddr::new("127.0.0.1:0"), false)
+        .await
+        .unwrap()
+        .remove(0);
+    let addr = listener.local_addr().unwrap().to_string();
+    let serving = broker.clone();
+    tokio::spawn(async move { serving.serve(&listener).await });
+
+    (addr, broker)
+}
+
+/// Connects and logs in, which waits for the CONNACK.
+async fn connect(addr: &str) -> io::Result<Client> {
+    let options = ConnectOptions {
+        client_id: Some("limited".to_string()),
+        ..Default::default()
+    };
+    Client::connect_with(addr, &options).await
+}
+
+fn assert_refused(result: io::Result<Client>) {
+    let e = result.err().expect("expected the connection to be refused");
+    assert_eq!(e.kind(), io::ErrorKind::PermissionDenied);
+    assert!(e.to_string().contains("ServerUnavailable"), "{}", e);
+}
+
+#[tokio::test]
+async fn connections_over_the_limit_are_refused() {
+    let limits = Limits {
+        max_connections: Some(2),
+        ..Default::default()
+    };
+    let (addr, broker) = start_broker(Broker::builder().limits(limits)).await;
+
+    let _a = connect(&addr).await.unwrap();
+    let b = connect(&addr).await.unwrap();
+    assert_refused(connect(&addr).await);
+    assert_eq!(
+        broker.stats().connections_rejected.load(Ordering::Relaxed),
+        1
+    );
+
+    // A slot frees up once a client leaves.
+    drop(b);
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    connect(&addr).await.unwrap();
+}
+
+#[tokio::test]
+async fn connections_per_ip_are_limited() {
+    let limits = Limits {
+        max_connections_per_ip: Some(1),
+        ..Default::default()
+    };
+    let (addr, broker) = start_broker(Broker::builder().limits(limits)).await;
+
+    let _a = connect(&addr).await.unwrap();
+    assert_refused(connect(&addr).await);
+    assert_eq!(broker.stats().clients.load(Ordering::Relaxed), 1);
+}
+
+#[tokio::test]
+async fn subscriptions_over_the_limit_fail() {
+    let limits = Limits {
+        max_subscriptions: Some(2),
+        ..Default::default()
+    };
+    let (addr, broker) = start_broker(Broker::builder().limits(limits)).await;
+
+    let mut client = connect(&addr).await.unwrap();
+    // Subscribing again to a topic does not count twice. Only failures are
+    // acknowledged.
+    for topic in [1, 2, 2, 3] {
+        client.subscribe(topic).await.unwrap();
+    }
+    match timeout(Duration::from_secs(2), client.recv()).await {
+        Ok(Some(Ok(MqttPacket::Suback(suback)))) => {
+            assert_eq!(suback.topic_name, 3);
+            assert_eq!(suback.return_code, SubackReturnCode::Failure);
+        }
+        other => panic!("expected a SUBACK, got {:?}", other),
+    }
+    let stats = broker.stats();
+    assert_eq!(stats.subscriptions_rejected.load(Ordering::Relaxed), 1);
+
+    // Unsubscribing makes room.
+    client.unsubscribe(1).await.unwrap();
+    client.subscribe(3).await.unwrap();
+    let refused = timeout(Duration::from_millis(300), client.recv()).await;
+    assert!(refused.is_err(), "unexpected {:?}", refused);
+    assert_eq!(stats.subscriptions.load(Ordering::Relaxed), 2);
+}
+
+#[tokio::test]
+async fn shared_subscriptions_count_against_the_limit() {
+    let limits = Limits {
+        max_subscriptions: Some(2),
+        ..Default::default()
+    };
+    let (addr, broker) = start_broker(Broker::builder().limits(limits)).await;
+
+    let mut client = connect(&addr).await.unwrap();
+    client.subscribe(1).await.unwrap();
+    // Joining the same group again does not count twice.
+    for _ in 0..2 {
+        client
+            .subscribe_shared(2, "workers", ShareStrategy::RoundRobin)
+            .await
+            .unwrap();
+    }
+    client
+        .subscribe_shared(3, "workers", ShareStrategy::RoundRobin)
+        .await
+        .unwrap();
+    client.subscribe(4).await.unwrap();
+    for topic in [3, 4] {
+        match timeout(Duration::from_secs(2), client.recv()).await {
+            Ok(Some(Ok(MqttPacket::Suback(suback)))) => {
+                assert_eq!(suback.topic_name, topic);
+                assert_eq!(suback.return_code, SubackReturnCode::Failure);
+            }
+            other => panic!("expected a SUBACK, got {:?}", other),
+        }
+    }
+    let stats = broker.stats();
+    assert_eq!(stats.subscriptions_rejected.load(Ordering::Relaxed), 2);
+}
+
+#[tokio::test]
+async fn oversized_packets_close_the_connection() {
+    let limits = Limits {
+        max_packet_size: Some(64),
+        ..Default::default()
+    };
+    let (addr, broker) = start_broker(Broker::builder().limits(limits)).await;
+
+    let mut client = connect(&addr).await.unwrap();
+    client
+        .publish(1, QoS::AtMostOnce, vec![0; 32])
+        .await
+        .unwrap();
+    client
+        .publish(1, QoS::AtMostOnce, vec![0; 100])
+        .await
+        .unwrap();
+    let closed = timeout(Duration::from_secs(2), async {
+        while let Some(Ok(_)) = client.recv().await {}
+    })
+    .await;
+    assert!(closed.is_ok(), "expected the connection to close");
+    let stats = broker.stats();
+    assert_eq!(stats.messages_in(), 1);
+    assert_eq!(stats.invalid_packets.load(Ordering::Relaxed), 1);
+}
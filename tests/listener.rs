@@ -0,0 +1,123 @@
+use mqtiny::{
+    broker::Broker,
+    client::{Client, ConnectOptions},
+    listener::{ListenAddr, Listener},
+    transport::Endpoint,
+    MqttPacket, QoS,
+};
+use std::{
+    fs, io,
+    os::unix::fs::{FileTypeExt, PermissionsExt},
+    path::PathBuf,
+    time::Duration,
+};
+use tokio::time::{sleep, timeout};
+
+async fn serve(listen: &ListenAddr) -> String {
+    let broker = Broker::spawn();
+    let listener = Listener::bind(listen, false).await.unwrap().remove(0);
+    let addr = listener.local_addr().unwrap().to_string();
+    tokio::spawn(async move { broker.serve(&listener).await });
+    addr
+}
+
+/// Connects and logs in, which waits for the CONNACK.
+async fn connect(addr: &str) -> io::Result<Client> {
+    let options = ConnectOptions {
+        client_id: Some("listener".to_string()),
+        ..Default::default()
+    };
+    Client::connect_with(addr, &options).await
+}
+
+#[test]
+fn listen_addresses_are_parsed() {
+    let listen: ListenAddr = "0.0.0.0:1883".parse().unwrap();
+    assert_eq!(listen.endpoint, Endpoint::Tcp("0.0.0.0:1883".to_string()));
+    assert_eq!(listen.max_connections, None);
+
+    let listen: ListenAddr = "[::1]:1883,max-connections=10".parse().unwrap();
+    assert_eq!(listen.endpoint, Endpoint::Tcp("[::1]:1883".to_string()));
+    assert_eq!(listen.max_connections, Some(10));
+
+    let listen: ListenAddr = "unix:///run/mqtiny.sock,mode=660,max-connections=2"
+        .parse()
+        .unwrap();
+    assert_eq!(
+        listen.endpoint,
+        Endpoint::Unix(PathBuf::from("/run/mqtiny.sock"))
+    );
+    assert_eq!(listen.mode, Some(0o660));
+    assert_eq!(listen.max_connections, Some(2));
+
+    for invalid in [
+        "",
+        ",max-connections=1",
+        "0.0.0.0:1883,max-connections=many",
+        "0.0.0.0:1883,max-connections",
+        "0.0.0.0:1883,mode=600",
+        "unix:///run/mqtiny.sock,mode=999",
+        "0.0.0.0:1883,colour=blue",
+    ] {
+        assert!(invalid.parse::<ListenAddr>().is_err(), "{:?}", invalid);
+    }
+}
+
+#[tokio::test]
+async fn listeners_close_connections_over_their_limit() {
+    let listen = ListenAddr::new("127.0.0.1:0").max_connections(1);
+    let addr = serve(&listen).await;
+
+    let first = connect(&addr).await.unwrap();
+    assert!(connect(&addr).await.is_err());
+
+    // The slot is freed once the connection is gone.
+    drop(first);
+    sleep(Duration::from_millis(100)).await;
+    connect(&addr).await.unwrap();
+}
+
+#[tokio::test]
+async fn unix_socket_listeners_take_a_mode_and_replace_stale_sockets() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("mqtiny.sock");
+    // A socket file left behind by a broker that did not shut down cleanly.
+    drop(std::os::unix::net::UnixListener::bind(&path).unwrap());
+
+    let listen: ListenAddr = format!("unix://{},mode=600", path.display())
+        .parse()
+        .unwrap();
+    let addr = serve(&listen).await;
+    assert_eq!(addr, format!("unix://{}", path.display()));
+    let metadata = fs::metadata(&path).unwrap();
+    assert!(metadata.file_type().is_socket());
+    assert_eq!(metadata.permissions().mode() & 0o777, 0o600);
+
+    let mut subscriber = Client::connect(&addr).await.unwrap();
+    subscriber.subscribe(1).await.unwrap();
+    sleep(Duration::from_millis(100)).await;
+    let mut publisher = Client::connect(&addr).await.unwrap();
+    publisher
+        .publish(1, QoS::AtMostOnce, b"local".to_vec())
+        .await
+        .unwrap();
+    match timeout(Duration::from_millis(500), subscriber.recv()).await {
+        Ok(Some(Ok(MqttPacket::Publish(publish)))) => assert_eq!(publish.payload, b"local"),
+        other => panic!("expected a publish, got {:?}", other),
+    }
+    // Nothing is left behind from binding the socket with its mode.
+    assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 1);
+}
+
+#[tokio::test]
+async fn unix_socket_listeners_refuse_live_sockets() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("mqtiny.sock");
+    let listen: ListenAddr = format!("unix://{}", path.display()).parse().unwrap();
+    let addr = serve(&listen).await;
+
+    let err = Listener::bind(&listen, false).await.err().unwrap();
+    assert_eq!(err.kind(), io::ErrorKind::AddrInUse);
+    // The first broker still takes connections.
+    Client::connect(&addr).await.unwrap();
+}
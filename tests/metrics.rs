@@ -0,0 +1,136 @@
+use mqtiny::{
+    broker::Broker,
+    client::Client,
+    listener::{ListenAddr, Listener},
+    metrics, MqttPacket, QoS,
+};
+use std::time::Duration;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    time::timeout,
+};
+
+/// Starts a broker and its metrics endpoint, returning both addresses.
+async fn start_broker() -> (String, String) {
+    let broker = Broker::spawn();
+    let listener = Listener::bind(&ListenAddr::new("127.0.0.1:0"), false)
+        .await
+        .unwrap()
+        .remove(0);
+    let addr = listener.local_addr().unwrap().to_string();
+    let metrics_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let metrics_addr = metrics_listener.local_addr().unwrap().to_string();
+    tokio::spawn(metrics::serve(metrics_listener, broker.stats().clone()));
+    tokio::spawn(async move { broker.serve(&listener).await });
+
+    (addr, metrics_addr)
+}
+
+async fn get(addr: &str, path: &str) -> String {
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+    let request = format!("GET {} HTTP/1.1\r\nHost: {}\r\n\r\n", path, addr);
+    stream.write_all(request.as_bytes()).await.unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).await.unwrap();
+    response
+}
+
+/// The value of the sample of metric `name`, which has no labels.
+async fn gauge(metrics_addr: &str, name: &str) -> String {
+    let response = get(metrics_addr, "/metrics").await;
+    let prefix = format!("{} ", name);
+    match response.lines().find_map(|l| l.strip_prefix(&prefix)) {
+        Some(value) => value.to_string(),
+        None => panic!("missing {} in\n{}", name, response),
+    }
+}
+
+#[tokio::test]
+async fn scrape_reports_traffic() {
+    let (addr, metrics_addr) = start_broker().await;
+
+    let mut subscriber = Client::connect(&addr).await.unwrap();
+    subscriber.subscribe(1).await.unwrap();
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    let mut publisher = Client::connect(&addr).await.unwrap();
+    publisher
+        .publish(1, QoS::AtLeastOnce, b"hello".to_vec())
+        .await
+        .unwrap();
+    match timeout(Duration::from_secs(2), subscriber.recv()).await {
+        Ok(Some(Ok(MqttPacket::Publish(_)))) => {}
+        other => panic!("expected a publish, got {:?}", other),
+    }
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    let response = get(&metrics_addr, "/metrics").await;
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+    assert!(response.contains("Content-Type: text/plain; version=0.0.4"));
+    for line in [
+        "# TYPE mqtiny_clients gauge",
+        "mqtiny_clients 2",
+        "mqtiny_connections_total 2",
+        "mqtiny_subscriptions 1",
+        "mqtiny_messages_received_total{qos=\"1\"} 1",
+        "mqtiny_messages_received_total{qos=\"0\"} 0",
+        "mqtiny_messages_sent_total{qos=\"1\"} 1",
+        "mqtiny_received_bytes_total 5",
+        "mqtiny_queued_messages 0",
+        "mqtiny_dropped_total{reason=\"denied\"} 0",
+        "# TYPE mqtiny_payload_size_bytes histogram",
+        "mqtiny_payload_size_bytes_bucket{le=\"8\"} 1",
+        "mqtiny_payload_size_bytes_bucket{le=\"+Inf\"} 1",
+        "mqtiny_payload_size_bytes_sum 5",
+        "mqtiny_routing_latency_seconds_count 1",
+    ] {
+        assert!(
+            response.lines().any(|l| l == line),
+            "missing {:?} in\n{}",
+            line,
+            response
+        );
+    }
+}
+
+#[tokio::test]
+async fn other_paths_are_not_found() {
+    let (_, metrics_addr) = start_broker().await;
+
+    let response = get(&metrics_addr, "/").await;
+    assert!(
+        response.starts_with("HTTP/1.1 404 Not Found\r\n"),
+        "{}",
+        response
+    );
+}
+
+#[tokio::test]
+async fn scrape_reports_inflight_and_retained_publishes() {
+    let (addr, metrics_addr) = start_broker().await;
+
+    let mut subscriber = Client::connect(&addr).await.unwrap();
+    subscriber.subscribe(1).await.unwrap();
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    let mut publisher = Client::connect(&addr).await.unwrap();
+    publisher
+        .publish_retained(2, QoS::AtMostOnce, b"kept".to_vec())
+        .await
+        .unwrap();
+    publisher
+        .publish(1, QoS::AtLeastOnce, b"unacked".to_vec())
+        .await
+        .unwrap();
+    match timeout(Duration::from_secs(2), subscriber.recv()).await {
+        Ok(Some(Ok(MqttPacket::Publish(_)))) => {}
+        other => panic!("expected a publish, got {:?}", other),
+    }
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    assert_eq!(gauge(&metrics_addr, "mqtiny_inflight_messages").await, "1");
+    assert_eq!(gauge(&metrics_addr, "mqtiny_retained_messages").await, "1");
+
+    subscriber.ack().await.unwrap();
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    assert_eq!(gauge(&metrics_addr, "mqtiny_inflight_messages").await, "0");
+    assert_eq!(gauge(&metrics_addr, "mqtiny_retained_messages").await, "1");
+}
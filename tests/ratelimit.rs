@@ -0,0 +1,146 @@
+use mqtiny::{
+    broker::{Broker, BrokerBuilder},
+    client::Client,
+    listener::{ListenAddr, Listener},
+    ratelimit::{RateAction, RateLimit},
+    MqttPacket, QoS,
+};
+use std::{
+    sync::atomic::Ordering,
+    time::{Duration, Instant},
+};
+use tokio::time::timeout;
+
+async fn start_broker(builder: BrokerBuilder, listen: &str) -> (String, Broker) {
+    let broker = builder.spawn();
+    let listener = Listener::bind(&listen.parse().unwrap(), false)
+        .await
+        .unwrap()
+        .remove(0);
+    let addr = listener.local_addr().unwrap().to_string();
+    let serving = broker.clone();
+    tokio::spawn(async move { serving.serve(&listener).await });
+
+    (addr, broker)
+}
+
+fn limit(messages: u32, action: RateAction) -> RateLimit {
+    RateLimit {
+        messages: Some(messages),
+        bytes: None,
+        action,
+    }
+}
+
+/// Subscribes to `topic` and returns the client once the subscription is in
+/// place.
+async fn subscriber(addr: &str, topic: u16) -> Client {
+    let mut client = Client::connect(addr).await.unwrap();
+    client.subscribe(topic).await.unwrap();
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    client
+}
+
+async fn publish(addr: &str, topic: u16, count: usize) -> Client {
+    let mut client = Client::connect(addr).await.unwrap();
+    for _ in 0..count {
+        client
+            .publish(topic, QoS::AtMostOnce, b"x".to_vec())
+            .await
+            .unwrap();
+    }
+    client
+}
+
+/// Counts the publishes received until none arrives for `quiet`.
+async fn count_received(client: &mut Client, quiet: Duration) -> usize {
+    let mut received = 0;
+    while let Ok(Some(Ok(MqttPacket::Publish(_)))) = timeout(quiet, client.recv()).await {
+        received += 1;
+    }
+    received
+}
+
+#[tokio::test]
+async fn client_over_its_limit_is_dropped() {
+    let builder = Broker::builder().client_limit(limit(5, RateAction::Drop));
+    let (addr, broker) = start_broker(builder, "127.0.0.1:0").await;
+
+    let mut sub = subscriber(&addr, 1).await;
+    let _publisher = publish(&addr, 1, 20).await;
+
+    // The burst of 5 passes, plus whatever refilled while publishing.
+    let received = count_received(&mut sub, Duration::from_millis(300)).await;
+    assert!((5..10).contains(&received), "received {}", received);
+    let dropped = broker.stats().throttled[RateAction::Drop as usize].load(Ordering::Relaxed);
+    assert_eq!(dropped as usize, 20 - received);
+}
+
+#[tokio::test]
+async fn delayed_publishes_all_arrive() {
+    let builder = Broker::builder().client_limit(limit(10, RateAction::Delay));
+    let (addr, broker) = start_broker(builder, "127.0.0.1:0").await;
+
+    let mut sub = subscriber(&addr, 1).await;
+    let start = Instant::now();
+    let _publisher = publish(&addr, 1, 15).await;
+
+    assert_eq!(count_received(&mut sub, Duration::from_secs(1)).await, 15);
+    // 10 in the burst, then 5 at 10 per second.
+    assert!(start.elapsed() >= Duration::from_millis(400));
+    let delayed = broker.stats().throttled[RateAction::Delay as usize].load(Ordering::Relaxed);
+    assert!(delayed >= 4, "delayed {}", delayed);
+}
+
+#[tokio::test]
+async fn listener_limit_can_disconnect() {
+    let listen = "127.0.0.1:0,rate-messages=3,rate-action=disconnect";
+    let (addr, broker) = start_broker(Broker::builder(), listen).await;
+
+    let mut publisher = publish(&addr, 1, 10).await;
+    let closed = timeout(Duration::from_secs(2), async {
+        while let Some(Ok(_)) = publisher.recv().await {}
+    })
+    .await;
+    assert!(closed.is_ok(), "expected the connection to close");
+    let stats = broker.stats();
+    assert_eq!(
+        stats.throttled[RateAction::Disconnect as usize].load(Ordering::Relaxed),
+        1
+    );
+    assert_eq!(stats.messages_in(), 4);
+}
+
+#[tokio::test]
+async fn topic_limit_is_shared_by_publishers() {
+    let builder = Broker::builder().topic_limit(1..=1, limit(4, RateAction::Drop));
+    let (addr, _broker) = start_broker(builder, "127.0.0.1:0").await;
+
+    let mut limited = subscriber(&addr, 1).await;
+    let mut unlimited = subscriber(&addr, 2).await;
+    let _a = publish(&addr, 1, 4).await;
+    let _b = publish(&addr, 1, 4).await;
+    let _c = publish(&addr, 2, 8).await;
+
+    let received = count_received(&mut limited, Duration::from_millis(300)).await;
+    assert!((4..6).contains(&received), "received {}", received);
+    assert_eq!(
+        count_received(&mut unlimited, Duration::from_millis(300)).await,
+        8
+    );
+}
+
+#[test]
+fn limits_are_parsed() {
+    let limit: RateLimit = "messages=100,bytes=4096,action=delay".parse().unwrap();
+    assert_eq!(limit.messages, Some(100));
+    assert_eq!(limit.bytes, Some(4096));
+    assert_eq!(limit.action, RateAction::Delay);
+
+    assert!("messages=lots".parse::<RateLimit>().is_err());
+    assert!("messages=0".parse::<RateLimit>().is_err());
+    assert!("bytes=0".parse::<RateLimit>().is_err());
+    assert!("127.0.0.1:0,rate-messages=0".parse::<ListenAddr>().is_err());
+    assert!("action=ignore".parse::<RateLimit>().is_err());
+    assert!("127.0.0.1:0,rate-speed=1".parse::<ListenAddr>().is_err());
+}
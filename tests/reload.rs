@@ -0,0 +1,180 @@
+use async_trait::async_trait;
+use mqtiny::{
+    auth::{AclFile, Authenticator},
+    broker::{Broker, Limits},
+    client::{Client, ConnectOptions},
+    listener::{ListenAddr, Listener},
+    ratelimit::{RateAction, RateLimit},
+    MqttPacket, QoS, SubackReturnCode,
+};
+use std::{sync::Arc, time::Duration};
+use tokio::time::{sleep, timeout};
+
+/// Accepts the listed users with any password.
+struct Users(&'static [&'static str]);
+
+#[async_trait]
+impl Authenticator for Users {
+    async fn authenticate(&self, _client_id: &str, username: &str, _password: &[u8]) -> bool {
+        self.0.contains(&username)
+    }
+
+    async fn fingerprint(&self, username: &str) -> Option<u64> {
+        self.0.contains(&username).then_some(0)
+    }
+}
+
+async fn start_broker(users: &'static [&'static str], acl: &str) -> (String, Broker) {
+    let broker = Broker::builder()
+        .authenticator(Arc::new(Users(users)))
+        .authorizer(Arc::new(AclFile::parse(acl).unwrap()))
+        .spawn();
+    let listener = Listener::bind(&ListenAddr::new("127.0.0.1:0"), false)
+        .await
+        .unwrap()
+        .remove(0);
+    let addr = listener.local_addr().unwrap().to_string();
+    let serving = broker.clone();
+    tokio::spawn(async move { serving.serve(&listener).await });
+
+    (addr, broker)
+}
+
+async fn connect(addr: &str, username: &str) -> Client {
+    let options = ConnectOptions {
+        username: Some(username.to_string()),
+        ..Default::default()
+    };
+    Client::connect_with(addr, &options).await.unwrap()
+}
+
+async fn recv(client: &mut Client) -> Option<Option<MqttPacket>> {
+    match timeout(Duration::from_millis(500), client.recv()).await {
+        Ok(Some(Ok(packet))) => Some(Some(packet)),
+        Ok(None) => Some(None),
+        Ok(Some(Err(e))) => panic!("{}", e),
+        Err(_) => None,
+    }
+}
+
+#[tokio::test]
+async fn revoked_subscriptions_are_dropped_and_others_kept() {
+    let (addr, broker) = start_broker(&["alice"], "topic readwrite 1-2").await;
+
+    let mut alice = connect(&addr, "alice").await;
+    alice.subscribe(1).await.unwrap();
+    alice.subscribe(2).await.unwrap();
+    sleep(Duration::from_millis(100)).await;
+
+    let settings = Broker::builder()
+        .authenticator(Arc::new(Users(&["alice"])))
+        .authorizer(Arc::new(AclFile::parse("topic readwrite 2").unwrap()))
+        .settings()
+        .clone();
+    broker.reload(settings);
+
+    match recv(&mut alice).await {
+        Some(Some(MqttPacket::Suback(suback))) => {
+            assert_eq!(suback.topic_name, 1);
+            assert_eq!(suback.return_code, SubackReturnCode::Failure);
+        }
+        other => panic!("expected a suback, got {:?}", other),
+    }
+
+    let mut publisher = connect(&addr, "alice").await;
+    for topic in [1, 2] {
+        publisher
+            .publish(topic, QoS::AtMostOnce, vec![topic as u8])
+            .await
+            .unwrap();
+    }
+    match recv(&mut alice).await {
+        Some(Some(MqttPacket::Publish(publish))) => assert_eq!(publish.topic_name, 2),
+        other => panic!("expected a publish, got {:?}", other),
+    }
+    assert!(recv(&mut alice).await.is_none());
+}
+
+#[tokio::test]
+async fn only_sessions_that_lost_their_login_are_closed() {
+    let (addr, broker) = start_broker(&["alice", "bob"], "topic readwrite 1").await;
+
+    let mut alice = connect(&addr, "alice").await;
+    let mut bob = connect(&addr, "bob").await;
+    sleep(Duration::from_millis(100)).await;
+
+    let settings = Broker::builder()
+        .authenticator(Arc::new(Users(&["alice"])))
+        .authorizer(Arc::new(AclFile::parse("topic readwrite 1").unwrap()))
+        .settings()
+        .clone();
+    broker.reload(settings);
+
+    assert!(matches!(recv(&mut bob).await, Some(None)));
+    assert!(recv(&mut alice).await.is_none());
+}
+
+#[tokio::test]
+async fn limits_apply_to_connected_clients() {
+    let (addr, broker) = start_broker(&["alice"], "topic readwrite 1-2").await;
+    let mut alice = connect(&addr, "alice").await;
+    alice.subscribe(1).await.unwrap();
+    sleep(Duration::from_millis(100)).await;
+
+    let settings = Broker::builder()
+        .authenticator(Arc::new(Users(&["alice"])))
+        .authorizer(Arc::new(AclFile::parse("topic readwrite 1-2").unwrap()))
+        .limits(Limits {
+            max_subscriptions: Some(1),
+            ..Default::default()
+        })
+        .client_limit(RateLimit {
+            messages: Some(1),
+            bytes: None,
+            action: RateAction::Drop,
+        })
+        .settings()
+        .clone();
+    broker.reload(settings);
+    sleep(Duration::from_millis(100)).await;
+
+    alice.subscribe(2).await.unwrap();
+    match recv(&mut alice).await {
+        Some(Some(MqttPacket::Suback(suback))) => {
+            assert_eq!(suback.topic_name, 2);
+            assert_eq!(suback.return_code, SubackReturnCode::Failure);
+        }
+        other => panic!("expected a suback, got {:?}", other),
+    }
+    for _ in 0..3 {
+        alice.publish(1, QoS::AtMostOnce, vec![1]).await.unwrap();
+    }
+    assert!(matches!(
+        recv(&mut alice).await,
+        Some(Some(MqttPacket::Publish(_)))
+    ));
+    assert!(recv(&mut alice).await.is_none());
+    assert_eq!(broker.stats().throttled(), 2);
+}
+
+#[tokio::test]
+async fn listener_limits_change_in_place() {
+    let mut listen: ListenAddr = "127.0.0.1:0,max-connections=1".parse().unwrap();
+    let listener = Listener::bind(&listen, false).await.unwrap().remove(0);
+    let addr = listener.local_addr().unwrap().to_string();
+    let broker = Broker::spawn();
+    tokio::spawn(async move { broker.serve(&listener).await });
+
+    let options = ConnectOptions {
+        client_id: Some("counted".to_string()),
+        ..Default::default()
+    };
+    let _first = Client::connect_with(&addr, &options).await.unwrap();
+    assert!(Client::connect_with(&addr, &options).await.is_err());
+
+    let new: ListenAddr = "127.0.0.1:0,max-connections=2".parse().unwrap();
+    assert!(!listen.rebind(&new));
+    listen.reload(&new);
+    let _second = Client::connect_with(&addr, &options).await.unwrap();
+    assert!(Client::connect_with(&addr, &options).await.is_err());
+}
@@ -0,0 +1,122 @@
+use bytes::BytesMut;
+use mqtiny::{
+    broker::Broker,
+    client::Client,
+    listener::{ListenAddr, Listener},
+    MQTinyCodec, MqttPacket, QoS,
+};
+use std::{io, time::Duration};
+use tokio::time::sleep;
+use tokio_util::codec::Decoder;
+
+async fn start_broker() -> String {
+    let broker = Broker::spawn();
+    let listener = Listener::bind(&ListenAddr::new("127.0.0.1:0"), false)
+        .await
+        .unwrap()
+        .remove(0);
+    let addr = listener.local_addr().unwrap().to_string();
+    tokio::spawn(async move { broker.serve(&listener).await });
+    addr
+}
+
+/// Serves requests on topic 1 with their payload reversed.
+async fn start_responder(addr: &str) {
+    let mut responder = Client::connect(addr).await.unwrap();
+    tokio::spawn(async move {
+        responder
+            .serve(1, |request| async move {
+                request.payload.into_iter().rev().collect()
+            })
+            .await
+    });
+    sleep(Duration::from_millis(100)).await;
+}
+
+#[tokio::test]
+async fn requests_get_their_own_responses() {
+    let addr = start_broker().await;
+    start_responder(&addr).await;
+
+    // Both requesters share the response topic.
+    let mut first = Client::connect(&addr).await.unwrap();
+    first.subscribe_responses(2).await.unwrap();
+    let mut second = Client::connect(&addr).await.unwrap();
+    second.subscribe_responses(2).await.unwrap();
+    sleep(Duration::from_millis(100)).await;
+
+    let timeout = Duration::from_secs(1);
+    for i in 0..5u8 {
+        let (a, b) = tokio::join!(
+            first.request(1, vec![i, 1], timeout),
+            second.request(1, vec![i, 2], timeout),
+        );
+        assert_eq!(a.unwrap().payload, [1, i]);
+        assert_eq!(b.unwrap().payload, [2, i]);
+    }
+}
+
+#[tokio::test]
+async fn other_packets_are_kept_while_waiting() {
+    let addr = start_broker().await;
+    start_responder(&addr).await;
+    let mut requester = Client::connect(&addr).await.unwrap();
+    requester.subscribe_responses(2).await.unwrap();
+    requester.subscribe(3).await.unwrap();
+    sleep(Duration::from_millis(100)).await;
+
+    let mut publisher = Client::connect(&addr).await.unwrap();
+    publisher
+        .publish(3, QoS::AtMostOnce, b"news".to_vec())
+        .await
+        .unwrap();
+    sleep(Duration::from_millis(100)).await;
+
+    let response = requester
+        .request(1, b"ping".to_vec(), Duration::from_secs(1))
+        .await
+        .unwrap();
+    assert_eq!(response.payload, b"gnip");
+    let Some(Ok(MqttPacket::Publish(publish))) = requester.recv().await else {
+        panic!("the publish received during the request was lost");
+    };
+    assert_eq!(publish.payload, b"news");
+
+    // Nobody serves topic 4.
+    let e = requester
+        .request(4, b"ping".to_vec(), Duration::from_millis(200))
+        .await
+        .unwrap_err();
+    assert_eq!(e.kind(), io::ErrorKind::TimedOut);
+}
+
+#[test]
+fn malformed_publishes_fail_to_decode() {
+    let decode = |packet: &[u8]| MQTinyCodec::default().decode(&mut BytesMut::from(packet));
+
+    // Topic 7, a response topic and correlation data "ab", payload "x".
+    let valid = [0x38, 10, 0, 7, 0x18, 0, 8, 0, 2, b'a', b'b', b'x'];
+    let Ok(Some(MqttPacket::Publish(publish))) = decode(&valid) else {
+        panic!("the valid publish did not decode");
+    };
+    assert_eq!(publish.response_topic, Some(8));
+    assert_eq!(publish.correlation_data, Some(b"ab".to_vec()));
+    assert_eq!(publish.payload, b"x");
+
+    for invalid in [
+        // QoS 3.
+        &[0x36, 2, 0, 7][..],
+        // Cut short in the topic.
+        &[0x30, 1, 0],
+        // Properties flagged but missing.
+        &[0x38, 2, 0, 7],
+        // A sequence number cut short.
+        &[0x38, 7, 0, 7, 0x01, 0, 0, 0, 1],
+        // An expiry interval cut short.
+        &[0x38, 5, 0, 7, 0x02, 0, 1],
+        // Correlation data longer than the packet.
+        &[0x38, 8, 0, 7, 0x18, 0, 8, 0, 5, b'a'],
+    ] {
+        assert!(decode(invalid).is_err(), "{:?}", invalid);
+    }
+}
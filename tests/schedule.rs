@@ -0,0 +1,100 @@
+use mqtiny::{
+    admin::{self, AdminClient},
+    broker::Broker,
+    client::Client,
+    listener::{ListenAddr, Listener},
+    MqttPacket, QoS,
+};
+use std::{
+    io,
+    sync::atomic::Ordering,
+    time::{Duration, SystemTime},
+};
+use tokio::{
+    net::TcpListener,
+    time::{sleep, timeout},
+};
+
+/// Starts a broker and its admin API, returning the broker, its address and
+/// an admin client.
+async fn start_broker() -> (Broker, String, AdminClient) {
+    let broker = Broker::spawn();
+    let listener = Listener::bind(&ListenAddr::new("127.0.0.1:0"), false)
+        .await
+        .unwrap()
+        .remove(0);
+    let addr = listener.local_addr().unwrap().to_string();
+    let admin_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let admin = AdminClient::new(&admin_listener.local_addr().unwrap().to_string());
+    tokio::spawn(admin::serve(admin_listener, broker.clone()));
+    let serving = broker.clone();
+    tokio::spawn(async move { serving.serve(&listener).await });
+
+    (broker, addr, admin)
+}
+
+/// The payloads received until none arrives for `idle`.
+async fn received(client: &mut Client, idle: Duration) -> Vec<String> {
+    let mut received = Vec::new();
+    while let Ok(Some(Ok(MqttPacket::Publish(publish)))) = timeout(idle, client.recv()).await {
+        received.push(String::from_utf8(publish.payload).unwrap());
+    }
+    received
+}
+
+#[tokio::test]
+async fn delayed_publishes_are_routed_when_due_unless_cancelled() {
+    let (broker, addr, admin) = start_broker().await;
+    let mut subscriber = Client::connect(&addr).await.unwrap();
+    subscriber.subscribe(1).await.unwrap();
+    sleep(Duration::from_millis(100)).await;
+
+    let mut publisher = Client::connect(&addr).await.unwrap();
+    publisher
+        .publish_delayed(
+            1,
+            QoS::AtMostOnce,
+            b"later".to_vec(),
+            Duration::from_secs(2),
+        )
+        .await
+        .unwrap();
+    publisher
+        .publish_delayed(
+            1,
+            QoS::AtMostOnce,
+            b"cancelled".to_vec(),
+            Duration::from_secs(2),
+        )
+        .await
+        .unwrap();
+    let past = SystemTime::now() - Duration::from_secs(10);
+    publisher
+        .publish_at(1, QoS::AtMostOnce, b"overdue".to_vec(), past)
+        .await
+        .unwrap();
+    publisher
+        .publish(1, QoS::AtMostOnce, b"now".to_vec())
+        .await
+        .unwrap();
+    assert_eq!(
+        received(&mut subscriber, Duration::from_millis(300)).await,
+        ["now"]
+    );
+
+    // The overdue one is routed on the next turn of the wheel.
+    let scheduled = admin.scheduled().await.unwrap();
+    assert!((2..=3).contains(&scheduled.len()), "{:?}", scheduled);
+    let cancelled = scheduled.iter().find(|s| s.size == 9).unwrap();
+    assert_eq!(cancelled.topic, 1);
+    admin.cancel(cancelled.id).await.unwrap();
+    let e = admin.cancel(cancelled.id).await.unwrap_err();
+    assert_eq!(e.kind(), io::ErrorKind::NotFound);
+
+    assert_eq!(
+        received(&mut subscriber, Duration::from_secs(3)).await,
+        ["overdue", "later"]
+    );
+    assert!(admin.scheduled().await.unwrap().is_empty());
+    assert_eq!(broker.stats().scheduled.load(Ordering::Relaxed), 0);
+}
@@ -0,0 +1,222 @@
+use mqtiny::{
+    broker::Broker,
+    client::{Client, ConnectOptions},
+    listener::{ListenAddr, Listener},
+    MqttPacket, QoS, ShareStrategy,
+};
+use std::time::Duration;
+use tokio::time::{sleep, timeout};
+
+async fn start_broker() -> (String, Broker) {
+    let broker = Broker::spawn();
+    let listener = Listener::bind(&ListenAddr::new("127.0.0.1:0"), false)
+        .await
+        .unwrap()
+        .remove(0);
+    let addr = listener.local_addr().unwrap().to_string();
+    let serving = broker.clone();
+    tokio::spawn(async move { serving.serve(&listener).await });
+    (addr, broker)
+}
+
+/// The payloads received until none arrives for 300ms.
+async fn received(client: &mut Client) -> Vec<String> {
+    let mut received = Vec::new();
+    while let Ok(Some(Ok(MqttPacket::Publish(publish)))) =
+        timeout(Duration::from_millis(300), client.recv()).await
+    {
+        received.push(String::from_utf8(publish.payload).unwrap());
+    }
+    received
+}
+
+async fn publish_all(publisher: &mut Client, topic: u16, qos: QoS, payloads: &[&str]) {
+    for payload in payloads {
+        publisher
+            .publish(topic, qos, payload.as_bytes().to_vec())
+            .await
+            .unwrap();
+    }
+}
+
+#[tokio::test]
+async fn each_group_gets_a_publish_once() {
+    let (addr, _broker) = start_broker().await;
+    let mut members = Vec::new();
+    for _ in 0..3 {
+        let mut member = Client::connect(&addr).await.unwrap();
+        member
+            .subscribe_shared(5, "workers", ShareStrategy::RoundRobin)
+            .await
+            .unwrap();
+        members.push(member);
+    }
+    let mut auditor = Client::connect(&addr).await.unwrap();
+    auditor
+        .subscribe_shared(5, "audit", ShareStrategy::RoundRobin)
+        .await
+        .unwrap();
+    let mut subscriber = Client::connect(&addr).await.unwrap();
+    subscriber.subscribe(5).await.unwrap();
+    sleep(Duration::from_millis(100)).await;
+
+    let mut publisher = Client::connect(&addr).await.unwrap();
+    let payloads = ["1", "2", "3", "4", "5", "6"];
+    publish_all(&mut publisher, 5, QoS::AtMostOnce, &payloads).await;
+
+    let mut all = Vec::new();
+    for member in &mut members {
+        let got = received(member).await;
+        assert_eq!(got.len(), 2, "round robin is even: {:?}", got);
+        all.extend(got);
+    }
+    all.sort();
+    assert_eq!(all, payloads);
+    assert_eq!(received(&mut auditor).await, payloads);
+    assert_eq!(received(&mut subscriber).await, payloads);
+
+    // A member that leaves gets no more.
+    members[0].unsubscribe_shared(5, "workers").await.unwrap();
+    sleep(Duration::from_millis(100)).await;
+    publish_all(&mut publisher, 5, QoS::AtMostOnce, &["7", "8"]).await;
+    assert!(received(&mut members[0]).await.is_empty());
+    assert_eq!(received(&mut members[1]).await.len(), 1);
+    assert_eq!(received(&mut members[2]).await.len(), 1);
+}
+
+#[tokio::test]
+async fn sticky_groups_keep_a_publisher_on_one_member() {
+    let (addr, _broker) = start_broker().await;
+    let mut members = Vec::new();
+    for _ in 0..3 {
+        let mut member = Client::connect(&addr).await.unwrap();
+        member
+            .subscribe_shared(5, "workers", ShareStrategy::Sticky)
+            .await
+            .unwrap();
+        members.push(member);
+    }
+    sleep(Duration::from_millis(100)).await;
+
+    for name in ["a", "b", "c", "d"] {
+        let options = ConnectOptions {
+            client_id: Some(name.to_string()),
+            ..Default::default()
+        };
+        let mut publisher = Client::connect_with(&addr, &options).await.unwrap();
+        let payloads: Vec<String> = (0..5).map(|i| format!("{}{}", name, i)).collect();
+        let payloads: Vec<&str> = payloads.iter().map(String::as_str).collect();
+        publish_all(&mut publisher, 5, QoS::AtMostOnce, &payloads).await;
+    }
+
+    let mut total = 0;
+    for member in &mut members {
+        let got = received(member).await;
+        total += got.len();
+        for name in ["a", "b", "c", "d"] {
+            let count = got.iter().filter(|p| p.starts_with(name)).count();
+            assert!(count == 0 || count == 5, "{} split: {:?}", name, got);
+        }
+    }
+    assert_eq!(total, 20);
+}
+
+#[tokio::test]
+async fn least_queued_groups_avoid_a_stalled_member() {
+    let (addr, broker) = start_broker().await;
+    // Never reads, so publishes queue up for it once its 64KiB buffer fills;
+    // round robin would give it half of them.
+    let mut stalled = broker
+        .connect_local(&ConnectOptions::default())
+        .await
+        .unwrap();
+    stalled
+        .subscribe_shared(5, "workers", ShareStrategy::LeastQueued)
+        .await
+        .unwrap();
+    let mut member = Client::connect(&addr).await.unwrap();
+    member
+        .subscribe_shared(5, "workers", ShareStrategy::LeastQueued)
+        .await
+        .unwrap();
+    sleep(Duration::from_millis(100)).await;
+
+    let reading = tokio::spawn(async move { received(&mut member).await.len() });
+    let mut publisher = Client::connect(&addr).await.unwrap();
+    for _ in 0..2000 {
+        publisher
+            .publish(5, QoS::AtMostOnce, vec![0; 200])
+            .await
+            .unwrap();
+    }
+    let count = reading.await.unwrap();
+    assert!(count >= 1200, "the reading member got {} of 2000", count);
+}
+
+#[tokio::test]
+async fn unacknowledged_publishes_go_to_another_member() {
+    let (addr, _broker) = start_broker().await;
+    let mut leaving = Client::connect(&addr).await.unwrap();
+    leaving
+        .subscribe_shared(5, "workers", ShareStrategy::RoundRobin)
+        .await
+        .unwrap();
+    let mut staying = Client::connect(&addr).await.unwrap();
+    staying
+        .subscribe_shared(5, "workers", ShareStrategy::RoundRobin)
+        .await
+        .unwrap();
+    sleep(Duration::from_millis(100)).await;
+
+    let mut publisher = Client::connect(&addr).await.unwrap();
+    publish_all(&mut publisher, 5, QoS::AtLeastOnce, &["1", "2", "3", "4"]).await;
+    let mut left = received(&mut leaving).await;
+    assert_eq!(left.len(), 2);
+    // One acknowledged, one not.
+    leaving.ack().await.unwrap();
+    let mut kept = received(&mut staying).await;
+    assert_eq!(kept.len(), 2);
+    sleep(Duration::from_millis(100)).await;
+
+    drop(leaving);
+    let redistributed = received(&mut staying).await;
+    assert_eq!(redistributed, left[1..]);
+
+    kept.extend(redistributed);
+    left.truncate(1);
+    kept.extend(left);
+    kept.sort();
+    assert_eq!(kept, ["1", "2", "3", "4"]);
+}
+
+#[tokio::test]
+async fn acks_match_publishes_of_plain_and_shared_subscriptions() {
+    let (addr, _broker) = start_broker().await;
+    let mut mixed = Client::connect(&addr).await.unwrap();
+    mixed.subscribe(6).await.unwrap();
+    sleep(Duration::from_millis(100)).await;
+    let mut publisher = Client::connect(&addr).await.unwrap();
+    publish_all(&mut publisher, 6, QoS::AtLeastOnce, &["plain"]).await;
+    assert_eq!(received(&mut mixed).await, ["plain"]);
+
+    // Joins a group before acknowledging the plain publish.
+    mixed
+        .subscribe_shared(5, "workers", ShareStrategy::RoundRobin)
+        .await
+        .unwrap();
+    sleep(Duration::from_millis(100)).await;
+    publish_all(&mut publisher, 5, QoS::AtLeastOnce, &["shared"]).await;
+    assert_eq!(received(&mut mixed).await, ["shared"]);
+
+    let mut staying = Client::connect(&addr).await.unwrap();
+    staying
+        .subscribe_shared(5, "workers", ShareStrategy::RoundRobin)
+        .await
+        .unwrap();
+    // Acknowledges the plain publish only.
+    mixed.ack().await.unwrap();
+    sleep(Duration::from_millis(100)).await;
+
+    drop(mixed);
+    assert_eq!(received(&mut staying).await, ["shared"]);
+}
@@ -0,0 +1,314 @@
+use mqtiny::{
+    broker::Broker,
+    client::{Client, ConnectOptions},
+    listener::{ListenAddr, Listener},
+    MqttPacket, QoS,
+};
+use std::{
+    collections::HashSet,
+    fs::{self, OpenOptions},
+    io::Write,
+    path::Path,
+    process::{Child, Command, Stdio},
+    time::Duration,
+};
+use tokio::time::{sleep, timeout, Instant};
+
+/// A broker process, killed when dropped.
+struct BrokerProcess {
+    child: Child,
+    addr: String,
+}
+
+impl BrokerProcess {
+    /// Starts the broker binary on a free port with its store at `store`, and
+    /// waits until it accepts connections.
+    async fn start(store: &Path) -> BrokerProcess {
+        let port = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let addr = format!("127.0.0.1:{}", port);
+        let child = Command::new(env!("CARGO_BIN_EXE_test"))
+            .args([
+                "--listen",
+                &addr,
+                "--fsync",
+                "always",
+                "--log-level",
+                "warn",
+            ])
+            .arg("--store")
+            .arg(store)
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+            .unwrap();
+        let broker = BrokerProcess { child, addr };
+
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while tokio::net::TcpStream::connect(&broker.addr).await.is_err() {
+            assert!(Instant::now() < deadline, "the broker did not start");
+            sleep(Duration::from_millis(20)).await;
+        }
+        broker
+    }
+
+    /// Kills the broker with SIGKILL, as a crash would.
+    fn kill(mut self) {
+        self.child.kill().unwrap();
+        self.child.wait().unwrap();
+    }
+}
+
+impl Drop for BrokerProcess {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+async fn connect_persistent(addr: &str, client_id: &str) -> Client {
+    let options = ConnectOptions {
+        client_id: Some(client_id.to_string()),
+        persistent: true,
+        ..Default::default()
+    };
+    Client::connect_with(addr, &options).await.unwrap()
+}
+
+/// Receives and acknowledges publishes until none arrives for 500ms, and
+/// returns their payloads.
+async fn drain(client: &mut Client) -> Vec<Vec<u8>> {
+    let mut payloads = Vec::new();
+    while let Ok(Some(Ok(packet))) = timeout(Duration::from_millis(500), client.recv()).await {
+        if let MqttPacket::Publish(publish) = packet {
+            if publish.qos != QoS::AtMostOnce {
+                client.ack().await.unwrap();
+            }
+            payloads.push(publish.payload);
+        }
+    }
+    payloads
+}
+
+#[tokio::test]
+async fn acknowledged_publishes_survive_a_crash() {
+    let dir = tempfile::tempdir().unwrap();
+    let store = dir.path().join("mqtiny.wal");
+
+    let broker = BrokerProcess::start(&store).await;
+    let mut sink = connect_persistent(&broker.addr, "sink").await;
+    sink.subscribe(1).await.unwrap();
+    sleep(Duration::from_millis(100)).await;
+    drop(sink);
+
+    // Publish one at a time until the broker is killed under the publisher,
+    // remembering which publishes it acknowledged.
+    let mut publisher = Client::connect(&broker.addr).await.unwrap();
+    let pid = broker.child.id();
+    let killer = tokio::spawn(async move {
+        sleep(Duration::from_millis(300)).await;
+        broker.kill();
+    });
+    let mut acked = HashSet::new();
+    for n in 0u32.. {
+        let payload = n.to_be_bytes().to_vec();
+        if publisher
+            .publish(1, QoS::AtLeastOnce, payload.clone())
+            .await
+            .is_err()
+        {
+            break;
+        }
+        match publisher.recv().await {
+            Some(Ok(MqttPacket::Puback(_))) => acked.insert(payload),
+            _ => break,
+        };
+    }
+    killer.await.unwrap();
+    assert!(
+        acked.len() > 10,
+        "only {} publishes acknowledged",
+        acked.len()
+    );
+
+    let broker = BrokerProcess::start(&store).await;
+    assert_ne!(broker.child.id(), pid);
+    let mut sink = connect_persistent(&broker.addr, "sink").await;
+    let received: HashSet<_> = drain(&mut sink).await.into_iter().collect();
+    let lost = acked.difference(&received).count();
+    assert_eq!(
+        lost,
+        0,
+        "{} of {} acknowledged publishes lost",
+        lost,
+        acked.len()
+    );
+
+    // The subscription survived too, and acknowledged publishes are gone for
+    // good, so the compacted log is down to the subscription.
+    let mut publisher = Client::connect(&broker.addr).await.unwrap();
+    publisher
+        .publish(1, QoS::AtLeastOnce, b"after".to_vec())
+        .await
+        .unwrap();
+    assert_eq!(drain(&mut sink).await, vec![b"after".to_vec()]);
+    drop(sink);
+    sleep(Duration::from_millis(100)).await;
+    broker.kill();
+
+    let broker = BrokerProcess::start(&store).await;
+    let mut sink = connect_persistent(&broker.addr, "sink").await;
+    assert!(drain(&mut sink).await.is_empty());
+    assert!(fs::metadata(&store).unwrap().len() < 64);
+}
+
+#[tokio::test]
+async fn retained_publishes_survive_a_torn_log() {
+    let dir = tempfile::tempdir().unwrap();
+    let store = dir.path().join("mqtiny.wal");
+
+    let broker = BrokerProcess::start(&store).await;
+    let mut client = Client::connect(&broker.addr).await.unwrap();
+    client
+        .publish_retained(5, QoS::AtLeastOnce, b"on".to_vec())
+        .await
+        .unwrap();
+    assert!(matches!(
+        client.recv().await,
+        Some(Ok(MqttPacket::Puback(_)))
+    ));
+    broker.kill();
+
+    // A record cut short by the crash.
+    let mut log = OpenOptions::new().append(true).open(&store).unwrap();
+    log.write_all(&[0, 0, 0, 40, 0xde, 0xad]).unwrap();
+
+    let broker = BrokerProcess::start(&store).await;
+    let mut client = Client::connect(&broker.addr).await.unwrap();
+    client.subscribe(5).await.unwrap();
+    match timeout(Duration::from_secs(2), client.recv()).await {
+        Ok(Some(Ok(MqttPacket::Publish(publish)))) => {
+            assert_eq!(publish.payload, b"on");
+            assert!(publish.retain);
+        }
+        other => panic!("expected the retained publish, got {:?}", other),
+    }
+}
+
+#[tokio::test]
+async fn retained_publish_reaches_later_subscribers() {
+    let broker = Broker::spawn();
+    let listener = Listener::bind(&ListenAddr::new("127.0.0.1:0"), false)
+        .await
+        .unwrap()
+        .remove(0);
+    let addr = listener.local_addr().unwrap().to_string();
+    tokio::spawn(async move { broker.serve(&listener).await });
+
+    let mut publisher = Client::connect(&addr).await.unwrap();
+    publisher
+        .publish_retained(3, QoS::AtMostOnce, b"first".to_vec())
+        .await
+        .unwrap();
+    publisher
+        .publish_retained(3, QoS::AtMostOnce, b"second".to_vec())
+        .await
+        .unwrap();
+    sleep(Duration::from_millis(100)).await;
+
+    let mut subscriber = Client::connect(&addr).await.unwrap();
+    subscriber.subscribe(3).await.unwrap();
+    assert_eq!(drain(&mut subscriber).await, vec![b"second".to_vec()]);
+
+    // An empty payload clears it.
+    publisher
+        .publish_retained(3, QoS::AtMostOnce, Vec::new())
+        .await
+        .unwrap();
+    sleep(Duration::from_millis(100)).await;
+    let mut late = Client::connect(&addr).await.unwrap();
+    late.subscribe(3).await.unwrap();
+    assert!(drain(&mut late).await.is_empty());
+}
+
+#[tokio::test]
+async fn scheduled_publishes_survive_a_crash() {
+    let dir = tempfile::tempdir().unwrap();
+    let store = dir.path().join("mqtiny.wal");
+    let broker = BrokerProcess::start(&store).await;
+
+    let mut publisher = Client::connect(&broker.addr).await.unwrap();
+    publisher
+        .publish_delayed(
+            4,
+            QoS::AtLeastOnce,
+            b"later".to_vec(),
+            Duration::from_secs(2),
+        )
+        .await
+        .unwrap();
+    // Scheduled once acknowledged.
+    assert!(matches!(
+        publisher.recv().await,
+        Some(Ok(MqttPacket::Puback(_)))
+    ));
+    broker.kill();
+
+    let broker = BrokerProcess::start(&store).await;
+    let mut subscriber = Client::connect(&broker.addr).await.unwrap();
+    subscriber.subscribe(4).await.unwrap();
+    let received = timeout(Duration::from_secs(5), subscriber.recv()).await;
+    let Ok(Some(Ok(MqttPacket::Publish(publish)))) = received else {
+        panic!("the scheduled publish was not routed: {:?}", received);
+    };
+    assert_eq!(publish.payload, b"later");
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn resumed_sessions_get_each_publish_once() {
+    let broker = Broker::spawn();
+    let listener = Listener::bind(&ListenAddr::new("127.0.0.1:0"), false)
+        .await
+        .unwrap()
+        .remove(0);
+    let addr = listener.local_addr().unwrap().to_string();
+    tokio::spawn(async move { broker.serve(&listener).await });
+
+    let mut sink = connect_persistent(&addr, "sink").await;
+    sink.subscribe(1).await.unwrap();
+    sleep(Duration::from_millis(100)).await;
+    drop(sink);
+    sleep(Duration::from_millis(100)).await;
+
+    // Publishes keep coming while the session resumes, some stored just
+    // before it does and routed just after.
+    let mut publishing = Vec::new();
+    for publisher in 0u32..4 {
+        let mut client = Client::connect(&addr).await.unwrap();
+        publishing.push(tokio::spawn(async move {
+            for n in 0u32..1000 {
+                let payload = [publisher.to_be_bytes(), n.to_be_bytes()].concat();
+                client.publish(1, QoS::AtLeastOnce, payload).await.unwrap();
+            }
+            for _ in 0..1000 {
+                match client.recv().await {
+                    Some(Ok(MqttPacket::Puback(_))) => {}
+                    other => panic!("expected a PUBACK, got {:?}", other),
+                }
+            }
+        }));
+    }
+    sleep(Duration::from_millis(5)).await;
+    let mut sink = connect_persistent(&addr, "sink").await;
+    for publishing in publishing {
+        publishing.await.unwrap();
+    }
+
+    let received = drain(&mut sink).await;
+    let unique: HashSet<_> = received.iter().collect();
+    assert_eq!(unique.len(), received.len(), "publishes sent twice");
+    assert_eq!(unique.len(), 4000);
+}
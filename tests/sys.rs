@@ -0,0 +1,132 @@
+use mqtiny::{
+    broker::Broker,
+    client::Client,
+    listener::{ListenAddr, Listener},
+    stats::{
+        SYS_BYTES_IN, SYS_CLIENTS, SYS_DROPPED_DENIED, SYS_INFLIGHT, SYS_RETAINED,
+        SYS_SUBSCRIPTIONS,
+    },
+    MqttPacket, QoS,
+};
+use std::{sync::atomic::Ordering, time::Duration};
+use tokio::time::timeout;
+
+async fn start_broker() -> (String, Broker) {
+    let broker = Broker::builder()
+        .sys_interval(Duration::from_millis(100))
+        .spawn();
+    let listener = Listener::bind(&ListenAddr::new("127.0.0.1:0"), false)
+        .await
+        .unwrap()
+        .remove(0);
+    let addr = listener.local_addr().unwrap().to_string();
+    let serving = broker.clone();
+    tokio::spawn(async move { serving.serve(&listener).await });
+
+    (addr, broker)
+}
+
+/// Waits until `topic` is published with `value`.
+async fn wait_for(client: &mut Client, topic: u16, value: u64) {
+    let mut last = None;
+    let found = timeout(Duration::from_secs(2), async {
+        loop {
+            match client.recv().await {
+                Some(Ok(MqttPacket::Publish(publish))) if publish.topic_name == topic => {
+                    let received = u64::from_be_bytes(publish.payload.try_into().unwrap());
+                    if received == value {
+                        return;
+                    }
+                    last = Some(received);
+                }
+                Some(Ok(_)) => {}
+                other => panic!("connection failed: {:?}", other),
+            }
+        }
+    })
+    .await;
+    assert!(
+        found.is_ok(),
+        "topic {:#x}: expected {}, last got {:?}",
+        topic,
+        value,
+        last
+    );
+}
+
+#[tokio::test]
+async fn statistics_are_published_periodically() {
+    let (addr, _broker) = start_broker().await;
+
+    let mut monitor = Client::connect(&addr).await.unwrap();
+    monitor.subscribe(SYS_CLIENTS).await.unwrap();
+    monitor.subscribe(SYS_SUBSCRIPTIONS).await.unwrap();
+    monitor.subscribe(SYS_BYTES_IN).await.unwrap();
+    let mut publisher = Client::connect(&addr).await.unwrap();
+    publisher
+        .publish(1, QoS::AtMostOnce, b"hello".to_vec())
+        .await
+        .unwrap();
+
+    wait_for(&mut monitor, SYS_CLIENTS, 2).await;
+    wait_for(&mut monitor, SYS_SUBSCRIPTIONS, 3).await;
+    wait_for(&mut monitor, SYS_BYTES_IN, 5).await;
+
+    drop(publisher);
+    wait_for(&mut monitor, SYS_CLIENTS, 1).await;
+}
+
+#[tokio::test]
+async fn clients_cannot_publish_on_statistics_topics() {
+    let (addr, broker) = start_broker().await;
+
+    let mut publisher = Client::connect(&addr).await.unwrap();
+    publisher
+        .publish(SYS_CLIENTS, QoS::AtMostOnce, 1000u64.to_be_bytes().to_vec())
+        .await
+        .unwrap();
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    assert_eq!(broker.stats().publishes_denied.load(Ordering::Relaxed), 1);
+    let mut monitor = Client::connect(&addr).await.unwrap();
+    monitor.subscribe(SYS_DROPPED_DENIED).await.unwrap();
+    wait_for(&mut monitor, SYS_DROPPED_DENIED, 1).await;
+}
+
+#[tokio::test]
+async fn inflight_and_retained_publishes_are_counted() {
+    let (addr, _broker) = start_broker().await;
+
+    let mut monitor = Client::connect(&addr).await.unwrap();
+    monitor.subscribe(SYS_INFLIGHT).await.unwrap();
+    monitor.subscribe(SYS_RETAINED).await.unwrap();
+    let mut subscriber = Client::connect(&addr).await.unwrap();
+    subscriber.subscribe(1).await.unwrap();
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    let mut publisher = Client::connect(&addr).await.unwrap();
+    publisher
+        .publish_retained(2, QoS::AtMostOnce, b"kept".to_vec())
+        .await
+        .unwrap();
+    publisher
+        .publish(1, QoS::AtLeastOnce, b"unacked".to_vec())
+        .await
+        .unwrap();
+    wait_for(&mut monitor, SYS_RETAINED, 1).await;
+    wait_for(&mut monitor, SYS_INFLIGHT, 1).await;
+
+    match timeout(Duration::from_secs(2), subscriber.recv()).await {
+        Ok(Some(Ok(MqttPacket::Publish(_)))) => {}
+        other => panic!("expected a publish, got {:?}", other),
+    }
+    subscriber.ack().await.unwrap();
+    wait_for(&mut monitor, SYS_INFLIGHT, 0).await;
+
+    // An empty retained publish clears the topic.
+    publisher
+        .publish_retained(2, QoS::AtMostOnce, Vec::new())
+        .await
+        .unwrap();
+    wait_for(&mut monitor, SYS_RETAINED, 0).await;
+}
@@ -0,0 +1,119 @@
+use mqtiny::{
+    broker::Broker,
+    client::{Client, ConnectOptions},
+    listener::{ListenAddr, Listener},
+    MqttPacket, QoS,
+};
+use rcgen::{BasicConstraints, Certificate, CertificateParams, IsCa};
+use std::{fs, path::PathBuf, time::Duration};
+use tempfile::TempDir;
+use tokio::time::timeout;
+
+/// A throwaway CA with a server and a client certificate signed by it.
+struct Pki {
+    dir: TempDir,
+}
+
+impl Pki {
+    fn generate() -> Pki {
+        let dir = tempfile::tempdir().unwrap();
+
+        let mut params = CertificateParams::new(vec![]);
+        params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        let ca = Certificate::from_params(params).unwrap();
+        fs::write(dir.path().join("ca.pem"), ca.serialize_pem().unwrap()).unwrap();
+
+        for (name, san) in [("server", "localhost"), ("client", "client")] {
+            let cert = Certificate::from_params(CertificateParams::new(vec![san.into()])).unwrap();
+            let pem = cert.serialize_pem_with_signer(&ca).unwrap();
+            fs::write(dir.path().join(format!("{}.pem", name)), pem).unwrap();
+            let key = cert.serialize_private_key_pem();
+            fs::write(dir.path().join(format!("{}.key", name)), key).unwrap();
+        }
+
+        Pki { dir }
+    }
+
+    fn path(&self, name: &str) -> PathBuf {
+        self.dir.path().join(name)
+    }
+
+    fn client_options(&self, with_cert: bool) -> ConnectOptions {
+        ConnectOptions {
+            ca: Some(self.path("ca.pem")),
+            cert: with_cert.then(|| self.path("client.pem")),
+            key: with_cert.then(|| self.path("client.key")),
+            ..Default::default()
+        }
+    }
+}
+
+/// Starts a broker with a TLS listener and returns the address to connect to.
+async fn start_broker(pki: &Pki, require_client_cert: bool) -> String {
+    let mut listen = format!(
+        "tls://127.0.0.1:0,cert={},key={}",
+        pki.path("server.pem").display(),
+        pki.path("server.key").display()
+    );
+    if require_client_cert {
+        listen += &format!(",client-ca={}", pki.path("ca.pem").display());
+    }
+
+    let listener = Listener::bind(&listen.parse::<ListenAddr>().unwrap(), false)
+        .await
+        .unwrap()
+        .remove(0);
+    let port = listener.local_addr().unwrap().to_string();
+    let port = port.rsplit(':').next().unwrap().to_string();
+
+    let broker = Broker::spawn();
+    tokio::spawn(async move { broker.serve(&listener).await });
+
+    format!("tls://localhost:{}", port)
+}
+
+async fn round_trip(addr: &str, options: &ConnectOptions) -> std::io::Result<Vec<u8>> {
+    let mut subscriber = Client::connect_with(addr, options).await?;
+    subscriber.subscribe(1).await?;
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    let mut publisher = Client::connect_with(addr, options).await?;
+    publisher
+        .publish(1, QoS::AtMostOnce, b"secret".to_vec())
+        .await?;
+
+    match timeout(Duration::from_secs(5), subscriber.recv()).await {
+        Ok(Some(Ok(MqttPacket::Publish(publish)))) => Ok(publish.payload),
+        Ok(Some(Err(e))) => Err(e),
+        other => panic!("expected a publish, got {:?}", other),
+    }
+}
+
+#[tokio::test]
+async fn publishes_are_delivered_over_tls() {
+    let pki = Pki::generate();
+    let addr = start_broker(&pki, false).await;
+
+    let payload = round_trip(&addr, &pki.client_options(false)).await.unwrap();
+    assert_eq!(payload, b"secret");
+}
+
+#[tokio::test]
+async fn client_must_trust_the_broker_certificate() {
+    let pki = Pki::generate();
+    let addr = start_broker(&pki, false).await;
+
+    // Without the test CA the self-signed chain is rejected.
+    assert!(Client::connect(&addr).await.is_err());
+}
+
+#[tokio::test]
+async fn client_certificate_is_required_when_configured() {
+    let pki = Pki::generate();
+    let addr = start_broker(&pki, true).await;
+
+    assert!(round_trip(&addr, &pki.client_options(false)).await.is_err());
+
+    let payload = round_trip(&addr, &pki.client_options(true)).await.unwrap();
+    assert_eq!(payload, b"secret");
+}
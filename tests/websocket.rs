@@ -0,0 +1,100 @@
+use futures::{SinkExt, StreamExt};
+use mqtiny::{
+    broker::Broker,
+    client::Client,
+    listener::{ListenAddr, Listener},
+    MqttPacket, QoS,
+};
+use std::time::Duration;
+use tokio::time::timeout;
+use tokio_tungstenite::{connect_async, tungstenite::Message};
+
+/// Starts a broker with a TCP and a WebSocket listener on ephemeral ports.
+async fn start_broker() -> (String, String) {
+    let broker = Broker::spawn();
+    let mut addrs = Vec::new();
+    for listen in ["127.0.0.1:0", "ws://127.0.0.1:0"] {
+        let listener = Listener::bind(&ListenAddr::new(listen), false)
+            .await
+            .unwrap()
+            .remove(0);
+        addrs.push(listener.local_addr().unwrap().to_string());
+        let broker = broker.clone();
+        tokio::spawn(async move { broker.serve(&listener).await });
+    }
+
+    (addrs.remove(0), addrs.remove(0))
+}
+
+async fn recv_publish(client: &mut Client) -> (u16, Vec<u8>) {
+    match timeout(Duration::from_secs(5), client.recv()).await {
+        Ok(Some(Ok(MqttPacket::Publish(publish)))) => (publish.topic_name, publish.payload),
+        other => panic!("expected a publish, got {:?}", other),
+    }
+}
+
+#[tokio::test]
+async fn websocket_client_receives_tcp_publishes() {
+    let (tcp, ws) = start_broker().await;
+
+    let (mut ws, _) = connect_async(format!("{}/", ws)).await.unwrap();
+    // Subscribe to topic 7.
+    ws.send(Message::Binary(vec![0x80, 2, 0, 7])).await.unwrap();
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    let mut publisher = Client::connect(&tcp).await.unwrap();
+    publisher
+        .publish(7, QoS::AtMostOnce, b"hello".to_vec())
+        .await
+        .unwrap();
+
+    let message = timeout(Duration::from_secs(5), ws.next())
+        .await
+        .unwrap()
+        .unwrap()
+        .unwrap();
+    assert_eq!(
+        message,
+        Message::Binary(vec![0x30, 7, 0, 7, b'h', b'e', b'l', b'l', b'o'])
+    );
+}
+
+#[tokio::test]
+async fn websocket_messages_may_carry_several_or_partial_packets() {
+    let (tcp, ws) = start_broker().await;
+
+    let mut subscriber = Client::connect(&tcp).await.unwrap();
+    subscriber.subscribe(3).await.unwrap();
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    let (mut ws, _) = connect_async(format!("{}/", ws)).await.unwrap();
+    // Two publishes in one message, then one publish split across two.
+    ws.send(Message::Binary(vec![
+        0x30, 3, 0, 3, b'a', 0x30, 3, 0, 3, b'b',
+    ]))
+    .await
+    .unwrap();
+    ws.send(Message::Binary(vec![0x30, 3, 0])).await.unwrap();
+    ws.send(Message::Binary(vec![3, b'c'])).await.unwrap();
+
+    for expected in [b"a", b"b", b"c"] {
+        assert_eq!(recv_publish(&mut subscriber).await, (3, expected.to_vec()));
+    }
+}
+
+#[tokio::test]
+async fn client_library_connects_over_websocket() {
+    let (_, ws) = start_broker().await;
+
+    let mut subscriber = Client::connect(&ws).await.unwrap();
+    subscriber.subscribe(9).await.unwrap();
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    let mut publisher = Client::connect(&ws).await.unwrap();
+    publisher
+        .publish(9, QoS::AtMostOnce, b"ws".to_vec())
+        .await
+        .unwrap();
+
+    assert_eq!(recv_publish(&mut subscriber).await, (9, b"ws".to_vec()));
+}